@@ -1,6 +1,7 @@
 // build.rs
 
-use std::path::PathBuf;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // set by cargo, build scripts should use this directory for output files
@@ -9,15 +10,40 @@ fn main() {
     // https://doc.rust-lang.org/nightly/cargo/reference/unstable.html#artifact-dependencies
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_kernel").unwrap());
 
+    // pack the `ramdisk` directory into a tar archive, mounted by the kernel as its root filesystem
+    let ramdisk_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("ramdisk");
+    let ramdisk_path = out_dir.join("ramdisk.tar");
+    create_ramdisk(&ramdisk_dir, &ramdisk_path);
+    println!("cargo:rerun-if-changed={}", ramdisk_dir.display());
+
     // create an UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path).unwrap();
+    bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
+        .create_disk_image(&uefi_path)
+        .unwrap();
 
     // create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path).unwrap();
+    bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk_path)
+        .create_disk_image(&bios_path)
+        .unwrap();
 
     // pass the disk image paths as env variables to the `main.rs`
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
-}
\ No newline at end of file
+}
+
+// `tar::Builder` writes GNU headers and stores paths over 100 bytes as GNU long-name entries,
+// both of which the kernel reads. `HeaderMode::Deterministic` only fixes the mtime, uid/gid and
+// mode bits so that the image is the same on every build
+fn create_ramdisk(source: &Path, destination: &Path) {
+    let mut builder = tar::Builder::new(File::create(destination).unwrap());
+    builder.mode(tar::HeaderMode::Deterministic);
+    builder.follow_symlinks(true);
+    if source.is_dir() {
+        builder.append_dir_all(".", source).unwrap();
+    }
+    builder.into_inner().unwrap();
+}
@@ -0,0 +1,150 @@
+use acpi::fadt::Fadt as FadtTable;
+use acpi::sdt::Signature;
+use acpi::{AcpiHandler, AcpiTables, PciConfigRegions, PhysicalMapping};
+use conquer_once::spin::OnceCell;
+use core::ptr::NonNull;
+use log::debug;
+use x86_64::PhysAddr;
+use crate::memory::phys_to_virt;
+
+/// 一つのバスのコンフィギュレーション空間の大きさ(32デバイス x 8ファンクション x 4KiB)
+const BUS_SIZE: u64 = 1 << 20;
+
+static TABLES: OnceCell<AcpiTables<KernelAcpiHandler>> = OnceCell::uninit();
+static PCI_CONFIG_REGIONS: OnceCell<PciConfigRegions> = OnceCell::uninit();
+
+/// `acpi`クレートがテーブルを読むときに使う。物理メモリ全体がマップされているので、対応する仮想アドレスを返すだけでよい
+#[derive(Debug, Clone, Copy)]
+pub struct KernelAcpiHandler;
+
+impl AcpiHandler for KernelAcpiHandler {
+    unsafe fn map_physical_region<T>(&self, physical_address: usize, size: usize) -> PhysicalMapping<Self, T> {
+        let virt = phys_to_virt(PhysAddr::new(physical_address as u64));
+        PhysicalMapping::new(physical_address, NonNull::new(virt.as_mut_ptr()).unwrap(), size, size, *self)
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+}
+
+/// MCFGのエントリ一つ。一つのセグメントのバスの範囲と、そのECAM領域の物理アドレス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// バス0に対応するアドレス。`start_bus`の領域はこの`start_bus << 20`バイト後ろから始まる
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// FADTのうち、ACPIモードへの切り替えとスリープに使うフィールド
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    /// 0ならファームウェアは最初からACPIモードで動いている
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub pm1a_control: u32,
+    /// 0ならPM1bは無い
+    pub pm1b_control: u32,
+}
+
+/// bootloaderが渡したRSDPの物理アドレスから、ファームウェアのACPIテーブルを読む
+/// テーブルは物理メモリ全体のマップを通して読むので、`memory::init_global`の後に呼ぶ
+pub fn init(rsdp_addr: Option<u64>) {
+    let rsdp = match rsdp_addr {
+        Some(rsdp) => rsdp as usize,
+        None => {
+            debug!("acpi: no RSDP was passed by the bootloader");
+            return;
+        }
+    };
+    let tables = match unsafe { AcpiTables::from_rsdp(KernelAcpiHandler, rsdp) } {
+        Ok(tables) => tables,
+        Err(err) => {
+            debug!("acpi: failed to read the tables: {:?}", err);
+            return;
+        }
+    };
+    debug!("acpi: {} tables", tables.sdts.len());
+    match PciConfigRegions::new(&tables) {
+        Ok(regions) => {
+            let _ = PCI_CONFIG_REGIONS.try_init_once(|| regions);
+        }
+        Err(err) => debug!("acpi: no PCI configuration regions: {:?}", err),
+    }
+    if TABLES.try_init_once(|| tables).is_err() {
+        debug!("acpi: tables are already loaded");
+    }
+}
+
+pub fn tables() -> Option<&'static AcpiTables<KernelAcpiHandler>> {
+    TABLES.get()
+}
+
+/// MCFGに載っている、セグメント`segment`のPCI Expressのコンフィギュレーション空間の領域
+///
+/// `PciConfigRegions`は各ファンクションのアドレスしか教えてくれないので、バスごとに引いて範囲を求める
+pub fn pci_config_region(segment: u16) -> Option<McfgEntry> {
+    let regions = PCI_CONFIG_REGIONS.get()?;
+    let address = |bus: u8| regions.physical_address(segment, bus, 0, 0);
+    let start_bus = (0..=u8::MAX).find(|bus| address(*bus).is_some())?;
+    let end_bus = (start_bus..=u8::MAX).take_while(|bus| address(*bus).is_some()).last()?;
+    Some(McfgEntry {
+        base_address: address(start_bus)? - start_bus as u64 * BUS_SIZE,
+        segment,
+        start_bus,
+        end_bus,
+    })
+}
+
+pub fn fadt() -> Option<Fadt> {
+    let fadt = unsafe { tables()?.get_sdt::<FadtTable>(Signature::FADT) }.ok()??;
+    let pm1a_control = fadt.pm1a_control_block().ok()?.address as u32;
+    let pm1b_control = fadt.pm1b_control_block().ok()?.map_or(0, |block| block.address as u32);
+    Some(Fadt {
+        smi_command: fadt.smi_cmd_port,
+        acpi_enable: fadt.acpi_enable,
+        pm1a_control,
+        pm1b_control,
+    })
+}
+
+/// DSDTのAML。`acpi`クレートがFADTからたどってくれている
+pub fn dsdt() -> Option<&'static [u8]> {
+    let dsdt = tables()?.dsdt.as_ref()?;
+    let aml = phys_to_virt(PhysAddr::new(dsdt.address as u64));
+    Some(unsafe { core::slice::from_raw_parts(aml.as_ptr(), dsdt.length as usize) })
+}
+
+/// AMLから`\_S5`(ソフトオフ)オブジェクトを探し、PM1aとPM1bに書くSLP_TYPを返す
+///
+/// AMLを解釈せずに、`Name(_S5, Package() {a, b, ...})`のバイト列を直接読む
+pub fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const ROOT_CHAR: u8 = b'\\';
+
+    let start = aml.windows(4).enumerate().position(|(i, name)| {
+        name == b"_S5_"
+            && i >= 1
+            && (aml[i - 1] == NAME_OP || (i >= 2 && aml[i - 1] == ROOT_CHAR && aml[i - 2] == NAME_OP))
+    })?;
+    let mut bytes = aml.get(start + 4..)?.iter().copied();
+    if bytes.next()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLengthは、先頭バイトの上位2ビットが後に続くバイト数を表す
+    let lead = bytes.next()?;
+    for _ in 0..lead >> 6 {
+        bytes.next()?;
+    }
+    let _elements = bytes.next()?;
+    let mut integer = || match bytes.next()? {
+        // BytePrefix
+        0x0A => bytes.next(),
+        // ZeroOp、OneOp、またはプレフィックスの無い値
+        value => Some(value),
+    };
+    let a = integer()?;
+    let b = integer()?;
+    Some((a, b))
+}
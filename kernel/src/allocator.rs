@@ -12,7 +12,7 @@ pub mod fixed_size_block;
 pub mod linked_list;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+pub const HEAP_SIZE: usize = 8 * 1024 * 1024; // 8MiB
 
 #[global_allocator]
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
@@ -49,6 +49,18 @@ pub fn init_heap(
     Ok(())
 }
 
+/// ヒープの使用状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
 }
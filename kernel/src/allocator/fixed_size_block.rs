@@ -4,7 +4,7 @@ use core::{
     ptr::{self, NonNull},
 };
 
-use super::Locked;
+use super::{HeapStats, Locked};
 
 /// 使用するブロックサイズ
 /// これらは2の累乗でなければならない。
@@ -76,6 +76,29 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// リストに戻されたブロックは代替アロケータからは使用中に見えるので、空きとして数え直す
+    pub fn stats(&self) -> HeapStats {
+        let cached: usize = self
+            .list_heads
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(head, size)| {
+                let mut count = 0;
+                let mut node = head.as_deref();
+                while let Some(current) = node {
+                    count += 1;
+                    node = current.next.as_deref();
+                }
+                count * size
+            })
+            .sum();
+        HeapStats {
+            size: self.fallback_allocator.size(),
+            used: self.fallback_allocator.used() - cached,
+            free: self.fallback_allocator.free() + cached,
+        }
+    }
+
     // 代替アロケータを使って割り当てを行う
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
@@ -1,6 +1,124 @@
-use bootloader_api::BootInfo;
+//! Local APICと、MSIなどのメッセージ割り込みに使うベクタの割り当て
 
-pub fn init(info: &BootInfo)
-{
-    let platform = AcpiTable
-}
\ No newline at end of file
+pub mod msi;
+
+use conquer_once::spin::OnceCell;
+use log::debug;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use crate::memory::mmio::{self, MmioRegion};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+const REG_ID: usize = 0x20;
+const REG_TASK_PRIORITY: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REGISTERS_SIZE: usize = 0x400;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// 宛先の省略形で自分自身を指定する
+const ICR_DESTINATION_SELF: u32 = 0b01 << 18;
+
+/// MSIのアドレスの上位。下位の19:12bitに宛先のAPIC IDを入れる
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// 動的に割り当てるベクタの先頭。8259 PICのベクタ(32から48)とは重ならない
+pub const DYNAMIC_VECTOR_START: u8 = 0x50;
+pub const DYNAMIC_VECTOR_COUNT: usize = 32;
+
+/// `DYNAMIC_VECTOR_START`からの番号ごとのハンドラ
+type Handlers = [Option<fn()>; DYNAMIC_VECTOR_COUNT];
+
+static LOCAL_APIC: OnceCell<MmioRegion> = OnceCell::uninit();
+static HANDLERS: Mutex<Handlers> = Mutex::new([None; DYNAMIC_VECTOR_COUNT]);
+
+/// Local APICのレジスタをマップして有効にする。`memory::init_global`の後に呼ぶ
+pub fn init() {
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { msr.read() };
+    let registers = match mmio::map(base & APIC_BASE_ADDRESS_MASK, REGISTERS_SIZE) {
+        Ok(registers) => registers,
+        Err(err) => {
+            debug!("apic: failed to map the local APIC: {:?}", err);
+            return;
+        }
+    };
+    unsafe { msr.write(base | APIC_BASE_ENABLE) };
+    registers.write32(REG_TASK_PRIORITY, 0);
+    registers.write32(REG_SPURIOUS, SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    if LOCAL_APIC.try_init_once(|| registers).is_err() {
+        debug!("apic: local APIC is already initialized");
+        return;
+    }
+    debug!("apic: local APIC {} at {:#x}", id(), base & APIC_BASE_ADDRESS_MASK);
+}
+
+fn local_apic() -> Option<&'static MmioRegion> {
+    LOCAL_APIC.get()
+}
+
+/// 今のCPUのAPIC ID
+pub fn id() -> u8 {
+    local_apic().map(|registers| (registers.read32(REG_ID) >> 24) as u8).unwrap_or(0)
+}
+
+pub fn end_of_interrupt() {
+    if let Some(registers) = local_apic() {
+        registers.write32(REG_EOI, 0);
+    }
+}
+
+/// 今のCPUに割り込みを届けるMSIのアドレス。データにはベクタ番号をそのまま使う
+pub fn msi_address() -> u64 {
+    MSI_ADDRESS_BASE | (id() as u64) << 12
+}
+
+/// 空いているベクタを一つ確保し、割り込みが来たら`handler`を呼ぶようにする
+pub fn allocate_vector(handler: fn()) -> Option<u8> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let index = handlers.iter().position(|slot| slot.is_none())?;
+        handlers[index] = Some(handler);
+        Some(DYNAMIC_VECTOR_START + index as u8)
+    })
+}
+
+pub fn free_vector(vector: u8) {
+    if let Some(index) = vector.checked_sub(DYNAMIC_VECTOR_START) {
+        interrupts::without_interrupts(|| {
+            if let Some(slot) = HANDLERS.lock().get_mut(index as usize) {
+                *slot = None;
+            }
+        });
+    }
+}
+
+/// 動的に割り当てたベクタの割り込みハンドラから呼ばれる
+pub(crate) fn dispatch(index: usize) {
+    // ハンドラの中でベクタを確保、解放できるように、ロックを外してから呼ぶ
+    let handler = HANDLERS.lock()[index];
+    match handler {
+        Some(handler) => handler(),
+        None => debug!("apic: unexpected interrupt on vector {:#x}", DYNAMIC_VECTOR_START as usize + index),
+    }
+    end_of_interrupt();
+}
+
+/// 自分自身に`vector`の割り込みを送る。割り込みの経路を確かめるのに使う
+pub fn send_self_ipi(vector: u8) {
+    if let Some(registers) = local_apic() {
+        registers.write32(REG_ICR_HIGH, 0);
+        registers.write32(REG_ICR_LOW, ICR_DESTINATION_SELF | vector as u32);
+        while registers.read32(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use alloc::vec::Vec;
+use pci::capability::{
+    Msi, MsiX, MSI_X_ENTRY_ADDRESS_HIGH, MSI_X_ENTRY_ADDRESS_LOW, MSI_X_ENTRY_DATA, MSI_X_ENTRY_SIZE,
+    MSI_X_ENTRY_VECTOR_CONTROL,
+};
+use pci::device::Device;
+use pci::error::PciError;
+use pci::register::Command;
+use crate::memory::mmio;
+
+/// デバイスのメッセージ割り込みを有効にし、`handlers`の一つずつにベクタを割り当てる
+///
+/// MSI-Xがあればそれを、無ければMSIを使う。MSIではベクタを一つしか使わない
+/// 返り値のi番目が、デバイスのi番目の割り込みに割り当てたベクタ
+pub fn enable(device: &Device, handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+    if let Some(msi_x) = MsiX::read(device) {
+        return enable_msi_x(device, &msi_x, handlers);
+    }
+    if let Some(msi) = Msi::read(device) {
+        return enable_msi(device, &msi, handlers);
+    }
+    Err(PciError::NoMsi)
+}
+
+fn enable_msi(device: &Device, msi: &Msi, handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+    if handlers.len() > 1 {
+        return Err(PciError::TooManyVectors);
+    }
+    let vectors = allocate(handlers)?;
+    if let Some(&vector) = vectors.first() {
+        msi.enable(device, super::msi_address(), vector as u16, 1);
+        device.enable_command(Command::INTERRUPT_DISABLE);
+    }
+    Ok(vectors)
+}
+
+fn enable_msi_x(device: &Device, msi_x: &MsiX, handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+    if handlers.len() > msi_x.table_size as usize {
+        return Err(PciError::TooManyVectors);
+    }
+    // テーブルはドライバがマップしたレジスタと同じBARにあることが多い。`map_bar`は同じ領域を返す
+    let table = mmio::map_bar(device, msi_x.table_bar)?;
+    let vectors = allocate(handlers)?;
+    if let Some(msi) = Msi::read(device) {
+        msi.disable(device);
+    }
+
+    // テーブルを書き換えている間に中途半端なメッセージが送られないように、全体をマスクしておく
+    msi_x.set_function_mask(device, true);
+    msi_x.set_enabled(device, true);
+    let address = super::msi_address();
+    for (index, &vector) in vectors.iter().enumerate() {
+        let entry = msi_x.table_offset as usize + index * MSI_X_ENTRY_SIZE;
+        table.write32(entry + MSI_X_ENTRY_ADDRESS_LOW, address as u32);
+        table.write32(entry + MSI_X_ENTRY_ADDRESS_HIGH, (address >> 32) as u32);
+        table.write32(entry + MSI_X_ENTRY_DATA, vector as u32);
+        table.write32(entry + MSI_X_ENTRY_VECTOR_CONTROL, 0);
+    }
+    msi_x.set_function_mask(device, false);
+    device.enable_command(Command::INTERRUPT_DISABLE);
+    Ok(vectors)
+}
+
+/// 全部確保できなければ、途中まで確保したベクタを返して失敗する
+fn allocate(handlers: &[fn()]) -> Result<Vec<u8>, PciError> {
+    let mut vectors = Vec::with_capacity(handlers.len());
+    for &handler in handlers {
+        match super::allocate_vector(handler) {
+            Some(vector) => vectors.push(vector),
+            None => {
+                vectors.into_iter().for_each(super::free_vector);
+                return Err(PciError::NoFreeVector);
+            }
+        }
+    }
+    Ok(vectors)
+}
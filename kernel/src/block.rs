@@ -0,0 +1,203 @@
+pub mod ahci;
+pub mod ata;
+pub mod cache;
+pub mod error;
+pub mod identify;
+pub mod nvme;
+pub mod partition;
+pub mod ram_disk;
+pub mod virtio_blk;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use log::debug;
+use spin::Mutex;
+use crate::block::error::BlockError;
+
+lazy_static! {
+    /// ドライバが見つけたブロックデバイスの一覧
+    static ref DEVICES: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+}
+
+struct Registered {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    /// バッファキャッシュを通さずにドライバを呼ぶデバイス
+    uncached: Arc<dyn BlockDevice>,
+    /// パーティションなら、それを含むデバイスの名前
+    parent: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub sector_size: usize,
+    pub sector_count: u64,
+    pub read_only: bool,
+}
+
+impl Geometry {
+    pub fn capacity(&self) -> u64 {
+        self.sector_size as u64 * self.sector_count
+    }
+}
+
+/// セクタ単位で読み書きするストレージデバイス
+///
+/// 複数のファイルシステムやタスクから共有できるように、`&self`で読み書きする
+pub trait BlockDevice: Send + Sync {
+    fn geometry(&self) -> Geometry;
+
+    /// `lba`から`buf.len() / sector_size()`セクタを読み込む
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// `lba`から`buf.len() / sector_size()`セクタを書き込む
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// デバイスや途中のキャッシュに溜まっている書き込みを完了させる
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    fn sector_size(&self) -> usize {
+        self.geometry().sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.geometry().sector_count
+    }
+}
+
+/// 読み書きの範囲がデバイスに収まっているかを確認する
+pub fn check_range(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), BlockError> {
+    let sector_size = device.sector_size();
+    if !len.is_multiple_of(sector_size) {
+        return Err(BlockError::InvalidBuffer);
+    }
+    let count = (len / sector_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// `condition`が満たされるまで最大`spins`回ポーリングする
+pub(crate) fn wait_until(spins: usize, mut condition: impl FnMut() -> bool) -> Result<(), BlockError> {
+    for _ in 0..spins {
+        if condition() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(BlockError::Timeout)
+}
+
+/// ストレージコントローラのドライバを登録し、PCIバスで見つかったデバイスに結び付ける
+pub fn init() {
+    pci::register_driver(&ahci::DRIVER);
+    pci::register_driver(&nvme::DRIVER);
+    pci::register_driver(&virtio_blk::DRIVER);
+    // 他のコントローラが見つからない場合のためのフォールバック
+    pci::register_driver(&ata::DRIVER);
+    pci::probe();
+}
+
+/// ドライバが見つけたデバイスを登録する。登録されたデバイスはバッファキャッシュを通して使われる
+///
+/// パーティションテーブルがあれば、各パーティションも`<name><番号>`として登録する
+pub fn register(name: String, device: Arc<dyn BlockDevice>) -> Arc<dyn BlockDevice> {
+    let cached: Arc<dyn BlockDevice> = cache::BUFFER_CACHE.attach(device.clone());
+    DEVICES.lock().push(Registered {
+        name: name.clone(),
+        device: cached.clone(),
+        uncached: device.clone(),
+        parent: None,
+    });
+
+    // パーティションは元のデバイスのキャッシュを通して読み書きするので、重ねてキャッシュしない
+    match partition::partitions(&cached) {
+        Ok(partitions) => {
+            for partition in partitions {
+                let partition_name = partition_name(&name, partition.info().number);
+                debug!(
+                    "block: {}: {} sectors from {}",
+                    partition_name,
+                    partition.info().sector_count,
+                    partition.info().start_lba
+                );
+                DEVICES.lock().push(Registered {
+                    name: partition_name,
+                    uncached: Arc::new(partition::Partition::new(device.clone(), partition.info().clone())),
+                    device: Arc::new(partition),
+                    parent: Some(name.clone()),
+                });
+            }
+        }
+        Err(err) => debug!("block: {}: cannot read the partition table: {}", name, err),
+    }
+    cached
+}
+
+/// `prefix`の後にディスクの番号を表す英字を続けた名前を作る。`z`の次は`aa`になる
+pub fn disk_name(prefix: &str, index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        letters.push(b'a' + (rest % 26) as u8);
+        rest /= 26;
+    }
+    letters.reverse();
+    format!("{}{}", prefix, String::from_utf8(letters).unwrap())
+}
+
+/// 名前が数字で終わるデバイス(`nvme0n1`など)は、番号との間に`p`を挟む
+fn partition_name(device: &str, number: usize) -> String {
+    if device.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{}p{}", device, number)
+    } else {
+        format!("{}{}", device, number)
+    }
+}
+
+/// 取り外されたデバイスを、そのパーティションと一緒に一覧から外す。外した名前を返す
+///
+/// 変更はデバイスを使う者がいなくなったときに書き戻される
+pub fn unregister(name: &str) -> Vec<String> {
+    // 最後の参照を落とすと書き戻しが走るので、ロックを離してから捨てる
+    let removed: Vec<Registered> = {
+        let mut devices = DEVICES.lock();
+        let (removed, kept) = core::mem::take(&mut *devices)
+            .into_iter()
+            .partition(|registered| registered.name == name || registered.parent.as_deref() == Some(name));
+        *devices = kept;
+        removed
+    };
+    removed.into_iter().map(|registered| registered.name).collect()
+}
+
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES.lock()
+        .iter()
+        .map(|registered| (registered.name.clone(), registered.device.clone()))
+        .collect()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock()
+        .iter()
+        .find(|registered| registered.name == name)
+        .map(|registered| registered.device.clone())
+}
+
+/// バッファキャッシュを通さずに、ドライバへ直接読み書きするデバイス
+///
+/// キャッシュに載っている変更とは食い違うことがあるので、ドライバを試すときだけに使う
+pub fn find_uncached(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock()
+        .iter()
+        .find(|registered| registered.name == name)
+        .map(|registered| registered.uncached.clone())
+}
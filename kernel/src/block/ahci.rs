@@ -0,0 +1,342 @@
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use log::debug;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::identify::IdentifyData;
+use crate::block::{check_range, register, wait_until, BlockDevice, Geometry};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::mmio::{self, MmioRegion};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pci::device::Device;
+use pci::register::Command;
+use pci::{DeviceMatch, Driver};
+
+/// AHCIのベースアドレス(ABAR)はBAR5に入っている
+const ABAR_INDEX: usize = 5;
+
+// HBAの共通レジスタ
+const HBA_GHC: usize = 0x04;
+const HBA_PI: usize = 0x0C;
+const HBA_CAP2: usize = 0x24;
+const HBA_BOHC: usize = 0x28;
+
+const GHC_HR: u32 = 1 << 0;
+const GHC_AE: u32 = 1 << 31;
+const CAP2_BOH: u32 = 1 << 0;
+const BOHC_BOS: u32 = 1 << 0;
+const BOHC_OOS: u32 = 1 << 1;
+
+// ポートごとのレジスタ
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_FB: usize = 0x08;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+const IS_TFES: u32 = 1 << 30;
+const SSTS_DET_PRESENT: u32 = 3;
+const SSTS_IPM_ACTIVE: u32 = 1;
+const SIG_ATA: u32 = 0x0000_0101;
+/// デバイスから最初のFISがまだ届いていない
+const SIG_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+// ポートごとのDMA領域の中の配置。コマンドリストは1KiB、受信FISは256B、コマンドテーブルは128Bの境界に揃える
+const COMMAND_LIST_OFFSET: usize = 0x000;
+const RECEIVED_FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x800;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+const PRDT_OFFSET: usize = 0x80;
+
+/// 一度のコマンドで転送する最大のサイズ
+const BOUNCE_SIZE: usize = 64 * 1024;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+/// レジスタの変化を待つときのループ回数の上限
+const TIMEOUT_SPINS: usize = 10_000_000;
+/// デバイスが繋がっていないポートのリンクを待つときは短めにする
+const LINK_TIMEOUT_SPINS: usize = 100_000;
+
+/// PCIバス上のAHCIコントローラを初期化し、繋がっているSATAディスクを`sata0`, `sata1`...として登録する
+pub static DRIVER: Driver = Driver {
+    name: "ahci",
+    matches: &[DeviceMatch::Class { base: 0x01, sub: 0x06, interface: Some(0x01) }],
+    probe,
+};
+
+/// コントローラをまたいで通し番号を振る
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &Device) -> bool {
+    let controller = match AhciController::new(device) {
+        Ok(controller) => controller,
+        Err(err) => {
+            debug!("ahci: {}.{}.{}: {}", device.bus, device.device, device.function, err);
+            return false;
+        }
+    };
+    for disk in controller.disks() {
+        let index = DISK_COUNT.fetch_add(1, Ordering::Relaxed);
+        debug!("ahci: sata{}: {} ({} sectors)", index, disk.identify.model, disk.geometry.sector_count);
+        register(format!("sata{}", index), Arc::new(disk));
+    }
+    true
+}
+
+pub struct AhciController {
+    hba: MmioRegion,
+}
+
+impl AhciController {
+    pub fn new(device: &Device) -> Result<Self, BlockError> {
+        // メモリ空間へのアクセスとバスマスタ(DMA)を有効にする
+        device.enable_command(Command::MEMORY_SPACE | Command::BUS_MASTER);
+
+        let hba = mmio::map_bar(device, ABAR_INDEX)?;
+
+        // ファームウェアがコントローラを使っていれば、所有権を譲ってもらう
+        if hba.read32(HBA_CAP2) & CAP2_BOH != 0 {
+            hba.write32(HBA_BOHC, hba.read32(HBA_BOHC) | BOHC_OOS);
+            wait_until(TIMEOUT_SPINS, || hba.read32(HBA_BOHC) & BOHC_BOS == 0)?;
+        }
+
+        hba.write32(HBA_GHC, hba.read32(HBA_GHC) | GHC_AE);
+        hba.write32(HBA_GHC, hba.read32(HBA_GHC) | GHC_HR);
+        wait_until(TIMEOUT_SPINS, || hba.read32(HBA_GHC) & GHC_HR == 0)?;
+        // リセットでAEも落ちるので、もう一度AHCIモードにする。割り込みは使わない
+        hba.write32(HBA_GHC, GHC_AE);
+
+        Ok(Self { hba })
+    }
+
+    /// 実装されているポートを順に初期化し、ATAデバイスが繋がっているものを返す
+    pub fn disks(&self) -> Vec<AhciDisk> {
+        let implemented = self.hba.read32(HBA_PI);
+        (0..32)
+            .filter(|index| implemented & (1 << index) != 0)
+            .filter_map(|index| match AhciDisk::new(self.hba, index) {
+                Ok(disk) => Some(disk),
+                Err(BlockError::NoDevice) => None,
+                Err(err) => {
+                    debug!("ahci: port {}: {}", index, err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// ポートのレジスタ
+struct PortRegisters {
+    hba: MmioRegion,
+    index: usize,
+}
+
+impl PortRegisters {
+    fn read(&self, register: usize) -> u32 {
+        self.hba.read32(PORT_BASE + self.index * PORT_SIZE + register)
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        self.hba.write32(PORT_BASE + self.index * PORT_SIZE + register, value)
+    }
+
+    fn stop(&self) -> Result<(), BlockError> {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        wait_until(TIMEOUT_SPINS, || self.read(PX_CMD) & CMD_CR == 0)?;
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        wait_until(TIMEOUT_SPINS, || self.read(PX_CMD) & CMD_FR == 0)
+    }
+
+    fn wait_idle(&self) -> Result<(), BlockError> {
+        wait_until(TIMEOUT_SPINS, || self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0)
+    }
+}
+
+/// デバイスが繋がっているポートと、そのためのメモリ
+struct Port {
+    registers: PortRegisters,
+    /// コマンドリスト、受信FIS、コマンドテーブル
+    memory: DmaBuffer,
+    bounce: DmaBuffer,
+}
+
+impl Port {
+    /// コマンドスロット0を使ってコマンドを発行し、完了するまで待つ
+    /// データはバウンスバッファの先頭`bytes`バイトとやり取りする
+    fn issue(&mut self, command: u8, lba: u64, count: u16, bytes: usize, write: bool) -> Result<(), BlockError> {
+        let table = self.memory.phys_addr() + COMMAND_TABLE_OFFSET as u64;
+        let prdt_length: u32 = if bytes > 0 { 1 } else { 0 };
+        // DW0: FISの長さ(DWORD単位)、書き込みかどうか、PRDTのエントリ数
+        let flags = 5 | (write as u32) << 6 | prdt_length << 16;
+        self.memory.write::<u32>(COMMAND_LIST_OFFSET, flags);
+        self.memory.write::<u32>(COMMAND_LIST_OFFSET + 4, 0);
+        self.memory.write::<u64>(COMMAND_LIST_OFFSET + 8, table);
+
+        self.memory.as_mut_slice()[COMMAND_TABLE_OFFSET..COMMAND_TABLE_OFFSET + COMMAND_TABLE_SIZE].fill(0);
+        let fis = [
+            FIS_TYPE_REG_H2D,
+            1 << 7,
+            command,
+            0,
+            lba as u8,
+            (lba >> 8) as u8,
+            (lba >> 16) as u8,
+            1 << 6,
+            (lba >> 24) as u8,
+            (lba >> 32) as u8,
+            (lba >> 40) as u8,
+            0,
+            count as u8,
+            (count >> 8) as u8,
+            0,
+            0,
+        ];
+        self.memory.as_mut_slice()[COMMAND_TABLE_OFFSET..COMMAND_TABLE_OFFSET + fis.len()].copy_from_slice(&fis);
+        if bytes > 0 {
+            let prd = COMMAND_TABLE_OFFSET + PRDT_OFFSET;
+            self.memory.write::<u64>(prd, self.bounce.phys_addr());
+            self.memory.write::<u32>(prd + 12, (bytes - 1) as u32);
+        }
+
+        self.registers.wait_idle()?;
+        self.registers.write(PX_IS, u32::MAX);
+        self.registers.write(PX_CI, 1);
+        wait_until(TIMEOUT_SPINS, || self.registers.read(PX_CI) & 1 == 0 || self.registers.read(PX_IS) & IS_TFES != 0)?;
+        if self.registers.read(PX_IS) & IS_TFES != 0 || self.registers.read(PX_TFD) & TFD_ERR != 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+}
+
+/// AHCIのポートに繋がったSATAディスク
+pub struct AhciDisk {
+    port: Mutex<Port>,
+    geometry: Geometry,
+    identify: IdentifyData,
+}
+
+impl AhciDisk {
+    fn new(hba: MmioRegion, index: usize) -> Result<Self, BlockError> {
+        let registers = PortRegisters { hba, index };
+        registers.stop()?;
+        registers.write(PX_SERR, u32::MAX);
+        registers.write(PX_IS, u32::MAX);
+        registers.write(PX_IE, 0);
+
+        // DMAに使うメモリは解放できないので、デバイスが繋がっているポートにだけ確保する
+        let linked = wait_until(LINK_TIMEOUT_SPINS, || {
+            let status = registers.read(PX_SSTS);
+            status & 0xF == SSTS_DET_PRESENT && (status >> 8) & 0xF == SSTS_IPM_ACTIVE
+        });
+        if linked.is_err() {
+            return Err(BlockError::NoDevice);
+        }
+        // ATAPIやポートマルチプライヤには対応しない。シグネチャが届いていれば、ここで分かる
+        let signature = registers.read(PX_SIG);
+        if signature != SIG_ATA && signature != SIG_UNKNOWN {
+            return Err(BlockError::NoDevice);
+        }
+
+        let mut port = Port {
+            registers,
+            memory: DmaBuffer::new(4096).ok_or(BlockError::NoMemory)?,
+            bounce: DmaBuffer::new(BOUNCE_SIZE).ok_or(BlockError::NoMemory)?,
+        };
+        let list = port.memory.phys_addr() + COMMAND_LIST_OFFSET as u64;
+        let fis = port.memory.phys_addr() + RECEIVED_FIS_OFFSET as u64;
+        let registers = &port.registers;
+        registers.write(PX_CLB, list as u32);
+        registers.write(PX_CLB + 4, (list >> 32) as u32);
+        registers.write(PX_FB, fis as u32);
+        registers.write(PX_FB + 4, (fis >> 32) as u32);
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_FRE);
+        registers.wait_idle()?;
+        if registers.read(PX_SIG) != SIG_ATA {
+            return Err(BlockError::NoDevice);
+        }
+        registers.write(PX_CMD, registers.read(PX_CMD) | CMD_ST);
+
+        port.issue(ATA_IDENTIFY, 0, 0, 512, false)?;
+        let identify = IdentifyData::parse(port.bounce.as_slice()[..512].try_into().unwrap());
+        if !identify.lba48 || !identify.has_valid_sector_size() || identify.sector_size > BOUNCE_SIZE {
+            return Err(BlockError::NoDevice);
+        }
+
+        Ok(Self {
+            geometry: Geometry {
+                sector_size: identify.sector_size,
+                sector_count: identify.sector_count,
+                read_only: false,
+            },
+            identify,
+            port: Mutex::new(port),
+        })
+    }
+
+    pub fn identify(&self) -> &IdentifyData {
+        &self.identify
+    }
+
+    fn sectors_per_command(&self) -> usize {
+        (BOUNCE_SIZE / self.geometry.sector_size).min(u16::MAX as usize)
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut port = self.port.lock();
+        let chunk_size = self.sectors_per_command() * self.geometry.sector_size;
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let count = chunk.len() / self.geometry.sector_size;
+            let start = lba + (index * self.sectors_per_command()) as u64;
+            port.issue(ATA_READ_DMA_EXT, start, count as u16, chunk.len(), false)?;
+            chunk.copy_from_slice(&port.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut port = self.port.lock();
+        let chunk_size = self.sectors_per_command() * self.geometry.sector_size;
+        for (index, chunk) in buf.chunks(chunk_size).enumerate() {
+            let count = chunk.len() / self.geometry.sector_size;
+            let start = lba + (index * self.sectors_per_command()) as u64;
+            port.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            port.issue(ATA_WRITE_DMA_EXT, start, count as u16, chunk.len(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.port.lock().issue(ATA_FLUSH_CACHE_EXT, 0, 0, 0, false)
+    }
+}
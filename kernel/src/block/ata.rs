@@ -0,0 +1,371 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use log::debug;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use crate::block::error::BlockError;
+use crate::block::identify::IdentifyData;
+use crate::block::{check_range, disk_name, register, wait_until, BlockDevice, Geometry};
+use pci::device::Device;
+use pci::{DeviceMatch, Driver};
+
+/// 互換モードのIDEコントローラが使うI/Oポート
+const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
+// コマンドブロックのレジスタ
+const REG_DATA: u16 = 0;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+/// デバイス制御レジスタで立てると割り込みが止まる
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_SECTORS: u8 = 0x20;
+const ATA_READ_SECTORS_EXT: u8 = 0x24;
+const ATA_WRITE_SECTORS: u8 = 0x30;
+const ATA_WRITE_SECTORS_EXT: u8 = 0x34;
+const ATA_FLUSH_CACHE: u8 = 0xE7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+const SECTOR_SIZE: usize = 512;
+/// LBA28でも一度に扱えるセクタ数
+const SECTORS_PER_COMMAND: usize = 128;
+const LBA28_LIMIT: u64 = 1 << 28;
+const TIMEOUT_SPINS: usize = 10_000_000;
+
+/// IRQ 14/15が届いたかどうか
+static IRQ_RECEIVED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+/// これまでに届いたIRQ 14/15の数
+static IRQ_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// 互換モードのチャンネル。PICがIRQ 14/15を通すようになったら割り込みモードに切り替える
+static LEGACY: Mutex<Vec<Arc<Mutex<AtaChannel>>>> = Mutex::new(Vec::new());
+
+/// 割り込みハンドラから呼ばれる。ステータスレジスタを読むとデバイスの割り込みが解除される
+pub(crate) fn handle_interrupt(channel: usize) {
+    let (base, _) = LEGACY_CHANNELS[channel];
+    let _status: u8 = unsafe { Port::new(base + REG_STATUS).read() };
+    IRQ_RECEIVED[channel].store(true, Ordering::Release);
+    IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 互換モードのチャンネルを割り込みモードにする。`interrupts::init_pics`がIRQ 14/15を通してから呼ぶ
+///
+/// `block::init`の時点ではまだIRQが届かないので、それまではポーリングで動かす
+pub fn enable_interrupts() {
+    for channel in LEGACY.lock().iter() {
+        channel.lock().set_mode(Mode::Interrupt);
+    }
+}
+
+/// 割り込みモードで動いているチャンネルの数
+pub fn interrupt_channels() -> usize {
+    LEGACY.lock().iter().filter(|channel| channel.lock().mode == Mode::Interrupt).count()
+}
+
+pub fn interrupt_count() -> usize {
+    IRQ_COUNT.load(Ordering::Relaxed)
+}
+
+/// デバイスの完了を待つ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Polling,
+    /// IRQ 14/15を待つ。互換モードのチャンネルでしか使えない
+    Interrupt,
+}
+
+/// PCIのIDEコントローラを受け持ち、各チャンネルのドライブを`hda`, `hdb`...として登録する。`hdz`の次は`hdaa`
+/// 互換モードのチャンネルは、`enable_interrupts`の後は割り込みを待つ
+pub static DRIVER: Driver = Driver {
+    name: "ata",
+    matches: &[DeviceMatch::Class { base: 0x01, sub: 0x01, interface: None }],
+    probe,
+};
+
+/// コントローラをまたいでドライブ名を振るための通し番号。チャンネルごとに2つずつ進める
+static DRIVE_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(controller: &Device) -> bool {
+    let interface = controller.read_class_code().interface();
+    for (channel, &(legacy_base, legacy_control)) in LEGACY_CHANNELS.iter().enumerate() {
+        // プログラミングインターフェースのbit 0/2が立っていれば、そのチャンネルはBARのポートを使う
+        let native = interface & (1 << (channel * 2)) != 0;
+        let (base, control) = if native {
+            let port = |index| controller.bar(index).ok().and_then(|bar| bar.port()).unwrap_or(0);
+            (port(channel * 2), port(channel * 2 + 1) + 2)
+        } else {
+            (legacy_base, legacy_control)
+        };
+        let index = DRIVE_INDEX.fetch_add(2, Ordering::Relaxed);
+        if base == 0 {
+            continue;
+        }
+
+        let channel = Arc::new(Mutex::new(AtaChannel::new(channel, base, control, Mode::Polling)));
+        let mut found = false;
+        for slave in [false, true] {
+            let name = disk_name("hd", index + slave as usize);
+            match AtaDisk::new(channel.clone(), slave) {
+                Ok(disk) => {
+                    debug!("ata: {}: {} ({} sectors, {})", name, disk.identify.model, disk.geometry.sector_count,
+                           if native { "native" } else { "legacy" });
+                    register(name, Arc::new(disk));
+                    found = true;
+                }
+                Err(BlockError::NoDevice) => {}
+                Err(err) => debug!("ata: {}: {}", name, err),
+            }
+        }
+        // ネイティブモードのチャンネルのIRQはPCIの割り込み線で届くが、まだ受け取れないのでポーリングのまま
+        if found && !native {
+            LEGACY.lock().push(channel);
+        }
+    }
+    true
+}
+
+/// マスタとスレーブで共有する一つのチャンネル
+pub struct AtaChannel {
+    index: usize,
+    base: u16,
+    control: u16,
+    mode: Mode,
+}
+
+impl AtaChannel {
+    pub fn new(index: usize, base: u16, control: u16, mode: Mode) -> Self {
+        let mut channel = Self { index, base, control, mode };
+        channel.set_mode(mode);
+        channel
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// 割り込みモードでなければ、デバイスが割り込みを上げないようにしておく
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        let control = if mode == Mode::Interrupt { 0 } else { CONTROL_NIEN };
+        unsafe { Port::<u8>::new(self.control).write(control) };
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// 代替ステータスレジスタは、読んでも割り込みを解除しない
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// ドライブを選択した後などは、ステータスが更新されるまで400ns待つ必要がある
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn select(&self, slave: bool, head: u8) {
+        self.write(REG_DRIVE, 0xA0 | 1 << 6 | (slave as u8) << 4 | head);
+        self.delay();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        wait_until(TIMEOUT_SPINS, || self.alternate_status() & STATUS_BSY == 0)?;
+        Ok(self.alternate_status())
+    }
+
+    /// 割り込みモードならIRQが届くまで待つ。割り込みが止められている間はIRQが届かないので待たない
+    fn wait_interrupt(&self) -> Result<(), BlockError> {
+        if self.mode == Mode::Interrupt && x86_64::instructions::interrupts::are_enabled() {
+            wait_until(TIMEOUT_SPINS, || IRQ_RECEIVED[self.index].swap(false, Ordering::Acquire))?;
+        }
+        Ok(())
+    }
+
+    /// データの転送準備ができるまで待つ
+    /// 書き込みの最初のセクタではデバイスは割り込みを上げないので、`interrupt`を偽にする
+    fn wait_data(&self, interrupt: bool) -> Result<(), BlockError> {
+        if interrupt {
+            self.wait_interrupt()?;
+        }
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Io);
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn command(&self, command: u8) {
+        IRQ_RECEIVED[self.index].store(false, Ordering::Release);
+        self.write(REG_COMMAND, command);
+    }
+
+    fn read_data(&self, buf: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_data(&self, buf: &[u8]) {
+        let mut port = Port::<u16>::new(self.base + REG_DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    fn identify(&self, slave: bool) -> Result<IdentifyData, BlockError> {
+        self.select(slave, 0);
+        self.write(REG_SECTOR_COUNT, 0);
+        self.write(REG_LBA_LOW, 0);
+        self.write(REG_LBA_MID, 0);
+        self.write(REG_LBA_HIGH, 0);
+        self.command(ATA_IDENTIFY);
+        // ステータスが0ならドライブは繋がっていない。0xFFはチャンネル自体が無い
+        let status = self.alternate_status();
+        if status == 0 || status == 0xFF {
+            return Err(BlockError::NoDevice);
+        }
+        self.wait_not_busy().map_err(|_| BlockError::NoDevice)?;
+        // ATAPIやSATAのデバイスはここにシグネチャを返してくる
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return Err(BlockError::NoDevice);
+        }
+        self.wait_data(true)?;
+        let mut data = [0u8; 512];
+        self.read_data(&mut data);
+        Ok(IdentifyData::parse(&data))
+    }
+
+    /// LBAとセクタ数を設定してコマンドを発行する。LBA48では上位バイトを先に書く
+    fn issue(&self, slave: bool, lba: u64, count: usize, lba48: bool, command: u8) -> Result<(), BlockError> {
+        self.wait_not_busy()?;
+        if lba48 {
+            self.select(slave, 0);
+            self.write(REG_SECTOR_COUNT, (count >> 8) as u8);
+            self.write(REG_LBA_LOW, (lba >> 24) as u8);
+            self.write(REG_LBA_MID, (lba >> 32) as u8);
+            self.write(REG_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(slave, ((lba >> 24) & 0xF) as u8);
+        }
+        self.write(REG_SECTOR_COUNT, count as u8);
+        self.write(REG_LBA_LOW, lba as u8);
+        self.write(REG_LBA_MID, (lba >> 8) as u8);
+        self.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        self.command(command);
+        Ok(())
+    }
+}
+
+/// IDEチャンネルに繋がったATAドライブ
+pub struct AtaDisk {
+    channel: Arc<Mutex<AtaChannel>>,
+    slave: bool,
+    geometry: Geometry,
+    identify: IdentifyData,
+}
+
+impl AtaDisk {
+    pub fn new(channel: Arc<Mutex<AtaChannel>>, slave: bool) -> Result<Self, BlockError> {
+        let identify = channel.lock().identify(slave)?;
+        if identify.sector_count == 0 {
+            return Err(BlockError::NoDevice);
+        }
+        Ok(Self {
+            channel,
+            slave,
+            // PIOでは論理セクタサイズに関わらず512バイトずつ転送する
+            geometry: Geometry {
+                sector_size: SECTOR_SIZE,
+                sector_count: identify.sector_count,
+                read_only: false,
+            },
+            identify,
+        })
+    }
+
+    pub fn identify(&self) -> &IdentifyData {
+        &self.identify
+    }
+
+    fn use_lba48(&self, lba: u64, count: usize) -> bool {
+        self.identify.lba48 && lba + count as u64 > LBA28_LIMIT
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let channel = self.channel.lock();
+        for (index, chunk) in buf.chunks_mut(SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let start = lba + (index * SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.use_lba48(start, count);
+            channel.issue(self.slave, start, count, lba48, if lba48 { ATA_READ_SECTORS_EXT } else { ATA_READ_SECTORS })?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                channel.wait_data(true)?;
+                channel.read_data(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let channel = self.channel.lock();
+        for (index, chunk) in buf.chunks(SECTORS_PER_COMMAND * SECTOR_SIZE).enumerate() {
+            let start = lba + (index * SECTORS_PER_COMMAND) as u64;
+            let count = chunk.len() / SECTOR_SIZE;
+            let lba48 = self.use_lba48(start, count);
+            channel.issue(self.slave, start, count, lba48, if lba48 { ATA_WRITE_SECTORS_EXT } else { ATA_WRITE_SECTORS })?;
+            for (sector_index, sector) in chunk.chunks_exact(SECTOR_SIZE).enumerate() {
+                channel.wait_data(sector_index > 0)?;
+                channel.write_data(sector);
+            }
+            // 最後のセクタの書き込みが終わるのを待つ
+            channel.wait_interrupt()?;
+            if channel.wait_not_busy()? & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let channel = self.channel.lock();
+        channel.wait_not_busy()?;
+        channel.select(self.slave, 0);
+        channel.command(if self.identify.lba48 { ATA_FLUSH_CACHE_EXT } else { ATA_FLUSH_CACHE });
+        channel.wait_interrupt()?;
+        match channel.wait_not_busy()? & (STATUS_ERR | STATUS_DF) {
+            0 => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
@@ -0,0 +1,344 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::{check_range, BlockDevice, Geometry};
+
+/// キャッシュするセクタ数の既定値
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+lazy_static! {
+    pub static ref BUFFER_CACHE: Arc<BufferCache> = BufferCache::new(DEFAULT_CAPACITY);
+}
+
+type DeviceId = u64;
+type Key = (DeviceId, u64);
+
+/// すべてのブロックデバイスで共有するライトバック方式のバッファキャッシュ
+///
+/// 容量を超えたら最も長く使われていないセクタを追い出し、変更されていれば書き戻す
+pub struct BufferCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    buffers: BTreeMap<Key, Buffer>,
+    /// 最後に使った時刻からセクタを引く。先頭が最も長く使われていない
+    lru: BTreeMap<u64, Key>,
+    devices: BTreeMap<DeviceId, Arc<dyn BlockDevice>>,
+    /// 追い出されて、デバイスへの書き戻しが終わっていないセクタ
+    writing: BTreeMap<Key, Arc<Vec<u8>>>,
+    next_device_id: DeviceId,
+    tick: u64,
+    stats: CacheStats,
+}
+
+struct Buffer {
+    data: Vec<u8>,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub write_backs: u64,
+    pub evictions: u64,
+}
+
+impl BufferCache {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        assert!(capacity > 0, "BufferCache capacity must not be zero");
+        Arc::new(Self {
+            capacity,
+            inner: Mutex::new(CacheInner {
+                buffers: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                devices: BTreeMap::new(),
+                writing: BTreeMap::new(),
+                next_device_id: 0,
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        })
+    }
+
+    /// `device`への読み書きをこのキャッシュを通して行うデバイスを返す
+    pub fn attach(self: &Arc<Self>, device: Arc<dyn BlockDevice>) -> Arc<CachedDevice> {
+        let mut inner = self.inner.lock();
+        let id = inner.next_device_id;
+        inner.next_device_id += 1;
+        inner.devices.insert(id, device.clone());
+        Arc::new(CachedDevice {
+            id,
+            device,
+            cache: self.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats
+    }
+
+    /// キャッシュされているセクタ数と、そのうち書き戻されていないセクタ数
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        let dirty = inner.buffers.values().filter(|buffer| buffer.dirty).count();
+        (inner.buffers.len(), dirty)
+    }
+
+    /// すべてのデバイスの変更を書き戻す
+    pub fn flush_all(&self) -> Result<(), BlockError> {
+        let devices: Vec<DeviceId> = self.inner.lock().devices.keys().copied().collect();
+        for device in devices {
+            self.flush_device(device)?;
+        }
+        Ok(())
+    }
+
+    // デバイスの読み書きは必ずロックを離してから行う。パーティションのように、
+    // このキャッシュを通るデバイスの上に作られたデバイスもキャッシュされていることがあり、
+    // ロックを持ったままだと同じロックを取り直して止まってしまう
+    fn read(&self, device: DeviceId, lba: u64, buf: &mut [u8], sector_size: usize) -> Result<(), BlockError> {
+        for (index, chunk) in buf.chunks_exact_mut(sector_size).enumerate() {
+            let key = (device, lba + index as u64);
+            let (target, writing) = {
+                let mut inner = self.inner.lock();
+                if inner.buffers.contains_key(&key) {
+                    inner.stats.hits += 1;
+                    inner.touch(key);
+                    chunk.copy_from_slice(&inner.buffers[&key].data);
+                    continue;
+                }
+                inner.stats.misses += 1;
+                (inner.device(device)?, inner.writing.get(&key).cloned())
+            };
+
+            // 追い出されて書き戻し中のセクタは、デバイスより新しい
+            let data = match writing {
+                Some(data) => data.as_ref().clone(),
+                None => {
+                    let mut data = alloc::vec![0u8; sector_size];
+                    target.read_sectors(key.1, &mut data)?;
+                    data
+                }
+            };
+            let evicted = {
+                let mut inner = self.inner.lock();
+                // 読んでいる間に書き込まれていれば、そちらの方が新しい
+                let evicted = if inner.buffers.contains_key(&key) {
+                    Vec::new()
+                } else {
+                    inner.insert(key, data, false, self.capacity)
+                };
+                inner.touch(key);
+                chunk.copy_from_slice(&inner.buffers[&key].data);
+                evicted
+            };
+            self.write_back(evicted)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, device: DeviceId, lba: u64, buf: &[u8], sector_size: usize) -> Result<(), BlockError> {
+        let mut evicted = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            for (index, chunk) in buf.chunks_exact(sector_size).enumerate() {
+                let key = (device, lba + index as u64);
+                match inner.buffers.get_mut(&key) {
+                    Some(buffer) => {
+                        buffer.data.copy_from_slice(chunk);
+                        buffer.dirty = true;
+                    }
+                    // セクタ全体を上書きするので、デバイスから読む必要はない
+                    None => evicted.extend(inner.insert(key, chunk.to_vec(), true, self.capacity)),
+                }
+                inner.touch(key);
+            }
+        }
+        self.write_back(evicted)
+    }
+
+    /// 追い出したセクタを書き戻す。書き戻せなかったものは、変更されたままキャッシュに戻す
+    fn write_back(&self, evicted: Vec<Evicted>) -> Result<(), BlockError> {
+        let mut result = Ok(());
+        for (key, data) in evicted {
+            let target = self.inner.lock().device(key.0);
+            let written = target.and_then(|target| target.write_sectors(key.1, &data));
+            let mut inner = self.inner.lock();
+            // 書き戻している間に同じセクタがもう一度追い出されていれば、それは残しておく
+            let latest = matches!(inner.writing.get(&key), Some(pending) if Arc::ptr_eq(pending, &data));
+            if latest {
+                inner.writing.remove(&key);
+            }
+            match written {
+                Ok(()) => inner.stats.write_backs += 1,
+                Err(err) => {
+                    // 変更を失わないよう、書き戻されていないものとしてキャッシュに戻す
+                    if latest && inner.devices.contains_key(&key.0) {
+                        inner.restore_dirty(key, data);
+                    }
+                    result = result.and(Err(err));
+                }
+            }
+        }
+        result
+    }
+
+    /// デバイスの変更をLBAの順に書き戻す
+    ///
+    /// 書き戻せたセクタだけを変更されていないものとする。失敗したらそこで止め、残りは変更されたまま残す
+    fn flush_device(&self, device: DeviceId) -> Result<(), BlockError> {
+        let (target, dirty) = {
+            let inner = self.inner.lock();
+            let dirty: Vec<(Key, Vec<u8>)> = inner.buffers
+                .range((device, 0)..=(device, u64::MAX))
+                .filter(|(_, buffer)| buffer.dirty)
+                .map(|(key, buffer)| (*key, buffer.data.clone()))
+                .collect();
+            (inner.device(device)?, dirty)
+        };
+        for (key, data) in dirty {
+            target.write_sectors(key.1, &data)?;
+            let mut inner = self.inner.lock();
+            inner.stats.write_backs += 1;
+            // 書き戻している間に書き込まれていれば、変更されたままにする
+            if let Some(buffer) = inner.buffers.get_mut(&key) {
+                if buffer.data == data {
+                    buffer.dirty = false;
+                }
+            }
+        }
+        target.flush()
+    }
+}
+
+/// 追い出されて書き戻しを待っているセクタ
+type Evicted = (Key, Arc<Vec<u8>>);
+
+impl CacheInner {
+    fn device(&self, id: DeviceId) -> Result<Arc<dyn BlockDevice>, BlockError> {
+        self.devices.get(&id).cloned().ok_or(BlockError::Io)
+    }
+
+    fn touch(&mut self, key: Key) {
+        self.tick += 1;
+        let tick = self.tick;
+        let buffer = self.buffers.get_mut(&key).unwrap();
+        self.lru.remove(&buffer.last_used);
+        buffer.last_used = tick;
+        self.lru.insert(tick, key);
+    }
+
+    /// セクタを追加し、容量を超えた分を追い出す。書き戻しが必要なものを返す
+    fn insert(&mut self, key: Key, data: Vec<u8>, dirty: bool, capacity: usize) -> Vec<Evicted> {
+        let mut evicted = Vec::new();
+        while self.buffers.len() >= capacity {
+            evicted.extend(self.evict());
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key);
+        self.buffers.insert(key, Buffer {
+            data,
+            dirty,
+            last_used: self.tick,
+        });
+        evicted
+    }
+
+    /// 最も長く使われていないセクタを取り除く。変更されていれば、書き戻し中として覚えてから返す
+    fn evict(&mut self) -> Option<Evicted> {
+        let (&tick, &key) = self.lru.iter().next().unwrap();
+        self.lru.remove(&tick);
+        let buffer = self.buffers.remove(&key).unwrap();
+        self.stats.evictions += 1;
+        if !buffer.dirty {
+            return None;
+        }
+        let data = Arc::new(buffer.data);
+        self.writing.insert(key, data.clone());
+        Some((key, data))
+    }
+
+    /// 書き戻せなかったセクタを、変更されたものとしてキャッシュに戻す
+    ///
+    /// 追い出した後に読み込まれたり書き込まれたりしていれば、そちらの方が新しいので印だけつける。
+    /// 容量を超えた分は、次に追加するときに追い出される
+    fn restore_dirty(&mut self, key: Key, data: Arc<Vec<u8>>) {
+        if let Some(buffer) = self.buffers.get_mut(&key) {
+            buffer.dirty = true;
+            return;
+        }
+        self.tick += 1;
+        self.lru.insert(self.tick, key);
+        self.buffers.insert(key, Buffer {
+            data: Arc::try_unwrap(data).unwrap_or_else(|data| data.as_ref().clone()),
+            dirty: true,
+            last_used: self.tick,
+        });
+    }
+
+    fn forget_device(&mut self, device: DeviceId) {
+        let keys: Vec<Key> = self.buffers
+            .range((device, 0)..=(device, u64::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            let buffer = self.buffers.remove(&key).unwrap();
+            self.lru.remove(&buffer.last_used);
+        }
+        self.devices.remove(&device);
+    }
+}
+
+/// バッファキャッシュを通して読み書きするブロックデバイス
+///
+/// 破棄されるときに変更を書き戻し、キャッシュから取り除かれる
+pub struct CachedDevice {
+    id: DeviceId,
+    device: Arc<dyn BlockDevice>,
+    cache: Arc<BufferCache>,
+}
+
+impl CachedDevice {
+    pub fn inner(&self) -> &Arc<dyn BlockDevice> {
+        &self.device
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn geometry(&self) -> Geometry {
+        self.device.geometry()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        self.cache.read(self.id, lba, buf, self.sector_size())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let geometry = self.geometry();
+        if geometry.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, lba, buf.len())?;
+        self.cache.write(self.id, lba, buf, geometry.sector_size)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.cache.flush_device(self.id)
+    }
+}
+
+impl Drop for CachedDevice {
+    fn drop(&mut self) {
+        // 書き戻しに失敗しても、破棄する以外にできることはない
+        let _ = self.cache.flush_device(self.id);
+        self.cache.inner.lock().forget_device(self.id);
+    }
+}
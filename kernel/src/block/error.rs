@@ -0,0 +1,70 @@
+use core::error;
+use core::error::Error;
+use core::fmt::{Debug, Display, Formatter};
+use pci::error::PciError;
+use crate::usb::error::UsbError;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    InvalidBuffer,
+    ReadOnly,
+    Timeout,
+    Io,
+    NoDevice,
+    NoMemory,
+}
+
+impl Debug for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Display for BlockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self.description())
+    }
+}
+
+impl error::Error for BlockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+/// BARを使えないデバイスは無いものとして扱う
+impl From<PciError> for BlockError {
+    fn from(value: PciError) -> Self {
+        match value {
+            PciError::MapFailed => BlockError::NoMemory,
+            _ => BlockError::NoDevice,
+        }
+    }
+}
+
+/// USBの転送の失敗は、デバイスのI/Oエラーとして扱う
+impl From<UsbError> for BlockError {
+    fn from(value: UsbError) -> Self {
+        match value {
+            UsbError::Timeout => BlockError::Timeout,
+            UsbError::NoDevice => BlockError::NoDevice,
+            UsbError::NoMemory => BlockError::NoMemory,
+            _ => BlockError::Io,
+        }
+    }
+}
+
+impl BlockError {
+    fn description(&self) -> &'static str {
+        match self {
+            BlockError::OutOfRange => "Sector out of range",
+            BlockError::InvalidBuffer => "Buffer length is not a multiple of the sector size",
+            BlockError::ReadOnly => "Device is read-only",
+            BlockError::Timeout => "Device did not respond in time",
+            BlockError::Io => "Device reported an I/O error",
+            BlockError::NoDevice => "No usable device is attached",
+            BlockError::NoMemory => "Failed to allocate memory for the device",
+        }
+    }
+}
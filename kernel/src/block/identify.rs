@@ -0,0 +1,55 @@
+use alloc::string::String;
+
+/// ATAのIDENTIFY DEVICEコマンドが返す512バイトのデータから、必要な情報だけを取り出したもの
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentifyData {
+    pub model: String,
+    pub serial: String,
+    pub sector_count: u64,
+    pub sector_size: usize,
+    pub lba48: bool,
+}
+
+impl IdentifyData {
+    pub fn parse(data: &[u8; 512]) -> Self {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]);
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            (0..4).fold(0u64, |count, index| count | (word(100 + index) as u64) << (16 * index))
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+
+        // word 106の bit 14:13 が 01 のときだけ内容が有効で、bit 12 が立っていれば論理セクタサイズ(ワード単位)が入っている
+        let sector_info = word(106);
+        let sector_size = if sector_info & 0xC000 == 0x4000 && sector_info & (1 << 12) != 0 {
+            (word(117) as usize | (word(118) as usize) << 16) * 2
+        } else {
+            512
+        };
+
+        Self {
+            model: ata_string(&data[54..94]),
+            serial: ata_string(&data[20..40]),
+            sector_count,
+            sector_size,
+            lba48,
+        }
+    }
+
+    /// 論理セクタサイズが0でなく、512バイトの倍数になっているか。壊れた値のまま割り算に使わないようにする
+    pub fn has_valid_sector_size(&self) -> bool {
+        self.sector_size != 0 && self.sector_size % 512 == 0
+    }
+}
+
+/// ATAの文字列は2バイトごとに上位と下位が入れ替わっていて、後ろが空白で埋められている
+fn ata_string(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for pair in bytes.chunks_exact(2) {
+        text.push(pair[1] as char);
+        text.push(pair[0] as char);
+    }
+    String::from(text.trim())
+}
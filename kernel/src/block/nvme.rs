@@ -0,0 +1,397 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::debug;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::{check_range, register, wait_until, BlockDevice, Geometry};
+use crate::memory::dma::DmaBuffer;
+use crate::memory::mmio::{self, MmioRegion};
+use pci::device::Device;
+use pci::register::Command;
+use pci::{DeviceMatch, Driver};
+
+// コントローラのレジスタ
+const REG_CAP: usize = 0x00;
+const REG_INTMS: usize = 0x0C;
+const REG_CC: usize = 0x14;
+const REG_CSTS: usize = 0x1C;
+const REG_AQA: usize = 0x24;
+const REG_ASQ: usize = 0x28;
+const REG_ACQ: usize = 0x30;
+const DOORBELL_BASE: usize = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+/// 送信キューのエントリは64バイト(2^6)、完了キューのエントリは16バイト(2^4)
+const CC_IOSQES: u32 = 6 << 16;
+const CC_IOCQES: u32 = 4 << 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const PAGE_SIZE: usize = 4096;
+const SQ_ENTRY_SIZE: usize = 64;
+const CQ_ENTRY_SIZE: usize = 16;
+/// 1ページに収まるエントリ数にする
+const QUEUE_SIZE: u16 = 64;
+const ADMIN_QUEUE_ID: u16 = 0;
+const IO_QUEUE_ID: u16 = 1;
+
+/// 一度のコマンドで転送する最大のサイズ。PRPリストは1ページに収まる
+const BOUNCE_SIZE: usize = 64 * 1024;
+
+// 管理コマンド
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+
+// I/Oコマンド
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+const TIMEOUT_SPINS: usize = 10_000_000;
+
+/// PCIバス上のNVMeコントローラを初期化し、各名前空間を`nvme0n1`のような名前で登録する
+pub static DRIVER: Driver = Driver {
+    name: "nvme",
+    matches: &[DeviceMatch::Class { base: 0x01, sub: 0x08, interface: Some(0x02) }],
+    probe,
+};
+
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &Device) -> bool {
+    let controller = match NvmeController::new(device) {
+        Ok(controller) => Arc::new(controller),
+        Err(err) => {
+            debug!("nvme: {}.{}.{}: {}", device.bus, device.device, device.function, err);
+            return false;
+        }
+    };
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+    debug!("nvme{}: {} (serial {})", index, controller.model, controller.serial);
+    match NvmeController::namespaces(&controller) {
+        Ok(namespaces) => {
+            for namespace in namespaces {
+                let name = format!("nvme{}n{}", index, namespace.nsid);
+                debug!("nvme: {}: {} sectors", name, namespace.geometry.sector_count);
+                register(name, Arc::new(namespace));
+            }
+        }
+        Err(err) => debug!("nvme{}: failed to list namespaces: {}", index, err),
+    }
+    true
+}
+
+/// 送信キューと完了キューの組。コマンドは一つずつ発行して完了を待つ
+struct QueuePair {
+    id: u16,
+    submission: DmaBuffer,
+    completion: DmaBuffer,
+    tail: u16,
+    head: u16,
+    /// 完了キューのエントリが新しいかどうかを見分けるビット。キューを一周するたびに反転する
+    phase: bool,
+    next_command_id: u16,
+}
+
+impl QueuePair {
+    fn new(id: u16) -> Result<Self, BlockError> {
+        Ok(Self {
+            id,
+            submission: DmaBuffer::new(QUEUE_SIZE as usize * SQ_ENTRY_SIZE).ok_or(BlockError::NoMemory)?,
+            completion: DmaBuffer::new(QUEUE_SIZE as usize * CQ_ENTRY_SIZE).ok_or(BlockError::NoMemory)?,
+            tail: 0,
+            head: 0,
+            phase: true,
+            next_command_id: 0,
+        })
+    }
+
+    /// コマンドを送信し、完了エントリのDW0を返す
+    fn submit(&mut self, doorbells: &Doorbells, mut command: [u32; 16]) -> Result<u32, BlockError> {
+        let command_id = self.next_command_id;
+        self.next_command_id = self.next_command_id.wrapping_add(1);
+        command[0] |= (command_id as u32) << 16;
+
+        let offset = self.tail as usize * SQ_ENTRY_SIZE;
+        for (index, dword) in command.iter().enumerate() {
+            self.submission.write::<u32>(offset + index * 4, *dword);
+        }
+        self.tail = (self.tail + 1) % QUEUE_SIZE;
+        doorbells.ring_submission(self.id, self.tail);
+
+        let entry = self.head as usize * CQ_ENTRY_SIZE;
+        let phase = self.phase;
+        wait_until(TIMEOUT_SPINS, || (self.completion.read::<u32>(entry + 12) >> 16) & 1 == phase as u32)?;
+        let result = self.completion.read::<u32>(entry);
+        let status = self.completion.read::<u32>(entry + 12) >> 17;
+
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        if self.head == 0 {
+            self.phase = !self.phase;
+        }
+        doorbells.ring_completion(self.id, self.head);
+
+        if status & 0x7FFF != 0 {
+            debug!("nvme: command {:#x} failed with status {:#x}", command[0] & 0xFF, status);
+            return Err(BlockError::Io);
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Doorbells {
+    regs: MmioRegion,
+    stride: usize,
+}
+
+impl Doorbells {
+    fn ring_submission(&self, queue: u16, tail: u16) {
+        self.regs.write32(DOORBELL_BASE + (2 * queue as usize) * self.stride, tail as u32);
+    }
+
+    fn ring_completion(&self, queue: u16, head: u16) {
+        self.regs.write32(DOORBELL_BASE + (2 * queue as usize + 1) * self.stride, head as u32);
+    }
+}
+
+fn command(opcode: u8, nsid: u32) -> [u32; 16] {
+    let mut command = [0u32; 16];
+    command[0] = opcode as u32;
+    command[1] = nsid;
+    command
+}
+
+fn set_prp(command: &mut [u32; 16], prp1: u64, prp2: u64) {
+    command[6] = prp1 as u32;
+    command[7] = (prp1 >> 32) as u32;
+    command[8] = prp2 as u32;
+    command[9] = (prp2 >> 32) as u32;
+}
+
+struct IoQueue {
+    queue: QueuePair,
+    bounce: DmaBuffer,
+    prp_list: DmaBuffer,
+}
+
+impl IoQueue {
+    /// バウンスバッファの先頭`bytes`バイトを指すPRPを設定する
+    /// 2ページを超える場合は、2ページ目以降のアドレスを並べたPRPリストを使う
+    fn set_data(&mut self, command: &mut [u32; 16], bytes: usize) {
+        let base = self.bounce.phys_addr();
+        let pages = bytes.div_ceil(PAGE_SIZE);
+        let prp2 = match pages {
+            0 | 1 => 0,
+            2 => base + PAGE_SIZE as u64,
+            _ => {
+                for page in 1..pages {
+                    self.prp_list.write::<u64>((page - 1) * 8, base + (page * PAGE_SIZE) as u64);
+                }
+                self.prp_list.phys_addr()
+            }
+        };
+        set_prp(command, base, prp2);
+    }
+}
+
+pub struct NvmeController {
+    doorbells: Doorbells,
+    admin: Mutex<QueuePair>,
+    io: Mutex<IoQueue>,
+    /// 一度に転送できる最大のバイト数
+    max_transfer: usize,
+    pub model: String,
+    pub serial: String,
+}
+
+impl NvmeController {
+    pub fn new(device: &Device) -> Result<Self, BlockError> {
+        // メモリ空間へのアクセスとバスマスタを有効にし、INTxは使わない
+        device.enable_command(Command::MEMORY_SPACE | Command::BUS_MASTER | Command::INTERRUPT_DISABLE);
+
+        let regs = mmio::map_bar(device, 0)?;
+        let stride = 4usize << ((regs.read64(REG_CAP) >> 32) & 0xF);
+        // 管理キューとI/Oキューの2組分のドアベルが、BARの中に収まっていなければならない
+        if regs.size() < DOORBELL_BASE + 4 * stride {
+            return Err(BlockError::NoDevice);
+        }
+        let doorbells = Doorbells { regs, stride };
+
+        let max_entries = (regs.read64(REG_CAP) & 0xFFFF) as u32 + 1;
+        if max_entries < QUEUE_SIZE as u32 {
+            return Err(BlockError::NoDevice);
+        }
+
+        regs.write32(REG_CC, regs.read32(REG_CC) & !CC_EN);
+        wait_until(TIMEOUT_SPINS, || regs.read32(REG_CSTS) & CSTS_RDY == 0)?;
+
+        let mut admin = QueuePair::new(ADMIN_QUEUE_ID)?;
+        regs.write32(REG_AQA, (QUEUE_SIZE as u32 - 1) << 16 | (QUEUE_SIZE as u32 - 1));
+        regs.write64(REG_ASQ, admin.submission.phys_addr());
+        regs.write64(REG_ACQ, admin.completion.phys_addr());
+        // 割り込みは使わずに完了キューをポーリングする
+        regs.write32(REG_INTMS, u32::MAX);
+        regs.write32(REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+        wait_until(TIMEOUT_SPINS, || regs.read32(REG_CSTS) & (CSTS_RDY | CSTS_CFS) != 0)?;
+        if regs.read32(REG_CSTS) & CSTS_CFS != 0 {
+            return Err(BlockError::Io);
+        }
+
+        let identify = DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::NoMemory)?;
+        let mut identify_command = command(ADMIN_IDENTIFY, 0);
+        set_prp(&mut identify_command, identify.phys_addr(), 0);
+        identify_command[10] = IDENTIFY_CONTROLLER;
+        admin.submit(&doorbells, identify_command)?;
+        let data = identify.as_slice();
+        let model = String::from(String::from_utf8_lossy(&data[24..64]).trim());
+        let serial = String::from(String::from_utf8_lossy(&data[4..24]).trim());
+        // MDTSは最小ページサイズを単位とした2の冪。0なら制限なし
+        let max_transfer = match data[77] {
+            0 => BOUNCE_SIZE,
+            mdts => (PAGE_SIZE << mdts).min(BOUNCE_SIZE),
+        };
+
+        let io = IoQueue {
+            queue: QueuePair::new(IO_QUEUE_ID)?,
+            bounce: DmaBuffer::new(BOUNCE_SIZE).ok_or(BlockError::NoMemory)?,
+            prp_list: DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::NoMemory)?,
+        };
+        // 完了キューを先に作り、送信キューをそれに結びつける。どちらも物理的に連続している
+        let mut create_cq = command(ADMIN_CREATE_IO_CQ, 0);
+        set_prp(&mut create_cq, io.queue.completion.phys_addr(), 0);
+        create_cq[10] = (QUEUE_SIZE as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+        create_cq[11] = 1;
+        admin.submit(&doorbells, create_cq)?;
+        let mut create_sq = command(ADMIN_CREATE_IO_SQ, 0);
+        set_prp(&mut create_sq, io.queue.submission.phys_addr(), 0);
+        create_sq[10] = (QUEUE_SIZE as u32 - 1) << 16 | IO_QUEUE_ID as u32;
+        create_sq[11] = (IO_QUEUE_ID as u32) << 16 | 1;
+        admin.submit(&doorbells, create_sq)?;
+
+        Ok(Self {
+            doorbells,
+            admin: Mutex::new(admin),
+            io: Mutex::new(io),
+            max_transfer,
+            model,
+            serial,
+        })
+    }
+
+    /// 有効な名前空間をそれぞれブロックデバイスとして返す
+    pub fn namespaces(controller: &Arc<Self>) -> Result<Vec<NvmeNamespace>, BlockError> {
+        let list = DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::NoMemory)?;
+        let identify = DmaBuffer::new(PAGE_SIZE).ok_or(BlockError::NoMemory)?;
+        let mut admin = controller.admin.lock();
+
+        let mut list_command = command(ADMIN_IDENTIFY, 0);
+        set_prp(&mut list_command, list.phys_addr(), 0);
+        list_command[10] = IDENTIFY_ACTIVE_NAMESPACES;
+        admin.submit(&controller.doorbells, list_command)?;
+        let nsids: Vec<u32> = list.as_slice()
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .take_while(|id| *id != 0)
+            .collect();
+
+        let mut namespaces = Vec::new();
+        for nsid in nsids {
+            let mut identify_command = command(ADMIN_IDENTIFY, nsid);
+            set_prp(&mut identify_command, identify.phys_addr(), 0);
+            identify_command[10] = IDENTIFY_NAMESPACE;
+            admin.submit(&controller.doorbells, identify_command)?;
+
+            let data = identify.as_slice();
+            let sector_count = u64::from_le_bytes([data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7]]);
+            // FLBASが選んでいるLBAフォーマットの、LBADSがセクタサイズの2の冪
+            let format = (data[26] & 0xF) as usize;
+            let sector_size = 1usize << data[128 + format * 4 + 2];
+            if sector_count == 0 || sector_size > controller.max_transfer {
+                continue;
+            }
+            namespaces.push(NvmeNamespace {
+                controller: controller.clone(),
+                nsid,
+                geometry: Geometry {
+                    sector_size,
+                    sector_count,
+                    read_only: false,
+                },
+            });
+        }
+        Ok(namespaces)
+    }
+}
+
+/// NVMeの名前空間。一つのコントローラのI/Oキューを名前空間の間で共有する
+pub struct NvmeNamespace {
+    controller: Arc<NvmeController>,
+    nsid: u32,
+    geometry: Geometry,
+}
+
+impl NvmeNamespace {
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
+
+    fn sectors_per_command(&self) -> usize {
+        self.controller.max_transfer / self.geometry.sector_size
+    }
+
+    fn transfer(&self, opcode: u8, lba: u64, sectors: usize) -> [u32; 16] {
+        let mut command = command(opcode, self.nsid);
+        command[10] = lba as u32;
+        command[11] = (lba >> 32) as u32;
+        command[12] = (sectors - 1) as u32;
+        command
+    }
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut io = self.controller.io.lock();
+        let chunk_size = self.sectors_per_command() * self.geometry.sector_size;
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let start = lba + (index * self.sectors_per_command()) as u64;
+            let mut command = self.transfer(IO_READ, start, chunk.len() / self.geometry.sector_size);
+            io.set_data(&mut command, chunk.len());
+            io.queue.submit(&self.controller.doorbells, command)?;
+            chunk.copy_from_slice(&io.bounce.as_slice()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut io = self.controller.io.lock();
+        let chunk_size = self.sectors_per_command() * self.geometry.sector_size;
+        for (index, chunk) in buf.chunks(chunk_size).enumerate() {
+            let start = lba + (index * self.sectors_per_command()) as u64;
+            let mut command = self.transfer(IO_WRITE, start, chunk.len() / self.geometry.sector_size);
+            io.bounce.as_mut_slice()[..chunk.len()].copy_from_slice(chunk);
+            io.set_data(&mut command, chunk.len());
+            io.queue.submit(&self.controller.doorbells, command)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let mut io = self.controller.io.lock();
+        io.queue.submit(&self.controller.doorbells, command(IO_FLUSH, self.nsid))?;
+        Ok(())
+    }
+}
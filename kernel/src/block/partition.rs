@@ -0,0 +1,340 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt::{Display, Formatter};
+use crate::block::error::BlockError;
+use crate::block::{check_range, BlockDevice, Geometry};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+const MBR_TYPE_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPE_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// 壊れた拡張パーティションのリンクで無限ループしないための上限
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// パーティションエントリの配列の大きさの上限。普通は128バイトx128個の16KiB
+const MAX_GPT_TABLE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const fn is_zero(&self) -> bool {
+        let mut index = 0;
+        while index < 16 {
+            if self.0[index] != 0 {
+                return false;
+            }
+            index += 1;
+        }
+        true
+    }
+}
+
+/// GUIDの先頭3つのフィールドはリトルエンディアンで格納されている
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionKind {
+    Mbr { system_id: u8, bootable: bool },
+    Gpt { type_guid: Guid, unique_guid: Guid, name: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// 1から始まるパーティション番号。MBRの論理パーティションは5から
+    pub number: usize,
+    pub start_lba: u64,
+    pub sector_count: u64,
+    pub kind: PartitionKind,
+}
+
+/// デバイスの一部分を一つのブロックデバイスとして見せる
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    info: PartitionInfo,
+}
+
+impl Partition {
+    pub fn new(device: Arc<dyn BlockDevice>, info: PartitionInfo) -> Self {
+        Self { device, info }
+    }
+
+    pub fn info(&self) -> &PartitionInfo {
+        &self.info
+    }
+}
+
+impl BlockDevice for Partition {
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            sector_count: self.info.sector_count,
+            ..self.device.geometry()
+        }
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        self.device.read_sectors(self.info.start_lba + lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        self.device.write_sectors(self.info.start_lba + lba, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// デバイスのパーティションテーブルを読み、各パーティションをブロックデバイスとして返す
+/// パーティションテーブルが無ければ空の`Vec`を返す
+pub fn partitions(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    Ok(scan(device.as_ref())?
+        .into_iter()
+        .map(|info| Partition::new(device.clone(), info))
+        .collect())
+}
+
+pub fn scan(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, BlockError> {
+    let mut mbr = vec![0u8; device.sector_size()];
+    device.read_sectors(0, &mut mbr)?;
+    // パーティションテーブルを持たずに先頭からFATでフォーマットされたディスクでも、ブートセクタは0x55AAで終わる
+    if mbr[510..512] != MBR_SIGNATURE || is_fat_boot_sector(&mbr) {
+        return Ok(Vec::new());
+    }
+
+    let entries = mbr_entries(&mbr);
+    if !entries.iter().all(|entry| entry.is_valid()) {
+        return Ok(Vec::new());
+    }
+    let gpt = if entries.iter().any(|entry| entry.system_id == MBR_TYPE_PROTECTIVE) {
+        scan_gpt(device)?
+    } else {
+        None
+    };
+    let mut partitions = match gpt {
+        Some(partitions) => partitions,
+        None => scan_mbr(device, &entries)?,
+    };
+    // 壊れたテーブルがデバイスの外まで指していれば、そのパーティションは見せない
+    let sector_count = device.sector_count();
+    partitions.retain(|partition| {
+        matches!(partition.start_lba.checked_add(partition.sector_count), Some(end) if end <= sector_count)
+    });
+    Ok(partitions)
+}
+
+/// FATのブートセクタにあるBPBとして読めるか
+fn is_fat_boot_sector(sector: &[u8]) -> bool {
+    let jump = (sector[0] == 0xEB && sector[2] == 0x90) || sector[0] == 0xE9;
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let sectors_per_cluster = sector[13];
+    let reserved_sectors = u16::from_le_bytes([sector[14], sector[15]]);
+    let num_fats = sector[16];
+    jump
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors != 0
+        && num_fats != 0
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MbrEntry {
+    boot_indicator: u8,
+    bootable: bool,
+    system_id: u8,
+    start_lba: u64,
+    sector_count: u64,
+}
+
+impl MbrEntry {
+    /// ブートフラグは0x00か0x80しか取らない。使われているエントリは先頭のセクタを指せない
+    fn is_valid(&self) -> bool {
+        let unused = self.system_id == 0 || self.sector_count == 0;
+        matches!(self.boot_indicator, 0x00 | 0x80) && (unused || self.start_lba != 0)
+    }
+}
+
+fn mbr_entries(sector: &[u8]) -> [MbrEntry; 4] {
+    core::array::from_fn(|index| {
+        let entry = &sector[MBR_PARTITION_TABLE + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        MbrEntry {
+            boot_indicator: entry[0],
+            bootable: entry[0] == 0x80,
+            system_id: entry[4],
+            start_lba: read_u32(entry, 8) as u64,
+            sector_count: read_u32(entry, 12) as u64,
+        }
+    })
+}
+
+fn scan_mbr(device: &dyn BlockDevice, entries: &[MbrEntry; 4]) -> Result<Vec<PartitionInfo>, BlockError> {
+    let mut partitions = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.system_id == 0 || entry.sector_count == 0 {
+            continue;
+        }
+        if MBR_TYPE_EXTENDED.contains(&entry.system_id) {
+            scan_extended(device, entry.start_lba, &mut partitions)?;
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            number: index + 1,
+            start_lba: entry.start_lba,
+            sector_count: entry.sector_count,
+            kind: PartitionKind::Mbr {
+                system_id: entry.system_id,
+                bootable: entry.bootable,
+            },
+        });
+    }
+    partitions.sort_by_key(|partition| partition.number);
+    Ok(partitions)
+}
+
+/// 拡張パーティションの中のEBRを順に辿って論理パーティションを集める
+/// EBRの2番目のエントリの位置は拡張パーティションの先頭からの相対位置
+fn scan_extended(device: &dyn BlockDevice, extended_start: u64, partitions: &mut Vec<PartitionInfo>) -> Result<(), BlockError> {
+    let mut ebr = vec![0u8; device.sector_size()];
+    let mut ebr_lba = extended_start;
+    for logical in 0..MAX_LOGICAL_PARTITIONS {
+        device.read_sectors(ebr_lba, &mut ebr)?;
+        if ebr[510..512] != MBR_SIGNATURE {
+            break;
+        }
+        let entries = mbr_entries(&ebr);
+        let (data, next) = (entries[0], entries[1]);
+        if data.system_id != 0 && data.sector_count != 0 {
+            partitions.push(PartitionInfo {
+                number: 5 + logical,
+                start_lba: ebr_lba + data.start_lba,
+                sector_count: data.sector_count,
+                kind: PartitionKind::Mbr {
+                    system_id: data.system_id,
+                    bootable: data.bootable,
+                },
+            });
+        }
+        if next.system_id == 0 || next.start_lba == 0 {
+            break;
+        }
+        ebr_lba = extended_start + next.start_lba;
+    }
+    Ok(())
+}
+
+/// GPTヘッダを検証してパーティションを返す。ヘッダが壊れていれば`None`
+fn scan_gpt(device: &dyn BlockDevice) -> Result<Option<Vec<PartitionInfo>>, BlockError> {
+    let sector_size = device.sector_size();
+    let mut header = vec![0u8; sector_size];
+    device.read_sectors(1, &mut header)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = read_u32(&header, 12) as usize;
+    if !(92..=sector_size).contains(&header_size) {
+        return Ok(None);
+    }
+    let expected_crc = read_u32(&header, 16);
+    let mut checked = header[..header_size].to_vec();
+    checked[16..20].fill(0);
+    if crc32(&checked) != expected_crc {
+        return Ok(None);
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let entries_crc = read_u32(&header, 88);
+    if entry_size < 128 || entry_count == 0 {
+        return Ok(None);
+    }
+    // 壊れたヘッダの値のまま確保しないように、大きさを確かめてから読む
+    let table_size = match entry_count.checked_mul(entry_size) {
+        Some(size) if size <= MAX_GPT_TABLE_SIZE => size,
+        _ => return Ok(None),
+    };
+    let table_sectors = table_size.div_ceil(sector_size);
+    match entries_lba.checked_add(table_sectors as u64) {
+        Some(end) if end <= device.sector_count() => {}
+        _ => return Ok(None),
+    }
+
+    let mut table = vec![0u8; table_sectors * sector_size];
+    device.read_sectors(entries_lba, &mut table)?;
+    if crc32(&table[..table_size]) != entries_crc {
+        return Ok(None);
+    }
+
+    let partitions = table
+        .chunks_exact(entry_size)
+        .take(entry_count)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let type_guid = Guid(entry[0..16].try_into().unwrap());
+            if type_guid.is_zero() {
+                return None;
+            }
+            let first_lba = read_u64(entry, 32);
+            let last_lba = read_u64(entry, 40);
+            // 最後のLBAも含むので、先頭より前を指すエントリは壊れている
+            if last_lba < first_lba {
+                return None;
+            }
+            let name_units = entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|unit| *unit != 0);
+            Some(PartitionInfo {
+                number: index + 1,
+                start_lba: first_lba,
+                sector_count: (last_lba - first_lba).checked_add(1)?,
+                kind: PartitionKind::Gpt {
+                    type_guid,
+                    unique_guid: Guid(entry[16..32].try_into().unwrap()),
+                    name: char::decode_utf16(name_units)
+                        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                        .collect(),
+                },
+            })
+        })
+        .collect();
+    Ok(Some(partitions))
+}
+
+/// GPTで使われるCRC32(IEEE 802.3)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,50 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::{check_range, BlockDevice, Geometry};
+
+/// ヒープ上に置かれたブロックデバイス。主にテストで使う
+pub struct RamDisk {
+    sector_size: usize,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    pub fn new(sector_size: usize, sector_count: usize) -> Self {
+        Self::from_vec(sector_size, vec![0; sector_size * sector_count])
+    }
+
+    /// `data`の長さはセクタサイズの倍数でなければならない
+    pub fn from_vec(sector_size: usize, data: Vec<u8>) -> Self {
+        assert_eq!(data.len() % sector_size, 0, "RamDisk size must be a multiple of the sector size");
+        Self {
+            sector_size,
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            sector_size: self.sector_size,
+            sector_count: (self.data.lock().len() / self.sector_size) as u64,
+            read_only: false,
+        }
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * self.sector_size;
+        buf.copy_from_slice(&self.data.lock()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let start = lba as usize * self.sector_size;
+        self.data.lock()[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
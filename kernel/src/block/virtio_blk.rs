@@ -0,0 +1,204 @@
+pub mod queue;
+pub mod transport;
+
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::debug;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::virtio_blk::queue::{Buffer, VirtQueue};
+use crate::block::virtio_blk::transport::{
+    Transport, F_VERSION_1, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK, STATUS_FAILED, STATUS_FEATURES_OK,
+};
+use crate::block::{check_range, disk_name, register, BlockDevice, Geometry};
+use crate::memory::dma::DmaBuffer;
+use pci::device::Device;
+use pci::register::Command;
+use pci::{DeviceMatch, Driver};
+
+const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+// デバイス固有の設定領域
+const CONFIG_CAPACITY: usize = 0x00;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const STATUS_OK: u8 = 0;
+
+/// virtio-blkは常に512バイト単位でセクタを数える
+const SECTOR_SIZE: usize = 512;
+const QUEUE_SIZE: u16 = 128;
+/// 要求ヘッダとステータスを置くページの後ろに、データ用のバウンスバッファを置く
+const STATUS_OFFSET: usize = 16;
+const DATA_OFFSET: usize = 4096;
+const BOUNCE_SIZE: usize = 64 * 1024;
+
+/// PCIバス上のvirtio-blkデバイスを初期化し、`vda`, `vdb`...として登録する。`vdz`の次は`vdaa`
+pub static DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    // transitionalデバイスとmodern専用デバイスのデバイスID
+    matches: &[
+        DeviceMatch::Id { vendor: VIRTIO_VENDOR_ID, device: 0x1001 },
+        DeviceMatch::Id { vendor: VIRTIO_VENDOR_ID, device: 0x1042 },
+    ],
+    probe,
+};
+
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &Device) -> bool {
+    match VirtioBlk::new(device) {
+        Ok(disk) => {
+            let name = disk_name("vd", DISK_COUNT.fetch_add(1, Ordering::Relaxed));
+            debug!("virtio-blk: {}: {} sectors ({})", name, disk.geometry.sector_count, if disk.transport.is_modern() { "modern" } else { "legacy" });
+            register(name, Arc::new(disk));
+            true
+        }
+        Err(err) => {
+            debug!("virtio-blk: {}.{}.{}: {}", device.bus, device.device, device.function, err);
+            false
+        }
+    }
+}
+
+struct Request {
+    queue: VirtQueue,
+    memory: DmaBuffer,
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    request: Mutex<Request>,
+    geometry: Geometry,
+    flush_supported: bool,
+}
+
+impl VirtioBlk {
+    pub fn new(device: &Device) -> Result<Self, BlockError> {
+        // I/O空間、メモリ空間へのアクセスとバスマスタを有効にする
+        device.enable_command(Command::IO_SPACE | Command::MEMORY_SPACE | Command::BUS_MASTER);
+
+        let transport = Transport::new(device)?;
+        transport.reset();
+        transport.add_status(STATUS_ACKNOWLEDGE);
+        transport.add_status(STATUS_DRIVER);
+
+        let offered = transport.device_features();
+        let mut features = offered & (F_RO | F_FLUSH);
+        if transport.is_modern() {
+            if offered & F_VERSION_1 == 0 {
+                transport.add_status(STATUS_FAILED);
+                return Err(BlockError::NoDevice);
+            }
+            features |= F_VERSION_1;
+        }
+        transport.set_driver_features(features);
+        if transport.is_modern() {
+            transport.add_status(STATUS_FEATURES_OK);
+            if transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.add_status(STATUS_FAILED);
+                return Err(BlockError::NoDevice);
+            }
+        }
+
+        let queue = match VirtQueue::new(&transport, 0, QUEUE_SIZE) {
+            Ok(queue) => queue,
+            Err(err) => {
+                transport.add_status(STATUS_FAILED);
+                return Err(err);
+            }
+        };
+        // キューはもうデバイスに渡してあるので、ここで諦めるときもデバイスに伝える
+        let memory = match DmaBuffer::new(DATA_OFFSET + BOUNCE_SIZE) {
+            Some(memory) => memory,
+            None => {
+                transport.add_status(STATUS_FAILED);
+                return Err(BlockError::NoMemory);
+            }
+        };
+        transport.add_status(STATUS_DRIVER_OK);
+
+        let geometry = Geometry {
+            sector_size: SECTOR_SIZE,
+            sector_count: transport.read_config64(CONFIG_CAPACITY),
+            read_only: features & F_RO != 0,
+        };
+        Ok(Self {
+            transport,
+            request: Mutex::new(Request { queue, memory }),
+            geometry,
+            flush_supported: features & F_FLUSH != 0,
+        })
+    }
+}
+
+impl Request {
+    /// ヘッダ、データ、ステータスの3つのバッファで要求を送る
+    fn send(&mut self, transport: &Transport, kind: u32, sector: u64, data_len: usize) -> Result<(), BlockError> {
+        self.memory.write::<u32>(0, kind);
+        self.memory.write::<u32>(4, 0);
+        self.memory.write::<u64>(8, sector);
+        self.memory.write::<u8>(STATUS_OFFSET, 0xFF);
+
+        let base = self.memory.phys_addr();
+        let header = Buffer { addr: base, len: 16, writable: false };
+        let status = Buffer { addr: base + STATUS_OFFSET as u64, len: 1, writable: true };
+        if data_len == 0 {
+            self.queue.submit(transport, &[header, status])?;
+        } else {
+            let data = Buffer {
+                addr: base + DATA_OFFSET as u64,
+                len: data_len as u32,
+                writable: kind == REQUEST_IN,
+            };
+            self.queue.submit(transport, &[header, data, status])?;
+        }
+
+        match self.memory.read::<u8>(STATUS_OFFSET) {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut request = self.request.lock();
+        for (index, chunk) in buf.chunks_mut(BOUNCE_SIZE).enumerate() {
+            let sector = lba + (index * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            request.send(&self.transport, REQUEST_IN, sector, chunk.len())?;
+            chunk.copy_from_slice(&request.memory.as_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.geometry.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, lba, buf.len())?;
+        let mut request = self.request.lock();
+        for (index, chunk) in buf.chunks(BOUNCE_SIZE).enumerate() {
+            let sector = lba + (index * BOUNCE_SIZE / SECTOR_SIZE) as u64;
+            request.memory.as_mut_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()].copy_from_slice(chunk);
+            request.send(&self.transport, REQUEST_OUT, sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush_supported {
+            return Ok(());
+        }
+        self.request.lock().send(&self.transport, REQUEST_FLUSH, 0, 0)
+    }
+}
@@ -0,0 +1,102 @@
+use core::sync::atomic::{fence, Ordering};
+use crate::block::error::BlockError;
+use crate::block::virtio_blk::transport::{QueueAddress, Transport};
+use crate::block::wait_until;
+use crate::memory::dma::DmaBuffer;
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+/// 完了時の割り込みは使わずに、usedリングをポーリングする
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESC_SIZE: usize = 16;
+const ALIGN: usize = 4096;
+const TIMEOUT_SPINS: usize = 10_000_000;
+
+/// デバイスに渡すバッファ一つ分
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// デバイスが書き込むバッファかどうか
+    pub writable: bool,
+}
+
+/// split virtqueue。ディスクリプタテーブル、availableリング、usedリングを一つの連続した領域に置く
+///
+/// 要求は一つずつ発行して完了を待つので、ディスクリプタは常に先頭から使う
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    available_offset: usize,
+    used_offset: usize,
+    next_available: u16,
+    last_used: u16,
+}
+
+impl VirtQueue {
+    pub fn new(transport: &Transport, index: u16, max_size: u16) -> Result<Self, BlockError> {
+        let device_max = transport.max_queue_size(index);
+        if device_max == 0 {
+            return Err(BlockError::NoDevice);
+        }
+        // legacyではデバイスが決めたサイズをそのまま使わなければならない
+        let size = if transport.is_modern() { device_max.min(max_size) } else { device_max };
+
+        let available_offset = size as usize * DESC_SIZE;
+        let used_offset = (available_offset + 6 + 2 * size as usize).div_ceil(ALIGN) * ALIGN;
+        let total = used_offset + 6 + 8 * size as usize;
+        let mut memory = DmaBuffer::new(total).ok_or(BlockError::NoMemory)?;
+        memory.write::<u16>(available_offset, AVAIL_F_NO_INTERRUPT);
+
+        let base = memory.phys_addr();
+        transport.setup_queue(index, size, QueueAddress {
+            descriptors: base,
+            available: base + available_offset as u64,
+            used: base + used_offset as u64,
+        });
+
+        Ok(Self {
+            index,
+            size,
+            memory,
+            available_offset,
+            used_offset,
+            next_available: 0,
+            last_used: 0,
+        })
+    }
+
+    /// バッファを一つのディスクリプタチェーンにしてデバイスに渡し、処理が終わるまで待つ
+    pub fn submit(&mut self, transport: &Transport, buffers: &[Buffer]) -> Result<(), BlockError> {
+        assert!(!buffers.is_empty() && buffers.len() <= self.size as usize);
+        for (index, buffer) in buffers.iter().enumerate() {
+            let offset = index * DESC_SIZE;
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if index + 1 < buffers.len() {
+                flags |= DESC_F_NEXT;
+            }
+            self.memory.write::<u64>(offset, buffer.addr);
+            self.memory.write::<u32>(offset + 8, buffer.len);
+            self.memory.write::<u16>(offset + 12, flags);
+            self.memory.write::<u16>(offset + 14, (index + 1) as u16);
+        }
+
+        let slot = self.available_offset + 4 + 2 * (self.next_available % self.size) as usize;
+        self.memory.write::<u16>(slot, 0);
+        // ディスクリプタとリングの内容が、インデックスより先にデバイスから見えるようにする
+        fence(Ordering::SeqCst);
+        self.next_available = self.next_available.wrapping_add(1);
+        self.memory.write::<u16>(self.available_offset + 2, self.next_available);
+        fence(Ordering::SeqCst);
+        transport.notify(self.index);
+
+        let used_index = self.used_offset + 2;
+        let expected = self.last_used.wrapping_add(1);
+        wait_until(TIMEOUT_SPINS, || self.memory.read::<u16>(used_index) == expected)?;
+        self.last_used = expected;
+        fence(Ordering::SeqCst);
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use x86_64::instructions::port::Port;
+use crate::block::error::BlockError;
+use crate::memory::mmio::{self, MmioRegion};
+use pci::capability::CAP_VENDOR_SPECIFIC;
+use pci::device::Device;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// modernなデバイスでは必ずネゴシエーションしなければならない
+pub const F_VERSION_1: u64 = 1 << 32;
+
+// legacyデバイスのI/Oポート上のレジスタ
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+/// MSI-Xを使わない場合のデバイス固有の設定領域
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+const LEGACY_QUEUE_ALIGN: u64 = 4096;
+
+// modernデバイスの共通設定領域
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// virtio-pciのベンダ固有ケーパビリティの種類
+const CAP_COMMON_CFG: u8 = 1;
+const CAP_NOTIFY_CFG: u8 = 2;
+const CAP_DEVICE_CFG: u8 = 4;
+
+/// 仮想キューの3つの領域の物理アドレス
+#[derive(Debug, Clone, Copy)]
+pub struct QueueAddress {
+    pub descriptors: u64,
+    pub available: u64,
+    pub used: u64,
+}
+
+/// virtio-pciのレジスタへのアクセス方法
+/// legacyはBAR0のI/Oポート、modernはケーパビリティが指すMMIO領域を使う
+pub enum Transport {
+    Legacy {
+        port: u16,
+    },
+    Modern {
+        common: MmioRegion,
+        notify: MmioRegion,
+        notify_multiplier: u32,
+        device: MmioRegion,
+    },
+}
+
+impl Transport {
+    /// modernのケーパビリティがあればそれを使い、無ければlegacyのI/Oポートを使う
+    pub fn new(device: &Device) -> Result<Self, BlockError> {
+        if let Some(transport) = Self::modern(device)? {
+            return Ok(transport);
+        }
+        let port = device.bar(0)?.port().ok_or(BlockError::NoDevice)?;
+        Ok(Transport::Legacy { port })
+    }
+
+    fn modern(device: &Device) -> Result<Option<Self>, BlockError> {
+        let (mut common, mut notify, mut device_cfg) = (None, None, None);
+        let mut notify_multiplier = 0;
+        for capability in device.capabilities() {
+            if capability.id != CAP_VENDOR_SPECIFIC {
+                continue;
+            }
+            let pointer = capability.offset;
+            let cfg_type = device.read_conf_reg8(pointer + 3);
+            let bar = device.read_conf_reg8(pointer + 4) as usize;
+            let offset = device.read_conf_reg(pointer + 8) as u64;
+            let length = device.read_conf_reg(pointer + 12) as usize;
+            let region = match cfg_type {
+                CAP_COMMON_CFG | CAP_NOTIFY_CFG | CAP_DEVICE_CFG => {
+                    let bar = device.bar(bar)?;
+                    if !bar.is_memory() || offset + length as u64 > bar.size {
+                        return Err(BlockError::NoDevice);
+                    }
+                    Some(mmio::map(bar.address + offset, length).map_err(|_| BlockError::NoMemory)?)
+                }
+                _ => None,
+            };
+            match cfg_type {
+                CAP_COMMON_CFG => common = common.or(region),
+                CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = region;
+                    notify_multiplier = device.read_conf_reg(pointer + 16);
+                }
+                CAP_DEVICE_CFG => device_cfg = device_cfg.or(region),
+                _ => {}
+            }
+        }
+
+        match (common, notify, device_cfg) {
+            (Some(common), Some(notify), Some(device)) => Ok(Some(Transport::Modern {
+                common,
+                notify,
+                notify_multiplier,
+                device,
+            })),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        matches!(self, Transport::Modern { .. })
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u8>::new(port + LEGACY_DEVICE_STATUS).read() },
+            Transport::Modern { common, .. } => common.read8(COMMON_DEVICE_STATUS),
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u8>::new(port + LEGACY_DEVICE_STATUS).write(status) },
+            Transport::Modern { common, .. } => common.write8(COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    /// 0を書き込むとデバイスがリセットされる
+    pub fn reset(&self) {
+        self.set_status(0);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u32>::new(port + LEGACY_DEVICE_FEATURES).read() as u64 },
+            Transport::Modern { common, .. } => {
+                common.write32(COMMON_DEVICE_FEATURE_SELECT, 0);
+                let low = common.read32(COMMON_DEVICE_FEATURE) as u64;
+                common.write32(COMMON_DEVICE_FEATURE_SELECT, 1);
+                low | (common.read32(COMMON_DEVICE_FEATURE) as u64) << 32
+            }
+        }
+    }
+
+    pub fn set_driver_features(&self, features: u64) {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u32>::new(port + LEGACY_DRIVER_FEATURES).write(features as u32) },
+            Transport::Modern { common, .. } => {
+                common.write32(COMMON_DRIVER_FEATURE_SELECT, 0);
+                common.write32(COMMON_DRIVER_FEATURE, features as u32);
+                common.write32(COMMON_DRIVER_FEATURE_SELECT, 1);
+                common.write32(COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    /// キューのサイズの上限を返す。0ならそのキューは存在しない
+    pub fn max_queue_size(&self, queue: u16) -> u16 {
+        match self {
+            Transport::Legacy { port } => unsafe {
+                Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(queue);
+                Port::<u16>::new(port + LEGACY_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => {
+                common.write16(COMMON_QUEUE_SELECT, queue);
+                common.read16(COMMON_QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// legacyではキューのサイズを変えられず、3つの領域も決まった配置で並べなければならない
+    pub fn setup_queue(&self, queue: u16, size: u16, address: QueueAddress) {
+        match self {
+            Transport::Legacy { port } => unsafe {
+                Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(queue);
+                Port::<u32>::new(port + LEGACY_QUEUE_ADDRESS).write((address.descriptors / LEGACY_QUEUE_ALIGN) as u32);
+            },
+            Transport::Modern { common, .. } => {
+                common.write16(COMMON_QUEUE_SELECT, queue);
+                common.write16(COMMON_QUEUE_SIZE, size);
+                common.write64(COMMON_QUEUE_DESC, address.descriptors);
+                common.write64(COMMON_QUEUE_DRIVER, address.available);
+                common.write64(COMMON_QUEUE_DEVICE, address.used);
+                common.write16(COMMON_QUEUE_ENABLE, 1);
+            }
+        }
+    }
+
+    pub fn notify(&self, queue: u16) {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u16>::new(port + LEGACY_QUEUE_NOTIFY).write(queue) },
+            Transport::Modern { common, notify, notify_multiplier, .. } => {
+                common.write16(COMMON_QUEUE_SELECT, queue);
+                let offset = common.read16(COMMON_QUEUE_NOTIFY_OFF) as usize * *notify_multiplier as usize;
+                notify.write16(offset, queue);
+            }
+        }
+    }
+
+    /// デバイス固有の設定領域を読む
+    pub fn read_config32(&self, offset: usize) -> u32 {
+        match self {
+            Transport::Legacy { port } => unsafe { Port::<u32>::new(port + LEGACY_DEVICE_CONFIG + offset as u16).read() },
+            Transport::Modern { device, .. } => device.read32(offset),
+        }
+    }
+
+    pub fn read_config64(&self, offset: usize) -> u64 {
+        self.read_config32(offset) as u64 | (self.read_config32(offset + 4) as u64) << 32
+    }
+}
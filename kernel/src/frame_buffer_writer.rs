@@ -1,26 +1,57 @@
 use bootloader_api::info::{FrameBufferInfo, Optional, PixelFormat};
+use core::convert::TryInto;
 use lazy_static::lazy_static;
+use log::debug;
 use spin::Mutex;
+use x86_64::VirtAddr;
 use crate::frame_buffer_writer::cursor::MOUSE_CURSOR_SHAPE;
+use crate::frame_buffer_writer::dirty::{DirtyRegion, Rectangle};
 use crate::frame_buffer_writer::pixel_color::PixelColor;
 use crate::frame_buffer_writer::vector2d::Vector2D;
+use crate::frame_buffer_writer::writing_text::Terminal;
+use crate::memory;
 
+pub mod ansi;
 pub mod cursor;
+pub mod dirty;
 pub mod writing_text;
 pub mod writing_shapes;
 pub mod vector2d;
 pub mod pixel_color;
 
+/// 裏の画面をマップする仮想アドレス。ヒープやMMIOの領域とは重ならないようにする
+pub const BACK_BUFFER_START: u64 = 0x_6666_6666_0000;
+
 lazy_static! {
     pub static ref FRAME_BUFFER_WRITER: Mutex<FrameBufferWriter> = Mutex::new(FrameBufferWriter::new());
 }
 
+/// 裏の画面をRAMに確保して、以後の描画をそちらに向ける。`memory::init_global`の後に呼ぶ
+///
+/// 確保できなければ、これまでどおり画面に直接描く
+pub fn init_back_buffer() {
+    let size = match &FRAME_BUFFER_WRITER.lock().framebuffer {
+        Optional::Some(framebuffer) => framebuffer.len(),
+        Optional::None => return,
+    };
+    match memory::map_zeroed(VirtAddr::new(BACK_BUFFER_START), size) {
+        Ok(buffer) => FRAME_BUFFER_WRITER.lock().set_back_buffer(buffer),
+        Err(err) => debug!("framebuffer: drawing without a back buffer: {:?}", err),
+    }
+}
 
 pub struct FrameBufferWriter {
     framebuffer: Optional<&'static mut [u8]>,
+    /// 描画はすべてこちらに行い、`flush`で`framebuffer`に写す。無ければ`framebuffer`に直接描く
+    back_buffer: Option<&'static mut [u8]>,
+    /// `back_buffer`のうち、まだ`framebuffer`に写していない範囲
+    dirty: DirtyRegion,
+    /// `flush`のたびに画面へ重ねて描くマウスカーソルの位置。裏の画面には描かない
+    mouse_cursor: Option<Vector2D<usize>>,
+    /// `flush`で裏の画面から画面に写したバイト数の合計
+    copied_bytes: u64,
     pub info: FrameBufferInfo,
-    x_pos: usize,
-    y_pos: usize,
+    terminal: Terminal,
 }
 
 impl FrameBufferWriter {
@@ -28,6 +59,10 @@ impl FrameBufferWriter {
         Self {
             // tmp values
             framebuffer: Optional::None,
+            back_buffer: None,
+            dirty: DirtyRegion::new(),
+            mouse_cursor: None,
+            copied_bytes: 0,
             info: FrameBufferInfo {
                 byte_len: 0,
                 width: 0,
@@ -36,8 +71,7 @@ impl FrameBufferWriter {
                 bytes_per_pixel: 0,
                 stride: 0,
             },
-            x_pos: 0,
-            y_pos: 0,
+            terminal: Terminal::new(),
         }
     }
 
@@ -47,28 +81,148 @@ impl FrameBufferWriter {
         self.clear();
     }
 
-    #[doc(hidden)]
-    fn write_framebuffer(&mut self, pos: usize, value: u8) {
-        self.framebuffer.as_mut().unwrap()[pos] = value
+    pub fn is_initialized(&self) -> bool {
+        matches!(self.framebuffer, Optional::Some(_))
     }
 
-    fn write_pixel(&mut self, pos: Vector2D<usize>, color: &PixelColor) -> bool {
-        let pixel_position = pos.x + self.info.stride * pos.y;
-        let byte_position = pixel_position * self.info.bytes_per_pixel;
-        match self.info.pixel_format {
-            PixelFormat::Rgb => {
-                self.write_framebuffer(byte_position, color.r);
-                self.write_framebuffer(byte_position + 1, color.g);
-                self.write_framebuffer(byte_position + 2, color.b);
-                true
+    /// 以後の描画を`buffer`に向ける。今の画面の内容は`buffer`に写しておく
+    pub fn set_back_buffer(&mut self, buffer: &'static mut [u8]) {
+        let framebuffer = self.framebuffer.as_mut().unwrap();
+        assert!(buffer.len() >= framebuffer.len(), "back buffer is smaller than the framebuffer");
+        buffer[..framebuffer.len()].copy_from_slice(framebuffer);
+        self.back_buffer = Some(buffer);
+        self.dirty.clear();
+        // 次の`flush`でマウスカーソルを重ねる
+        if let Some(pos) = self.mouse_cursor {
+            self.mark_dirty(cursor::bounds(pos));
+        }
+    }
+
+    pub fn has_back_buffer(&self) -> bool {
+        self.back_buffer.is_some()
+    }
+
+    /// 残りを画面に写してから裏の画面を外し、以後は画面に直接描く。`set_back_buffer`で戻せる
+    pub fn take_back_buffer(&mut self) -> Option<&'static mut [u8]> {
+        // 裏の画面が無いとマウスカーソルは描けないので、画面からも消しておく
+        let mouse_cursor = self.mouse_cursor;
+        self.set_mouse_cursor(None);
+        self.flush();
+        self.mouse_cursor = mouse_cursor;
+        self.back_buffer.take()
+    }
+
+    /// 描いたものがすべて画面に写されている
+    pub fn is_flushed(&self) -> bool {
+        self.dirty.is_empty()
+    }
+
+    /// これまでに`flush`で画面に写したバイト数。書き換えた範囲だけを写していることを確かめるのに使う
+    pub fn copied_bytes(&self) -> u64 {
+        self.copied_bytes
+    }
+
+    /// マウスカーソルを`pos`に重ねて描く。Noneなら消す。画面に出すには`flush`を呼ぶ
+    ///
+    /// 裏の画面が無いと、描いたものを壊さずに重ねられないので描かない
+    pub fn set_mouse_cursor(&mut self, pos: Option<Vector2D<usize>>) {
+        // 元の位置は裏の画面から写し直し、新しい位置には描き直す
+        if let Some(old) = self.mouse_cursor {
+            self.mark_dirty(cursor::bounds(old));
+        }
+        if let Some(new) = pos {
+            self.mark_dirty(cursor::bounds(new));
+        }
+        self.mouse_cursor = pos;
+    }
+
+    /// 裏の画面のうち、前回から書き換えた範囲だけを画面に写し、マウスカーソルを重ねる
+    pub fn flush(&mut self) {
+        let dirty = core::mem::take(&mut self.dirty);
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let info = self.info;
+        // 写し直した範囲にかかるときだけ描き直す
+        let mouse_cursor = self.mouse_cursor.filter(|&pos| {
+            let bounds = cursor::bounds(pos);
+            dirty.rectangles().iter().any(|rectangle| rectangle.touches(&bounds))
+        });
+        let colors = self.pixel_bytes(&PixelColor::black()).zip(self.pixel_bytes(&PixelColor::white()));
+        let (back_buffer, framebuffer) = match (&self.back_buffer, &mut self.framebuffer) {
+            (Some(back_buffer), Optional::Some(framebuffer)) => (back_buffer, framebuffer),
+            _ => return,
+        };
+        for rectangle in dirty.rectangles() {
+            let len = rectangle.size.x * bytes_per_pixel;
+            for y in rectangle.pos.y..rectangle.bottom() {
+                let start = y * row_bytes + rectangle.pos.x * bytes_per_pixel;
+                copy_wide(&mut framebuffer[start..start + len], &back_buffer[start..start + len]);
             }
-            PixelFormat::Bgr => {
-                self.write_framebuffer(byte_position, color.b);
-                self.write_framebuffer(byte_position + 1, color.g);
-                self.write_framebuffer(byte_position + 2, color.r);
+            self.copied_bytes += (len * rectangle.size.y) as u64;
+        }
+        if let (Some(pos), Some((border, fill))) = (mouse_cursor, colors) {
+            cursor::draw(framebuffer, info, pos, border, fill);
+        }
+    }
+
+    /// 描画先。裏の画面があればそちら
+    fn buffer(&self) -> &[u8] {
+        match &self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer.as_ref().unwrap(),
+        }
+    }
+
+    fn buffer_mut(&mut self) -> &mut [u8] {
+        match &mut self.back_buffer {
+            Some(back_buffer) => back_buffer,
+            None => self.framebuffer.as_mut().unwrap(),
+        }
+    }
+
+    /// 書き換えた範囲を覚えておく。画面からはみ出た部分は捨てる
+    fn mark_dirty(&mut self, rectangle: Rectangle) {
+        self.dirty.add(rectangle.clipped(self.info.width, self.info.height));
+    }
+
+    /// 画素の形式に合わせて並べた色。対応していない形式ならNone
+    fn pixel_bytes(&self, color: &PixelColor) -> Option<[u8; 3]> {
+        match self.info.pixel_format {
+            PixelFormat::Rgb => Some([color.r, color.g, color.b]),
+            PixelFormat::Bgr => Some([color.b, color.g, color.r]),
+            _ => None,
+        }
+    }
+
+    /// 画面には写さないので、呼び出し側が`mark_dirty`する
+    fn write_pixel(&mut self, pos: Vector2D<usize>, color: &PixelColor) -> bool {
+        let byte_position = (pos.x + self.info.stride * pos.y) * self.info.bytes_per_pixel;
+        match self.pixel_bytes(color) {
+            Some(bytes) => {
+                self.buffer_mut()[byte_position..byte_position + 3].copy_from_slice(&bytes);
                 true
             }
-            _ => { false }
+            None => false,
+        }
+    }
+
+    /// `write_pixel`で書いた色を読み戻す
+    pub fn read_pixel(&self, pos: Vector2D<usize>) -> Option<PixelColor> {
+        self.decode_pixel(self.buffer(), pos)
+    }
+
+    /// 画面に写っている色。裏の画面があれば、`flush`するまでは`read_pixel`と違うことがある
+    pub fn read_screen_pixel(&self, pos: Vector2D<usize>) -> Option<PixelColor> {
+        self.decode_pixel(self.framebuffer.as_ref().into_option()?, pos)
+    }
+
+    fn decode_pixel(&self, buffer: &[u8], pos: Vector2D<usize>) -> Option<PixelColor> {
+        let byte_position = (pos.x + self.info.stride * pos.y) * self.info.bytes_per_pixel;
+        let bytes = buffer.get(byte_position..byte_position + 3)?;
+        match self.info.pixel_format {
+            PixelFormat::Rgb => Some(PixelColor::new(bytes[0], bytes[1], bytes[2])),
+            PixelFormat::Bgr => Some(PixelColor::new(bytes[2], bytes[1], bytes[0])),
+            _ => None,
         }
     }
 
@@ -86,5 +240,20 @@ impl FrameBufferWriter {
                 }
             }
         }
+        self.mark_dirty(Rectangle::new(pos, Vector2D::new(MOUSE_CURSOR_SHAPE[0].len(), MOUSE_CURSOR_SHAPE.len())));
+    }
+}
+
+/// `src`を`dst`に写す。MMIOの画面には1バイトずつより8バイトずつ書く方がずっと速い
+fn copy_wide(dst: &mut [u8], src: &[u8]) {
+    // `align_to_mut`は、`dst`の先頭のそろっていない部分と、そろった8バイトの並びと、残りに分ける
+    let (head, body, tail) = unsafe { dst.align_to_mut::<u64>() };
+    let (src_head, src) = src.split_at(head.len());
+    let (src_body, src_tail) = src.split_at(body.len() * 8);
+    head.copy_from_slice(src_head);
+    for (dst, src) in body.iter_mut().zip(src_body.chunks_exact(8)) {
+        // volatileで書いて、コンパイラに1バイトずつのコピーへ戻されないようにする
+        unsafe { (dst as *mut u64).write_volatile(u64::from_ne_bytes(src.try_into().unwrap())) };
     }
+    tail.copy_from_slice(src_tail);
 }
@@ -0,0 +1,305 @@
+//! ANSI/VT100のエスケープシーケンスの解釈と、SGRで決まる文字の色
+
+use crate::frame_buffer_writer::pixel_color::PixelColor;
+
+/// CSIの引数の最大数。これを超えた引数は最後のものに上書きされる
+pub const MAX_PARAMETERS: usize = 16;
+
+pub const DEFAULT_FOREGROUND: PixelColor = PixelColor::new(255, 255, 255);
+pub const DEFAULT_BACKGROUND: PixelColor = PixelColor::new(0, 0, 0);
+
+/// CSIの数値の引数。省略された引数は0になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parameters {
+    values: [u16; MAX_PARAMETERS],
+    len: usize,
+}
+
+impl Parameters {
+    const fn new() -> Self {
+        Self { values: [0; MAX_PARAMETERS], len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> u16 {
+        if index < self.len { self.values[index] } else { 0 }
+    }
+
+    /// 省略されたか0なら`default`。カーソルの移動量はこれで読む
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.get(index) {
+            0 => default,
+            value => value,
+        }
+    }
+
+    fn push_digit(&mut self, digit: u16) {
+        if self.len == 0 {
+            self.len = 1;
+        }
+        let value = &mut self.values[self.len - 1];
+        *value = value.saturating_mul(10).saturating_add(digit);
+    }
+
+    fn next(&mut self) {
+        if self.len == 0 {
+            self.len = 1;
+        }
+        if self.len < MAX_PARAMETERS {
+            self.len += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(char),
+    /// `\n`や`\x08`のような制御文字
+    Control(char),
+    /// `ESC [`で始まるシーケンス。`private`は`ESC [?25h`の`?`のような引数の前の文字
+    Csi { private: Option<char>, parameters: Parameters, command: char },
+    /// `ESC 7`のような二文字のシーケンス
+    Escape(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    /// `ESC ]`で始まるOSC。BELか`ESC \`まで読み捨てる
+    Osc,
+    OscEscape,
+}
+
+/// 文字を一つずつ受け取り、エスケープシーケンスがそろったら動作を返す
+pub struct Parser {
+    state: State,
+    private: Option<char>,
+    parameters: Parameters,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self { state: State::Ground, private: None, parameters: Parameters::new() }
+    }
+
+    pub fn advance(&mut self, c: char) -> Option<Action> {
+        match self.state {
+            State::Ground => match c {
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                '\x7f' => None,
+                c if c.is_control() => Some(Action::Control(c)),
+                c => Some(Action::Print(c)),
+            },
+            State::Escape => {
+                self.state = State::Ground;
+                match c {
+                    '[' => {
+                        self.state = State::Csi;
+                        self.private = None;
+                        self.parameters = Parameters::new();
+                        None
+                    }
+                    ']' => {
+                        self.state = State::Osc;
+                        None
+                    }
+                    '\x1b' => {
+                        self.state = State::Escape;
+                        None
+                    }
+                    c => Some(Action::Escape(c)),
+                }
+            }
+            State::Csi => match c {
+                '0'..='9' => {
+                    self.parameters.push_digit(c as u16 - '0' as u16);
+                    None
+                }
+                ';' | ':' => {
+                    self.parameters.next();
+                    None
+                }
+                '<'..='?' if self.parameters.is_empty() && self.private.is_none() => {
+                    self.private = Some(c);
+                    None
+                }
+                // 中間文字は使わないので読み捨てる
+                ' '..='/' => None,
+                '@'..='~' => {
+                    self.state = State::Ground;
+                    Some(Action::Csi { private: self.private, parameters: self.parameters, command: c })
+                }
+                '\x1b' => {
+                    self.state = State::Escape;
+                    None
+                }
+                // シーケンスの途中の制御文字は、そのまま実行する
+                c if c.is_control() => Some(Action::Control(c)),
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Osc => {
+                match c {
+                    '\x07' => self.state = State::Ground,
+                    '\x1b' => self.state = State::OscEscape,
+                    _ => {}
+                }
+                None
+            }
+            State::OscEscape => {
+                self.state = if c == '\x1b' { State::OscEscape } else { State::Ground };
+                None
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    Default,
+    /// 256色のパレットの番号
+    Indexed(u8),
+    Rgb(PixelColor),
+}
+
+/// SGRで決まる文字の属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub foreground: Color,
+    pub background: Color,
+    pub bold: bool,
+    pub inverse: bool,
+}
+
+impl Attributes {
+    pub const fn new() -> Self {
+        Self { foreground: Color::Default, background: Color::Default, bold: false, inverse: false }
+    }
+
+    /// `ESC [ ... m`の引数を順に適用する。引数が無ければリセットする
+    pub fn apply_sgr(&mut self, parameters: &Parameters) {
+        if parameters.is_empty() {
+            *self = Self::new();
+            return;
+        }
+        let mut i = 0;
+        while i < parameters.len() {
+            match parameters.get(i) {
+                0 => *self = Self::new(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.inverse = true,
+                27 => self.inverse = false,
+                code @ 30..=37 => self.foreground = Color::Indexed((code - 30) as u8),
+                38 => self.foreground = extended_color(parameters, &mut i).unwrap_or(self.foreground),
+                39 => self.foreground = Color::Default,
+                code @ 40..=47 => self.background = Color::Indexed((code - 40) as u8),
+                48 => self.background = extended_color(parameters, &mut i).unwrap_or(self.background),
+                49 => self.background = Color::Default,
+                code @ 90..=97 => self.foreground = Color::Indexed((code - 90 + 8) as u8),
+                code @ 100..=107 => self.background = Color::Indexed((code - 100 + 8) as u8),
+                // 下線や斜体などは描けないので無視する
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+
+    /// 太字と反転を反映した、文字と背景の色
+    pub fn colors(&self) -> (PixelColor, PixelColor) {
+        let foreground = match self.foreground {
+            Color::Default => DEFAULT_FOREGROUND,
+            // 太字の基本8色は明るい色で描く
+            Color::Indexed(index) if self.bold && index < 8 => palette(index + 8),
+            Color::Indexed(index) => palette(index),
+            Color::Rgb(color) => color,
+        };
+        let background = match self.background {
+            Color::Default => DEFAULT_BACKGROUND,
+            Color::Indexed(index) => palette(index),
+            Color::Rgb(color) => color,
+        };
+        if self.inverse { (background, foreground) } else { (foreground, background) }
+    }
+}
+
+impl Default for Attributes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `38;5;n`と`38;2;r;g;b`。`i`は読んだ引数の最後に進める
+fn extended_color(parameters: &Parameters, i: &mut usize) -> Option<Color> {
+    match parameters.get(*i + 1) {
+        5 if *i + 2 < parameters.len() => {
+            *i += 2;
+            Some(Color::Indexed(parameters.get(*i).min(255) as u8))
+        }
+        2 if *i + 4 < parameters.len() => {
+            let component = |offset: usize| parameters.get(*i + offset).min(255) as u8;
+            let color = PixelColor::new(component(2), component(3), component(4));
+            *i += 4;
+            Some(Color::Rgb(color))
+        }
+        _ => {
+            *i = parameters.len();
+            None
+        }
+    }
+}
+
+/// xtermの256色。0から15は基本の16色、16から231は6x6x6の色の立方体、232から255は灰色
+pub fn palette(index: u8) -> PixelColor {
+    const BASE: [PixelColor; 16] = [
+        PixelColor::new(0, 0, 0),
+        PixelColor::new(170, 0, 0),
+        PixelColor::new(0, 170, 0),
+        PixelColor::new(170, 85, 0),
+        PixelColor::new(0, 0, 170),
+        PixelColor::new(170, 0, 170),
+        PixelColor::new(0, 170, 170),
+        PixelColor::new(170, 170, 170),
+        PixelColor::new(85, 85, 85),
+        PixelColor::new(255, 85, 85),
+        PixelColor::new(85, 255, 85),
+        PixelColor::new(255, 255, 85),
+        PixelColor::new(85, 85, 255),
+        PixelColor::new(255, 85, 255),
+        PixelColor::new(85, 255, 255),
+        PixelColor::new(255, 255, 255),
+    ];
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    match index {
+        0..=15 => BASE[index as usize],
+        16..=231 => {
+            let index = index - 16;
+            PixelColor::new(
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        _ => PixelColor::byte_to_color(8 + (index - 232) * 10),
+    }
+}
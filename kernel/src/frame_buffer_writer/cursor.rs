@@ -1,3 +1,10 @@
+use bootloader_api::info::FrameBufferInfo;
+use spin::Mutex;
+use crate::frame_buffer_writer::dirty::Rectangle;
+use crate::frame_buffer_writer::vector2d::Vector2D;
+use crate::frame_buffer_writer::FrameBufferWriter;
+
+const K_MOUSE_CURSOR_WIDTH: usize = 15;
 const K_MOUSE_CURSOR_HEIGHT: usize = 24;
 pub static MOUSE_CURSOR_SHAPE: [&[u8]; K_MOUSE_CURSOR_HEIGHT] = [
     "@              ".as_bytes(),
@@ -24,4 +31,101 @@ pub static MOUSE_CURSOR_SHAPE: [&[u8]; K_MOUSE_CURSOR_HEIGHT] = [
     "@       @.@    ".as_bytes(),
     "         @.@   ".as_bytes(),
     "         @@@   ".as_bytes(),
-];
\ No newline at end of file
+];
+
+/// マウスの動きに合わせて画面上を動くカーソル
+pub static MOUSE_CURSOR: Mutex<MouseCursor> = Mutex::new(MouseCursor::new());
+
+/// 画面の上に重ねて描くカーソル
+///
+/// 裏の画面には描かず、`FrameBufferWriter::flush`で画面に写すときに重ねる。
+/// そのため、カーソルの下に文字を書いたりスクロールしたりしても跡が残らない
+pub struct MouseCursor {
+    position: Vector2D<usize>,
+    visible: bool,
+}
+
+impl Default for MouseCursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MouseCursor {
+    pub const fn new() -> Self {
+        Self {
+            position: Vector2D { x: 0, y: 0 },
+            visible: false,
+        }
+    }
+
+    /// カーソルの先端の位置
+    pub fn position(&self) -> Vector2D<usize> {
+        self.position
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn show(&mut self, writer: &mut FrameBufferWriter) {
+        if self.visible {
+            return;
+        }
+        writer.set_mouse_cursor(Some(self.position));
+        self.visible = true;
+    }
+
+    pub fn hide(&mut self, writer: &mut FrameBufferWriter) {
+        if !self.visible {
+            return;
+        }
+        writer.set_mouse_cursor(None);
+        self.visible = false;
+    }
+
+    /// 画面の内側に収まるように`pos`へ動かす
+    pub fn move_to(&mut self, writer: &mut FrameBufferWriter, pos: Vector2D<usize>) {
+        self.position = Vector2D::new(
+            pos.x.min(writer.info.width.saturating_sub(1)),
+            pos.y.min(writer.info.height.saturating_sub(1)),
+        );
+        if self.visible {
+            writer.set_mouse_cursor(Some(self.position));
+        }
+    }
+
+    pub fn move_by(&mut self, writer: &mut FrameBufferWriter, dx: i16, dy: i16) {
+        let x = (self.position.x as isize + dx as isize).max(0) as usize;
+        let y = (self.position.y as isize + dy as isize).max(0) as usize;
+        self.move_to(writer, Vector2D::new(x, y));
+    }
+}
+
+/// 先端を`origin`に置いたカーソルが覆う範囲
+pub(crate) fn bounds(origin: Vector2D<usize>) -> Rectangle {
+    Rectangle::new(origin, Vector2D::new(K_MOUSE_CURSOR_WIDTH, K_MOUSE_CURSOR_HEIGHT))
+}
+
+/// `buffer`にカーソルを描く。`border`と`fill`は画素の形式に合わせて並べた色
+pub(crate) fn draw(buffer: &mut [u8], info: FrameBufferInfo, origin: Vector2D<usize>, border: [u8; 3], fill: [u8; 3]) {
+    for (pos, shape) in pixels(origin, info) {
+        let start = (pos.x + info.stride * pos.y) * info.bytes_per_pixel;
+        let color = if shape == b'@' { &border } else { &fill };
+        buffer[start..start + 3].copy_from_slice(color);
+    }
+}
+
+/// カーソルの形のうち画面に収まる画素の、画面上の位置と形の文字
+fn pixels(origin: Vector2D<usize>, info: FrameBufferInfo) -> impl Iterator<Item = (Vector2D<usize>, u8)> {
+    MOUSE_CURSOR_SHAPE.iter().enumerate().flat_map(move |(y, row)| {
+        row.iter().enumerate().filter_map(move |(x, &shape)| {
+            let pos = origin + Vector2D::new(x, y);
+            if shape == b' ' || pos.x >= info.width || pos.y >= info.height {
+                None
+            } else {
+                Some((pos, shape))
+            }
+        })
+    })
+}
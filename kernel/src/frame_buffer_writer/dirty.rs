@@ -0,0 +1,128 @@
+//! 裏の画面のうち、まだ画面に写していない範囲
+
+use crate::frame_buffer_writer::vector2d::Vector2D;
+
+/// 覚えておく四角形の最大数。これを超えると近いものどうしをまとめる
+pub const MAX_DIRTY_RECTANGLES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rectangle {
+    pub pos: Vector2D<usize>,
+    pub size: Vector2D<usize>,
+}
+
+impl Rectangle {
+    pub const fn new(pos: Vector2D<usize>, size: Vector2D<usize>) -> Self {
+        Self { pos, size }
+    }
+
+    pub fn right(&self) -> usize {
+        self.pos.x + self.size.x
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.pos.y + self.size.y
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size.x == 0 || self.size.y == 0
+    }
+
+    pub fn area(&self) -> usize {
+        self.size.x * self.size.y
+    }
+
+    /// 両方を含む最小の四角形
+    pub fn union(&self, other: &Self) -> Self {
+        let left = self.pos.x.min(other.pos.x);
+        let top = self.pos.y.min(other.pos.y);
+        let right = self.right().max(other.right());
+        let bottom = self.bottom().max(other.bottom());
+        Self::new(Vector2D::new(left, top), Vector2D::new(right - left, bottom - top))
+    }
+
+    /// 重なっているか、辺で接している
+    pub fn touches(&self, other: &Self) -> bool {
+        self.pos.x <= other.right() && other.pos.x <= self.right()
+            && self.pos.y <= other.bottom() && other.pos.y <= self.bottom()
+    }
+
+    /// `width`x`height`の画面に収まる部分
+    pub fn clipped(&self, width: usize, height: usize) -> Self {
+        let left = self.pos.x.min(width);
+        let top = self.pos.y.min(height);
+        Self::new(
+            Vector2D::new(left, top),
+            Vector2D::new(self.right().min(width) - left, self.bottom().min(height) - top),
+        )
+    }
+}
+
+/// 書き換えた範囲を、互いに接しない四角形の集まりとして覚える
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyRegion {
+    rectangles: [Rectangle; MAX_DIRTY_RECTANGLES],
+    len: usize,
+}
+
+impl DirtyRegion {
+    pub const fn new() -> Self {
+        Self {
+            rectangles: [Rectangle::new(Vector2D { x: 0, y: 0 }, Vector2D { x: 0, y: 0 }); MAX_DIRTY_RECTANGLES],
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn rectangles(&self) -> &[Rectangle] {
+        &self.rectangles[..self.len]
+    }
+
+    pub fn add(&mut self, rectangle: Rectangle) {
+        if rectangle.is_empty() {
+            return;
+        }
+        // 接するものとまとめる。まとめた結果が別のものと接することもあるので、最初から見直す
+        let mut rectangle = rectangle;
+        let mut i = 0;
+        while i < self.len {
+            if self.rectangles[i].touches(&rectangle) {
+                rectangle = rectangle.union(&self.rectangles[i]);
+                self.remove(i);
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if self.len == MAX_DIRTY_RECTANGLES {
+            // 一杯なら、まとめたときに余分に写す面積が一番小さいものとまとめる
+            let (index, _) = self.rectangles().iter().enumerate()
+                .min_by_key(|(_, other)| other.union(&rectangle).area() - other.area())
+                .unwrap();
+            let merged = self.rectangles[index].union(&rectangle);
+            self.remove(index);
+            self.add(merged);
+            return;
+        }
+        self.rectangles[self.len] = rectangle;
+        self.len += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.len -= 1;
+        self.rectangles[index] = self.rectangles[self.len];
+    }
+}
+
+impl Default for DirtyRegion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
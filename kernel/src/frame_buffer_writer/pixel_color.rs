@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PixelColor {
     pub r: u8,
     pub g: u8,
@@ -43,4 +44,21 @@ impl PixelColor {
             byte, byte, byte,
         )
     }
+
+    /// フォントの濃さ`alpha`で、`self`の上に`foreground`を重ねる
+    pub const fn blend(self, foreground: Self, alpha: u8) -> Self {
+        const fn mix(background: u8, foreground: u8, alpha: u8) -> u8 {
+            ((background as u16 * (255 - alpha as u16) + foreground as u16 * alpha as u16) / 255) as u8
+        }
+        Self::new(
+            mix(self.r, foreground.r, alpha),
+            mix(self.g, foreground.g, alpha),
+            mix(self.b, foreground.b, alpha),
+        )
+    }
+
+    /// 二回反転すると元に戻る
+    pub const fn inverted(self) -> Self {
+        Self::new(255 - self.r, 255 - self.g, 255 - self.b)
+    }
 }
@@ -1,15 +1,29 @@
 use crate::frame_buffer_writer::{FrameBufferWriter};
+use crate::frame_buffer_writer::dirty::Rectangle;
 use crate::frame_buffer_writer::pixel_color::PixelColor;
 use crate::frame_buffer_writer::vector2d::Vector2D;
 
 
 impl FrameBufferWriter {
+    /// 一行目を画素ごとに塗り、残りの行はそれを写して塗る。画面からはみ出た部分は描かない
     pub fn fill_rectangle(&mut self, pos: Vector2D<usize>, size: Vector2D<usize>, color: PixelColor) {
-        for x in 0..size.x {
-            for y in 0..size.y {
-                self.write_pixel(pos + Vector2D::new(x, y), &color);
-            }
+        let rectangle = Rectangle::new(pos, size).clipped(self.info.width, self.info.height);
+        let bytes = match self.pixel_bytes(&color) {
+            Some(bytes) if !rectangle.is_empty() => bytes,
+            _ => return,
+        };
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let row_bytes = self.info.stride * bytes_per_pixel;
+        let start = rectangle.pos.y * row_bytes + rectangle.pos.x * bytes_per_pixel;
+        let len = rectangle.size.x * bytes_per_pixel;
+        let buffer = self.buffer_mut();
+        for pixel in buffer[start..start + len].chunks_exact_mut(bytes_per_pixel) {
+            pixel[..3].copy_from_slice(&bytes);
+        }
+        for y in 1..rectangle.size.y {
+            buffer.copy_within(start..start + len, start + y * row_bytes);
         }
+        self.mark_dirty(rectangle);
     }
 
     pub fn draw_rectangle(&mut self, pos: Vector2D<usize>, size: Vector2D<usize>, color: PixelColor) {
@@ -22,5 +36,6 @@ impl FrameBufferWriter {
             self.write_pixel(pos + Vector2D::new(0, y), &color);
             self.write_pixel(pos + Vector2D::new(size.x, y), &color);
         }
+        self.mark_dirty(Rectangle::new(pos, size + Vector2D::new(1, 1)));
     }
-}
\ No newline at end of file
+}
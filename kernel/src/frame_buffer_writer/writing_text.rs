@@ -1,10 +1,13 @@
 use alloc::fmt;
 use core::fmt::Write;
+use core::time::Duration;
 use noto_sans_mono_bitmap::{get_raster, RasterizedChar};
 use crate::frame_buffer_writer::{FRAME_BUFFER_WRITER, FrameBufferWriter};
-use crate::frame_buffer_writer::pixel_color::PixelColor;
+use crate::frame_buffer_writer::ansi::{Action, Attributes, Parameters, Parser};
+use crate::frame_buffer_writer::dirty::Rectangle;
 use crate::frame_buffer_writer::vector2d::Vector2D;
 use crate::frame_buffer_writer::writing_text::font_constants::BACKUP_CHAR;
+use crate::time;
 
 // 行と行の間
 const LINE_SPACING: usize = 2;
@@ -12,6 +15,9 @@ const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 // 画面端からの距離
 const BORDER_PADDING: usize = 1;
+// タブの間隔
+const TAB_WIDTH: usize = 8;
+pub const CURSOR_BLINK_INTERVAL: Duration = Duration::from_millis(500);
 
 mod font_constants {
     use noto_sans_mono_bitmap::{FontWeight, get_raster_width, RasterHeight};
@@ -37,6 +43,14 @@ pub fn _print(args: core::fmt::Arguments) {
     FRAME_BUFFER_WRITER.lock().write_fmt(args).unwrap();
 }
 
+/// 文字のカーソルを`CURSOR_BLINK_INTERVAL`ごとに点滅させる
+pub async fn blink_cursor() {
+    loop {
+        time::sleep(CURSOR_BLINK_INTERVAL).await;
+        FRAME_BUFFER_WRITER.lock().toggle_cursor();
+    }
+}
+
 fn get_char_raster(c: char) -> RasterizedChar {
     fn get(c: char) -> Option<RasterizedChar> {
         get_raster(
@@ -48,20 +62,97 @@ fn get_char_raster(c: char) -> RasterizedChar {
     get(c).unwrap_or_else(|| get(BACKUP_CHAR).expect("Should get raster of backup char."))
 }
 
+fn cell_width() -> usize {
+    font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING
+}
+
+fn line_height() -> usize {
+    font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING
+}
+
+/// 文字を格子に並べて表示する端末の状態。位置は文字単位で、左上が(0, 0)
+pub(super) struct Terminal {
+    parser: Parser,
+    attributes: Attributes,
+    column: usize,
+    row: usize,
+    /// 最後の列に書いた後。次の文字を書く前に折り返す
+    wrap_pending: bool,
+    saved_cursor: (usize, usize),
+    /// `ESC [?25l`で隠される
+    cursor_enabled: bool,
+    /// 点滅のうち、表示している側
+    blink_on: bool,
+    /// カーソルの位置の文字を反転させている
+    cursor_drawn: bool,
+}
+
+impl Terminal {
+    pub(super) const fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            attributes: Attributes::new(),
+            column: 0,
+            row: 0,
+            wrap_pending: false,
+            saved_cursor: (0, 0),
+            cursor_enabled: true,
+            blink_on: true,
+            cursor_drawn: false,
+        }
+    }
+}
+
 impl FrameBufferWriter {
-    fn new_line(&mut self) {
-        self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
-        self.carriage_return();
+    pub fn columns(&self) -> usize {
+        self.width().saturating_sub(2 * BORDER_PADDING) / cell_width()
     }
 
-    fn carriage_return(&mut self) {
-        self.x_pos = BORDER_PADDING;
+    pub fn rows(&self) -> usize {
+        self.height().saturating_sub(2 * BORDER_PADDING) / line_height()
+    }
+
+    /// カーソルの(列, 行)
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.terminal.column, self.terminal.row)
     }
 
     pub fn clear(&mut self) {
-        self.x_pos = BORDER_PADDING;
-        self.y_pos = BORDER_PADDING;
-        self.framebuffer.as_mut().unwrap().fill(0);
+        self.terminal.column = 0;
+        self.terminal.row = 0;
+        self.terminal.wrap_pending = false;
+        self.terminal.cursor_drawn = false;
+        self.buffer_mut().fill(0);
+        self.mark_dirty(Rectangle::new(Vector2D::new(0, 0), Vector2D::new(self.width(), self.height())));
+        self.flush();
+    }
+
+    /// 今の行を消して、行頭に戻る
+    pub fn clear_line(&mut self) {
+        self.hide_cursor();
+        self.erase_in_line(0, self.columns());
+        self.move_cursor_to(0, self.terminal.row);
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// 今の行の`column`文字目に移る。次の文字はそこに上書きされる
+    pub fn set_column(&mut self, column: usize) {
+        self.hide_cursor();
+        self.move_cursor_to(column, self.terminal.row);
+        self.show_cursor();
+        self.flush();
+    }
+
+    /// カーソルの点滅を一段進める
+    pub fn toggle_cursor(&mut self) {
+        if !self.is_initialized() {
+            return;
+        }
+        self.hide_cursor();
+        self.terminal.blink_on = !self.terminal.blink_on;
+        self.show_cursor();
+        self.flush();
     }
 
     fn width(&self) -> usize {
@@ -72,32 +163,202 @@ impl FrameBufferWriter {
         self.info.height
     }
 
-    fn write_char(&mut self, c: char) {
+    /// `column`列`row`行目の文字の左上のピクセル
+    pub fn cell_position(&self, column: usize, row: usize) -> Vector2D<usize> {
+        Vector2D::new(BORDER_PADDING + column * cell_width(), BORDER_PADDING + row * line_height())
+    }
+
+    fn process(&mut self, c: char) {
+        match self.terminal.parser.advance(c) {
+            Some(Action::Print(c)) => self.print_char(c),
+            Some(Action::Control(c)) => self.control(c),
+            Some(Action::Csi { private, parameters, command }) => self.csi(private, &parameters, command),
+            Some(Action::Escape(c)) => self.escape(c),
+            None => {}
+        }
+    }
+
+    fn print_char(&mut self, c: char) {
+        if self.terminal.wrap_pending {
+            self.terminal.column = 0;
+            self.terminal.wrap_pending = false;
+            self.line_feed();
+        }
+        self.write_rendered_char(get_char_raster(c));
+        if self.terminal.column + 1 < self.columns() {
+            self.terminal.column += 1;
+        } else {
+            self.terminal.wrap_pending = true;
+        }
+    }
+
+    fn control(&mut self, c: char) {
+        let (column, row) = self.cursor_position();
+        match c {
+            '\n' => {
+                self.terminal.column = 0;
+                self.terminal.wrap_pending = false;
+                self.line_feed();
+            }
+            '\r' => self.move_cursor_to(0, row),
+            '\t' => self.move_cursor_to((column / TAB_WIDTH + 1) * TAB_WIDTH, row),
+            '\x08' => self.move_cursor_to(column.saturating_sub(1), row),
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, c: char) {
         match c {
-            '\n' => self.new_line(),
-            '\r' => self.carriage_return(),
-            c => {
-                let new_x_pos = self.x_pos + font_constants::CHAR_RASTER_WIDTH;
-                if new_x_pos >= self.width() {
-                    self.new_line();
+            '7' => self.terminal.saved_cursor = self.cursor_position(),
+            '8' => {
+                let (column, row) = self.terminal.saved_cursor;
+                self.move_cursor_to(column, row);
+            }
+            'c' => {
+                self.terminal.attributes = Attributes::new();
+                self.terminal.cursor_enabled = true;
+                self.clear();
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, private: Option<char>, parameters: &Parameters, command: char) {
+        let (column, row) = self.cursor_position();
+        let count = parameters.get_or(0, 1) as usize;
+        match (private, command) {
+            (None, 'A') => self.move_cursor_to(column, row.saturating_sub(count)),
+            (None, 'B') => self.move_cursor_to(column, row + count),
+            (None, 'C') => self.move_cursor_to(column + count, row),
+            (None, 'D') => self.move_cursor_to(column.saturating_sub(count), row),
+            (None, 'E') => self.move_cursor_to(0, row + count),
+            (None, 'F') => self.move_cursor_to(0, row.saturating_sub(count)),
+            (None, 'G') => self.move_cursor_to(count - 1, row),
+            (None, 'd') => self.move_cursor_to(column, count - 1),
+            (None, 'H') | (None, 'f') => {
+                self.move_cursor_to(parameters.get_or(1, 1) as usize - 1, parameters.get_or(0, 1) as usize - 1)
+            }
+            (None, 'J') => match parameters.get(0) {
+                0 => {
+                    self.erase_in_line(column, self.columns());
+                    self.erase_rows(row + 1, self.rows());
                 }
-                let new_y_pos =
-                    self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() + BORDER_PADDING;
-                if new_y_pos >= self.height() {
-                    self.clear();
+                1 => {
+                    self.erase_rows(0, row);
+                    self.erase_in_line(0, column + 1);
                 }
-                self.write_rendered_char(get_char_raster(c));
+                _ => self.erase_rows(0, self.rows()),
+            },
+            (None, 'K') => match parameters.get(0) {
+                0 => self.erase_in_line(column, self.columns()),
+                1 => self.erase_in_line(0, column + 1),
+                _ => self.erase_in_line(0, self.columns()),
+            },
+            (None, 'm') => self.terminal.attributes.apply_sgr(parameters),
+            (None, 's') => self.terminal.saved_cursor = (column, row),
+            (None, 'u') => {
+                let (column, row) = self.terminal.saved_cursor;
+                self.move_cursor_to(column, row);
             }
+            (Some('?'), 'h') | (Some('?'), 'l') if (0..parameters.len()).any(|i| parameters.get(i) == 25) => {
+                self.terminal.cursor_enabled = command == 'h';
+            }
+            _ => {}
+        }
+    }
+
+    /// 画面の外を指したら、端に寄せる
+    fn move_cursor_to(&mut self, column: usize, row: usize) {
+        self.terminal.column = column.min(self.columns().saturating_sub(1));
+        self.terminal.row = row.min(self.rows().saturating_sub(1));
+        self.terminal.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        if self.terminal.row + 1 < self.rows() {
+            self.terminal.row += 1;
+        } else {
+            self.scroll_up();
+        }
+    }
+
+    /// 文字の行を一行分上へ動かし、空いた最後の行を背景色で埋める
+    fn scroll_up(&mut self) {
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let top = self.cell_position(0, 0).y * row_bytes;
+        let bottom = self.cell_position(0, self.rows()).y * row_bytes;
+        let line = line_height() * row_bytes;
+        self.buffer_mut().copy_within(top + line..bottom, top);
+        let origin = self.cell_position(0, 0);
+        let rows = self.rows();
+        self.mark_dirty(Rectangle::new(Vector2D::new(0, origin.y), Vector2D::new(self.width(), rows * line_height())));
+        self.erase_rows(rows - 1, rows);
+    }
+
+    /// `from`行目から`to`行目の手前までを、行の左右の余白も含めて消す
+    fn erase_rows(&mut self, from: usize, to: usize) {
+        if from >= to {
+            return;
         }
+        let (_, background) = self.terminal.attributes.colors();
+        let top = self.cell_position(0, from).y;
+        self.fill_rectangle(Vector2D::new(0, top), Vector2D::new(self.width(), (to - from) * line_height()), background);
+    }
+
+    /// 今の行の`from`列目から`to`列目の手前までを消す。行末まで消すときは右の余白も消す
+    fn erase_in_line(&mut self, from: usize, to: usize) {
+        let to = to.min(self.columns());
+        if from >= to {
+            return;
+        }
+        let (_, background) = self.terminal.attributes.colors();
+        let origin = self.cell_position(from, self.terminal.row);
+        let right = if to == self.columns() { self.width() } else { self.cell_position(to, 0).x };
+        let left = if from == 0 { 0 } else { origin.x };
+        self.fill_rectangle(Vector2D::new(left, origin.y), Vector2D::new(right - left, line_height()), background);
     }
 
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        let (foreground, background) = self.terminal.attributes.colors();
+        let origin = self.cell_position(self.terminal.column, self.terminal.row);
+        // 行間も背景色で塗り、背景色のある行がつながって見えるようにする
+        self.fill_rectangle(origin, Vector2D::new(cell_width(), line_height()), background);
         for (y, row) in rendered_char.raster().iter().enumerate() {
             for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(Vector2D { x: self.x_pos + x, y: self.y_pos + y }, &PixelColor::byte_to_color(*byte));
+                // 背景色はもう塗ってある
+                if *byte != 0 {
+                    self.write_pixel(origin + Vector2D::new(x, y), &background.blend(foreground, *byte));
+                }
             }
         }
-        self.x_pos += rendered_char.width() + LETTER_SPACING;
+    }
+
+    fn show_cursor(&mut self) {
+        let terminal = &self.terminal;
+        if terminal.cursor_enabled && terminal.blink_on && !terminal.cursor_drawn {
+            self.invert_cursor_cell();
+            self.terminal.cursor_drawn = true;
+        }
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.terminal.cursor_drawn {
+            self.invert_cursor_cell();
+            self.terminal.cursor_drawn = false;
+        }
+    }
+
+    fn invert_cursor_cell(&mut self) {
+        let origin = self.cell_position(self.terminal.column, self.terminal.row);
+        for y in 0..line_height() {
+            for x in 0..cell_width() {
+                let position = origin + Vector2D::new(x, y);
+                if let Some(color) = self.read_pixel(position) {
+                    self.write_pixel(position, &color.inverted());
+                }
+            }
+        }
+        self.mark_dirty(Rectangle::new(origin, Vector2D::new(cell_width(), line_height())));
     }
 }
 
@@ -107,9 +368,17 @@ unsafe impl Sync for FrameBufferWriter {}
 
 impl fmt::Write for FrameBufferWriter {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if !self.is_initialized() || self.columns() == 0 || self.rows() == 0 {
+            return Ok(());
+        }
+        self.hide_cursor();
+        // 書いている間は点滅させず、カーソルを見せておく
+        self.terminal.blink_on = true;
         for char in s.chars() {
-            self.write_char(char);
+            self.process(char);
         }
+        self.show_cursor();
+        self.flush();
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,211 @@
+pub mod error;
+pub mod fat;
+pub mod file;
+pub mod initrd;
+pub mod mount;
+pub mod tmpfs;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use spin::Mutex;
+use crate::block::{self, BlockDevice};
+use crate::fs::error::FsError;
+use crate::fs::mount::Vfs;
+
+lazy_static! {
+    pub static ref VFS: Mutex<Vfs> = Mutex::new(Vfs::new());
+}
+
+/// ファイルシステム内でinodeを一意に識別する番号
+pub type InodeId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: InodeId,
+    pub file_type: FileType,
+    pub size: usize,
+    pub nlink: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: InodeId,
+    pub file_type: FileType,
+}
+
+/// ファイルシステムドライバが実装するトレイト
+///
+/// 読み取り専用のファイルシステムは書き込み系のメソッドを実装しなくてよい
+pub trait FileSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> InodeId;
+
+    /// ディレクトリ`dir`から`name`という名前のエントリを探す
+    /// `.`と`..`はVFSが解決するので渡されることはない
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError>;
+
+    fn read(&self, inode: InodeId, offset: usize, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    fn write(&self, _inode: InodeId, _offset: usize, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _inode: InodeId, _size: usize) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError>;
+
+    fn create(&self, _dir: InodeId, _name: &str, _file_type: FileType) -> Result<InodeId, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _dir: InodeId, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError>;
+
+    /// キャッシュされている変更を書き戻す
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// マウントされたファイルシステム上のinode
+#[derive(Clone)]
+pub struct Inode {
+    pub fs: Arc<dyn FileSystem>,
+    pub id: InodeId,
+}
+
+impl Inode {
+    pub fn new(fs: Arc<dyn FileSystem>, id: InodeId) -> Self {
+        Self { fs, id }
+    }
+
+    pub fn root_of(fs: Arc<dyn FileSystem>) -> Self {
+        let id = fs.root();
+        Self { fs, id }
+    }
+
+    /// 同じファイルシステム上の同じinodeを指しているか
+    pub fn is(&self, other: &Inode) -> bool {
+        self.id == other.id && same_fs(&self.fs, &other.fs)
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<Inode, FsError> {
+        let id = self.fs.lookup(self.id, name)?;
+        Ok(Inode::new(self.fs.clone(), id))
+    }
+
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.fs.read(self.id, offset, buf)
+    }
+
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        self.fs.write(self.id, offset, buf)
+    }
+
+    pub fn truncate(&self, size: usize) -> Result<(), FsError> {
+        self.fs.truncate(self.id, size)
+    }
+
+    pub fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.fs.readdir(self.id)
+    }
+
+    pub fn create(&self, name: &str, file_type: FileType) -> Result<Inode, FsError> {
+        let id = self.fs.create(self.id, name, file_type)?;
+        Ok(Inode::new(self.fs.clone(), id))
+    }
+
+    pub fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.fs.unlink(self.id, name)
+    }
+
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        self.fs.stat(self.id)
+    }
+
+    pub fn is_dir(&self) -> Result<bool, FsError> {
+        Ok(self.stat()?.file_type == FileType::Directory)
+    }
+}
+
+pub(crate) fn same_fs(a: &Arc<dyn FileSystem>, b: &Arc<dyn FileSystem>) -> bool {
+    // vtableのアドレスは比較に使えないので、データ部分のアドレスだけを比べる
+    Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+}
+
+/// ブロックデバイス上のファイルシステムをマウントするディレクトリ
+pub const MOUNT_POINT: &str = "/mnt";
+
+/// initrdがあればそれを、無ければ空のtmpfsをルートにマウントする
+///
+/// ルートは読み取り専用のことがあるので、`/mnt`にはtmpfsを重ねてマウントポイントを作れるようにしておく
+pub fn init() {
+    let root: Arc<dyn FileSystem> = match crate::initrd::root() {
+        Some(initrd) => Arc::new(*initrd),
+        None => Arc::new(tmpfs::TmpFs::new()),
+    };
+    let mut vfs = VFS.lock();
+    vfs.mount("/", root)
+        .expect("failed to mount the root filesystem");
+
+    let mount_point = match vfs.resolve(MOUNT_POINT) {
+        Ok(inode) => Ok(inode),
+        Err(FsError::NotFound) => vfs.mkdir(MOUNT_POINT),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = mount_point.and_then(|_| vfs.mount(MOUNT_POINT, Arc::new(tmpfs::TmpFs::new()))) {
+        warn!("fs: cannot prepare {}: {}", MOUNT_POINT, err);
+    }
+}
+
+/// 登録されているブロックデバイスのうち、FATとして読めるものをすべて`/mnt/<名前>`にマウントする
+pub fn mount_block_devices() {
+    for (name, device) in block::devices() {
+        match mount_device(&name, device) {
+            Ok(()) => info!("fs: mounted {} on {}/{}", name, MOUNT_POINT, name),
+            Err(err) => debug!("fs: {} is not mounted: {}", name, err),
+        }
+    }
+}
+
+/// ブロックデバイスをFATとして`/mnt/<name>`にマウントする
+pub fn mount_device(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), FsError> {
+    let path = format!("{}/{}", MOUNT_POINT, name);
+    let mut vfs = VFS.lock();
+    if vfs.mounts().iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    let fs = fat::FatFs::new(device)?;
+    match vfs.mkdir(&path) {
+        Ok(_) | Err(FsError::AlreadyExists) => {}
+        Err(err) => return Err(err),
+    }
+    vfs.mount(&path, Arc::new(fs))
+}
+
+/// `/mnt/<name>`にマウントしたファイルシステムを外す
+///
+/// デバイスが取り外されたときにも呼ぶので、変更を書き戻せなくても外す
+pub fn unmount_device(name: &str) -> Result<(), FsError> {
+    let path = format!("{}/{}", MOUNT_POINT, name);
+    let mut vfs = VFS.lock();
+    vfs.force_unmount(&path)?;
+    vfs.unlink(&path)
+}
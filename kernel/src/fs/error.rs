@@ -0,0 +1,65 @@
+use core::error;
+use core::error::Error;
+use core::fmt::{Debug, Display, Formatter};
+use crate::block::error::BlockError;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    NotEmpty,
+    ReadOnly,
+    InvalidPath,
+    InvalidArgument,
+    PermissionDenied,
+    Busy,
+    NoSpace,
+    Corrupted,
+    Io,
+}
+
+impl Debug for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Display for FsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self.description())
+    }
+}
+
+impl error::Error for FsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl FsError {
+    fn description(&self) -> &'static str {
+        match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::AlreadyExists => "File exists",
+            FsError::NotEmpty => "Directory not empty",
+            FsError::ReadOnly => "Read-only file system",
+            FsError::InvalidPath => "Invalid path",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::PermissionDenied => "Permission denied",
+            FsError::Busy => "Device or resource busy",
+            FsError::NoSpace => "No space left on device",
+            FsError::Corrupted => "File system is corrupted",
+            FsError::Io => "I/O error",
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(_value: BlockError) -> Self {
+        FsError::Io
+    }
+}
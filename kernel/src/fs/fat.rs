@@ -0,0 +1,456 @@
+pub mod bpb;
+pub mod dir;
+mod table;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::BlockDevice;
+use crate::fs::error::FsError;
+use crate::fs::fat::bpb::{BiosParameterBlock, FatType};
+use crate::fs::fat::dir::{FatEntry, ATTR_ARCHIVE, ATTR_DIRECTORY, DELETED, ENTRY_SIZE};
+use crate::fs::{DirEntry, FileSystem, FileType, InodeId, Stat};
+
+/// ブートセクタは必ずディレクトリエントリではないので、ルートディレクトリの番号として使う
+const ROOT_INODE: InodeId = 0;
+
+/// FAT12/16/32のファイルシステムドライバ
+///
+/// inode番号には短い名前のディレクトリエントリのディスク上のバイト位置を使う
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    bpb: BiosParameterBlock,
+    /// FATとディレクトリは一緒に書き換えるので、操作は一度に一つずつ行う
+    state: Mutex<FatState>,
+}
+
+pub(crate) struct FatState {
+    /// 次に空きクラスタを探し始める位置
+    next_free: u32,
+    fs_info_invalidated: bool,
+}
+
+/// ディレクトリエントリから読み取ったファイルの情報
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    first_cluster: u32,
+    size: u32,
+    is_dir: bool,
+}
+
+impl FatFs {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut boot_sector = vec![0u8; device.sector_size().max(512)];
+        device.read_sectors(0, &mut boot_sector)?;
+        let bpb = BiosParameterBlock::parse(&boot_sector)?;
+
+        let volume_bytes = bpb.total_sectors as u64 * bpb.bytes_per_sector as u64;
+        if volume_bytes > device.sector_count() * device.sector_size() as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(Self {
+            device,
+            bpb,
+            state: Mutex::new(FatState {
+                next_free: 2,
+                fs_info_invalidated: false,
+            }),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.bpb.fat_type
+    }
+
+    /// ディスク上の任意のバイト位置から読み込む
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let sector_size = self.device.sector_size();
+        let mut sector = vec![0u8; sector_size];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let lba = position / sector_size as u64;
+            let within = (position % sector_size as u64) as usize;
+            let len = (sector_size - within).min(buf.len() - done);
+
+            if within == 0 && len == sector_size {
+                self.device.read_sectors(lba, &mut buf[done..done + len])?;
+            } else {
+                self.device.read_sectors(lba, &mut sector)?;
+                buf[done..done + len].copy_from_slice(&sector[within..within + len]);
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// ディスク上の任意のバイト位置に書き込む。セクタの一部だけを書く場合は読んでから書き戻す
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let sector_size = self.device.sector_size();
+        let mut sector = vec![0u8; sector_size];
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let lba = position / sector_size as u64;
+            let within = (position % sector_size as u64) as usize;
+            let len = (sector_size - within).min(buf.len() - done);
+
+            if within == 0 && len == sector_size {
+                self.device.write_sectors(lba, &buf[done..done + len])?;
+            } else {
+                self.device.read_sectors(lba, &mut sector)?;
+                sector[within..within + len].copy_from_slice(&buf[done..done + len]);
+                self.device.write_sectors(lba, &sector)?;
+            }
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.bpb.cluster_to_sector(cluster) as u64 * self.bpb.bytes_per_sector as u64
+    }
+
+    /// FAT32のFSInfoにある空きクラスタ数は、書き換えたら不明(0xFFFFFFFF)にしておく
+    fn invalidate_fs_info(&self, state: &mut FatState) -> Result<(), FsError> {
+        if self.bpb.fat_type != FatType::Fat32 || state.fs_info_invalidated || self.bpb.fs_info_sector == 0 {
+            return Ok(());
+        }
+        let offset = self.bpb.fs_info_sector as u64 * self.bpb.bytes_per_sector as u64 + 488;
+        self.write_at(offset, &u32::MAX.to_le_bytes())?;
+        state.fs_info_invalidated = true;
+        Ok(())
+    }
+
+    fn node(&self, inode: InodeId) -> Result<Node, FsError> {
+        if inode == ROOT_INODE {
+            return Ok(Node {
+                first_cluster: self.bpb.root_cluster,
+                size: 0,
+                is_dir: true,
+            });
+        }
+
+        let mut raw = [0u8; ENTRY_SIZE];
+        self.read_at(inode, &mut raw)?;
+        if raw[0] == 0x00 || raw[0] == DELETED {
+            return Err(FsError::NotFound);
+        }
+        let entry = dir::parse_entries(&[(inode, raw)]).pop().ok_or(FsError::NotFound)?;
+        Ok(Node {
+            first_cluster: entry.first_cluster,
+            size: entry.size,
+            is_dir: entry.is_dir(),
+        })
+    }
+
+    /// ファイルの先頭クラスタとサイズをディレクトリエントリに書き戻す
+    fn update_node(&self, inode: InodeId, node: &Node) -> Result<(), FsError> {
+        let mut fields = [0u8; 12];
+        self.read_at(inode + 20, &mut fields)?;
+        fields[0..2].copy_from_slice(&((node.first_cluster >> 16) as u16).to_le_bytes());
+        fields[6..8].copy_from_slice(&(node.first_cluster as u16).to_le_bytes());
+        fields[8..12].copy_from_slice(&node.size.to_le_bytes());
+        self.write_at(inode + 20, &fields)
+    }
+
+    /// ディレクトリの中身が置かれている(バイト位置, 長さ)の一覧
+    fn dir_regions(&self, node: &Node) -> Result<Vec<(u64, usize)>, FsError> {
+        if node.first_cluster == 0 {
+            // FAT12/16のルートディレクトリはデータ領域の前の固定の場所にある
+            let offset = self.bpb.first_root_dir_sector() as u64 * self.bpb.bytes_per_sector as u64;
+            let len = (self.bpb.root_entry_count as usize) * ENTRY_SIZE;
+            return Ok(vec![(offset, len)]);
+        }
+        let cluster_bytes = self.bpb.bytes_per_cluster() as usize;
+        Ok(self.chain(node.first_cluster)?
+            .into_iter()
+            .map(|cluster| (self.cluster_offset(cluster), cluster_bytes))
+            .collect())
+    }
+
+    fn dir_slots(&self, node: &Node) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, FsError> {
+        let mut slots = Vec::new();
+        for (offset, len) in self.dir_regions(node)? {
+            let mut data = vec![0u8; len];
+            self.read_at(offset, &mut data)?;
+            for (index, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                let mut slot = [0u8; ENTRY_SIZE];
+                slot.copy_from_slice(raw);
+                slots.push((offset + (index * ENTRY_SIZE) as u64, slot));
+            }
+        }
+        Ok(slots)
+    }
+
+    fn dir_entries(&self, dir: InodeId) -> Result<(Node, Vec<FatEntry>), FsError> {
+        let node = self.node(dir)?;
+        if !node.is_dir {
+            return Err(FsError::NotADirectory);
+        }
+        let entries = dir::parse_entries(&self.dir_slots(&node)?);
+        Ok((node, entries))
+    }
+
+    fn find(&self, dir: InodeId, name: &str) -> Result<FatEntry, FsError> {
+        let (_, entries) = self.dir_entries(dir)?;
+        entries
+            .into_iter()
+            .find(|entry| !entry.is_dot() && entry.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    /// 連続した空きスロットに`slots`を書き込み、最後のスロットの位置を返す
+    fn insert_slots(&self, state: &mut FatState, dir: &Node, slots: &[[u8; ENTRY_SIZE]]) -> Result<u64, FsError> {
+        loop {
+            let existing = self.dir_slots(dir)?;
+            let mut run = 0;
+            let mut end_reached = false;
+            for (index, (_, raw)) in existing.iter().enumerate() {
+                // 0x00のエントリ以降はすべて空いている
+                end_reached |= raw[0] == 0x00;
+                if end_reached || raw[0] == DELETED {
+                    run += 1;
+                } else {
+                    run = 0;
+                }
+                if run == slots.len() {
+                    let first = index + 1 - run;
+                    for (slot, raw) in existing[first..=index].iter().zip(slots) {
+                        self.write_at(slot.0, raw)?;
+                    }
+                    return Ok(existing[index].0);
+                }
+            }
+
+            // 空きが足りなければディレクトリを1クラスタ伸ばす
+            if dir.first_cluster == 0 {
+                return Err(FsError::NoSpace);
+            }
+            let last = *self.chain(dir.first_cluster)?.last().ok_or(FsError::Corrupted)?;
+            self.allocate_cluster(state, Some(last))?;
+        }
+    }
+
+    fn read_data(&self, node: &Node, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let size = node.size as usize;
+        if offset >= size || node.first_cluster == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        self.transfer(node, offset, len, |fs, disk_offset, range| {
+            fs.read_at(disk_offset, &mut buf[range])
+        })?;
+        Ok(len)
+    }
+
+    fn write_data(&self, state: &mut FatState, inode: InodeId, node: &mut Node, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        // 書き込み位置がファイルの末尾より後ろなら、間を0で埋める
+        while (node.size as usize) < offset {
+            let zeros = [0u8; 512];
+            let gap = (offset - node.size as usize).min(zeros.len());
+            let size = node.size as usize;
+            self.write_data(state, inode, node, size, &zeros[..gap])?;
+        }
+
+        let end = offset.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if end > u32::MAX as usize {
+            return Err(FsError::NoSpace);
+        }
+
+        let cluster_bytes = self.bpb.bytes_per_cluster() as usize;
+        let mut chain = if node.first_cluster == 0 { Vec::new() } else { self.chain(node.first_cluster)? };
+        let needed = (end + cluster_bytes - 1) / cluster_bytes;
+        while chain.len() < needed {
+            let cluster = self.allocate_cluster(state, chain.last().copied())?;
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+
+        self.transfer(node, offset, buf.len(), |fs, disk_offset, range| {
+            fs.write_at(disk_offset, &buf[range])
+        })?;
+
+        node.size = node.size.max(end as u32);
+        self.update_node(inode, node)?;
+        Ok(buf.len())
+    }
+
+    /// ファイルの`offset`から`len`バイトをクラスタごとに分けて`f`に渡す
+    fn transfer<F>(&self, node: &Node, offset: usize, len: usize, mut f: F) -> Result<(), FsError>
+    where
+        F: FnMut(&Self, u64, core::ops::Range<usize>) -> Result<(), FsError>,
+    {
+        let cluster_bytes = self.bpb.bytes_per_cluster() as usize;
+        let chain = self.chain(node.first_cluster)?;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let cluster = *chain.get(position / cluster_bytes).ok_or(FsError::Corrupted)?;
+            let within = position % cluster_bytes;
+            let chunk = (cluster_bytes - within).min(len - done);
+            f(self, self.cluster_offset(cluster) + within as u64, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.bpb.fat_type {
+            FatType::Fat12 => "fat12",
+            FatType::Fat16 => "fat16",
+            FatType::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> InodeId {
+        ROOT_INODE
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let _state = self.state.lock();
+        Ok(self.find(dir, name)?.location)
+    }
+
+    fn read(&self, inode: InodeId, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.state.lock();
+        let node = self.node(inode)?;
+        if node.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        self.read_data(&node, offset, buf)
+    }
+
+    fn write(&self, inode: InodeId, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.state.lock();
+        let mut node = self.node(inode)?;
+        if node.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        self.write_data(&mut state, inode, &mut node, offset, buf)
+    }
+
+    fn truncate(&self, inode: InodeId, size: usize) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let mut node = self.node(inode)?;
+        if node.is_dir {
+            return Err(FsError::IsADirectory);
+        }
+        if size >= node.size as usize {
+            // 伸ばす場合は書き込みと同じく0で埋める
+            return self.write_data(&mut state, inode, &mut node, size, &[]).map(|_| ());
+        }
+
+        let cluster_bytes = self.bpb.bytes_per_cluster() as usize;
+        let keep = (size + cluster_bytes - 1) / cluster_bytes;
+        if keep == 0 {
+            if node.first_cluster != 0 {
+                self.free_chain(&mut state, node.first_cluster)?;
+            }
+            node.first_cluster = 0;
+        } else {
+            let chain = self.chain(node.first_cluster)?;
+            self.cut_chain(&mut state, chain[keep - 1])?;
+        }
+        node.size = size as u32;
+        self.update_node(inode, &node)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.state.lock();
+        let (_, entries) = self.dir_entries(dir)?;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| DirEntry {
+                inode: entry.location,
+                file_type: if entry.is_dir() { FileType::Directory } else { FileType::File },
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, dir: InodeId, name: &str, file_type: FileType) -> Result<InodeId, FsError> {
+        let mut state = self.state.lock();
+        if !dir::is_valid_long_name(name) {
+            return Err(FsError::InvalidPath);
+        }
+        let (dir_node, entries) = self.dir_entries(dir)?;
+        if entries.iter().any(|entry| !entry.is_dot() && entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, case_flags, needs_long_name) = dir::short_name_for(name, &entries)?;
+        let (attr, first_cluster) = match file_type {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let cluster = self.allocate_cluster(&mut state, None)?;
+                // `..`がルートを指すときは、FAT32でもクラスタ番号0を書く
+                let parent_cluster = if dir == ROOT_INODE { 0 } else { dir_node.first_cluster };
+                let dot = dir::short_entry(b".          ", ATTR_DIRECTORY, 0, cluster, 0);
+                let dot_dot = dir::short_entry(b"..         ", ATTR_DIRECTORY, 0, parent_cluster, 0);
+                self.write_at(self.cluster_offset(cluster), &dot)?;
+                self.write_at(self.cluster_offset(cluster) + ENTRY_SIZE as u64, &dot_dot)?;
+                (ATTR_DIRECTORY, cluster)
+            }
+        };
+
+        let mut slots = if needs_long_name {
+            dir::long_name_slots(name, &short_name)
+        } else {
+            Vec::new()
+        };
+        slots.push(dir::short_entry(&short_name, attr, case_flags, first_cluster, 0));
+
+        match self.insert_slots(&mut state, &dir_node, &slots) {
+            Ok(location) => Ok(location),
+            Err(err) => {
+                if first_cluster != 0 {
+                    self.free_chain(&mut state, first_cluster)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn unlink(&self, dir: InodeId, name: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        let entry = self.find(dir, name)?;
+        if entry.is_dir() {
+            let (_, children) = self.dir_entries(entry.location)?;
+            if children.iter().any(|child| !child.is_dot()) {
+                return Err(FsError::NotEmpty);
+            }
+        }
+
+        if entry.first_cluster != 0 {
+            self.free_chain(&mut state, entry.first_cluster)?;
+        }
+        for slot in &entry.slots {
+            self.write_at(*slot, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let _state = self.state.lock();
+        let node = self.node(inode)?;
+        Ok(Stat {
+            inode,
+            file_type: if node.is_dir { FileType::Directory } else { FileType::File },
+            size: node.size as usize,
+            nlink: 1,
+        })
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let _state = self.state.lock();
+        Ok(self.device.flush()?)
+    }
+}
@@ -0,0 +1,170 @@
+use crate::fs::error::FsError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// FATの種類はBPBの文字列ではなくクラスタ数だけで決まる
+    pub fn from_cluster_count(cluster_count: u32) -> Self {
+        if cluster_count < 4085 {
+            FatType::Fat12
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// これ以上の値はクラスタチェーンの終端を表す
+    pub fn end_of_chain(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+
+    pub fn bad_cluster(&self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FF7,
+            FatType::Fat16 => 0xFFF7,
+            FatType::Fat32 => 0x0FFF_FFF7,
+        }
+    }
+}
+
+/// ブートセクタにあるBIOS Parameter Block
+#[derive(Debug, Clone, Copy)]
+pub struct BiosParameterBlock {
+    pub bytes_per_sector: u32,
+    pub sectors_per_cluster: u32,
+    pub reserved_sectors: u32,
+    pub num_fats: u32,
+    pub root_entry_count: u32,
+    pub total_sectors: u32,
+    pub fat_size: u32,
+    /// FAT32のみ。FAT12/16ではルートディレクトリは固定の領域にある
+    pub root_cluster: u32,
+    pub fs_info_sector: u32,
+    pub fat_type: FatType,
+}
+
+impl BiosParameterBlock {
+    pub fn parse(boot_sector: &[u8]) -> Result<Self, FsError> {
+        if boot_sector.len() < 512 || boot_sector[510] != 0x55 || boot_sector[511] != 0xAA {
+            return Err(FsError::Corrupted);
+        }
+
+        let bytes_per_sector = read_u16(boot_sector, 11) as u32;
+        let sectors_per_cluster = boot_sector[13] as u32;
+        let reserved_sectors = read_u16(boot_sector, 14) as u32;
+        let num_fats = boot_sector[16] as u32;
+        let root_entry_count = read_u16(boot_sector, 17) as u32;
+        let total_sectors = match read_u16(boot_sector, 19) {
+            0 => read_u32(boot_sector, 32),
+            sectors => sectors as u32,
+        };
+        let fat_size = match read_u16(boot_sector, 22) {
+            0 => read_u32(boot_sector, 36),
+            sectors => sectors as u32,
+        };
+
+        let valid = matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors != 0
+            && num_fats != 0
+            && fat_size != 0;
+        if !valid {
+            return Err(FsError::Corrupted);
+        }
+        // 壊れた値で計算があふれないように、データ領域の先頭がボリュームに収まることを確かめておく
+        // これが通れば、以下のメソッドの計算はあふれない
+        let first_data_sector = num_fats
+            .checked_mul(fat_size)
+            .and_then(|fats| fats.checked_add(reserved_sectors))
+            .and_then(|sectors| sectors.checked_add((root_entry_count * 32).div_ceil(bytes_per_sector)))
+            .ok_or(FsError::Corrupted)?;
+        if first_data_sector >= total_sectors {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut bpb = Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            num_fats,
+            root_entry_count,
+            total_sectors,
+            fat_size,
+            root_cluster: 0,
+            fs_info_sector: 0,
+            fat_type: FatType::Fat12,
+        };
+        let cluster_count = bpb.cluster_count();
+        if cluster_count == 0 {
+            return Err(FsError::Corrupted);
+        }
+        bpb.fat_type = FatType::from_cluster_count(cluster_count);
+        if bpb.fat_type == FatType::Fat32 {
+            bpb.root_cluster = read_u32(boot_sector, 44);
+            bpb.fs_info_sector = read_u16(boot_sector, 48) as u32;
+            // クラスタ番号は2から`cluster_count + 1`まで
+            if !(2..cluster_count + 2).contains(&bpb.root_cluster) {
+                return Err(FsError::Corrupted);
+            }
+        }
+        Ok(bpb)
+    }
+
+    pub fn first_fat_sector(&self) -> u32 {
+        self.reserved_sectors
+    }
+
+    /// FAT12/16のルートディレクトリが占めるセクタ数。FAT32では0になる
+    ///
+    /// エントリ数は16ビットなので、32バイトを掛けてもあふれない
+    pub fn root_dir_sectors(&self) -> u32 {
+        (self.root_entry_count * 32).div_ceil(self.bytes_per_sector)
+    }
+
+    pub fn first_root_dir_sector(&self) -> u32 {
+        self.reserved_sectors + self.num_fats * self.fat_size
+    }
+
+    pub fn first_data_sector(&self) -> u32 {
+        self.first_root_dir_sector() + self.root_dir_sectors()
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        (self.total_sectors - self.first_data_sector()) / self.sectors_per_cluster
+    }
+
+    pub fn bytes_per_cluster(&self) -> u32 {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+
+    /// データ領域のクラスタ番号は2から始まる
+    pub fn cluster_to_sector(&self, cluster: u32) -> u32 {
+        self.first_data_sector() + (cluster - 2) * self.sectors_per_cluster
+    }
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+pub(crate) fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
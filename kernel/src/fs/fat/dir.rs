@@ -0,0 +1,305 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::fs::error::FsError;
+use crate::fs::fat::bpb::{read_u16, read_u32, write_u16, write_u32};
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// 削除されたエントリの先頭バイト
+pub const DELETED: u8 = 0xE5;
+/// 短い名前の先頭が本当に0xE5のときは代わりにこの値が記録されている
+const KANJI_LEAD_BYTE: u8 = 0x05;
+
+/// NTRes(オフセット12)の小文字フラグ
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXT: u8 = 0x10;
+
+/// 長い名前のエントリ1つに入るUCS-2の文字数
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_LAST_ENTRY: u8 = 0x40;
+
+/// ディレクトリ内の1ファイル分のエントリ。長い名前のエントリもまとめて扱う
+#[derive(Debug, Clone)]
+pub struct FatEntry {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// 短い名前のエントリがあるディスク上のバイト位置
+    pub location: u64,
+    /// 長い名前のエントリも含めた、このファイルが使っているすべてのスロットの位置
+    pub slots: Vec<u64>,
+}
+
+impl FatEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn is_dot(&self) -> bool {
+        &self.short_name == b".          " || &self.short_name == b"..         "
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || display_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// ディレクトリの生のスロットを順に読み、長い名前を組み立てたエントリを返す
+pub fn parse_entries(slots: &[(u64, [u8; ENTRY_SIZE])]) -> Vec<FatEntry> {
+    let mut entries = Vec::new();
+    let mut long_name_slots: Vec<(u64, [u8; ENTRY_SIZE])> = Vec::new();
+
+    for (location, raw) in slots {
+        match raw[0] {
+            // これ以降のスロットはすべて未使用
+            0x00 => break,
+            DELETED => {
+                long_name_slots.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        let attr = raw[11];
+        if attr & 0x3F == ATTR_LONG_NAME {
+            if raw[0] & LFN_LAST_ENTRY != 0 {
+                long_name_slots.clear();
+            }
+            long_name_slots.push((*location, *raw));
+            continue;
+        }
+        if attr & ATTR_VOLUME_ID != 0 {
+            long_name_slots.clear();
+            continue;
+        }
+
+        let mut short_name = [0u8; 11];
+        short_name.copy_from_slice(&raw[0..11]);
+        if short_name[0] == KANJI_LEAD_BYTE {
+            short_name[0] = DELETED;
+        }
+
+        let long_name = decode_long_name(&long_name_slots, lfn_checksum(&raw[0..11]));
+        let mut slots: Vec<u64> = match long_name {
+            Some(_) => long_name_slots.iter().map(|(location, _)| *location).collect(),
+            None => Vec::new(),
+        };
+        slots.push(*location);
+
+        entries.push(FatEntry {
+            name: long_name.unwrap_or_else(|| display_short_name(&short_name, raw[12])),
+            short_name,
+            attr,
+            first_cluster: (read_u16(raw, 20) as u32) << 16 | read_u16(raw, 26) as u32,
+            size: read_u32(raw, 28),
+            location: *location,
+            slots,
+        });
+        long_name_slots.clear();
+    }
+    entries
+}
+
+/// 長い名前のエントリは最後の部分から順にディスクに並んでいる
+fn decode_long_name(slots: &[(u64, [u8; ENTRY_SIZE])], checksum: u8) -> Option<String> {
+    let count = slots.len();
+    if count == 0 {
+        return None;
+    }
+    for (index, (_, raw)) in slots.iter().enumerate() {
+        let sequence = (raw[0] & !LFN_LAST_ENTRY) as usize;
+        if sequence != count - index || raw[13] != checksum {
+            return None;
+        }
+    }
+
+    let mut units = Vec::with_capacity(count * LFN_CHARS_PER_ENTRY);
+    'slots: for (_, raw) in slots.iter().rev() {
+        for offset in LFN_CHAR_OFFSETS {
+            let unit = read_u16(raw, offset);
+            if unit == 0x0000 {
+                break 'slots;
+            }
+            units.push(unit);
+        }
+    }
+    char::decode_utf16(units.iter().copied())
+        .collect::<Result<String, _>>()
+        .ok()
+}
+
+pub fn lfn_checksum(short_name: &[u8]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*byte))
+}
+
+pub fn display_short_name(short_name: &[u8; 11], case_flags: u8) -> String {
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        let trimmed = match bytes.iter().rposition(|byte| *byte != b' ') {
+            Some(end) => &bytes[..=end],
+            None => &[],
+        };
+        trimmed
+            .iter()
+            .map(|byte| {
+                let c = *byte as char;
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    };
+
+    let base = convert(&short_name[0..8], case_flags & LOWER_CASE_BASE != 0);
+    let ext = convert(&short_name[8..11], case_flags & LOWER_CASE_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+/// 長い名前として使えない文字を含んでいないか
+pub fn is_valid_long_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= 255
+        && !name.ends_with('.')
+        && !name.ends_with(' ')
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c))
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// `name`がそのまま8.3形式で表せるならその短い名前と小文字フラグを返す
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+
+    let mut case_flags = 0;
+    let mut short_name = [b' '; 11];
+    for (part, range, flag) in [(base, 0..8, LOWER_CASE_BASE), (ext, 8..11, LOWER_CASE_EXT)] {
+        let has_lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        let has_upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        // 大文字と小文字が混ざっていると短い名前だけでは表せない
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            case_flags |= flag;
+        }
+        for (index, c) in part.bytes().enumerate() {
+            let c = c.to_ascii_uppercase();
+            if !is_short_name_char(c) {
+                return None;
+            }
+            short_name[range.start + index] = c;
+        }
+    }
+    Some((short_name, case_flags))
+}
+
+/// 新しく作るファイルの短い名前を決める
+/// 8.3形式で表せない名前には`BASE~N.EXT`という名前を付けて、長い名前のエントリが必要なことを返す
+pub fn short_name_for(name: &str, existing: &[FatEntry]) -> Result<([u8; 11], u8, bool), FsError> {
+    let taken = |candidate: &[u8; 11]| existing.iter().any(|entry| &entry.short_name == candidate);
+
+    if let Some((short_name, case_flags)) = exact_short_name(name) {
+        if !taken(&short_name) {
+            return Ok((short_name, case_flags, false));
+        }
+    }
+
+    let sanitize = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                let c = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+                if is_short_name_char(c) { c } else { b'_' }
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((base, ext)) => (sanitize(base), sanitize(ext)),
+        None => (sanitize(trimmed), Vec::new()),
+    };
+
+    for number in 1..1_000_000u32 {
+        let tail = format!("~{}", number);
+        let base_len = base.len().min(8 - tail.len());
+        let mut candidate = [b' '; 11];
+        candidate[..base_len].copy_from_slice(&base[..base_len]);
+        candidate[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        for (index, c) in ext.iter().take(3).enumerate() {
+            candidate[8 + index] = *c;
+        }
+        if !taken(&candidate) {
+            return Ok((candidate, 0, true));
+        }
+    }
+    Err(FsError::AlreadyExists)
+}
+
+/// 長い名前のエントリを、ディスクに並べる順(最後の部分から)で返す
+pub fn long_name_slots(name: &str, short_name: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LFN_CHARS_PER_ENTRY - 1) / LFN_CHARS_PER_ENTRY;
+    // 名前が区切りちょうどでなければ0で終端し、残りは0xFFFFで埋める
+    if units.len() % LFN_CHARS_PER_ENTRY != 0 {
+        units.push(0x0000);
+    }
+    units.resize(count * LFN_CHARS_PER_ENTRY, 0xFFFF);
+
+    let checksum = lfn_checksum(short_name);
+    (0..count)
+        .rev()
+        .map(|index| {
+            let mut raw = [0u8; ENTRY_SIZE];
+            raw[0] = (index + 1) as u8;
+            if index == count - 1 {
+                raw[0] |= LFN_LAST_ENTRY;
+            }
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            for (unit, offset) in units[index * LFN_CHARS_PER_ENTRY..].iter().zip(LFN_CHAR_OFFSETS) {
+                write_u16(&mut raw, offset, *unit);
+            }
+            raw
+        })
+        .collect()
+}
+
+pub fn short_entry(short_name: &[u8; 11], attr: u8, case_flags: u8, first_cluster: u32, size: u32) -> [u8; ENTRY_SIZE] {
+    let mut raw = [0u8; ENTRY_SIZE];
+    raw[0..11].copy_from_slice(short_name);
+    if raw[0] == DELETED {
+        raw[0] = KANJI_LEAD_BYTE;
+    }
+    raw[11] = attr;
+    raw[12] = case_flags;
+    write_u16(&mut raw, 20, (first_cluster >> 16) as u16);
+    write_u16(&mut raw, 26, first_cluster as u16);
+    write_u32(&mut raw, 28, size);
+    raw
+}
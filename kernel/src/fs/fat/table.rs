@@ -0,0 +1,145 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::error::FsError;
+use crate::fs::fat::bpb::FatType;
+use crate::fs::fat::{FatFs, FatState};
+
+/// FATの0番と1番は予約されているので、データ領域のクラスタは2から始まる
+const FIRST_CLUSTER: u32 = 2;
+
+impl FatFs {
+    /// `cluster`番目のFATエントリのバイト位置
+    fn fat_entry_offset(&self, fat_index: u32, cluster: u32) -> u64 {
+        let fat_start = (self.bpb.first_fat_sector() + fat_index * self.bpb.fat_size) as u64
+            * self.bpb.bytes_per_sector as u64;
+        let offset = match self.bpb.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        fat_start + offset as u64
+    }
+
+    pub(super) fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let offset = self.fat_entry_offset(0, cluster);
+        match self.bpb.fat_type {
+            FatType::Fat12 => {
+                // FAT12のエントリは1.5バイトなので、セクタの境界を跨ぐことがある
+                let mut bytes = [0u8; 2];
+                self.read_at(offset, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes) as u32;
+                Ok(if cluster & 1 == 1 { value >> 4 } else { value & 0x0FFF })
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0u8; 2];
+                self.read_at(offset, &mut bytes)?;
+                Ok(u16::from_le_bytes(bytes) as u32)
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0u8; 4];
+                self.read_at(offset, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// すべてのFATのコピーを書き換える
+    pub(super) fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for fat_index in 0..self.bpb.num_fats {
+            let offset = self.fat_entry_offset(fat_index, cluster);
+            match self.bpb.fat_type {
+                FatType::Fat12 => {
+                    let mut bytes = [0u8; 2];
+                    self.read_at(offset, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let value = (value & 0x0FFF) as u16;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000F) | (value << 4)
+                    } else {
+                        (old & 0xF000) | value
+                    };
+                    self.write_at(offset, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => {
+                    self.write_at(offset, &(value as u16).to_le_bytes())?;
+                }
+                FatType::Fat32 => {
+                    // 上位4ビットは予約されているので保持する
+                    let mut bytes = [0u8; 4];
+                    self.read_at(offset, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+                    let new = (old & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write_at(offset, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (FIRST_CLUSTER..self.bpb.cluster_count() + FIRST_CLUSTER).contains(&cluster)
+    }
+
+    /// `first`から始まるクラスタチェーンを返す
+    pub(super) fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        while self.is_valid_cluster(cluster) {
+            chain.push(cluster);
+            // 壊れたFATでループしていても止まるようにする
+            if chain.len() > self.bpb.cluster_count() as usize {
+                return Err(FsError::Corrupted);
+            }
+            cluster = self.fat_entry(cluster)?;
+            if cluster == self.bpb.fat_type.bad_cluster() {
+                return Err(FsError::Corrupted);
+            }
+        }
+        Ok(chain)
+    }
+
+    /// 空いているクラスタを確保して0で埋め、`previous`の後ろにつなぐ
+    pub(super) fn allocate_cluster(&self, state: &mut FatState, previous: Option<u32>) -> Result<u32, FsError> {
+        let cluster_count = self.bpb.cluster_count();
+        let start = if self.is_valid_cluster(state.next_free) { state.next_free } else { FIRST_CLUSTER };
+
+        let mut found = None;
+        for index in 0..cluster_count {
+            let cluster = FIRST_CLUSTER + (start - FIRST_CLUSTER + index) % cluster_count;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, self.bpb.fat_type.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        state.next_free = cluster + 1;
+        self.invalidate_fs_info(state)?;
+
+        let zeros = vec![0u8; self.bpb.bytes_per_cluster() as usize];
+        self.write_at(self.cluster_offset(cluster), &zeros)?;
+        Ok(cluster)
+    }
+
+    /// `first`から始まるクラスタチェーンをすべて解放する
+    pub(super) fn free_chain(&self, state: &mut FatState, first: u32) -> Result<(), FsError> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        self.invalidate_fs_info(state)
+    }
+
+    /// チェーンを`last`で終わらせ、それより後ろのクラスタを解放する
+    pub(super) fn cut_chain(&self, state: &mut FatState, last: u32) -> Result<(), FsError> {
+        let next = self.fat_entry(last)?;
+        self.set_fat_entry(last, self.bpb.fat_type.end_of_chain())?;
+        if self.is_valid_cluster(next) {
+            self.free_chain(state, next)?;
+        }
+        Ok(())
+    }
+}
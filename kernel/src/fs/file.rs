@@ -0,0 +1,111 @@
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use crate::fs::error::FsError;
+use crate::fs::{Inode, Stat};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const CREATE: Self = Self(1 << 2);
+    pub const TRUNCATE: Self = Self(1 << 3);
+    pub const APPEND: Self = Self(1 << 4);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(usize),
+    Current(isize),
+    End(isize),
+}
+
+/// open されたファイル。読み書きする位置(オフセット)を持つ
+pub struct OpenFile {
+    inode: Inode,
+    offset: usize,
+    flags: OpenFlags,
+}
+
+impl OpenFile {
+    pub fn new(inode: Inode, flags: OpenFlags) -> Self {
+        Self {
+            inode,
+            offset: 0,
+            flags,
+        }
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn stat(&self) -> Result<Stat, FsError> {
+        self.inode.stat()
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.inode.is_dir()? {
+            return Err(FsError::IsADirectory);
+        }
+        let read = self.inode.read(self.offset, buf)?;
+        self.offset += read;
+        Ok(read)
+    }
+
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::PermissionDenied);
+        }
+        if self.flags.contains(OpenFlags::APPEND) {
+            self.offset = self.inode.stat()?.size;
+        }
+        let written = self.inode.write(self.offset, buf)?;
+        self.offset += written;
+        Ok(written)
+    }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<usize, FsError> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.inode.stat()?.size, delta),
+        };
+        self.offset = base
+            .checked_add_signed(delta)
+            .ok_or(FsError::InvalidArgument)?;
+        Ok(self.offset)
+    }
+
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 512];
+        loop {
+            let read = self.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use crate::fs::error::FsError;
+use crate::fs::{DirEntry, FileSystem, FileType, InodeId, Stat};
+use crate::initrd::{Entry, EntryKind, Initrd};
+
+/// アーカイブ内のヘッダの位置をinode番号として使うので、ルートには使われない番号を割り当てる
+const ROOT_INODE: InodeId = u64::MAX;
+
+impl Initrd {
+    fn inode_of(entry: &Entry) -> InodeId {
+        if entry.path.is_empty() {
+            ROOT_INODE
+        } else {
+            entry.offset as InodeId
+        }
+    }
+
+    fn entry(&self, inode: InodeId) -> Result<Entry, FsError> {
+        if inode == ROOT_INODE {
+            return self.lookup("").ok_or(FsError::NotFound);
+        }
+        self.entry_at(inode as usize).ok_or(FsError::NotFound)
+    }
+}
+
+fn file_type(kind: EntryKind) -> FileType {
+    match kind {
+        EntryKind::File => FileType::File,
+        EntryKind::Directory => FileType::Directory,
+    }
+}
+
+impl FileSystem for Initrd {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(&self) -> InodeId {
+        ROOT_INODE
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let dir = self.entry(dir)?;
+        if dir.kind != EntryKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let path = if dir.path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", dir.path, name)
+        };
+        self.lookup(&path)
+            .map(|entry| Self::inode_of(&entry))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read(&self, inode: InodeId, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let entry = self.entry(inode)?;
+        if entry.kind == EntryKind::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if offset >= entry.data.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(entry.data.len() - offset);
+        buf[..len].copy_from_slice(&entry.data[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.entry(dir)?;
+        if dir.kind != EntryKind::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(self.read_dir(dir.path)
+            .map(|entry| DirEntry {
+                name: entry.path.rsplit('/').next().unwrap_or(entry.path).to_string(),
+                inode: Self::inode_of(&entry),
+                file_type: file_type(entry.kind),
+            })
+            .collect())
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let entry = self.entry(inode)?;
+        Ok(Stat {
+            inode,
+            file_type: file_type(entry.kind),
+            size: entry.data.len(),
+            nlink: 1,
+        })
+    }
+}
@@ -0,0 +1,225 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::fs::error::FsError;
+use crate::fs::file::{OpenFile, OpenFlags};
+use crate::fs::{DirEntry, FileSystem, FileType, Inode, Stat};
+
+pub struct Mount {
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+    /// マウントによって隠されたディレクトリ。ルートのマウントでは`None`
+    covered: Option<Inode>,
+}
+
+/// マウントテーブルとパス解決を持つ仮想ファイルシステム
+///
+/// パスはすべてルートからの絶対パスとして解釈される
+pub struct Vfs {
+    mounts: Vec<Mount>,
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Self { mounts: Vec::new() }
+    }
+
+    pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+
+    pub fn mount(&mut self, path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+        let path = canonicalize(path);
+        if path == "/" {
+            if self.mounts.iter().any(|mount| mount.covered.is_none()) {
+                return Err(FsError::Busy);
+            }
+            self.mounts.push(Mount { path, fs, covered: None });
+            return Ok(());
+        }
+
+        let dir = self.resolve(&path)?;
+        if !dir.is_dir()? {
+            return Err(FsError::NotADirectory);
+        }
+        self.mounts.push(Mount { path, fs, covered: Some(dir) });
+        Ok(())
+    }
+
+    pub fn unmount(&mut self, path: &str) -> Result<(), FsError> {
+        let index = self.unmountable(path)?;
+        self.mounts[index].fs.sync()?;
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    /// 変更を書き戻せなくても外す。取り外されたデバイス上のファイルシステムに使う
+    pub fn force_unmount(&mut self, path: &str) -> Result<(), FsError> {
+        let index = self.unmountable(path)?;
+        // 書き戻しに失敗しても、外す以外にできることはない
+        let _ = self.mounts[index].fs.sync();
+        self.mounts.remove(index);
+        Ok(())
+    }
+
+    /// `path`にあるマウントのうち、外せるものの位置
+    fn unmountable(&self, path: &str) -> Result<usize, FsError> {
+        let path = canonicalize(path);
+        let index = self.mounts
+            .iter()
+            .rposition(|mount| mount.path == path)
+            .ok_or(FsError::InvalidArgument)?;
+
+        // 上に別のファイルシステムがマウントされていれば外せない
+        let fs = &self.mounts[index].fs;
+        let in_use = self.mounts
+            .iter()
+            .filter_map(|mount| mount.covered.as_ref())
+            .any(|covered| crate::fs::same_fs(&covered.fs, fs));
+        if in_use {
+            return Err(FsError::Busy);
+        }
+        Ok(index)
+    }
+
+    pub fn root(&self) -> Result<Inode, FsError> {
+        let mount = self.mounts
+            .iter()
+            .find(|mount| mount.covered.is_none())
+            .ok_or(FsError::NotFound)?;
+        Ok(self.cross_mounts(Inode::root_of(mount.fs.clone())))
+    }
+
+    pub fn resolve(&self, path: &str) -> Result<Inode, FsError> {
+        Ok(self.walk(path)?.pop().unwrap())
+    }
+
+    /// 最後の要素を除いたパスを解決し、親ディレクトリと最後の要素の名前を返す
+    pub fn resolve_parent<'a>(&self, path: &'a str) -> Result<(Inode, &'a str), FsError> {
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rfind('/') {
+            Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+            None => ("", trimmed),
+        };
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let parent = self.resolve(parent)?;
+        if !parent.is_dir()? {
+            return Err(FsError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    pub fn open(&self, path: &str, flags: OpenFlags) -> Result<OpenFile, FsError> {
+        let inode = match self.resolve(path) {
+            Ok(inode) => inode,
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+                let (parent, name) = self.resolve_parent(path)?;
+                parent.create(name, FileType::File)?
+            }
+            Err(err) => return Err(err),
+        };
+
+        if flags.contains(OpenFlags::WRITE) {
+            if inode.is_dir()? {
+                return Err(FsError::IsADirectory);
+            }
+            if flags.contains(OpenFlags::TRUNCATE) {
+                inode.truncate(0)?;
+            }
+        }
+        Ok(OpenFile::new(inode, flags))
+    }
+
+    pub fn create(&self, path: &str, file_type: FileType) -> Result<Inode, FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        parent.create(name, file_type)
+    }
+
+    pub fn mkdir(&self, path: &str) -> Result<Inode, FsError> {
+        self.create(path, FileType::Directory)
+    }
+
+    pub fn unlink(&self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let target = parent.lookup(name)?;
+        if self.is_mount_point(&target) {
+            return Err(FsError::Busy);
+        }
+        parent.unlink(name)
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Stat, FsError> {
+        self.resolve(path)?.stat()
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let dir = self.resolve(path)?;
+        if !dir.is_dir()? {
+            return Err(FsError::NotADirectory);
+        }
+        dir.readdir()
+    }
+
+    /// ルートから辿ったinodeを順に返す
+    /// `..`で一つ前に戻れるように、辿ってきたinodeをスタックに積んでいく
+    fn walk(&self, path: &str) -> Result<Vec<Inode>, FsError> {
+        let mut stack = vec![self.root()?];
+        for component in path.split('/').filter(|component| !component.is_empty()) {
+            match component {
+                "." => {}
+                ".." => {
+                    // ルートの親はルート自身
+                    if stack.len() > 1 {
+                        stack.pop();
+                    }
+                }
+                name => {
+                    let current = stack.last().unwrap();
+                    if !current.is_dir()? {
+                        return Err(FsError::NotADirectory);
+                    }
+                    let next = current.lookup(name)?;
+                    stack.push(self.cross_mounts(next));
+                }
+            }
+        }
+        Ok(stack)
+    }
+
+    /// `inode`がマウントポイントであれば、そこにマウントされたファイルシステムのルートを返す
+    fn cross_mounts(&self, mut inode: Inode) -> Inode {
+        // 同じ場所に重ねてマウントされている場合は最後にマウントされたものが見える
+        while let Some(mount) = self.mounts
+            .iter()
+            .rev()
+            .find(|mount| matches!(&mount.covered, Some(covered) if covered.is(&inode)))
+        {
+            inode = Inode::root_of(mount.fs.clone());
+        }
+        inode
+    }
+
+    fn is_mount_point(&self, inode: &Inode) -> bool {
+        self.mounts
+            .iter()
+            .any(|mount| matches!(&mount.covered, Some(covered) if covered.is(inode)))
+    }
+}
+
+/// `.`や連続した`/`を取り除いた絶対パスにする
+/// `..`はマウントを跨ぐ可能性があるので文字列上では解決しない
+fn canonicalize(path: &str) -> String {
+    let mut canonical = String::new();
+    for component in path.split('/').filter(|component| !component.is_empty() && *component != ".") {
+        canonical.push('/');
+        canonical.push_str(component);
+    }
+    if canonical.is_empty() {
+        canonical.push('/');
+    }
+    canonical
+}
@@ -0,0 +1,194 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::fs::error::FsError;
+use crate::fs::{DirEntry, FileSystem, FileType, InodeId, Stat};
+
+const ROOT_INODE: InodeId = 1;
+/// 一つのファイルの大きさの上限。大きな位置へのシークの後に書いても、ヒープを使い切らないようにする
+pub const MAX_FILE_SIZE: usize = 4 * 1024 * 1024;
+
+/// ヒープ上にだけ存在するファイルシステム
+pub struct TmpFs {
+    inner: Mutex<TmpFsInner>,
+}
+
+struct TmpFsInner {
+    nodes: BTreeMap<InodeId, Node>,
+    next_inode: InodeId,
+}
+
+enum Node {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, InodeId>),
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self {
+            Node::File(_) => FileType::File,
+            Node::Directory(_) => FileType::Directory,
+        }
+    }
+}
+
+impl TmpFs {
+    pub fn new() -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INODE, Node::Directory(BTreeMap::new()));
+        Self {
+            inner: Mutex::new(TmpFsInner {
+                nodes,
+                next_inode: ROOT_INODE + 1,
+            }),
+        }
+    }
+}
+
+impl TmpFsInner {
+    fn node(&self, inode: InodeId) -> Result<&Node, FsError> {
+        self.nodes.get(&inode).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, inode: InodeId) -> Result<&mut Node, FsError> {
+        self.nodes.get_mut(&inode).ok_or(FsError::NotFound)
+    }
+
+    fn dir(&self, inode: InodeId) -> Result<&BTreeMap<String, InodeId>, FsError> {
+        match self.node(inode)? {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn dir_mut(&mut self, inode: InodeId) -> Result<&mut BTreeMap<String, InodeId>, FsError> {
+        match self.node_mut(inode)? {
+            Node::Directory(entries) => Ok(entries),
+            Node::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn file_mut(&mut self, inode: InodeId) -> Result<&mut Vec<u8>, FsError> {
+        match self.node_mut(inode)? {
+            Node::File(data) => Ok(data),
+            Node::Directory(_) => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+/// ファイルを`size`バイトにする。伸ばした部分は0で埋める
+fn resize(data: &mut Vec<u8>, size: usize) -> Result<(), FsError> {
+    if size > MAX_FILE_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    if size > data.len() {
+        data.try_reserve(size - data.len()).map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(size, 0);
+    Ok(())
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> InodeId {
+        ROOT_INODE
+    }
+
+    fn lookup(&self, dir: InodeId, name: &str) -> Result<InodeId, FsError> {
+        let inner = self.inner.lock();
+        inner.dir(dir)?.get(name).copied().ok_or(FsError::NotFound)
+    }
+
+    fn read(&self, inode: InodeId, offset: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inner = self.inner.lock();
+        let data = match inner.node(inode)? {
+            Node::File(data) => data,
+            Node::Directory(_) => return Err(FsError::IsADirectory),
+        };
+        if offset >= data.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(data.len() - offset);
+        buf[..len].copy_from_slice(&data[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write(&self, inode: InodeId, offset: usize, buf: &[u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let data = inner.file_mut(inode)?;
+        let end = offset.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if data.len() < end {
+            resize(data, end)?;
+        }
+        data[offset..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, inode: InodeId, size: usize) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        resize(inner.file_mut(inode)?, size)
+    }
+
+    fn readdir(&self, dir: InodeId) -> Result<Vec<DirEntry>, FsError> {
+        let inner = self.inner.lock();
+        inner.dir(dir)?
+            .iter()
+            .map(|(name, inode)| {
+                Ok(DirEntry {
+                    name: name.clone(),
+                    inode: *inode,
+                    file_type: inner.node(*inode)?.file_type(),
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, dir: InodeId, name: &str, file_type: FileType) -> Result<InodeId, FsError> {
+        let mut inner = self.inner.lock();
+        if inner.dir(dir)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let inode = inner.next_inode;
+        inner.next_inode += 1;
+        let node = match file_type {
+            FileType::File => Node::File(Vec::new()),
+            FileType::Directory => Node::Directory(BTreeMap::new()),
+        };
+        inner.nodes.insert(inode, node);
+        inner.dir_mut(dir)?.insert(name.to_string(), inode);
+        Ok(inode)
+    }
+
+    fn unlink(&self, dir: InodeId, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = *inner.dir(dir)?.get(name).ok_or(FsError::NotFound)?;
+        if let Node::Directory(entries) = inner.node(inode)? {
+            if !entries.is_empty() {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        inner.dir_mut(dir)?.remove(name);
+        inner.nodes.remove(&inode);
+        Ok(())
+    }
+
+    fn stat(&self, inode: InodeId) -> Result<Stat, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        let size = match node {
+            Node::File(data) => data.len(),
+            Node::Directory(entries) => entries.len(),
+        };
+        Ok(Stat {
+            inode,
+            file_type: node.file_type(),
+            size,
+            nlink: 1,
+        })
+    }
+}
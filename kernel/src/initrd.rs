@@ -0,0 +1,215 @@
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use log::debug;
+
+/// tarのヘッダおよびデータはこのサイズ単位で並んでいる
+const BLOCK_SIZE: usize = 512;
+
+static ROOT_FS: OnceCell<Initrd> = OnceCell::uninit();
+
+/// bootloaderが渡したramdiskを読み取り専用のルートファイルシステムとしてマウントする
+/// ramdiskが無い場合は何もしない
+pub fn init(boot_info: &BootInfo) {
+    let addr = match boot_info.ramdisk_addr.into_option() {
+        Some(addr) => addr,
+        None => {
+            debug!("initrd: no ramdisk was passed by the bootloader");
+            return;
+        }
+    };
+
+    // bootloaderはramdiskをカーネルの仮想アドレス空間にマップしてから渡してくる
+    let initrd = unsafe { Initrd::new(addr as *const u8, boot_info.ramdisk_len as usize) };
+    if ROOT_FS.try_init_once(|| initrd).is_err() {
+        debug!("initrd: root filesystem is already mounted");
+    }
+}
+
+/// マウントされたルートファイルシステムを返す
+pub fn root() -> Option<&'static Initrd> {
+    ROOT_FS.get()
+}
+
+/// ustar形式のアーカイブを読み取り専用のファイルシステムとして扱う
+///
+/// 内容はカーネルが動いている間ずっと有効なので、ファイルのデータはコピーせずにそのまま返す
+#[derive(Debug, Clone, Copy)]
+pub struct Initrd {
+    data: &'static [u8],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    /// アーカイブ内でのヘッダの位置。エントリを一意に識別するのに使える
+    pub offset: usize,
+    /// 先頭の`/`や`./`を取り除いたパス。ルートディレクトリは空文字列
+    pub path: &'static str,
+    pub kind: EntryKind,
+    pub data: &'static [u8],
+}
+
+impl Initrd {
+    /// この関数はunsafeである：`addr`から`len`バイトがカーネルの終了まで
+    /// 読み取り可能であることを呼び出し元が保証しなければならない
+    pub unsafe fn new(addr: *const u8, len: usize) -> Self {
+        Self::from_bytes(core::slice::from_raw_parts(addr, len))
+    }
+
+    pub fn from_bytes(data: &'static [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries {
+            data: self.data,
+            offset: 0,
+        }
+    }
+
+    pub fn lookup(&self, path: &str) -> Option<Entry> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Some(Entry {
+                offset: usize::MAX,
+                path: "",
+                kind: EntryKind::Directory,
+                data: &[],
+            });
+        }
+        self.entries().find(|entry| entry.path == path)
+    }
+
+    pub fn entry_at(&self, offset: usize) -> Option<Entry> {
+        self.entries().find(|entry| entry.offset == offset)
+    }
+
+    /// `path`の直下にあるエントリを返す
+    pub fn read_dir<'a>(&self, path: &'a str) -> impl Iterator<Item = Entry> + 'a {
+        let dir = normalize(path);
+        self.entries().filter(move |entry| {
+            !entry.path.is_empty() && parent(entry.path) == dir
+        })
+    }
+}
+
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // GNU形式の長いファイル名は直後のエントリに適用される
+        let mut long_name: Option<&'static str> = None;
+
+        loop {
+            let offset = self.offset;
+            let header = self.data.get(offset..offset + BLOCK_SIZE)?;
+            // アーカイブの終端は0で埋められたブロックで示される
+            if header.iter().all(|byte| *byte == 0) {
+                return None;
+            }
+
+            let size = parse_octal(&header[124..136])?;
+            let data_start = offset + BLOCK_SIZE;
+            let data = self.data.get(data_start..data_start + size)?;
+            self.offset = data_start + align_up(size, BLOCK_SIZE);
+
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                b'L' => {
+                    long_name = core::str::from_utf8(trim_nul(data)).ok();
+                    continue;
+                }
+                // リンクや拡張ヘッダなどは扱わない
+                _ => continue,
+            };
+
+            let path = match long_name.take() {
+                Some(name) => name,
+                None => match header_name(header) {
+                    Some(name) => name,
+                    // 読めない名前のエントリだけを飛ばし、残りのエントリは読み続ける
+                    None => continue,
+                },
+            };
+
+            return Some(Entry {
+                offset,
+                path: normalize(path),
+                kind,
+                data: if kind == EntryKind::File { data } else { &[] },
+            });
+        }
+    }
+}
+
+fn header_name(header: &'static [u8]) -> Option<&'static str> {
+    let name = core::str::from_utf8(trim_nul(&header[0..100])).ok()?;
+    // GNU形式のヘッダではこの領域はprefixではなくatimeなどに使われている
+    if &header[257..263] != b"ustar\0" || header[345] == 0 {
+        return Some(name);
+    }
+    // POSIX ustarではprefixとnameを`/`で連結したものがパスになるが、
+    // アロケートせずには連結できないので扱わない
+    // (build.rsの`tar::Builder`はGNU形式のヘッダと長い名前を使うので問題にならない)
+    None
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for byte in field {
+        match byte {
+            b'0'..=b'7' => value = value * 8 + (byte - b'0') as usize,
+            b' ' | 0 => {
+                if value != 0 {
+                    break;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    if path == "." {
+        return "";
+    }
+    path.trim_end_matches('/')
+}
+
+fn parent(path: &str) -> &str {
+    match path.rfind('/') {
+        Some(index) => &path[..index],
+        None => "",
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
+}
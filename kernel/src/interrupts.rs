@@ -1,10 +1,19 @@
-use crate::gdt;
-use crate::{hlt_loop, print, println};
+use crate::{apic, gdt};
+use crate::{hlt_loop, println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
+/// 動的に割り当てるベクタのハンドラを、`apic::DYNAMIC_VECTOR_START`からの番号ごとに登録する
+macro_rules! set_dynamic_handlers {
+    ($idt:ident, $($index:literal),*) => {
+        $(
+            $idt[apic::DYNAMIC_VECTOR_START as usize + $index].set_handler_fn(dynamic_interrupt_handler::<$index>);
+        )*
+    };
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -17,6 +26,14 @@ lazy_static! {
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        // apic::DYNAMIC_VECTOR_COUNTと同じ数だけ並べる
+        set_dynamic_handlers!(idt, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+                              16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31);
         idt
     };
 }
@@ -36,7 +53,7 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+    crate::time::tick();
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -55,6 +72,48 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive_pending();
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    crate::ps2::mouse::handle_byte(byte);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::block::ata::handle_interrupt(0);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::block::ata::handle_interrupt(1);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn dynamic_interrupt_handler<const INDEX: usize>(_stack_frame: InterruptStackFrame) {
+    apic::dispatch(INDEX);
+}
+
+/// Local APICのスプリアス割り込みにはEOIを送らない
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
@@ -73,11 +132,32 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// 8259 PICを初期化し、ハンドラのあるIRQだけを通す。割り込みを使うドライバはここで割り込みに切り替える
+pub fn init_pics() {
+    // タイマー(0)、キーボード(1)とセカンダリへのカスケード(2)、COM1(4)、マウス(12)とATA(14, 15)
+    let primary_mask = !(1 << 0 | 1 << 1 | 1 << 2 | 1 << 4);
+    let secondary_mask = !(1 << (12 - 8) | 1 << (14 - 8) | 1 << (15 - 8));
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        pics.write_masks(primary_mask, secondary_mask);
+    }
+    // IRQ 14/15が届くようになったので、ATAのチャンネルは割り込みを待てる
+    crate::block::ata::enable_interrupts();
+}
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 4。COM1
+    Serial = PIC_1_OFFSET + 4,
+    /// IRQ 12。PS/2コントローラの2つ目のポート
+    Mouse = PIC_2_OFFSET + 4,
+    /// IRQ 14/15はIDEコントローラの互換モードのチャンネルが使う
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta = PIC_2_OFFSET + 7,
 }
 
 impl InterruptIndex {
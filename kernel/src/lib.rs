@@ -9,12 +9,13 @@
 
 use core::alloc::Layout;
 use bootloader_api::BootInfo;
+use bootloader_api::config::{BootloaderConfig, Mapping};
 
 #[cfg(test)]
 use bootloader_api::{entry_point};
 
 #[cfg(test)]
-entry_point!(test_kernel_main);
+entry_point!(test_kernel_main, config = &BOOTLOADER_CONFIG);
 
 #[cfg(test)]
 fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
@@ -25,7 +26,6 @@ fn test_kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
 extern crate alloc;
 
-pub mod usb;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
@@ -33,23 +33,69 @@ pub mod allocator;
 pub mod task;
 pub mod frame_buffer_writer;
 pub mod serial;
-mod apic;
+pub mod initrd;
+pub mod fs;
+pub mod block;
+pub mod usb;
+pub mod acpi_table;
+pub mod pcie;
+pub mod apic;
+pub mod ps2;
+pub mod time;
+pub mod power;
+pub mod shell;
+pub mod logger;
 
 use core::panic::PanicInfo;
 use log::debug;
+use x86_64::VirtAddr;
 use crate::frame_buffer_writer::{FRAME_BUFFER_WRITER};
+use crate::memory::BootInfoFrameAllocator;
 
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
 
 pub fn init(boot_info: &'static mut BootInfo) {
+    logger::init();
+    initrd::init(boot_info);
+    let rsdp_addr = boot_info.rsdp_addr.into_option();
+
     let BootInfo {
         framebuffer,
+        memory_regions,
+        physical_memory_offset,
         ..
     } = boot_info;
 
     let frame_buffer_info = framebuffer.as_ref().unwrap().info();
     FRAME_BUFFER_WRITER.lock().init(framebuffer.as_mut().unwrap().buffer_mut(), frame_buffer_info);
+    // デバイスの初期化中の例外も報告できるように、先にGDTとIDTを読み込む
     gdt::init();
     interrupts::init_idt();
+
+    let physical_memory_offset = VirtAddr::new(
+        physical_memory_offset.into_option().expect("physical memory is not mapped"),
+    );
+    let mut mapper = unsafe { memory::init(physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(memory_regions) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_global(physical_memory_offset, mapper, frame_allocator);
+    frame_buffer_writer::init_back_buffer();
+    fs::init();
+    acpi_table::init(rsdp_addr);
+    pcie::init();
+    apic::init();
+    pci::scan_all_bus();
+    debug!("pci: {} devices", pci::devices().len());
+    block::init();
+    usb::init();
+    fs::mount_block_devices();
+    ps2::init();
+    time::init();
+    serial::init();
     // unsafe { interrupts::PICS.lock().initialize() };
     // x86_64::instructions::interrupts::enable();
 }
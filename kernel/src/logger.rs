@@ -0,0 +1,189 @@
+//! `log`クレートのロガー。シリアルポートと画面に色付きで出力し、`dmesg`で読めるようにリングバッファにも残す
+//!
+//! 割り込みハンドラからも呼ばれるので、ログを書くときにはアロケートせず、画面のロックは待たない
+
+pub mod filter;
+pub mod ring_buffer;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::frame_buffer_writer::FRAME_BUFFER_WRITER;
+use crate::logger::filter::LevelFilters;
+use crate::logger::ring_buffer::RingBuffer;
+use crate::serial::SERIAL1;
+use crate::time;
+
+/// `dmesg`のために残す大きさ
+pub const LOG_BUFFER_SIZE: usize = 64 * 1024;
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Debug;
+
+static LOGGER: KernelLogger = KernelLogger;
+static FILTERS: Mutex<LevelFilters> = Mutex::new(LevelFilters::new(DEFAULT_LEVEL));
+static OUTPUTS: Mutex<OutputLevels> = Mutex::new(OutputLevels { serial: LevelFilter::Trace, screen: LevelFilter::Warn });
+static BUFFER: Mutex<RingBuffer<LOG_BUFFER_SIZE>> = Mutex::new(RingBuffer::new());
+
+/// ログの出力先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// COM1。レベルに色を付ける
+    Serial,
+    /// フレームバッファ。シェルの表示を崩さないよう、既定では警告以上だけ出す
+    Screen,
+}
+
+pub const OUTPUTS_ALL: [Output; 2] = [Output::Serial, Output::Screen];
+
+impl Output {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::Serial => "serial",
+            Output::Screen => "screen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        OUTPUTS_ALL.iter().copied().find(|output| output.name() == name)
+    }
+}
+
+/// フィルタを通ったログのうち、出力先ごとにさらに絞るレベル。リングバッファには全部残る
+#[derive(Debug, Clone, Copy)]
+struct OutputLevels {
+    serial: LevelFilter,
+    screen: LevelFilter,
+}
+
+struct KernelLogger;
+
+/// ロガーを登録する。画面への出力は`FRAME_BUFFER_WRITER`が初期化されてから始まる
+pub fn init() {
+    match log::set_logger(&LOGGER) {
+        Ok(()) => update_max_level(&FILTERS.lock()),
+        Err(_) => log::debug!("logger: a logger is already installed"),
+    }
+}
+
+/// 既定のレベル
+pub fn level() -> LevelFilter {
+    interrupts::without_interrupts(|| FILTERS.lock().default_level())
+}
+
+pub fn set_level(level: LevelFilter) {
+    with_filters(|filters| filters.set_default(level));
+}
+
+/// `kernel::usb`のようなモジュールのパスと、そのレベル
+pub fn module_levels() -> Vec<(String, LevelFilter)> {
+    interrupts::without_interrupts(|| FILTERS.lock().modules().to_vec())
+}
+
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    with_filters(|filters| filters.set_module(module, level));
+}
+
+/// モジュールの設定を消して、既定のレベルに戻す
+pub fn reset_module_level(module: &str) -> bool {
+    with_filters(|filters| filters.remove_module(module))
+}
+
+pub fn output_level(output: Output) -> LevelFilter {
+    interrupts::without_interrupts(|| {
+        let outputs = OUTPUTS.lock();
+        match output {
+            Output::Serial => outputs.serial,
+            Output::Screen => outputs.screen,
+        }
+    })
+}
+
+pub fn set_output_level(output: Output, level: LevelFilter) {
+    interrupts::without_interrupts(|| {
+        let mut outputs = OUTPUTS.lock();
+        match output {
+            Output::Serial => outputs.serial = level,
+            Output::Screen => outputs.screen = level,
+        }
+    });
+}
+
+/// リングバッファに残っているログ
+pub fn dmesg() -> String {
+    let bytes = interrupts::without_interrupts(|| BUFFER.lock().read());
+    String::from_utf8_lossy(&bytes).into()
+}
+
+pub fn clear_dmesg() {
+    interrupts::without_interrupts(|| BUFFER.lock().clear());
+}
+
+fn with_filters<T>(f: impl FnOnce(&mut LevelFilters) -> T) -> T {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let result = f(&mut filters);
+        update_max_level(&filters);
+        result
+    })
+}
+
+/// `log`のマクロは、このレベルより詳しいものをロガーを呼ばずに捨てる
+fn update_max_level(filters: &LevelFilters) {
+    log::set_max_level(filters.max_level());
+}
+
+fn color(level: Level) -> &'static str {
+    match level {
+        Level::Error => "\x1b[31m",
+        Level::Warn => "\x1b[33m",
+        Level::Info => "\x1b[32m",
+        Level::Debug => "\x1b[36m",
+        Level::Trace => "\x1b[90m",
+    }
+}
+
+const COLOR_RESET: &str = "\x1b[0m";
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        interrupts::without_interrupts(|| metadata.level() <= FILTERS.lock().level_for(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let uptime = time::uptime();
+        let (seconds, millis) = (uptime.as_secs(), uptime.subsec_millis());
+        let level = record.level();
+        interrupts::without_interrupts(|| {
+            let outputs = *OUTPUTS.lock();
+            let _ = writeln!(
+                BUFFER.lock(),
+                "[{:>5}.{:03}] {:<5} {}: {}",
+                seconds, millis, level, record.target(), record.args()
+            );
+            // 画面もエスケープシーケンスを解釈するので、シリアルと同じ色付きの形式で書く
+            let write_colored = |out: &mut dyn Write| writeln!(
+                out,
+                "[{:>5}.{:03}] {}{:<5}{} {}: {}",
+                seconds, millis, color(level), level, COLOR_RESET, record.target(), record.args()
+            );
+            if level <= outputs.serial {
+                let _ = write_colored(&mut *SERIAL1.lock());
+            }
+            // 割り込まれた側が画面に書いている途中なら、画面には出さない
+            if level <= outputs.screen {
+                if let Some(mut writer) = FRAME_BUFFER_WRITER.try_lock() {
+                    if writer.is_initialized() {
+                        let _ = write_colored(&mut *writer);
+                    }
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
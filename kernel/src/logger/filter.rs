@@ -0,0 +1,60 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use log::LevelFilter;
+
+/// 既定のレベルと、モジュールごとのレベル
+///
+/// モジュールの設定は、そのモジュールと子のモジュールに効く。複数が当てはまるときは最も長いものを使う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LevelFilters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LevelFilters {
+    pub const fn new(default: LevelFilter) -> Self {
+        Self { default, modules: Vec::new() }
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn set_default(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    pub fn modules(&self) -> &[(String, LevelFilter)] {
+        &self.modules
+    }
+
+    pub fn set_module(&mut self, module: &str, level: LevelFilter) {
+        match self.modules.iter_mut().find(|(name, _)| name == module) {
+            Some((_, current)) => *current = level,
+            None => self.modules.push((module.to_string(), level)),
+        }
+    }
+
+    /// 設定が無かったときは`false`
+    pub fn remove_module(&mut self, module: &str) -> bool {
+        let length = self.modules.len();
+        self.modules.retain(|(name, _)| name != module);
+        self.modules.len() != length
+    }
+
+    /// `target`はふつう`kernel::usb::xhci`のようなモジュールのパス
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module || (target.starts_with(module.as_str()) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    /// どれか一つでも通す最も詳しいレベル。`log::set_max_level`に渡す
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+/// 大きさが決まったバイトのリングバッファ。いっぱいになると古いものから上書きする
+///
+/// ログを書くときにアロケートしないよう、領域は最初から持っておく
+pub struct RingBuffer<const N: usize> {
+    data: [u8; N],
+    /// 次に書く位置
+    head: usize,
+    len: usize,
+    /// 一度でも上書きした。先頭の行は途中から始まっているかもしれない
+    wrapped: bool,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self { data: [0; N], head: 0, len: 0, wrapped: false }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[self.head] = byte;
+            self.head = (self.head + 1) % N;
+            if self.len == N {
+                self.wrapped = true;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    /// 古いものから順に読み出す。上書きで途中から始まる行は捨てる
+    pub fn read(&self) -> Vec<u8> {
+        let start = (self.head + N - self.len) % N;
+        let mut bytes = Vec::with_capacity(self.len);
+        bytes.extend_from_slice(&self.data[start..(start + self.len).min(N)]);
+        if start + self.len > N {
+            bytes.extend_from_slice(&self.data[..self.head]);
+        }
+        if self.wrapped {
+            let line_start = bytes.iter().position(|&byte| byte == b'\n').map_or(bytes.len(), |index| index + 1);
+            bytes.drain(..line_start);
+        }
+        bytes
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.wrapped = false;
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for RingBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
@@ -6,13 +6,15 @@
 
 extern crate alloc;
 
-use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use kernel::{init, println, serial_println};
+use kernel::{init, println, serial_println, BOOTLOADER_CONFIG};
+use kernel::block::BlockDevice;
 use kernel::frame_buffer_writer::FRAME_BUFFER_WRITER;
 use kernel::frame_buffer_writer::pixel_color::PixelColor;
 use kernel::frame_buffer_writer::vector2d::Vector2D;
+use kernel::task::executor::Executor;
+use kernel::task::{keyboard, mouse, Task};
 
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
@@ -27,61 +29,44 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         writer.fill_rectangle(Vector2D::new(0, height - 50), Vector2D::new(width, 50), PixelColor::cyan());
         writer.fill_rectangle(Vector2D::new(0, height - 50), Vector2D::new(width / 5, 50), PixelColor::new(80, 80, 80));
         writer.draw_rectangle(Vector2D::new(10, height - 40), Vector2D::new(30, 30), PixelColor::new(160, 160, 160));
+        writer.flush();
     }
 
     println!("Hello World{}", "!");
     serial_println!("Hello World{}", "!");
 
+    for (name, device) in kernel::block::devices() {
+        let geometry = device.geometry();
+        println!("{}: {} sectors x {} bytes", name, geometry.sector_count, geometry.sector_size);
+    }
 
-
-    // if let Err(err) = scan_all_bus() {
-    //     println!("Error: {:?}", err);
-    // }
-    // println!("Scan all Bus Success");
-    // {
-    //     let devices = DEVICES.lock();
-    //     for i in 0..devices.num {
-    //         let device = &devices.devices[i];
-    //         println!("{}.{}.{}: vend {:04X}, class {:08X}, head {:02X}",
-    //         device.bus, device.device, device.function,
-    //         device.read_vendor_id(), u32::from(device.read_class_code()), device.header_type);
-    //     }
-    //     let mut xhc_dev: Option<&Device> = None;
-    //     for i in 0..devices.num {
-    //         if ClassCode::new(0x0C, 0x03, 0x30) == devices.devices[i].read_class_code() {
-    //             xhc_dev = Some(&devices.devices[i]);
-    //
-    //             if 0x8086 == xhc_dev.unwrap().read_vendor_id() {
-    //                 break;
-    //             }
-    //         }
-    //     }
-    //
-    //     if let Some(xhc_dev) = xhc_dev {
-    //         println!("xHC has been found: {}.{}.{}",
-    //         xhc_dev.bus, xhc_dev.device, xhc_dev.function);
-    //     }
-    //
-    //     let xhc_bar = xhc_dev.unwrap().read_bar(0).unwrap();
-    //     println!("read_bar: {}", xhc_bar);
-    //     let xhc_mmio_base = xhc_bar & !0xFu64;
-    //     println!("xHC mmio_base = {:08}",xhc_mmio_base);
-    // }
-
+    for device in kernel::usb::devices() {
+        let descriptor = device.descriptor();
+        println!(
+            "usb port {}: {:04x}:{:04x} {} ({:?} speed)",
+            device.port(),
+            descriptor.vendor_id,
+            descriptor.product_id,
+            device.read_string(descriptor.product_index).unwrap_or_default(),
+            device.speed()
+        );
+    }
 
     #[cfg(test)]
     test_main();
 
-    kernel::hlt_loop();
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::dispatch_keys()));
+    executor.spawn(Task::new(kernel::shell::run()));
+    executor.spawn(Task::new(kernel::shell::run_serial()));
+    executor.spawn(Task::new(mouse::track_cursor()));
+    executor.spawn(Task::new(kernel::frame_buffer_writer::writing_text::blink_cursor()));
+    executor.spawn(Task::new(kernel::usb::handle_events()));
+    kernel::interrupts::init_pics();
+    x86_64::instructions::interrupts::enable();
+    executor.run();
 }
 
-
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
@@ -1,9 +1,61 @@
+use alloc::vec::Vec;
 use bootloader_api::info::{MemoryRegions, MemoryRegionKind};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB};
+use conquer_once::spin::OnceCell;
+use spin::{Mutex, MutexGuard};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::page_table::FrameError;
 
+pub mod dma;
+pub mod mmio;
+
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+static KERNEL_MEMORY: OnceCell<Mutex<KernelMemory>> = OnceCell::uninit();
+
+/// ヒープの初期化後に、ドライバがページのマップやフレームの確保に使う
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+/// ページテーブルとフレームアロケータをカーネル全体から使えるようにする
+pub fn init_global(physical_memory_offset: VirtAddr, mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator) {
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_MEMORY.init_once(|| Mutex::new(KernelMemory { mapper, frame_allocator }));
+}
+
+pub fn kernel_memory() -> MutexGuard<'static, KernelMemory> {
+    KERNEL_MEMORY.get().expect("memory::init_global has not been called").lock()
+}
+
+/// 物理メモリ全体がマップされている領域を通して、物理アドレスに対応する仮想アドレスを返す
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.get().expect("memory::init_global has not been called");
+    *offset + addr.as_u64()
+}
+
+/// ページ境界の`start`から`size`バイトに新しいフレームをマップし、0で埋めて返す
+/// フレームアロケータは解放に対応していないので、起動時に一度だけ確保するものに使う
+pub fn map_zeroed(start: VirtAddr, size: usize) -> Result<&'static mut [u8], MapToError<Size4KiB>> {
+    let pages = Page::range_inclusive(
+        Page::<Size4KiB>::containing_address(start),
+        Page::containing_address(start + (size.max(1) - 1) as u64),
+    );
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut memory = kernel_memory();
+    let memory = &mut *memory;
+    for page in pages {
+        let frame = memory.frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+        }
+    }
+    let buffer = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), size) };
+    buffer.fill(0);
+    Ok(buffer)
+}
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let level_4_table = active_level_4_table(physical_memory_offset);
@@ -83,6 +135,8 @@ pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_al
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryRegions,
     next: usize,
+    /// `allocate_contiguous`が連続した領域を探すときに飛ばしたフレーム。`allocate_frame`が先に使う
+    skipped: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -94,6 +148,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            skipped: Vec::new(),
         }
     }
 
@@ -112,10 +167,53 @@ impl BootInfoFrameAllocator {
         // 開始アドレスから`PhysFrame`型を作る
         frame_address.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// usableなフレームの総数
+    pub fn total_frames(&self) -> usize {
+        self.usable_frames().count()
+    }
+
+    /// これまでに確保したフレームの数。確保したフレームは返されないので、解放済みのものも含む
+    pub fn allocated_frames(&self) -> usize {
+        self.next.min(self.total_frames()) - self.skipped.len()
+    }
+
+    /// 物理的に連続した`count`個のフレームを確保して、先頭のフレームを返す
+    ///
+    /// 連続した領域の手前で飛ばしたフレームは捨てずに取っておき、後の`allocate_frame`で使う
+    /// 飛ばしたフレームをヒープに置くので、ヒープの初期化後に呼ぶ
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut start: Option<PhysFrame> = None;
+        let mut start_index = self.next;
+        let mut len = 0;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            match start {
+                Some(start) if frame == start + len as u64 => len += 1,
+                _ => {
+                    start = Some(frame);
+                    start_index = index;
+                    len = 1;
+                }
+            }
+            if len >= count {
+                break;
+            }
+        }
+        if len < count {
+            return None;
+        }
+        let skipped: Vec<PhysFrame> = self.usable_frames().skip(self.next).take(start_index - self.next).collect();
+        self.skipped.extend(skipped);
+        self.next = start_index + len;
+        start
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = self.skipped.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
@@ -0,0 +1,58 @@
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::{kernel_memory, phys_to_virt};
+
+const PAGE_SIZE: usize = 4096;
+
+/// デバイスが直接読み書きする、物理的に連続したメモリ
+///
+/// フレームアロケータは解放に対応していないので、一度確保したら解放されない
+/// ドライバの初期化時に確保して使い回すこと
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// ページ境界に揃った、0で初期化された領域を確保する
+    pub fn new(size: usize) -> Option<Self> {
+        let frames = size.div_ceil(PAGE_SIZE).max(1);
+        let start = kernel_memory().frame_allocator.allocate_contiguous(frames)?;
+        let phys = start.start_address();
+        let virt = phys_to_virt(phys);
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * PAGE_SIZE) };
+        Some(Self {
+            phys,
+            virt,
+            size: frames * PAGE_SIZE,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.phys.as_u64()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size) }
+    }
+
+    /// デバイスが書き換える値は、コンパイラに最適化で消されないようにvolatileで読む
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size, "DMA access out of range: {:#x}", offset);
+        unsafe { (self.virt + offset as u64).as_ptr::<T>().read_volatile() }
+    }
+
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.size, "DMA access out of range: {:#x}", offset);
+        unsafe { (self.virt + offset as u64).as_mut_ptr::<T>().write_volatile(value) }
+    }
+}
@@ -0,0 +1,165 @@
+use alloc::vec::Vec;
+use pci::device::Device;
+use pci::error::PciError;
+use spin::Mutex;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory::kernel_memory;
+
+/// デバイスのレジスタをマップする仮想アドレス空間の先頭。ヒープとは重ならないようにする
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+
+const PAGE_SIZE: u64 = 4096;
+const LARGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+static NEXT_MMIO: Mutex<u64> = Mutex::new(MMIO_START);
+/// マップ済みのBAR。バス、デバイス、ファンクションとBARの番号で引く
+static BARS: Mutex<Vec<((u8, u8, u8, usize), MmioRegion)>> = Mutex::new(Vec::new());
+
+/// 物理アドレス`phys`から`size`バイトのレジスタ領域を、キャッシュ無効でマップする
+pub fn map(phys: u64, size: usize) -> Result<MmioRegion, MapToError<Size4KiB>> {
+    let phys_start = phys & !(PAGE_SIZE - 1);
+    let phys_end = phys + size as u64;
+    let pages = (phys_end - phys_start).div_ceil(PAGE_SIZE);
+
+    let virt_start = {
+        let mut next = NEXT_MMIO.lock();
+        let start = *next;
+        *next += pages * PAGE_SIZE;
+        start
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let mut memory = kernel_memory();
+    let memory = &mut *memory;
+    for index in 0..pages {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(virt_start + index * PAGE_SIZE));
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys_start + index * PAGE_SIZE));
+        unsafe {
+            memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+        }
+    }
+
+    Ok(MmioRegion {
+        base: VirtAddr::new(virt_start + (phys - phys_start)),
+        phys: PhysAddr::new(phys),
+        size,
+    })
+}
+
+/// `map`と同じだが、2MiBのページでマップする。ページテーブルを作らずに済むので、大きな領域に使う
+///
+/// 前後の2MiB境界まで広げてマップするので、`phys`の近くに別のデバイスの領域があればそれもマップされる
+pub fn map_large(phys: u64, size: usize) -> Result<MmioRegion, MapToError<Size2MiB>> {
+    let phys_start = phys & !(LARGE_PAGE_SIZE - 1);
+    let phys_end = phys + size as u64;
+    let pages = (phys_end - phys_start).div_ceil(LARGE_PAGE_SIZE);
+
+    let virt_start = {
+        let mut next = NEXT_MMIO.lock();
+        let start = next.next_multiple_of(LARGE_PAGE_SIZE);
+        *next = start + pages * LARGE_PAGE_SIZE;
+        start
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let mut memory = kernel_memory();
+    let memory = &mut *memory;
+    for index in 0..pages {
+        let page: Page<Size2MiB> = Page::containing_address(VirtAddr::new(virt_start + index * LARGE_PAGE_SIZE));
+        let frame = PhysFrame::containing_address(PhysAddr::new(phys_start + index * LARGE_PAGE_SIZE));
+        unsafe {
+            memory.mapper.map_to(page, frame, flags, &mut memory.frame_allocator)?.flush();
+        }
+    }
+
+    Ok(MmioRegion {
+        base: VirtAddr::new(virt_start + (phys - phys_start)),
+        phys: PhysAddr::new(phys),
+        size,
+    })
+}
+
+/// PCIデバイスのメモリ空間のBARを、BARの大きさ全体でマップする
+///
+/// 同じBARをもう一度マップしようとしたら、仮想アドレスを使い足さずに前にマップした領域を返す
+pub fn map_bar(device: &Device, index: usize) -> Result<MmioRegion, PciError> {
+    let key = (device.bus, device.device, device.function, index);
+    let mut bars = BARS.lock();
+    if let Some((_, region)) = bars.iter().find(|(bar, _)| *bar == key) {
+        return Ok(*region);
+    }
+    let bar = device.bar(index)?;
+    if !bar.is_memory() {
+        return Err(PciError::NotMemoryBar(index));
+    }
+    if bar.address == 0 {
+        return Err(PciError::UnassignedBar(index));
+    }
+    let region = map(bar.address, bar.size as usize).map_err(|_| PciError::MapFailed)?;
+    bars.push((key, region));
+    Ok(region)
+}
+
+/// マップ済みのレジスタ領域。読み書きはすべてvolatileで行う
+#[derive(Debug, Clone, Copy)]
+pub struct MmioRegion {
+    base: VirtAddr,
+    phys: PhysAddr,
+    size: usize,
+}
+
+impl MmioRegion {
+    pub fn base(&self) -> VirtAddr {
+        self.base
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(offset + core::mem::size_of::<T>() <= self.size, "MMIO access out of range: {:#x}", offset);
+        (self.base + offset as u64).as_mut_ptr()
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { self.ptr::<u8>(offset).read_volatile() }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        unsafe { self.ptr::<u16>(offset).read_volatile() }
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { self.ptr::<u32>(offset).read_volatile() }
+    }
+
+    /// 64bitレジスタを下位、上位の順に32bitずつ読む
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.read32(offset) as u64 | (self.read32(offset + 4) as u64) << 32
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        unsafe { self.ptr::<u8>(offset).write_volatile(value) }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        unsafe { self.ptr::<u16>(offset).write_volatile(value) }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        unsafe { self.ptr::<u32>(offset).write_volatile(value) }
+    }
+
+    /// 64bitレジスタを下位、上位の順に32bitずつ書く
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
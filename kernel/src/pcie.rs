@@ -0,0 +1,35 @@
+use alloc::boxed::Box;
+use log::debug;
+use pci::config::Ecam;
+use crate::acpi_table;
+use crate::memory::mmio;
+
+/// 一つのバスのコンフィギュレーション空間の大きさ(32デバイス x 8ファンクション x 4KiB)
+const BUS_SIZE: u64 = 1 << 20;
+
+/// MCFGテーブルがあればセグメント0のECAM領域をマップし、PCIのコンフィギュレーションアクセスに使う
+/// 無ければ0xCF8/0xCFCのI/Oポートを使い続ける
+///
+/// 256バス分で256MiBにもなるので、4KiBのページではなく2MiBのページでマップする
+pub fn init() {
+    let entry = match acpi_table::pci_config_region(0) {
+        Some(entry) => entry,
+        None => {
+            debug!("pcie: no MCFG entry for segment 0, using port I/O");
+            return;
+        }
+    };
+
+    let phys = entry.base_address + entry.start_bus as u64 * BUS_SIZE;
+    let size = (entry.end_bus - entry.start_bus) as u64 + 1;
+    let region = match mmio::map_large(phys, (size * BUS_SIZE) as usize) {
+        Ok(region) => region,
+        Err(err) => {
+            debug!("pcie: failed to map ECAM at {:#x}: {:?}", phys, err);
+            return;
+        }
+    };
+    let ecam = unsafe { Ecam::new(region.base().as_u64() as usize, entry.start_bus, entry.end_bus) };
+    pci::config::set_access(Box::leak(Box::new(ecam)));
+    debug!("pcie: ECAM at {:#x}, buses {}..={}", entry.base_address, entry.start_bus, entry.end_bus);
+}
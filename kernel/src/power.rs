@@ -0,0 +1,67 @@
+//! 再起動と電源断
+
+pub mod error;
+
+use log::debug;
+use x86_64::instructions::port::Port;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+use crate::acpi_table;
+use crate::power::error::PowerError;
+
+/// PM1制御レジスタのビット
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+/// ACPIモードへの切り替えや電源断を待つ回数
+const WAIT_SPINS: usize = 1_000_000;
+
+/// 8042コントローラでCPUをリセットする。効かなければトリプルフォールトを起こす
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    if let Err(err) = crate::ps2::pulse_reset_line() {
+        debug!("power: 8042 reset failed: {}", err);
+    }
+    for _ in 0..WAIT_SPINS {
+        core::hint::spin_loop();
+    }
+    // 大きさ0のIDTでは例外を処理できないので、ダブルフォールトを経てリセットされる
+    let idt = DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) };
+    unsafe { lidt(&idt) };
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+/// ACPIのS5(ソフトオフ)に入って電源を切る。成功すれば戻らない
+pub fn shutdown() -> Result<(), PowerError> {
+    let fadt = acpi_table::fadt().filter(|fadt| fadt.pm1a_control != 0).ok_or(PowerError::NoFadt)?;
+    let (sleep_type_a, sleep_type_b) = acpi_table::dsdt()
+        .and_then(acpi_table::parse_s5)
+        .ok_or(PowerError::NoSoftOff)?;
+
+    let mut pm1a = Port::<u16>::new(fadt.pm1a_control as u16);
+    if unsafe { pm1a.read() } & PM1_SCI_EN == 0 && fadt.smi_command != 0 {
+        unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+        let enabled = (0..WAIT_SPINS).any(|_| unsafe { pm1a.read() } & PM1_SCI_EN != 0);
+        if !enabled {
+            return Err(PowerError::AcpiModeTimeout);
+        }
+    }
+
+    x86_64::instructions::interrupts::disable();
+    let sleep = |port: &mut Port<u16>, sleep_type: u8| unsafe {
+        let value = port.read() & !(0b111 << PM1_SLP_TYP_SHIFT);
+        port.write(value | (sleep_type as u16 & 0b111) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+    };
+    sleep(&mut pm1a, sleep_type_a);
+    if fadt.pm1b_control != 0 {
+        sleep(&mut Port::new(fadt.pm1b_control as u16), sleep_type_b);
+    }
+    for _ in 0..WAIT_SPINS {
+        core::hint::spin_loop();
+    }
+    x86_64::instructions::interrupts::enable();
+    Err(PowerError::StillRunning)
+}
@@ -0,0 +1,44 @@
+use core::error;
+use core::error::Error;
+use core::fmt::{Debug, Display, Formatter};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    /// FADTが無いか、PM1の制御レジスタが無い
+    NoFadt,
+    /// DSDTに`\_S5`が無い
+    NoSoftOff,
+    /// ACPIモードに切り替わらなかった
+    AcpiModeTimeout,
+    /// SLP_ENを書いたのに電源が切れなかった
+    StillRunning,
+}
+
+impl Debug for PowerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Display for PowerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for PowerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl PowerError {
+    fn description(&self) -> &'static str {
+        match self {
+            PowerError::NoFadt => "ACPI FADT with PM1 control registers was not found",
+            PowerError::NoSoftOff => "DSDT does not define the \\_S5 sleep state",
+            PowerError::AcpiModeTimeout => "firmware did not switch to ACPI mode",
+            PowerError::StillRunning => "machine did not power off",
+        }
+    }
+}
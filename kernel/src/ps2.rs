@@ -0,0 +1,138 @@
+//! 8042 PS/2コントローラ。1つ目のポートにキーボード、2つ目のポート(補助デバイス)にマウスがつながる
+
+pub mod error;
+pub mod mouse;
+
+use log::debug;
+use x86_64::instructions::port::Port;
+use crate::ps2::error::Ps2Error;
+
+const DATA_PORT: u16 = 0x60;
+/// 読むとステータス、書くとコマンド
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+// コントローラへのコマンド
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_AUX: u8 = 0xA7;
+const COMMAND_ENABLE_AUX: u8 = 0xA8;
+const COMMAND_DISABLE_KEYBOARD: u8 = 0xAD;
+const COMMAND_ENABLE_KEYBOARD: u8 = 0xAE;
+/// 次にデータポートに書くバイトを2つ目のポートへ送る
+const COMMAND_WRITE_AUX: u8 = 0xD4;
+/// 出力ポートのビット0(CPUのリセット線)をパルスする
+const COMMAND_PULSE_RESET: u8 = 0xFE;
+
+// コンフィギュレーションバイト
+const CONFIG_KEYBOARD_INTERRUPT: u8 = 1 << 0;
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLE: u8 = 1 << 5;
+
+// デバイスの応答
+pub const RESPONSE_ACK: u8 = 0xFA;
+pub const RESPONSE_RESEND: u8 = 0xFE;
+
+/// 応答を待つ回数
+const WAIT_SPINS: usize = 1_000_000;
+
+/// PS/2のマウスを初期化し、IRQ 12で動きを受け取れるようにする
+///
+/// 割り込みは`interrupts`の`mouse_interrupt_handler`が受けて、`mouse::handle_byte`に渡す
+pub fn init() {
+    if let Err(err) = mouse::init() {
+        debug!("ps2: mouse is not available: {}", err);
+    }
+}
+
+/// CPUのリセット線をパルスして、マシンを再起動させる。成功すれば戻らない
+pub fn pulse_reset_line() -> Result<(), Ps2Error> {
+    send_command(COMMAND_PULSE_RESET)
+}
+
+/// ポートの割り込みを止めてから`f`を呼ぶ。設定中の応答を割り込みハンドラに取られないようにする
+fn with_interrupts_disabled<T>(f: impl FnOnce() -> Result<T, Ps2Error>) -> Result<T, Ps2Error> {
+    send_command(COMMAND_DISABLE_KEYBOARD)?;
+    send_command(COMMAND_DISABLE_AUX)?;
+    flush();
+    let config = read_config()?;
+    write_config(config & !(CONFIG_KEYBOARD_INTERRUPT | CONFIG_AUX_INTERRUPT))?;
+
+    let result = f();
+
+    // 失敗しても、キーボードは元どおり使えるようにしておく
+    let mut config = config & !CONFIG_AUX_CLOCK_DISABLE;
+    if result.is_ok() {
+        config |= CONFIG_AUX_INTERRUPT;
+    }
+    write_config(config)?;
+    send_command(COMMAND_ENABLE_KEYBOARD)?;
+    if result.is_ok() {
+        send_command(COMMAND_ENABLE_AUX)?;
+    }
+    result
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    send_command(COMMAND_READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    send_command(COMMAND_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) -> Result<(), Ps2Error> {
+    for _ in 0..WAIT_SPINS {
+        if condition() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn send_command(command: u8) -> Result<(), Ps2Error> {
+    wait_until(|| status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<(), Ps2Error> {
+    wait_until(|| status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(data) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, Ps2Error> {
+    wait_until(|| status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
+
+/// 読まれずに残っているバイトを捨てる
+fn flush() {
+    while status() & STATUS_OUTPUT_FULL != 0 {
+        unsafe { Port::<u8>::new(DATA_PORT).read() };
+    }
+}
+
+/// 2つ目のポートのデバイスにコマンドを送り、ACKを待つ。RESENDが返ったら一度だけ送り直す
+fn send_aux(byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..2 {
+        send_command(COMMAND_WRITE_AUX)?;
+        write_data(byte)?;
+        match read_data()? {
+            RESPONSE_ACK => return Ok(()),
+            RESPONSE_RESEND => continue,
+            response => return Err(Ps2Error::Rejected(response)),
+        }
+    }
+    Err(Ps2Error::Rejected(RESPONSE_RESEND))
+}
@@ -0,0 +1,40 @@
+use core::error;
+use core::error::Error;
+use core::fmt::{Debug, Display, Formatter};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    Timeout,
+    /// デバイスがACK以外の応答を返した
+    Rejected(u8),
+}
+
+impl Debug for Ps2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Ps2Error::Rejected(response) => write!(f, "{} ({:#04x})", self.description(), response),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Display for Ps2Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for Ps2Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl Ps2Error {
+    fn description(&self) -> &'static str {
+        match self {
+            Ps2Error::Timeout => "PS/2 controller did not respond in time",
+            Ps2Error::Rejected(_) => "PS/2 device rejected the command",
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! PS/2マウス。ホイールはIntelliMouseの拡張で有効にする
+
+use log::debug;
+use spin::Mutex;
+use crate::ps2::error::Ps2Error;
+use crate::ps2::{read_data, send_aux, with_interrupts_disabled};
+use crate::task::mouse::{add_mouse_event, MouseEvent, BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT};
+
+// マウスへのコマンド
+const COMMAND_GET_DEVICE_ID: u8 = 0xF2;
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xF3;
+const COMMAND_ENABLE_REPORTING: u8 = 0xF4;
+const COMMAND_RESET: u8 = 0xFF;
+
+const SELF_TEST_PASSED: u8 = 0xAA;
+/// ホイールのあるマウスのデバイスID
+pub const ID_INTELLIMOUSE: u8 = 3;
+/// この順にサンプルレートを設定すると、ホイールのあるマウスはIDが変わる
+const INTELLIMOUSE_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// 1秒あたりのパケット数
+const SAMPLE_RATE: u8 = 100;
+
+// パケットの先頭バイト
+const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+const FLAG_X_SIGN: u8 = 1 << 4;
+const FLAG_Y_SIGN: u8 = 1 << 5;
+const FLAG_X_OVERFLOW: u8 = 1 << 6;
+const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+/// 割り込みハンドラだけが使う。初期化中はポートの割り込みを止めている
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new(false));
+
+/// 割り込みで1バイトずつ届くパケットを組み立てる
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    received: usize,
+    wheel: bool,
+}
+
+impl PacketDecoder {
+    /// `wheel`なら4バイト、そうでなければ3バイトのパケットを受け取る
+    pub const fn new(wheel: bool) -> Self {
+        Self { packet: [0; 4], received: 0, wheel }
+    }
+
+    pub fn packet_size(&self) -> usize {
+        if self.wheel {
+            4
+        } else {
+            3
+        }
+    }
+
+    /// パケットがそろったらマウスの動きを返す
+    pub fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        // 先頭バイトのビット3は常に1。途中から受け取ったときは、先頭らしいバイトが来るまで捨てる
+        if self.received == 0 && byte & FLAG_ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size() {
+            return None;
+        }
+        self.received = 0;
+        Some(parse_packet(&self.packet[..self.packet_size()]))
+    }
+}
+
+/// 3バイトか4バイトのパケットを読む。PS/2のY軸は上向きが正なので、画面の向きに反転する
+pub fn parse_packet(packet: &[u8]) -> MouseEvent {
+    let flags = packet[0];
+    let delta = |value: u8, sign: u8| value as i16 - if flags & sign != 0 { 0x100 } else { 0 };
+    // あふれたときの移動量は当てにならないので、ボタンの状態だけを伝える
+    let overflow = flags & (FLAG_X_OVERFLOW | FLAG_Y_OVERFLOW) != 0;
+    let (dx, dy) = if overflow {
+        (0, 0)
+    } else {
+        (delta(packet[1], FLAG_X_SIGN), -delta(packet[2], FLAG_Y_SIGN))
+    };
+    // 4バイト目の下位4ビットが符号付きのホイールの回転量。手前に回すと正
+    let wheel = packet.get(3).map_or(0, |&z| ((z << 4) as i8) >> 4);
+    MouseEvent {
+        dx,
+        dy,
+        wheel,
+        buttons: flags & (BUTTON_LEFT | BUTTON_RIGHT | BUTTON_MIDDLE),
+    }
+}
+
+/// マウスをリセットし、ホイールを有効にしてからパケットを送らせる
+pub fn init() -> Result<(), Ps2Error> {
+    let wheel = with_interrupts_disabled(|| {
+        send_aux(COMMAND_RESET)?;
+        // 自己診断の結果とデバイスIDが続く
+        match read_data()? {
+            SELF_TEST_PASSED => {}
+            response => return Err(Ps2Error::Rejected(response)),
+        }
+        read_data()?;
+
+        for rate in INTELLIMOUSE_SEQUENCE.iter() {
+            send_aux(COMMAND_SET_SAMPLE_RATE)?;
+            send_aux(*rate)?;
+        }
+        send_aux(COMMAND_GET_DEVICE_ID)?;
+        let wheel = read_data()? == ID_INTELLIMOUSE;
+
+        send_aux(COMMAND_SET_SAMPLE_RATE)?;
+        send_aux(SAMPLE_RATE)?;
+        send_aux(COMMAND_ENABLE_REPORTING)?;
+        *DECODER.lock() = PacketDecoder::new(wheel);
+        Ok(wheel)
+    })?;
+    debug!("ps2: mouse enabled{}", if wheel { " with a wheel" } else { "" });
+    Ok(())
+}
+
+/// マウスの割り込みハンドラから呼び出される
+/// 処理をブロックしたり、アロケートをしてはいけない
+pub(crate) fn handle_byte(byte: u8) {
+    if let Some(event) = DECODER.lock().push(byte) {
+        add_mouse_event(event);
+    }
+}
@@ -1,15 +1,50 @@
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+// COM1のレジスタ
+const INTERRUPT_ENABLE: u16 = COM1 + 1;
+const MODEM_CONTROL: u16 = COM1 + 4;
+const LINE_STATUS: u16 = COM1 + 5;
+
+const INTERRUPT_RECEIVED_DATA: u8 = 1 << 0;
+/// DTRとRTS、それにIRQをPICへつなぐOUT2
+const MODEM_CONTROL_IRQ: u8 = 0b1011;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
 
 lazy_static! {
     pub static ref SERIAL1:Mutex<SerialPort> = {
-        let mut serial_port = unsafe {SerialPort::new(0x03F8)};
+        let mut serial_port = unsafe {SerialPort::new(COM1)};
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// COM1がバイトを受信したらIRQ 4を上げるようにする
+///
+/// 受け取ったバイトは`interrupts`の`serial_interrupt_handler`から`task::serial`のキューに積まれる
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _port = SERIAL1.lock();
+        unsafe {
+            Port::<u8>::new(MODEM_CONTROL).write(MODEM_CONTROL_IRQ);
+            Port::<u8>::new(INTERRUPT_ENABLE).write(INTERRUPT_RECEIVED_DATA);
+        }
+    });
+}
+
+/// 受信したバイトをすべて読み出す。割り込みハンドラから呼ぶので`SERIAL1`はロックしない
+pub(crate) fn receive_pending() {
+    let mut line_status = Port::<u8>::new(LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        crate::task::serial::add_byte(unsafe { data.read() });
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -0,0 +1,150 @@
+//! 組み込みコマンドを実行する対話シェル
+//!
+//! 画面とキーボードで使うなら`run`を、シリアル端末で使うなら`run_serial`をタスクとして動かす。両方動かせば別々のセッションになる
+
+pub mod command;
+pub mod line_editor;
+pub mod serial;
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use futures_util::StreamExt;
+use crate::frame_buffer_writer::FRAME_BUFFER_WRITER;
+use crate::shell::line_editor::{Action, EditKey, LineEditor};
+use crate::shell::serial::{InputDecoder, SerialConsole};
+use crate::task::keyboard;
+use crate::task::serial::SerialStream;
+
+pub const PROMPT: &str = "> ";
+
+/// シェルの出力先。行を書き直すための操作を持つ
+pub trait Console: Write {
+    /// 今の行を消して、行頭に戻る
+    fn clear_line(&mut self);
+
+    /// 今の行の`column`文字目にカーソルを移す
+    fn set_column(&mut self, column: usize);
+}
+
+/// フレームバッファに表示するコンソール
+pub struct FrameBufferConsole;
+
+impl Write for FrameBufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        FRAME_BUFFER_WRITER.lock().write_str(s)
+    }
+}
+
+impl Console for FrameBufferConsole {
+    fn clear_line(&mut self) {
+        FRAME_BUFFER_WRITER.lock().clear_line();
+    }
+
+    fn set_column(&mut self, column: usize) {
+        FRAME_BUFFER_WRITER.lock().set_column(column);
+    }
+}
+
+pub struct Shell<C: Console> {
+    console: C,
+    editor: LineEditor,
+}
+
+impl<C: Console> Shell<C> {
+    pub fn new(console: C) -> Self {
+        Self { console, editor: LineEditor::new() }
+    }
+
+    pub fn console(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    pub fn editor(&self) -> &LineEditor {
+        &self.editor
+    }
+
+    /// プロンプトを表示する
+    pub fn start(&mut self) {
+        self.redraw();
+    }
+
+    pub fn handle_key(&mut self, key: EditKey) {
+        match self.editor.handle_key(key) {
+            Action::None => {}
+            Action::Redraw => self.redraw(),
+            Action::Submit(line) => {
+                let _ = self.console.write_char('\n');
+                let _ = command::execute(&mut self.console, &line);
+                self.redraw();
+            }
+            Action::Cancel => {
+                let _ = self.console.write_str("^C\n");
+                self.redraw();
+            }
+            Action::Complete => self.complete(),
+        }
+    }
+
+    fn redraw(&mut self) {
+        self.console.clear_line();
+        let _ = write!(self.console, "{}{}", PROMPT, self.editor.line());
+        self.console.set_column(PROMPT.chars().count() + self.editor.cursor());
+    }
+
+    /// 候補が一つならそれを挿入し、複数なら共通する部分まで挿入する。それ以上伸ばせなければ候補を並べる
+    fn complete(&mut self) {
+        let before_cursor = self.editor.before_cursor();
+        let word = before_cursor.rsplit(' ').next().unwrap_or("");
+        let candidates = command::complete(&before_cursor);
+        let common = match candidates.split_first() {
+            Some((first, rest)) => rest.iter().fold(first.as_str(), |common, candidate| common_prefix(common, candidate)),
+            None => return,
+        };
+        if common.len() > word.len() {
+            let rest = String::from(&common[word.len()..]);
+            self.editor.insert_str(&rest);
+        } else {
+            let _ = self.console.write_char('\n');
+            for candidate in candidates.iter() {
+                let _ = write!(self.console, "{}  ", candidate.trim_end());
+            }
+            let _ = self.console.write_char('\n');
+        }
+        self.redraw();
+    }
+}
+
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let length = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, x), y)| x != y)
+        .map_or(a.len().min(b.len()), |((index, _), _)| index);
+    &a[..length]
+}
+
+/// キーボードの入力を受け取り、フレームバッファに表示するシェル
+pub async fn run() {
+    let mut events = keyboard::subscribe();
+    events.focus();
+    let mut shell = Shell::new(FrameBufferConsole);
+    shell.start();
+    while let Some(event) = events.next().await {
+        if let Some(key) = EditKey::from_key_event(&event) {
+            shell.handle_key(key);
+        }
+    }
+}
+
+/// COM1から入力を受け取り、COM1に出力するシェル
+pub async fn run_serial() {
+    let mut bytes = SerialStream::new();
+    let mut decoder = InputDecoder::new();
+    let mut shell = Shell::new(SerialConsole);
+    shell.start();
+    while let Some(byte) = bytes.next().await {
+        if let Some(key) = decoder.push(byte) {
+            shell.handle_key(key);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+//! シェルの組み込みコマンドと、その補完
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use log::LevelFilter;
+use crate::fs::file::OpenFlags;
+use crate::fs::{FileType, VFS};
+use crate::logger::{self, Output, OUTPUTS_ALL};
+use crate::{allocator, memory, power, task, time, usb};
+
+const FRAME_SIZE: usize = 4096;
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+    run: fn(&mut dyn Write, &[&str]) -> fmt::Result,
+    /// 引数の補完。それまでの引数と補完する単語から候補を返す
+    complete: fn(&[&str], &str) -> Vec<String>,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "help", description: "list commands", run: help, complete: no_completion },
+    Command { name: "mem", usage: "mem", description: "show heap and frame usage", run: mem, complete: no_completion },
+    Command { name: "lspci", usage: "lspci [-v]", description: "list PCI devices", run: lspci, complete: no_completion },
+    Command { name: "lsusb", usage: "lsusb", description: "list USB devices", run: lsusb, complete: no_completion },
+    Command { name: "ls", usage: "ls [path]", description: "list a directory", run: ls, complete: complete_path },
+    Command { name: "cat", usage: "cat <path>...", description: "print files", run: cat, complete: complete_path },
+    Command { name: "uptime", usage: "uptime", description: "show time since boot", run: uptime, complete: no_completion },
+    Command { name: "ps", usage: "ps", description: "list tasks", run: ps, complete: no_completion },
+    Command { name: "log", usage: "log level [module] [level]", description: "show or set log levels", run: log, complete: complete_log },
+    Command { name: "dmesg", usage: "dmesg [-c]", description: "print the kernel log (and clear it)", run: dmesg, complete: no_completion },
+    Command { name: "reboot", usage: "reboot", description: "restart the machine", run: reboot, complete: no_completion },
+    Command { name: "shutdown", usage: "shutdown", description: "power off the machine", run: shutdown, complete: no_completion },
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// 一行を単語に分けて、コマンドを実行する
+pub fn execute(out: &mut dyn Write, line: &str) -> fmt::Result {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    match find(name) {
+        Some(command) => (command.run)(out, args),
+        None => writeln!(out, "unknown command: {} (try `help`)", name),
+    }
+}
+
+/// カーソルより前の文字列の最後の単語を補完する候補を返す
+///
+/// 候補は単語全体で、一つに決まったときにそのまま続けられるように、ディレクトリは`/`、
+/// それ以外は空白で終わる
+pub fn complete(before_cursor: &str) -> Vec<String> {
+    let word_start = before_cursor.rfind(' ').map_or(0, |index| index + 1);
+    let word = &before_cursor[word_start..];
+    let previous: Vec<&str> = before_cursor[..word_start].split_whitespace().collect();
+    match previous.split_first() {
+        None => COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(word))
+            .map(|command| format!("{} ", command.name))
+            .collect(),
+        Some((name, args)) => match find(name) {
+            Some(command) => (command.complete)(args, word),
+            None => Vec::new(),
+        },
+    }
+}
+
+/// パスは常にルートからたどる
+fn absolute(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+fn no_completion(_args: &[&str], _word: &str) -> Vec<String> {
+    Vec::new()
+}
+
+fn complete_path(_args: &[&str], word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(index) => word.split_at(index + 1),
+        None => ("", word),
+    };
+    let entries = match VFS.lock().read_dir(&absolute(dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = entries
+        .into_iter()
+        .filter(|entry| entry.name.starts_with(prefix))
+        .map(|entry| match entry.file_type {
+            FileType::Directory => format!("{}{}/", dir, entry.name),
+            FileType::File => format!("{}{} ", dir, entry.name),
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+fn complete_log(args: &[&str], word: &str) -> Vec<String> {
+    let words: Vec<&str> = match args {
+        [] => vec!["level", "output"],
+        ["level"] => LOG_LEVELS.to_vec(),
+        ["level", _] => LOG_LEVELS.iter().copied().chain(Some("default")).collect(),
+        ["output"] => OUTPUTS_ALL.iter().map(Output::name).collect(),
+        ["output", _] => LOG_LEVELS.to_vec(),
+        _ => Vec::new(),
+    };
+    words.iter().filter(|candidate| candidate.starts_with(word)).map(|candidate| format!("{} ", candidate)).collect()
+}
+
+fn help(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(out, "{:<20} {}", command.usage, command.description)?;
+    }
+    Ok(())
+}
+
+fn mem(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    let heap = allocator::heap_stats();
+    writeln!(out, "heap:   {} / {} KiB used, {} KiB free", heap.used / 1024, heap.size / 1024, heap.free / 1024)?;
+    let (allocated, total) = {
+        let memory = memory::kernel_memory();
+        (memory.frame_allocator.allocated_frames(), memory.frame_allocator.total_frames())
+    };
+    writeln!(
+        out,
+        "frames: {} / {} used ({} / {} MiB)",
+        allocated,
+        total,
+        allocated * FRAME_SIZE / (1024 * 1024),
+        total * FRAME_SIZE / (1024 * 1024)
+    )
+}
+
+fn lspci(out: &mut dyn Write, args: &[&str]) -> fmt::Result {
+    match args {
+        [] => out.write_str(&pci::lspci(false)),
+        ["-v"] => out.write_str(&pci::lspci(true)),
+        _ => writeln!(out, "usage: lspci [-v]"),
+    }
+}
+
+fn lsusb(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    for device in usb::devices() {
+        let descriptor = device.descriptor();
+        writeln!(
+            out,
+            "Port {}: ID {:04x}:{:04x} {} ({:?} speed)",
+            device.port(),
+            descriptor.vendor_id,
+            descriptor.product_id,
+            device.read_string(descriptor.product_index).unwrap_or_default(),
+            device.speed()
+        )?;
+    }
+    Ok(())
+}
+
+fn ls(out: &mut dyn Write, args: &[&str]) -> fmt::Result {
+    let path = absolute(args.first().copied().unwrap_or("/"));
+    let vfs = VFS.lock();
+    let stat = match vfs.stat(&path) {
+        Ok(stat) => stat,
+        Err(err) => return writeln!(out, "ls: {}: {}", path, err),
+    };
+    if stat.file_type == FileType::File {
+        return writeln!(out, "{:>10} {}", stat.size, path);
+    }
+    let mut entries = match vfs.read_dir(&path) {
+        Ok(entries) => entries,
+        Err(err) => return writeln!(out, "ls: {}: {}", path, err),
+    };
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        match entry.file_type {
+            FileType::Directory => writeln!(out, "{:>10} {}/", "", entry.name)?,
+            FileType::File => {
+                let size = vfs.stat(&format!("{}/{}", path.trim_end_matches('/'), entry.name)).map_or(0, |stat| stat.size);
+                writeln!(out, "{:>10} {}", size, entry.name)?
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cat(out: &mut dyn Write, args: &[&str]) -> fmt::Result {
+    if args.is_empty() {
+        return writeln!(out, "usage: cat <path>...");
+    }
+    for path in args {
+        let path = absolute(path);
+        let contents = VFS.lock().open(&path, OpenFlags::READ).and_then(|mut file| file.read_to_end());
+        match contents {
+            Ok(contents) => {
+                let text = String::from_utf8_lossy(&contents);
+                out.write_str(&text)?;
+                if !text.is_empty() && !text.ends_with('\n') {
+                    out.write_char('\n')?;
+                }
+            }
+            Err(err) => writeln!(out, "cat: {}: {}", path, err)?,
+        }
+    }
+    Ok(())
+}
+
+fn uptime(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    let seconds = time::uptime().as_secs();
+    writeln!(out, "up {}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn ps(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    writeln!(out, "{:>4} {:>8} NAME", "ID", "POLLS")?;
+    for info in task::tasks() {
+        writeln!(out, "{:>4} {:>8} {}", info.id, info.polls, info.name)?;
+    }
+    Ok(())
+}
+
+fn parse_level(out: &mut dyn Write, level: &str) -> Result<Option<LevelFilter>, fmt::Error> {
+    match LevelFilter::from_str(level) {
+        Ok(level) => Ok(Some(level)),
+        Err(_) => {
+            writeln!(out, "log: unknown level: {} (one of {})", level, LOG_LEVELS.join(", "))?;
+            Ok(None)
+        }
+    }
+}
+
+/// `log level`は既定のレベル、`log level <module> <level>`はモジュールのレベル、
+/// `log output <serial|screen> <level>`は出力先ごとのレベルを変える
+fn log(out: &mut dyn Write, args: &[&str]) -> fmt::Result {
+    match args {
+        ["level"] => {
+            writeln!(out, "{}", logger::level())?;
+            for (module, level) in logger::module_levels() {
+                writeln!(out, "{} {}", module, level)?;
+            }
+            Ok(())
+        }
+        ["level", level] => {
+            if let Some(level) = parse_level(out, level)? {
+                logger::set_level(level);
+            }
+            Ok(())
+        }
+        ["level", module, "default"] => {
+            if !logger::reset_module_level(module) {
+                writeln!(out, "log: no level is set for {}", module)?;
+            }
+            Ok(())
+        }
+        ["level", module, level] => {
+            if let Some(level) = parse_level(out, level)? {
+                logger::set_module_level(module, level);
+            }
+            Ok(())
+        }
+        ["output"] => {
+            for output in OUTPUTS_ALL {
+                writeln!(out, "{} {}", output.name(), logger::output_level(output))?;
+            }
+            Ok(())
+        }
+        ["output", output, level] => match Output::from_name(output) {
+            Some(output) => {
+                if let Some(level) = parse_level(out, level)? {
+                    logger::set_output_level(output, level);
+                }
+                Ok(())
+            }
+            None => writeln!(out, "log: unknown output: {} (serial or screen)", output),
+        },
+        _ => writeln!(out, "usage: log level [module] [level|default], log output [serial|screen] [level]"),
+    }
+}
+
+fn dmesg(out: &mut dyn Write, args: &[&str]) -> fmt::Result {
+    match args {
+        [] => out.write_str(&logger::dmesg()),
+        ["-c"] => {
+            out.write_str(&logger::dmesg())?;
+            logger::clear_dmesg();
+            Ok(())
+        }
+        _ => writeln!(out, "usage: dmesg [-c]"),
+    }
+}
+
+fn reboot(_out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    power::reboot()
+}
+
+fn shutdown(out: &mut dyn Write, _args: &[&str]) -> fmt::Result {
+    match power::shutdown() {
+        Ok(()) => Ok(()),
+        Err(err) => writeln!(out, "shutdown: {}", err),
+    }
+}
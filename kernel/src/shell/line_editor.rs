@@ -0,0 +1,206 @@
+//! 一行分の入力の編集と履歴
+
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::task::keyboard::{DecodedKey, KeyCode, KeyEvent};
+
+/// 覚えておく履歴の数
+pub const HISTORY_SIZE: usize = 64;
+
+/// 行の編集に使うキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKey {
+    Char(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    /// 一つ前の履歴
+    Up,
+    /// 一つ後の履歴
+    Down,
+    Tab,
+    Enter,
+    /// Ctrl+C。入力中の行を捨てる
+    Cancel,
+}
+
+impl EditKey {
+    /// 押したキーを編集のキーにする。離したキーと、編集に使わないキーは`None`
+    pub fn from_key_event(event: &KeyEvent) -> Option<Self> {
+        if !event.is_press() {
+            return None;
+        }
+        match event.key? {
+            DecodedKey::Unicode('\n') | DecodedKey::Unicode('\r') => Some(EditKey::Enter),
+            DecodedKey::Unicode('\u{8}') => Some(EditKey::Backspace),
+            DecodedKey::Unicode('\u{7f}') => Some(EditKey::Delete),
+            DecodedKey::Unicode('\t') => Some(EditKey::Tab),
+            DecodedKey::Unicode('\u{3}') => Some(EditKey::Cancel),
+            DecodedKey::Unicode(character) if !character.is_control() => Some(EditKey::Char(character)),
+            DecodedKey::Unicode(_) => None,
+            DecodedKey::RawKey(code) => match code {
+                KeyCode::ArrowLeft => Some(EditKey::Left),
+                KeyCode::ArrowRight => Some(EditKey::Right),
+                KeyCode::ArrowUp => Some(EditKey::Up),
+                KeyCode::ArrowDown => Some(EditKey::Down),
+                KeyCode::Home => Some(EditKey::Home),
+                KeyCode::End => Some(EditKey::End),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// キーを処理した結果、シェルがすること
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// 行かカーソルの位置が変わった
+    Redraw,
+    /// Enterで確定した行
+    Submit(String),
+    /// 入力中の行が捨てられた
+    Cancel,
+    /// カーソルの前の単語を補完する
+    Complete,
+}
+
+pub struct LineEditor {
+    buffer: Vec<char>,
+    /// カーソルの前にある文字の数
+    cursor: usize,
+    /// 古いものから順に並ぶ
+    history: VecDeque<String>,
+    /// 履歴をたどっているときの位置
+    browsing: Option<usize>,
+    /// 履歴をたどり始める前に入力していた行
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            browsing: None,
+            draft: Vec::new(),
+        }
+    }
+
+    pub fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn before_cursor(&self) -> String {
+        self.buffer[..self.cursor].iter().collect()
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// カーソルの位置に文字列を挿入する。補完で使う
+    pub fn insert_str(&mut self, s: &str) {
+        for character in s.chars() {
+            self.buffer.insert(self.cursor, character);
+            self.cursor += 1;
+        }
+    }
+
+    pub fn handle_key(&mut self, key: EditKey) -> Action {
+        match key {
+            EditKey::Char(character) => {
+                self.buffer.insert(self.cursor, character);
+                self.cursor += 1;
+            }
+            EditKey::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.buffer.remove(self.cursor);
+            }
+            EditKey::Delete if self.cursor < self.buffer.len() => {
+                self.buffer.remove(self.cursor);
+            }
+            EditKey::Left if self.cursor > 0 => self.cursor -= 1,
+            EditKey::Right if self.cursor < self.buffer.len() => self.cursor += 1,
+            EditKey::Home => self.cursor = 0,
+            EditKey::End => self.cursor = self.buffer.len(),
+            EditKey::Up => return self.previous(),
+            EditKey::Down => return self.next(),
+            EditKey::Tab => return Action::Complete,
+            EditKey::Enter => return Action::Submit(self.submit()),
+            EditKey::Cancel => {
+                self.reset();
+                return Action::Cancel;
+            }
+            _ => return Action::None,
+        }
+        Action::Redraw
+    }
+
+    fn previous(&mut self) -> Action {
+        let index = match self.browsing {
+            Some(0) => return Action::None,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return Action::None,
+            None => {
+                self.draft = core::mem::take(&mut self.buffer);
+                self.history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.buffer = self.history[index].chars().collect();
+        self.cursor = self.buffer.len();
+        Action::Redraw
+    }
+
+    fn next(&mut self) -> Action {
+        match self.browsing {
+            None => return Action::None,
+            Some(index) if index + 1 < self.history.len() => {
+                self.browsing = Some(index + 1);
+                self.buffer = self.history[index + 1].chars().collect();
+            }
+            Some(_) => {
+                self.browsing = None;
+                self.buffer = core::mem::take(&mut self.draft);
+            }
+        }
+        self.cursor = self.buffer.len();
+        Action::Redraw
+    }
+
+    /// 確定した行を返し、空でなく直前と同じでなければ履歴に加える
+    fn submit(&mut self) -> String {
+        let line = self.line();
+        self.reset();
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.draft.clear();
+        self.cursor = 0;
+        self.browsing = None;
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
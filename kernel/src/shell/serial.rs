@@ -0,0 +1,173 @@
+//! シリアル端末でシェルを使うためのコンソールと入力の解釈
+
+use core::fmt::{self, Write};
+use x86_64::instructions::interrupts;
+use crate::serial::SERIAL1;
+use crate::shell::line_editor::EditKey;
+use crate::shell::Console;
+
+const ESCAPE: u8 = 0x1B;
+
+/// COM1につながった端末。行の書き直しにはVT100のエスケープシーケンスを使う
+pub struct SerialConsole;
+
+impl Write for SerialConsole {
+    /// 端末は改行で行頭に戻らないので、`\n`は`\r\n`にして送る
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL1.lock();
+            for (i, line) in s.split('\n').enumerate() {
+                if i > 0 {
+                    port.write_str("\r\n")?;
+                }
+                port.write_str(line)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Console for SerialConsole {
+    fn clear_line(&mut self) {
+        let _ = self.write_str("\r\x1b[2K");
+    }
+
+    fn set_column(&mut self, column: usize) {
+        let _ = self.write_char('\r');
+        if column > 0 {
+            let _ = write!(self, "\x1b[{}C", column);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    /// ESCを受け取った
+    Escape,
+    /// `ESC [`の後。数値の引数を読んでいる
+    Csi(u16),
+    /// `ESC O`の後。アプリケーションモードのカーソルキー
+    Ss3,
+    /// UTF-8の先頭のバイトを受け取り、残りを待っている
+    Utf8 { buffer: [u8; 4], length: usize, expected: usize },
+}
+
+/// 端末から届くバイト列を編集のキーにする
+pub struct InputDecoder {
+    state: State,
+    /// 直前のバイトが`\r`だった。`\r\n`を一回のEnterとして扱う
+    after_carriage_return: bool,
+}
+
+impl InputDecoder {
+    pub const fn new() -> Self {
+        Self { state: State::Ground, after_carriage_return: false }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<EditKey> {
+        let after_carriage_return = core::mem::replace(&mut self.after_carriage_return, byte == b'\r');
+        match self.state {
+            State::Ground => self.ground(byte, after_carriage_return),
+            State::Escape => {
+                self.state = match byte {
+                    b'[' => State::Csi(0),
+                    b'O' => State::Ss3,
+                    _ => State::Ground,
+                };
+                None
+            }
+            State::Csi(parameter) => match byte {
+                b'0'..=b'9' => {
+                    self.state = State::Csi(parameter.saturating_mul(10).saturating_add((byte - b'0') as u16));
+                    None
+                }
+                // `ESC [1;5C`(Ctrl+→)のような修飾キーの引数は無視する
+                b';' => None,
+                0x40..=0x7E => {
+                    self.state = State::Ground;
+                    match byte {
+                        b'~' => match parameter {
+                            1 | 7 => Some(EditKey::Home),
+                            3 => Some(EditKey::Delete),
+                            4 | 8 => Some(EditKey::End),
+                            _ => None,
+                        },
+                        byte => cursor_key(byte),
+                    }
+                }
+                _ => {
+                    self.state = State::Ground;
+                    None
+                }
+            },
+            State::Ss3 => {
+                self.state = State::Ground;
+                cursor_key(byte)
+            }
+            State::Utf8 { mut buffer, length, expected } => {
+                if byte & 0xC0 != 0x80 {
+                    // 続きのバイトではないので、途中までのものは捨てて読み直す
+                    self.state = State::Ground;
+                    return self.ground(byte, after_carriage_return);
+                }
+                buffer[length] = byte;
+                if length + 1 < expected {
+                    self.state = State::Utf8 { buffer, length: length + 1, expected };
+                    return None;
+                }
+                self.state = State::Ground;
+                let text = core::str::from_utf8(&buffer[..expected]).ok()?;
+                text.chars().next().map(EditKey::Char)
+            }
+        }
+    }
+
+    fn ground(&mut self, byte: u8, after_carriage_return: bool) -> Option<EditKey> {
+        match byte {
+            ESCAPE => {
+                self.state = State::Escape;
+                None
+            }
+            b'\r' => Some(EditKey::Enter),
+            b'\n' if after_carriage_return => None,
+            b'\n' => Some(EditKey::Enter),
+            // 多くの端末はBackspaceでDELを送る
+            0x7F | 0x08 => Some(EditKey::Backspace),
+            b'\t' => Some(EditKey::Tab),
+            0x03 => Some(EditKey::Cancel),
+            0x00..=0x1F => None,
+            0x20..=0x7E => Some(EditKey::Char(byte as char)),
+            _ => {
+                let expected = match byte {
+                    0xC0..=0xDF => 2,
+                    0xE0..=0xEF => 3,
+                    0xF0..=0xF7 => 4,
+                    _ => return None,
+                };
+                let mut buffer = [0; 4];
+                buffer[0] = byte;
+                self.state = State::Utf8 { buffer, length: 1, expected };
+                None
+            }
+        }
+    }
+}
+
+impl Default for InputDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn cursor_key(byte: u8) -> Option<EditKey> {
+    match byte {
+        b'A' => Some(EditKey::Up),
+        b'B' => Some(EditKey::Down),
+        b'C' => Some(EditKey::Right),
+        b'D' => Some(EditKey::Left),
+        b'H' => Some(EditKey::Home),
+        b'F' => Some(EditKey::End),
+        _ => None,
+    }
+}
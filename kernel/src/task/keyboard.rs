@@ -1,42 +1,400 @@
+pub mod layout;
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::Poll;
 
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
 use futures_util::{Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::task::keyboard::layout::{Layout, LayoutKeyboard};
+use crate::print;
 
-use crate::{print, println};
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// 購読しているタスク。ストリームが捨てられたものは、次に配るときに取り除く
+static SUBSCRIBERS: Mutex<Vec<Weak<Subscriber>>> = Mutex::new(Vec::new());
+/// 文字の入力を受け取る購読者
+static FOCUS: Mutex<Option<SubscriberId>> = Mutex::new(None);
 
-pub async fn print_keypress() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        layouts::Us104Key,
-        ScancodeSet1,
-        pc_keyboard::HandleControl::Ignore,
-    );
+lazy_static! {
+    /// `dispatch_keys`が使うデコーダ。キー配列を切り替えるときにも触る
+    static ref DECODER: Mutex<KeyDecoder> = Mutex::new(KeyDecoder::new(Layout::Us));
+}
+
+/// 修飾キーとロックキーの状態
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// 左のAlt
+    pub alt: bool,
+    /// 右のAlt。UKやAZERTYの配列では、別の文字を打つのに使う
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+/// キーを押した、または離したこと
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// このキーを処理した後の修飾キーの状態
+    pub modifiers: Modifiers,
+    /// 押したときに、キー配列と修飾キーから決まる文字。Ctrlと文字のキーは制御文字になる
+    ///
+    /// 離したときと、修飾キーを押したときは`None`
+    pub key: Option<DecodedKey>,
+}
+
+impl KeyEvent {
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    /// 押したキーが表す文字
+    pub fn char(&self) -> Option<char> {
+        match self.key {
+            Some(DecodedKey::Unicode(character)) => Some(character),
+            _ => None,
+        }
+    }
+}
+
+/// スキャンコードセット1のバイト列を、キー配列に合わせてキーイベントにする
+pub struct KeyDecoder {
+    keyboard: LayoutKeyboard,
+    layout: Layout,
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    /// `pc_keyboard`に合わせて、Num Lockは最初から有効にしておく
+    num_lock: bool,
+}
+
+impl KeyDecoder {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            keyboard: LayoutKeyboard::new(layout),
+            layout,
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: true,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// キー配列を切り替える。押されている修飾キーとロックキーの状態は初めに戻る
+    pub fn set_layout(&mut self, layout: Layout) {
+        *self = Self::new(layout);
+    }
 
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            alt: self.alt,
+            alt_gr: self.alt_gr,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+        }
+    }
+
+    /// 1バイト読み、キーの操作がそろったらイベントを返す。E0で始まるキーは2バイト目で返す
+    pub fn add_scancode(&mut self, scancode: u8) -> Option<KeyEvent> {
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let (code, state) = (event.code, event.state);
+        self.update_modifiers(code, state);
+        let key = self.keyboard.process_keyevent(event);
+        Some(KeyEvent {
+            code,
+            state,
+            modifiers: self.modifiers(),
+            key,
+        })
+    }
+
+    fn update_modifiers(&mut self, code: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            _ => {}
+        }
+    }
+}
+
+/// 今のキー配列
+pub fn layout() -> Layout {
+    DECODER.lock().layout()
+}
+
+pub fn set_layout(layout: Layout) {
+    DECODER.lock().set_layout(layout);
+}
+
+/// スキャンコードをキー配列に合わせて読み、購読しているタスクに配る
+pub async fn dispatch_keys() {
+    let mut scancodes = ScancodeStream::new();
     while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
+        let event = DECODER.lock().add_scancode(scancode);
+        if let Some(event) = event {
+            publish(event);
+        }
+    }
+}
+
+/// 押したキーの文字を画面に出す
+pub async fn print_keypress() {
+    let mut events = subscribe();
+    while let Some(event) = events.next().await {
+        match event.key {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
+        }
+    }
+}
+
+/// フォーカスに関係なく受け取るキーの組み合わせ。修飾キーは完全に一致したときだけ受け取る
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    pub code: KeyCode,
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Hotkey {
+    pub const fn new(code: KeyCode) -> Self {
+        Self { code, shift: false, ctrl: false, alt: false }
+    }
+
+    pub const fn with_shift(mut self) -> Self {
+        self.shift = true;
+        self
+    }
+
+    pub const fn with_ctrl(mut self) -> Self {
+        self.ctrl = true;
+        self
+    }
+
+    pub const fn with_alt(mut self) -> Self {
+        self.alt = true;
+        self
+    }
+
+    /// 押したときだけ一致する
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        event.is_press()
+            && event.code == self.code
+            && event.modifiers.shift == self.shift
+            && event.modifiers.ctrl == self.ctrl
+            && event.modifiers.alt == self.alt
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SubscriberId(u64);
+
+impl SubscriberId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        SubscriberId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// 購読者がどのイベントを受け取るか
+enum Delivery {
+    /// フォーカスを持っている間、ホットキー以外のすべて
+    Focus,
+    /// 登録したホットキーを押したときだけ
+    Hotkeys(Vec<Hotkey>),
+    /// フォーカスやホットキーに関係なく、すべて
+    All,
+}
+
+struct Subscriber {
+    id: SubscriberId,
+    delivery: Delivery,
+    queue: ArrayQueue<KeyEvent>,
+    waker: AtomicWaker,
+}
+
+impl Subscriber {
+    /// いっぱいなら捨てる。呼び出し側が画面のロックを持っていても止まらないように、何も表示しない
+    fn push(&self, event: KeyEvent) {
+        let _ = self.queue.push(event);
+        self.waker.wake();
+    }
+}
+
+fn add_subscriber(delivery: Delivery) -> KeyEventStream {
+    let subscriber = Arc::new(Subscriber {
+        id: SubscriberId::new(),
+        delivery,
+        queue: ArrayQueue::new(100),
+        waker: AtomicWaker::new(),
+    });
+    SUBSCRIBERS.lock().push(Arc::downgrade(&subscriber));
+    KeyEventStream { subscriber }
+}
+
+/// 文字の入力を受け取る。まだフォーカスを持つ購読者がいなければ、フォーカスを得る
+pub fn subscribe() -> KeyEventStream {
+    // 捨てられたストリームがフォーカスを持ったままにならないよう、先に確かめておく
+    let focused = focused();
+    let stream = add_subscriber(Delivery::Focus);
+    *FOCUS.lock() = Some(focused.unwrap_or(stream.id()));
+    stream
+}
+
+/// `hotkeys`のどれかを押したときだけ受け取る。そのキーはフォーカスを持つ購読者には届かない
+pub fn subscribe_hotkeys(hotkeys: &[Hotkey]) -> KeyEventStream {
+    add_subscriber(Delivery::Hotkeys(hotkeys.to_vec()))
+}
+
+/// すべてのキーイベントを受け取る。フォーカスは変えない
+pub fn monitor() -> KeyEventStream {
+    add_subscriber(Delivery::All)
+}
+
+/// 文字の入力を受け取っている購読者
+pub fn focused() -> Option<SubscriberId> {
+    let subscribers = live_subscribers();
+    focused_in(&subscribers)
+}
+
+fn live_subscribers() -> Vec<Arc<Subscriber>> {
+    let mut subscribers = SUBSCRIBERS.lock();
+    subscribers.retain(|subscriber| subscriber.strong_count() > 0);
+    subscribers.iter().filter_map(Weak::upgrade).collect()
+}
+
+/// フォーカスを持つ購読者のストリームが捨てられていたら、最後に購読したものに移す
+fn focused_in(subscribers: &[Arc<Subscriber>]) -> Option<SubscriberId> {
+    let mut focus = FOCUS.lock();
+    let focusable = |id: &SubscriberId| {
+        subscribers
+            .iter()
+            .any(|subscriber| subscriber.id == *id && matches!(subscriber.delivery, Delivery::Focus))
+    };
+    if !focus.as_ref().is_some_and(focusable) {
+        *focus = subscribers
+            .iter()
+            .rev()
+            .find(|subscriber| matches!(subscriber.delivery, Delivery::Focus))
+            .map(|subscriber| subscriber.id);
+    }
+    *focus
+}
+
+/// キーイベントを購読者に配る。ホットキーは登録した購読者だけに、それ以外はフォーカスを持つ購読者に届く
+///
+/// キーボード以外から入力を送るときにも使う。割り込みハンドラから呼んではいけない
+pub fn publish(event: KeyEvent) {
+    let subscribers = live_subscribers();
+    let focused = focused_in(&subscribers);
+    let hotkey = subscribers.iter().any(|subscriber| match &subscriber.delivery {
+        Delivery::Hotkeys(hotkeys) => hotkeys.iter().any(|hotkey| hotkey.matches(&event)),
+        _ => false,
+    });
+    for subscriber in subscribers.iter() {
+        let deliver = match &subscriber.delivery {
+            Delivery::Focus => !hotkey && focused == Some(subscriber.id),
+            Delivery::Hotkeys(hotkeys) => hotkeys.iter().any(|hotkey| hotkey.matches(&event)),
+            Delivery::All => true,
+        };
+        if deliver {
+            subscriber.push(event);
+        }
+    }
+}
+
+/// 購読したキーイベントのストリーム。捨てると購読をやめる
+pub struct KeyEventStream {
+    subscriber: Arc<Subscriber>,
+}
+
+impl KeyEventStream {
+    pub fn id(&self) -> SubscriberId {
+        self.subscriber.id
+    }
+
+    /// 文字の入力をこの購読者に向ける。`subscribe`で作ったものでなければ何もしない
+    pub fn focus(&self) {
+        if let Delivery::Focus = self.subscriber.delivery {
+            *FOCUS.lock() = Some(self.id());
+        }
+    }
+
+    pub fn is_focused(&self) -> bool {
+        focused() == Some(self.id())
+    }
+
+    /// 待たずに、届いているイベントを一つ取り出す
+    pub fn try_next(&self) -> Option<KeyEvent> {
+        self.subscriber.queue.pop().ok()
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Option<Self::Item>> {
+        let subscriber = &self.subscriber;
+        if let Ok(event) = subscriber.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        subscriber.waker.register(cx.waker());
+        match subscriber.queue.pop() {
+            Ok(event) => {
+                subscriber.waker.take();
+                Poll::Ready(Some(event))
             }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
         }
     }
 }
 
-pub struct ScancodeStream {
+/// キーボードのスキャンコード。読むのは`dispatch_keys`だけで、他のタスクは`subscribe`でキーイベントを受け取る
+struct ScancodeStream {
     _private: (),
 }
 impl ScancodeStream {
-    pub fn new() -> Self {
+    fn new() -> Self {
         SCANCODE_QUEUE
             .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+            .expect("dispatch_keys should only be spawned once");
         ScancodeStream { _private: () }
     }
 }
@@ -45,11 +403,19 @@ impl Stream for ScancodeStream {
     type Item = u8;
     fn poll_next(
         self: core::pin::Pin<&mut Self>,
-        _cx: &mut core::task::Context<'_>,
+        cx: &mut core::task::Context<'_>,
     ) -> core::task::Poll<Option<Self::Item>> {
         let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+        if let Ok(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        // 登録している間に割り込みで積まれたものを取りこぼさないよう、もう一度見る
+        WAKER.register(cx.waker());
         match queue.pop() {
-            Ok(scancode) => Poll::Ready(Some(scancode)),
+            Ok(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
             Err(crossbeam_queue::PopError) => Poll::Pending,
         }
     }
@@ -58,11 +424,11 @@ impl Stream for ScancodeStream {
 /// キーボード割り込みハンドラから呼び出される
 /// 処理をブロックしたり、アロケートをしてはいけない
 pub(crate) fn add_scancode(scancode: u8) {
+    // 誰も読んでいないか、キューがいっぱいなら捨てる
+    // 割り込まれた側が画面のロックを持っているかもしれないので、ここでは何も表示しない
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
+        if queue.push(scancode).is_ok() {
+            WAKER.wake();
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
     }
 }
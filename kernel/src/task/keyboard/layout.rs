@@ -0,0 +1,80 @@
+//! 実行中に切り替えられるキー配列
+
+use pc_keyboard::{layouts, DecodedKey, Error, HandleControl, KeyEvent, Keyboard, ScancodeSet1};
+
+/// 対応しているキー配列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US 104キー
+    Us,
+    /// UK 105キー
+    Uk,
+    /// JIS 106/109キー。Windowsキーの有無しか違わないので同じ配列で扱う
+    Jis,
+    /// US配列のキーボードで使うDvorak
+    Dvorak,
+    /// フランス語のAZERTY
+    Azerty,
+}
+
+pub const LAYOUTS: [Layout; 5] = [Layout::Us, Layout::Uk, Layout::Jis, Layout::Dvorak, Layout::Azerty];
+
+impl Layout {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Jis => "jis",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        LAYOUTS.iter().copied().find(|layout| layout.name() == name)
+    }
+}
+
+/// `pc_keyboard::Keyboard`はキー配列を型で持つので、配列ごとに作って切り替える
+pub(super) enum LayoutKeyboard {
+    Us(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Jis(Keyboard<layouts::Jis109Key, ScancodeSet1>),
+    Dvorak(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+    Azerty(Keyboard<layouts::Azerty, ScancodeSet1>),
+}
+
+/// どの配列のキーボードでも同じ処理をする
+macro_rules! with_keyboard {
+    ($self:ident, $keyboard:ident => $body:expr) => {
+        match $self {
+            LayoutKeyboard::Us($keyboard) => $body,
+            LayoutKeyboard::Uk($keyboard) => $body,
+            LayoutKeyboard::Jis($keyboard) => $body,
+            LayoutKeyboard::Dvorak($keyboard) => $body,
+            LayoutKeyboard::Azerty($keyboard) => $body,
+        }
+    };
+}
+
+impl LayoutKeyboard {
+    /// Ctrlと文字のキーは、U+0001からU+001Aの制御文字にする
+    pub(super) fn new(layout: Layout) -> Self {
+        let control = HandleControl::MapLettersToUnicode;
+        match layout {
+            Layout::Us => LayoutKeyboard::Us(Keyboard::new(layouts::Us104Key, ScancodeSet1, control)),
+            Layout::Uk => LayoutKeyboard::Uk(Keyboard::new(layouts::Uk105Key, ScancodeSet1, control)),
+            Layout::Jis => LayoutKeyboard::Jis(Keyboard::new(layouts::Jis109Key, ScancodeSet1, control)),
+            Layout::Dvorak => LayoutKeyboard::Dvorak(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, control)),
+            Layout::Azerty => LayoutKeyboard::Azerty(Keyboard::new(layouts::Azerty, ScancodeSet1, control)),
+        }
+    }
+
+    pub(super) fn add_byte(&mut self, scancode: u8) -> Result<Option<KeyEvent>, Error> {
+        with_keyboard!(self, keyboard => keyboard.add_byte(scancode))
+    }
+
+    pub(super) fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        with_keyboard!(self, keyboard => keyboard.process_keyevent(event))
+    }
+}
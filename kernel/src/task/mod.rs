@@ -1,17 +1,34 @@
 pub mod executor;
 pub mod keyboard;
+pub mod mouse;
+pub mod serial;
 pub mod simple_executor;
 
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use core::sync::atomic::AtomicU64;
 use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
+use spin::Mutex;
+
+/// 生きているタスクの一覧。`Task`が作られたときに登録され、破棄されたときに取り除かれる
+static TASKS: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
 
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    /// タスクにしたasync関数のパス
+    pub name: &'static str,
+    /// これまでにpollされた回数
+    pub polls: u64,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 impl TaskId {
@@ -22,13 +39,38 @@ impl TaskId {
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new<F: Future<Output = ()> + 'static>(future: F) -> Task {
+        let id = TaskId::new();
+        TASKS.lock().insert(id, TaskInfo { id: id.0, name: task_name::<F>(), polls: 0 });
         Task {
-            id: TaskId::new(),
+            id,
             future: Box::pin(future),
         }
     }
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        if let Some(info) = TASKS.lock().get_mut(&self.id) {
+            info.polls += 1;
+        }
         self.future.as_mut().poll(context)
     }
 }
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        TASKS.lock().remove(&self.id);
+    }
+}
+
+/// 生きているタスクをIDの順に返す
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().values().cloned().collect()
+}
+
+/// async関数のFutureの型名は`kernel::task::keyboard::dispatch_keys::{{closure}}`のようになる
+fn task_name<F>() -> &'static str {
+    let mut name = core::any::type_name::<F>();
+    while let Some(stripped) = name.strip_suffix("::{{closure}}") {
+        name = stripped;
+    }
+    name
+}
@@ -0,0 +1,93 @@
+use core::task::Poll;
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use futures_util::{Stream, StreamExt};
+
+use crate::frame_buffer_writer::cursor::MOUSE_CURSOR;
+use crate::frame_buffer_writer::vector2d::Vector2D;
+use crate::frame_buffer_writer::FRAME_BUFFER_WRITER;
+
+// ボタンのビット
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_RIGHT: u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+static MOUSE_EVENT_QUEUE: OnceCell<ArrayQueue<MouseEvent>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// マウスが前回から動いた量と、押されているボタン
+///
+/// 画面の座標と同じく、`dy`は下向きが正。`wheel`は手前に回すと正
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub wheel: i8,
+    pub buttons: u8,
+}
+
+/// 画面の中央にカーソルを出し、マウスの動きに合わせて動かす
+pub async fn track_cursor() {
+    let mut events = MouseStream::new();
+    {
+        let mut writer = FRAME_BUFFER_WRITER.lock();
+        let center = Vector2D::new(writer.info.width / 2, writer.info.height / 2);
+        let mut cursor = MOUSE_CURSOR.lock();
+        cursor.move_to(&mut writer, center);
+        cursor.show(&mut writer);
+        writer.flush();
+    }
+
+    while let Some(event) = events.next().await {
+        let mut writer = FRAME_BUFFER_WRITER.lock();
+        MOUSE_CURSOR.lock().move_by(&mut writer, event.dx, event.dy);
+        writer.flush();
+    }
+}
+
+pub struct MouseStream {
+    _private: (),
+}
+impl MouseStream {
+    pub fn new() -> Self {
+        MOUSE_EVENT_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("MouseStream::new should only be called once");
+        MouseStream { _private: () }
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Option<Self::Item>> {
+        let queue = MOUSE_EVENT_QUEUE.try_get().expect("not initialized");
+        if let Ok(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        // 登録している間に割り込みで積まれたものを取りこぼさないよう、もう一度見る
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Ok(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// マウスのドライバから呼び出される。誰も読んでいなければ捨てる
+/// 処理をブロックしたり、アロケートをしてはいけない
+pub(crate) fn add_mouse_event(event: MouseEvent) {
+    if let Ok(queue) = MOUSE_EVENT_QUEUE.try_get() {
+        // いっぱいなら捨てる。割り込まれた側が画面のロックを持っているかもしれないので、何も表示しない
+        if queue.push(event).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
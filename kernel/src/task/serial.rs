@@ -0,0 +1,55 @@
+use core::task::Poll;
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::task::AtomicWaker;
+use futures_util::Stream;
+
+static SERIAL_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// COM1で受信したバイト。貼り付けた行もまとめて受け取れるよう、キーボードより大きめにとる
+pub struct SerialStream {
+    _private: (),
+}
+impl SerialStream {
+    pub fn new() -> Self {
+        SERIAL_QUEUE
+            .try_init_once(|| ArrayQueue::new(1024))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+    fn poll_next(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Option<Self::Item>> {
+        let queue = SERIAL_QUEUE.try_get().expect("not initialized");
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Ok(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// シリアルポートの割り込みハンドラから呼び出される
+/// 処理をブロックしたり、アロケートをしてはいけない
+pub(crate) fn add_byte(byte: u8) {
+    // 誰も読んでいないか、キューがいっぱいなら捨てる
+    // 割り込まれた側が画面やログのロックを持っているかもしれないので、ここでは何も表示しない
+    if let Ok(queue) = SERIAL_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
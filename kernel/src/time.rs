@@ -0,0 +1,109 @@
+//! PITのタイマー割り込みを数えて、起動してからの時間を測る
+
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// PITに入力されるクロックの周波数
+const PIT_FREQUENCY: u64 = 1_193_182;
+pub const TICKS_PER_SECOND: u64 = 100;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// チャンネル0、下位と上位のバイトの順に書く、モード2(レートジェネレータ)
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// 待っている`Sleep`。割り込みハンドラはWakerを起こすだけで、取り除くのは`Sleep`自身がする
+static SLEEPERS: Mutex<Vec<Sleeper>> = Mutex::new(Vec::new());
+
+struct Sleeper {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+    /// 一度起こしたら、pollされるまでは起こさない
+    woken: bool,
+}
+
+/// PITのチャンネル0を`TICKS_PER_SECOND`回/秒で割り込むように設定する
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TICKS_PER_SECOND) as u16;
+    unsafe {
+        Port::<u8>::new(PIT_COMMAND).write(PIT_RATE_GENERATOR);
+        let mut channel0 = Port::<u8>::new(PIT_CHANNEL0);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// タイマー割り込みハンドラから呼び出される
+/// 処理をブロックしたり、アロケートをしてはいけない
+pub(crate) fn tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // タスクが一覧を触っている途中なら、次の割り込みで起こす
+    if let Some(mut sleepers) = SLEEPERS.try_lock() {
+        for sleeper in sleepers.iter_mut().filter(|sleeper| !sleeper.woken && sleeper.deadline <= now) {
+            sleeper.woken = true;
+            sleeper.waker.wake_by_ref();
+        }
+    }
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 割り込みを有効にしてからの時間
+pub fn uptime() -> Duration {
+    let ticks = ticks();
+    Duration::from_secs(ticks / TICKS_PER_SECOND)
+        + Duration::from_millis(ticks % TICKS_PER_SECOND * 1000 / TICKS_PER_SECOND)
+}
+
+/// `duration`が経つと完了するFuture。タイマーの刻みより短い時間は切り上げる
+pub fn sleep(duration: Duration) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let ticks = (duration.as_millis() as u64 * TICKS_PER_SECOND + 999) / 1000;
+    Sleep { id: NEXT_ID.fetch_add(1, Ordering::Relaxed), deadline: self::ticks() + ticks.max(1) }
+}
+
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let (id, deadline) = (self.id, self.deadline);
+        interrupts::without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            if ticks() >= deadline {
+                sleepers.retain(|sleeper| sleeper.id != id);
+                return Poll::Ready(());
+            }
+            match sleepers.iter_mut().find(|sleeper| sleeper.id == id) {
+                Some(sleeper) => {
+                    sleeper.waker = cx.waker().clone();
+                    sleeper.woken = false;
+                }
+                None => sleepers.push(Sleeper { id, deadline, waker: cx.waker().clone(), woken: false }),
+            }
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let id = self.id;
+        interrupts::without_interrupts(|| SLEEPERS.lock().retain(|sleeper| sleeper.id != id));
+    }
+}
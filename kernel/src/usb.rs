@@ -1,176 +1,223 @@
+pub mod descriptor;
 pub mod device;
-mod error;
-
-use core::arch::asm;
-use core::fmt::{Debug};
-use lazy_static::lazy_static;
+pub mod driver;
+pub mod error;
+pub mod hid;
+pub mod request;
+pub mod storage;
+pub mod xhci;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use log::debug;
+use pci::device::Device;
+use pci::{HostController, UsbDeviceInfo};
 use spin::Mutex;
-use crate::usb::device::Device;
-use crate::usb::error::PciError;
-
-lazy_static! {
-    pub static ref DEVICES:Mutex<Devices> = Mutex::new(Devices::default());
-}
-#[derive(Default, Debug)]
-pub struct Devices {
-    pub devices: [Device; 32],
-    pub num: usize,
-}
-
-pub const fn calc_bar_address(bar_index: usize) -> u8 {
-    (0x10 + 4 * bar_index) as u8
-}
-
-pub fn scan_all_bus() -> Result<(), PciError> {
-    DEVICES.lock().clear();
-
-    let header_type = read_header_type(0, 0, 0);
-    if is_single_function_device(header_type) {
-        return scan_bus(0);
-    }
-
-    for function in 1..8 {
-        if read_vendor_id(0, 0, function) == 0xFFFF {
-            continue;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::usb::device::UsbDevice;
+use crate::usb::error::UsbError;
+use crate::usb::request::SetupPacket;
+use crate::usb::xhci::XhciController;
+
+pub use crate::usb::driver::{register_driver, Driver, InterfaceMatch};
+
+pub type Controller = Arc<Mutex<XhciController>>;
+
+/// ドライバが初期化したホストコントローラの一覧
+static CONTROLLERS: Mutex<Vec<Controller>> = Mutex::new(Vec::new());
+/// 列挙が終わり、接続されているデバイスの一覧
+static DEVICES: Mutex<Vec<Arc<UsbDevice>>> = Mutex::new(Vec::new());
+
+/// コントローラの割り込みが来てから、まだイベントを処理していない
+static EVENT_PENDING: AtomicBool = AtomicBool::new(false);
+/// 割り込みを待っているタスク。割り込みハンドラからも触るので、割り込みを止めてからロックする
+static EVENT_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// USBホストコントローラのドライバを登録し、PCIバスで見つかったコントローラに結び付ける
+///
+/// クラスドライバはこれより前に`register_driver`で登録しておく
+pub fn init() {
+    register_driver(&hid::keyboard::DRIVER);
+    register_driver(&hid::mouse::DRIVER);
+    register_driver(&storage::DRIVER);
+    pci::register_driver(&xhci::DRIVER);
+    pci::probe();
+    poll();
+}
+
+/// コントローラを一覧に加え、`pci::host_controllers`からも見えるようにする
+pub fn register(device: &Device, controller: Controller) {
+    CONTROLLERS.lock().push(controller.clone());
+    pci::register_host_controller(Arc::new(PciHostController { device: *device, controller }));
+}
+
+pub fn controllers() -> Vec<Controller> {
+    CONTROLLERS.lock().clone()
+}
+
+pub fn devices() -> Vec<Arc<UsbDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// すべてのコントローラに溜まっているイベントを処理し、つながったデバイスを列挙する
+pub fn poll() {
+    for controller in controllers() {
+        let (attached, detached) = {
+            let mut controller = controller.lock();
+            controller.poll();
+            (controller.take_attached_ports(), controller.take_detached_ports())
+        };
+        for port in detached {
+            remove_device(&controller, port);
         }
-        scan_bus(function)?;
-    }
-    Ok(())
-}
-
-fn scan_bus(bus: u8) -> Result<(), PciError> {
-    for device in 0..32 {
-        if read_vendor_id(bus, device, 0) == 0xFFFF {
-            continue;
+        for port in attached {
+            match device::enumerate(&controller, port) {
+                Ok(device) => {
+                    let descriptor = device.descriptor();
+                    debug!(
+                        "usb: port {}: slot {}, device {:04x}:{:04x}",
+                        port,
+                        device.slot(),
+                        descriptor.vendor_id,
+                        descriptor.product_id
+                    );
+                    DEVICES.lock().push(device.clone());
+                    driver::probe(&device);
+                }
+                Err(err) => debug!("usb: port {}: failed to enumerate the device: {}", port, err),
+            }
         }
-        scan_device(bus, device)?;
     }
-    Ok(())
+    hid::poll();
 }
 
-fn scan_device(bus: u8, device: u8) -> Result<(), PciError> {
-    scan_function(bus, device, 0)?;
-    if is_single_function_device(read_header_type(bus, device, 0)) {
-        return Ok(());
-    }
-
-    for function in 0..8 {
-        if read_vendor_id(bus, device, function) == 0xFFFF {
-            continue;
-        }
-        scan_function(bus, device, function)?;
+/// コントローラの割り込みが来るたびに`poll`を呼ぶタスク
+pub async fn handle_events() {
+    loop {
+        poll();
+        EventSignal.await;
     }
-    Ok(())
 }
 
-fn scan_function(bus: u8, device: u8, function: u8) -> Result<(), PciError> {
-    let header_type = read_header_type(bus, device, function);
-    {
-        DEVICES.lock().add_device(&Device::new(bus, device, function, header_type))?;
+/// コントローラの割り込みハンドラから呼ばれる。処理をブロックしてはいけない
+pub(crate) fn wake() {
+    EVENT_PENDING.store(true, Ordering::Release);
+    if let Some(waker) = EVENT_WAKER.lock().take() {
+        waker.wake();
     }
-    let class_code = read_class_code(bus, device, function);
-    let base = ((class_code >> 24) & 0xFF) as u8;
-    let sub = ((class_code >> 16) & 0xFF) as u8;
-
-    if base == 0x06 && sub == 0x04 {
-        // standard PCI-PCI bridge
-        let bus_numbers = read_bus_numbers(bus, device, function);
-        let secondary_bus: u8 = ((bus_numbers >> 8) & 0xFF) as u8;
-
-        return scan_bus(secondary_bus);
-    }
-    Ok(())
 }
 
-impl Devices {
-    fn add_device(&mut self, device: &Device) -> Result<(), PciError> {
-        if self.is_full() {
-            return Err(PciError::Full);
-        }
+/// 次の割り込みを待つ
+struct EventSignal;
 
-        self.set_new_device(device.clone());
-        self.num += 1;
-        Ok(())
-    }
-    fn clear(&mut self) {
-        self.num = 0;
-    }
-    fn is_full(&self) -> bool {
-        self.num == self.devices.len()
-    }
+impl Future for EventSignal {
+    type Output = ();
 
-    fn set_new_device(&mut self, device: Device) {
-        self.devices[self.num] = device;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if EVENT_PENDING.swap(false, Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+        without_interrupts(|| *EVENT_WAKER.lock() = Some(cx.waker().clone()));
+        // 登録する前に割り込みが来ていたら、待たずに進む
+        if EVENT_PENDING.swap(false, Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
     }
 }
 
-// CONFIG_ADDRESSレジスタのIOポートアドレス
-const K_CONFIG_ADDRESS: u16 = 0x0CF8;
-// CONFIG_DATAレジスタ
-const K_CONFIG_DATA: u16 = 0x0CFC;
-
-fn make_address(bus: u8, device: u8, function: u8, reg_addr: u8) -> u32 {
-    let shl = |x: u8, bits: usize| -> u32 {
-        (x as u32) << bits
-    };
-
-    shl(1, 31) | shl(bus, 16) | shl(device, 11) | shl(function, 8) | (reg_addr & 0xFC) as u32
-}
-
-fn write_address(addr: u32) {
-    io_out32(K_CONFIG_ADDRESS, addr);
-}
-
-fn write_data(value: u32) {
-    io_out32(K_CONFIG_DATA, value);
+/// まだドライバが結び付いていないインターフェースに、条件の合うクラスドライバを探して結び付ける
+///
+/// ドライバを後から登録した場合も、もう一度呼べばそのドライバが試される
+pub fn probe() {
+    for device in devices() {
+        driver::probe(&device);
+    }
 }
 
-fn read_data() -> u32 {
-    io_in32(K_CONFIG_DATA)
+/// 切断されたポートのデバイスを一覧から外し、スロットを解放する
+fn remove_device(controller: &Controller, port: u8) {
+    let mut removed = Vec::new();
+    DEVICES.lock().retain(|device| {
+        let detached = Arc::ptr_eq(device.controller(), controller) && device.port() == port;
+        if detached {
+            removed.push(device.clone());
+        }
+        !detached
+    });
+    for device in removed {
+        device.detach();
+        driver::disconnect(&device);
+        if let Err(err) = controller.lock().disable_slot(device.slot()) {
+            debug!("usb: failed to disable slot {}: {}", device.slot(), err);
+        }
+    }
 }
 
-fn read_device_id(bus: u8, device: u8, function: u8) -> u16 {
-    write_address(make_address(bus, device, function, 0x00));
-    (read_data() >> 16) as u16
+/// `pci::HostController`としてのxHCIコントローラ
+struct PciHostController {
+    device: Device,
+    controller: Controller,
 }
 
-fn read_class_code(bus: u8, device: u8, function: u8) -> u32 {
-    write_address(make_address(bus, device, function, 0x0C));
-    read_data()
+impl PciHostController {
+    fn usb_device(&self, slot: u8) -> Option<Arc<UsbDevice>> {
+        devices()
+            .into_iter()
+            .find(|device| Arc::ptr_eq(device.controller(), &self.controller) && device.slot() == slot)
+    }
 }
 
-fn read_header_type(bus: u8, device: u8, function: u8) -> u8 {
-    write_address(make_address(bus, device, function, 0x0C));
-    ((read_data() >> 16) & 0xFF) as u8
-}
+impl HostController for PciHostController {
+    fn name(&self) -> &'static str {
+        xhci::DRIVER.name
+    }
 
-fn read_vendor_id(bus: u8, device: u8, function: u8) -> u32 {
-    write_address(make_address(bus, device, function, 0x00));
-    read_data() & 0xFFFF
-}
+    fn device(&self) -> Device {
+        self.device
+    }
 
-fn read_bus_numbers(bus: u8, device: u8, function: u8) -> u32 {
-    write_address(make_address(bus, device, function, 0x18));
-    read_data()
-}
+    fn port_count(&self) -> usize {
+        self.controller.lock().ports().len()
+    }
 
-fn is_single_function_device(header_type: u8) -> bool {
-    (header_type & 0x80) == 0
-}
+    fn usb_devices(&self) -> Vec<UsbDeviceInfo> {
+        devices()
+            .iter()
+            .filter(|device| Arc::ptr_eq(device.controller(), &self.controller))
+            .map(|device| {
+                let descriptor = device.descriptor();
+                UsbDeviceInfo {
+                    port: device.port(),
+                    slot: device.slot(),
+                    vendor_id: descriptor.vendor_id,
+                    product_id: descriptor.product_id,
+                    class: descriptor.class,
+                    subclass: descriptor.subclass,
+                    protocol: descriptor.protocol,
+                }
+            })
+            .collect()
+    }
 
-fn io_out32(addr: u16, data: u32) {
-    unsafe {
-        asm!("mov dx, {0:x}", in(reg) addr);
-        asm!("mov eax, {0:e}", in(reg) data);
-        asm!("out dx, eax");
+    fn read_descriptor(&self, slot: u8, descriptor_type: u8, index: u8, length: u16) -> Option<Vec<u8>> {
+        let setup = SetupPacket::get_descriptor(descriptor_type, index, length);
+        self.usb_device(slot)?.control_in(setup).ok()
     }
 }
 
-fn io_in32(addr: u16) -> u32 {
-    let mut ret: u32;
-    unsafe {
-        asm!("mov dx, {0:x}", in(reg) addr);
-        asm!("in {0:e}, dx", out(reg) ret);
+/// `condition`が満たされるまで最大`spins`回ポーリングする
+pub(crate) fn wait_until(spins: usize, mut condition: impl FnMut() -> bool) -> Result<(), UsbError> {
+    for _ in 0..spins {
+        if condition() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
     }
-    ret
-}
\ No newline at end of file
+    Err(UsbError::Timeout)
+}
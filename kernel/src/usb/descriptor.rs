@@ -0,0 +1,269 @@
+//! デバイスから読み出すディスクリプタ
+
+use alloc::vec::Vec;
+
+// ディスクリプタの種類
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_STRING: u8 = 3;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+pub const DESCRIPTOR_HID: u8 = 0x21;
+pub const DESCRIPTOR_HID_REPORT: u8 = 0x22;
+
+pub const DEVICE_DESCRIPTOR_SIZE: usize = 18;
+pub const CONFIGURATION_DESCRIPTOR_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    /// BCDで表したUSBのバージョン。0x0200ならUSB 2.0
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    /// エンドポイント0の最大パケットサイズ。USB3では2の冪の指数
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_version: u16,
+    pub manufacturer_index: u8,
+    pub product_index: u8,
+    pub serial_number_index: u8,
+    pub num_configurations: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigurationDescriptor {
+    /// このディスクリプタに続くインターフェースやエンドポイントも含めた長さ
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    /// SET_CONFIGURATIONで指定する値
+    pub value: u8,
+    pub attributes: u8,
+    /// 2mA単位の最大消費電流
+    pub max_power: u8,
+    /// 代替設定0のインターフェース
+    pub interfaces: Vec<Interface>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate_setting: u8,
+    pub num_endpoints: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferType {
+    Control,
+    Isochronous,
+    Bulk,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// 最上位ビットが向き、下位4ビットがエンドポイント番号
+    pub address: u8,
+    pub attributes: u8,
+    /// 下位11ビットが最大パケットサイズ、その上の2ビットがマイクロフレームあたりの追加の転送数
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// HIDクラスのディスクリプタ。インターフェースディスクリプタの直後に置かれる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HidDescriptor {
+    pub hid_version: u16,
+    pub country_code: u8,
+    /// レポートディスクリプタの長さ
+    pub report_descriptor_length: u16,
+}
+
+/// インターフェースと、それに属するエンドポイント
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub descriptor: InterfaceDescriptor,
+    pub endpoints: Vec<EndpointDescriptor>,
+    pub hid: Option<HidDescriptor>,
+}
+
+fn read16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+impl DeviceDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < DEVICE_DESCRIPTOR_SIZE || bytes[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+        Some(Self {
+            usb_version: read16(bytes, 2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size0: bytes[7],
+            vendor_id: read16(bytes, 8),
+            product_id: read16(bytes, 10),
+            device_version: read16(bytes, 12),
+            manufacturer_index: bytes[14],
+            product_index: bytes[15],
+            serial_number_index: bytes[16],
+            num_configurations: bytes[17],
+        })
+    }
+}
+
+impl ConfigurationDescriptor {
+    /// コンフィギュレーションディスクリプタと、それに続くディスクリプタをまとめて読む
+    ///
+    /// 代替設定が0以外のインターフェースと、知らない種類のディスクリプタは読み飛ばす
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < CONFIGURATION_DESCRIPTOR_SIZE || bytes[1] != DESCRIPTOR_CONFIGURATION {
+            return None;
+        }
+        let total_length = read16(bytes, 2);
+        let bytes = &bytes[..(total_length as usize).min(bytes.len())];
+        let mut configuration = Self {
+            total_length,
+            num_interfaces: bytes[4],
+            value: bytes[5],
+            attributes: bytes[7],
+            max_power: bytes[8],
+            interfaces: Vec::new(),
+        };
+
+        let mut offset = bytes[0] as usize;
+        // 今読んでいるインターフェースが代替設定0か
+        let mut in_default_setting = false;
+        while offset + 2 <= bytes.len() {
+            let length = bytes[offset] as usize;
+            if length < 2 || offset + length > bytes.len() {
+                return None;
+            }
+            let descriptor = &bytes[offset..offset + length];
+            match descriptor[1] {
+                DESCRIPTOR_INTERFACE => {
+                    let interface = InterfaceDescriptor::parse(descriptor)?;
+                    in_default_setting = interface.alternate_setting == 0;
+                    if in_default_setting {
+                        configuration.interfaces.push(Interface {
+                            descriptor: interface,
+                            endpoints: Vec::new(),
+                            hid: None,
+                        });
+                    }
+                }
+                DESCRIPTOR_ENDPOINT if in_default_setting => {
+                    let endpoint = EndpointDescriptor::parse(descriptor)?;
+                    configuration.interfaces.last_mut()?.endpoints.push(endpoint);
+                }
+                DESCRIPTOR_HID if in_default_setting => {
+                    configuration.interfaces.last_mut()?.hid = HidDescriptor::parse(descriptor);
+                }
+                _ => {}
+            }
+            offset += length;
+        }
+        Some(configuration)
+    }
+
+    /// 設定するすべてのエンドポイント
+    pub fn endpoints(&self) -> impl Iterator<Item = &EndpointDescriptor> {
+        self.interfaces.iter().flat_map(|interface| interface.endpoints.iter())
+    }
+}
+
+impl InterfaceDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 9 || bytes[1] != DESCRIPTOR_INTERFACE {
+            return None;
+        }
+        Some(Self {
+            number: bytes[2],
+            alternate_setting: bytes[3],
+            num_endpoints: bytes[4],
+            class: bytes[5],
+            subclass: bytes[6],
+            protocol: bytes[7],
+        })
+    }
+}
+
+impl EndpointDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 7 || bytes[1] != DESCRIPTOR_ENDPOINT {
+            return None;
+        }
+        Some(Self {
+            address: bytes[2],
+            attributes: bytes[3],
+            max_packet_size: read16(bytes, 4),
+            interval: bytes[6],
+        })
+    }
+
+    pub fn number(&self) -> u8 {
+        self.address & 0xF
+    }
+
+    /// デバイスからホストへの向きか
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> TransferType {
+        match self.attributes & 0x3 {
+            0 => TransferType::Control,
+            1 => TransferType::Isochronous,
+            2 => TransferType::Bulk,
+            _ => TransferType::Interrupt,
+        }
+    }
+
+    /// 一つのパケットの最大の大きさ
+    pub fn max_packet_size(&self) -> u16 {
+        self.max_packet_size & 0x7FF
+    }
+
+    /// High Speedの周期的な転送で、マイクロフレームあたりに追加で送れるパケットの数
+    pub fn additional_transactions(&self) -> u8 {
+        ((self.max_packet_size >> 11) & 0x3) as u8
+    }
+}
+
+impl HidDescriptor {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 6 || bytes[1] != DESCRIPTOR_HID {
+            return None;
+        }
+        // 後ろに(種類, 長さ)の組が並ぶので、レポートディスクリプタのものを探す
+        let count = bytes[5] as usize;
+        let report_descriptor_length = (0..count)
+            .map(|index| 6 + index * 3)
+            .take_while(|&offset| offset + 3 <= bytes.len())
+            .find(|&offset| bytes[offset] == DESCRIPTOR_HID_REPORT)
+            .map(|offset| read16(bytes, offset + 1))
+            .unwrap_or(0);
+        Some(Self {
+            hid_version: read16(bytes, 2),
+            country_code: bytes[4],
+            report_descriptor_length,
+        })
+    }
+}
+
+/// 文字列ディスクリプタはUTF-16LEで書かれている
+pub fn parse_string(bytes: &[u8]) -> Option<alloc::string::String> {
+    if bytes.len() < 2 || bytes[1] != DESCRIPTOR_STRING {
+        return None;
+    }
+    let length = (bytes[0] as usize).min(bytes.len());
+    let units = bytes[2..length]
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]));
+    Some(core::char::decode_utf16(units).map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER)).collect())
+}
@@ -1,110 +1,246 @@
-use crate::usb::{
-    calc_bar_address, make_address, read_class_code, read_data, read_vendor_id, write_address,
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::usb::descriptor::{
+    parse_string, ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, CONFIGURATION_DESCRIPTOR_SIZE,
+    DESCRIPTOR_CONFIGURATION, DESCRIPTOR_DEVICE, DESCRIPTOR_STRING, DEVICE_DESCRIPTOR_SIZE,
 };
+use crate::usb::error::UsbError;
+use crate::usb::request::SetupPacket;
+use crate::usb::xhci::trb::COMPLETION_STALL;
+use crate::usb::xhci::{PortState, Speed, MAX_TRANSFER_SIZE};
+use crate::usb::Controller;
 
-#[derive(Default, Debug, Copy, Clone)]
-pub struct Device {
-    pub bus: u8,
-    pub device: u8,
-    pub function: u8,
-    pub header_type: u8,
-    pub class_code: ClassCode,
+/// コントロール転送のデータステージに使うバッファの大きさ
+const CONTROL_BUFFER_SIZE: usize = 4096;
+/// 文字列ディスクリプタを読むときの言語。英語(米国)
+const LANGUAGE_EN_US: u16 = 0x0409;
+
+/// アドレスを割り当て、コンフィギュレーションを設定したUSBデバイス
+pub struct UsbDevice {
+    controller: Controller,
+    slot: u8,
+    port: u8,
+    speed: Speed,
+    descriptor: DeviceDescriptor,
+    configuration: ConfigurationDescriptor,
+    /// コントロール転送のデータステージに使う
+    buffer: Mutex<DmaBuffer>,
+    attached: AtomicBool,
+    /// インターフェース番号と、そこに結び付いたクラスドライバの名前
+    bindings: Mutex<Vec<(u8, &'static str)>>,
 }
 
-#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
-pub struct ClassCode {
-    base: u8,
-    sub: u8,
-    interface: u8,
+/// ポートにつながったデバイスにアドレスを割り当て、最初のコンフィギュレーションを設定する
+pub fn enumerate(controller: &Controller, port: u8) -> Result<Arc<UsbDevice>, UsbError> {
+    let speed = match controller.lock().port(port).map(|port| port.state) {
+        Some(PortState::Enabled(speed)) => speed,
+        _ => return Err(UsbError::NoDevice),
+    };
+    let slot = controller.lock().enable_slot()?;
+    let result = initialize(controller, slot, port, speed);
+    if result.is_err() {
+        let _ = controller.lock().disable_slot(slot);
+    }
+    result
 }
 
-impl Device {
-    pub fn new(bus: u8, device: u8, function: u8, header_type: u8) -> Self {
-        let class_code = read_class_code(bus, device, function);
-        Self {
-            bus,
-            device,
-            function,
-            header_type,
-            class_code: ClassCode::from(class_code),
+fn initialize(controller: &Controller, slot: u8, port: u8, speed: Speed) -> Result<Arc<UsbDevice>, UsbError> {
+    controller.lock().address_device(slot, port, speed)?;
+    let mut buffer = DmaBuffer::new(CONTROL_BUFFER_SIZE).ok_or(UsbError::NoMemory)?;
+
+    // Full Speedのエンドポイント0の最大パケットサイズは8から64まであるので、先頭の8バイトで確かめる
+    if speed == Speed::Full {
+        let header = read_descriptor(controller, slot, &mut buffer, DESCRIPTOR_DEVICE, 8)?;
+        let max_packet_size = *header.get(7).ok_or(UsbError::InvalidDescriptor)? as u16;
+        if max_packet_size != speed.default_max_packet_size() {
+            controller.lock().set_max_packet_size0(slot, max_packet_size)?;
         }
     }
+    let bytes = read_descriptor(controller, slot, &mut buffer, DESCRIPTOR_DEVICE, DEVICE_DESCRIPTOR_SIZE)?;
+    let descriptor = DeviceDescriptor::parse(&bytes).ok_or(UsbError::InvalidDescriptor)?;
 
-    /// if return `None` your index out of range
-    pub fn read_bar(&self, bar_index: usize) -> Option<u64> {
-        if bar_index >= 6 {
-            return None;
-        }
-        let addr = calc_bar_address(bar_index);
-        let bar = self.read_conf_reg(addr);
+    // まず先頭だけを読み、続くディスクリプタも含めた長さを知る
+    let header = read_descriptor(controller, slot, &mut buffer, DESCRIPTOR_CONFIGURATION, CONFIGURATION_DESCRIPTOR_SIZE)?;
+    let header = ConfigurationDescriptor::parse(&header).ok_or(UsbError::InvalidDescriptor)?;
+    let total_length = (header.total_length as usize).min(CONTROL_BUFFER_SIZE);
+    let bytes = read_descriptor(controller, slot, &mut buffer, DESCRIPTOR_CONFIGURATION, total_length)?;
+    let configuration = ConfigurationDescriptor::parse(&bytes).ok_or(UsbError::InvalidDescriptor)?;
 
-        // 32bit address
-        if (bar & 0b0100) == 0 {
-            return Some(bar as u64);
-        }
+    let endpoints: Vec<EndpointDescriptor> = configuration.endpoints().copied().collect();
+    controller.lock().configure_endpoints(slot, &endpoints)?;
+    controller.lock().control_transfer(slot, SetupPacket::set_configuration(configuration.value), None)?;
 
-        // 64bit address
-        if bar_index >= 0b0101 {
-            return None;
-        }
+    Ok(Arc::new(UsbDevice {
+        controller: controller.clone(),
+        slot,
+        port,
+        speed,
+        descriptor,
+        configuration,
+        buffer: Mutex::new(buffer),
+        attached: AtomicBool::new(true),
+        bindings: Mutex::new(Vec::new()),
+    }))
+}
+
+fn read_descriptor(
+    controller: &Controller,
+    slot: u8,
+    buffer: &mut DmaBuffer,
+    descriptor_type: u8,
+    length: usize,
+) -> Result<Vec<u8>, UsbError> {
+    let setup = SetupPacket::get_descriptor(descriptor_type, 0, length as u16);
+    let transferred = controller.lock().control_transfer(slot, setup, Some(buffer))?;
+    Ok(buffer.as_slice()[..transferred].to_vec())
+}
 
-        let bar_upper = self.read_conf_reg(addr + 4) as u64;
-        Some((bar as u64) | bar_upper << 32)
+impl UsbDevice {
+    pub fn controller(&self) -> &Controller {
+        &self.controller
     }
 
-    pub fn read_class_code(&self) -> ClassCode {
-        self.class_code
+    pub fn slot(&self) -> u8 {
+        self.slot
     }
-    pub fn read_vendor_id(&self) -> u32 {
-        read_vendor_id(self.bus, self.device, self.function)
+
+    /// つながっているルートハブのポート番号
+    pub fn port(&self) -> u8 {
+        self.port
     }
-    pub fn read_conf_reg(&self, reg_addr: u8) -> u32 {
-        write_address(make_address(self.bus, self.device, self.function, reg_addr));
-        read_data()
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn descriptor(&self) -> &DeviceDescriptor {
+        &self.descriptor
+    }
+
+    pub fn configuration(&self) -> &ConfigurationDescriptor {
+        &self.configuration
+    }
+
+    /// 切断されていなければ`true`。切断されたデバイスへの転送は失敗する
+    pub fn is_attached(&self) -> bool {
+        self.attached.load(Ordering::Relaxed)
     }
-}
 
+    pub(crate) fn detach(&self) {
+        self.attached.store(false, Ordering::Relaxed);
+    }
+
+    /// データステージがINのコントロール転送を行い、受け取ったデータを返す
+    pub fn control_in(&self, setup: SetupPacket) -> Result<Vec<u8>, UsbError> {
+        debug_assert!(setup.is_in());
+        let mut buffer = self.buffer.lock();
+        let transferred = self.control_transfer(setup, &mut buffer)?;
+        Ok(buffer.as_slice()[..transferred].to_vec())
+    }
+
+    /// データステージがOUT、または無いコントロール転送を行う。データの長さは`setup.length`に合わせる
+    pub fn control_out(&self, mut setup: SetupPacket, data: &[u8]) -> Result<(), UsbError> {
+        debug_assert!(!setup.is_in());
+        let mut buffer = self.buffer.lock();
+        buffer.as_mut_slice()[..data.len()].copy_from_slice(data);
+        setup.length = data.len() as u16;
+        self.control_transfer(setup, &mut buffer).map(|_| ())
+    }
 
-impl ClassCode {
-    pub fn new(base: u8, sub: u8, interface: u8) -> Self {
-        Self {
-            base,
-            sub,
-            interface,
+    fn control_transfer(&self, setup: SetupPacket, buffer: &mut DmaBuffer) -> Result<usize, UsbError> {
+        if !self.is_attached() {
+            return Err(UsbError::NoDevice);
         }
+        self.controller.lock().control_transfer(self.slot, setup, Some(buffer))
     }
 
-    pub fn equal_b(&self, base: u8) -> bool {
-        self.base == base
+    /// 文字列ディスクリプタを読む。0番は文字列が無いことを表す
+    pub fn read_string(&self, index: u8) -> Option<String> {
+        if index == 0 {
+            return None;
+        }
+        let mut setup = SetupPacket::get_descriptor(DESCRIPTOR_STRING, index, 255);
+        setup.index = LANGUAGE_EN_US;
+        parse_string(&self.control_in(setup).ok()?)
+    }
+
+    /// バルク転送や割り込み転送を積み、完了を待たずにそのTRBのアドレスを返す
+    ///
+    /// 結果は`take_transfer`や`wait_transfer`で受け取る。それまで`buffer`を使ってはいけない
+    pub fn submit_transfer(&self, endpoint_address: u8, buffer: &mut DmaBuffer, length: usize) -> Result<u64, UsbError> {
+        if !self.is_attached() {
+            return Err(UsbError::NoDevice);
+        }
+        assert!(length <= buffer.size(), "transfer does not fit in the buffer");
+        self.controller.lock().submit_transfer(self.slot, endpoint_address, buffer.phys_addr(), length)
     }
-    pub fn equal_bs(&self, base: u8, sub: u8) -> bool {
-        self.equal_b(base) && self.sub == sub
+
+    /// 積んだ転送が終わっていれば、転送されたバイト数を返す
+    pub fn take_transfer(&self, address: u64) -> Option<Result<usize, UsbError>> {
+        self.controller.lock().take_transfer(address)
     }
-    pub fn equal_bsi(&self, base: u8, sub: u8, interface: u8) -> bool {
-        self.equal_bs(base, sub) && self.interface == interface
+
+    pub fn wait_transfer(&self, address: u64) -> Result<usize, UsbError> {
+        self.controller.lock().wait_transfer(address)
     }
-}
 
-impl From<u32> for ClassCode {
-    fn from(value: u32) -> Self {
-        Self::new(
-            ((value >> 24) & 0xFF) as u8,
-            ((value >> 16) & 0xFF) as u8,
-            ((value >> 8) & 0xFF) as u8,
-        )
+    /// `buffer`の先頭から`length`バイトを転送し、転送されたバイト数を返す
+    ///
+    /// TRBは64KiBの境界をまたげないので、境界ごとに分けて転送する。短いパケットが来たらそこで終わる
+    /// エンドポイントがSTALLを返したら、デバイス側のHaltも解除しておく
+    pub fn transfer(&self, endpoint_address: u8, buffer: &mut DmaBuffer, length: usize) -> Result<usize, UsbError> {
+        if !self.is_attached() {
+            return Err(UsbError::NoDevice);
+        }
+        assert!(length <= buffer.size(), "transfer does not fit in the buffer");
+        let mut transferred = 0;
+        while transferred < length {
+            let phys = buffer.phys_addr() + transferred as u64;
+            let boundary = MAX_TRANSFER_SIZE - (phys % MAX_TRANSFER_SIZE as u64) as usize;
+            let piece = (length - transferred).min(boundary);
+            let result = {
+                let mut controller = self.controller.lock();
+                controller
+                    .submit_transfer(self.slot, endpoint_address, phys, piece)
+                    .and_then(|address| controller.wait_transfer(address))
+            };
+            match result {
+                Ok(count) => {
+                    transferred += count;
+                    if count < piece {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    if err == UsbError::Completion(COMPLETION_STALL) {
+                        let _ = self.control_out(SetupPacket::clear_halt(endpoint_address), &[]);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(transferred)
     }
-}
 
-impl From<ClassCode> for u32 {
-    fn from(value: ClassCode) -> Self {
-        ((value.base as u32) << 24) | ((value.sub as u32) << 16) | ((value.interface as u32) << 8)
+    /// インターフェースに結び付いているクラスドライバの名前
+    pub fn bound_driver(&self, interface: u8) -> Option<&'static str> {
+        self.bindings
+            .lock()
+            .iter()
+            .find(|(number, _)| *number == interface)
+            .map(|(_, name)| *name)
     }
-}
 
-#[test]
-fn class_code_from_test() {
-    let class_code = ClassCode::from(1010);
-    let casted_class_code = u32::from(class_code);
+    /// いずれかのインターフェースが`driver`に結び付いている
+    pub fn is_bound_to(&self, driver: &str) -> bool {
+        self.bindings.lock().iter().any(|(_, name)| *name == driver)
+    }
 
-    assert_eq!(class_code, ClassCode::from(casted_class_code), "From implementation is wrong");
-}
\ No newline at end of file
+    pub(crate) fn bind(&self, interface: u8, driver: &'static str) {
+        self.bindings.lock().push((interface, driver));
+    }
+}
@@ -0,0 +1,76 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::debug;
+use spin::Mutex;
+use crate::usb::descriptor::{Interface, InterfaceDescriptor};
+use crate::usb::device::UsbDevice;
+
+/// クラスドライバが受け持つインターフェースの条件
+///
+/// `subclass`や`protocol`が`None`なら、その値は問わない
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterfaceMatch {
+    pub class: u8,
+    pub subclass: Option<u8>,
+    pub protocol: Option<u8>,
+}
+
+/// USBのインターフェースごとに結び付くクラスドライバ
+///
+/// `probe`はインターフェースを使えるようにできたら`true`を返す。`false`なら次に条件が合うドライバを試す
+/// `disconnect`はデバイスが切断されたときに、結び付いていたドライバだけに呼ばれる
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [InterfaceMatch],
+    pub probe: fn(&Arc<UsbDevice>, &Interface) -> bool,
+    pub disconnect: Option<fn(&Arc<UsbDevice>)>,
+}
+
+/// 登録されたクラスドライバ。先に登録されたものから順に試す
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+
+impl InterfaceMatch {
+    pub fn matches(&self, interface: &InterfaceDescriptor) -> bool {
+        interface.class == self.class
+            && self.subclass.is_none_or(|subclass| interface.subclass == subclass)
+            && self.protocol.is_none_or(|protocol| interface.protocol == protocol)
+    }
+}
+
+impl Driver {
+    pub fn matches(&self, interface: &InterfaceDescriptor) -> bool {
+        self.matches.iter().any(|condition| condition.matches(interface))
+    }
+}
+
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+}
+
+/// デバイスのまだドライバが結び付いていないインターフェースに、条件の合うドライバを結び付ける
+pub fn probe(device: &Arc<UsbDevice>) {
+    let drivers = DRIVERS.lock().clone();
+    for interface in device.configuration().interfaces.iter() {
+        let number = interface.descriptor.number;
+        if device.bound_driver(number).is_some() {
+            continue;
+        }
+        for driver in drivers.iter().filter(|driver| driver.matches(&interface.descriptor)) {
+            if (driver.probe)(device, interface) {
+                debug!("usb: slot {} interface {} bound to {}", device.slot(), number, driver.name);
+                device.bind(number, driver.name);
+                break;
+            }
+        }
+    }
+}
+
+/// 切断されたデバイスに結び付いていたドライバに、後片付けをさせる
+pub fn disconnect(device: &Arc<UsbDevice>) {
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers.iter().filter(|driver| device.is_bound_to(driver.name)) {
+        if let Some(disconnect) = driver.disconnect {
+            disconnect(device);
+        }
+    }
+}
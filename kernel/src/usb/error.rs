@@ -1,32 +1,61 @@
 use core::error;
 use core::error::Error;
 use core::fmt::{Debug, Display, Formatter};
+use pci::error::PciError;
 
-pub enum PciError {
-    Full
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    Timeout,
+    NoDevice,
+    NoMemory,
+    /// コントローラが内部のエラーで止まった
+    HostControllerError,
+    /// コマンドや転送が成功以外の完了コードで終わった
+    Completion(u8),
+    /// デバイスが返したディスクリプタを解釈できない
+    InvalidDescriptor,
 }
 
-
-impl Debug for PciError {
+impl Debug for UsbError {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{}", self.description())
+        match self {
+            UsbError::Completion(code) => write!(f, "{} (code {})", self.description(), code),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
-impl Display for PciError {
+impl Display for UsbError {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self.description())
+        write!(f, "{:?}", self)
     }
 }
 
-impl error::Error for PciError {
+impl error::Error for UsbError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         None
     }
 }
 
-impl PciError {
+/// BARを使えないコントローラは無いものとして扱う
+impl From<PciError> for UsbError {
+    fn from(value: PciError) -> Self {
+        match value {
+            PciError::MapFailed => UsbError::NoMemory,
+            _ => UsbError::NoDevice,
+        }
+    }
+}
+
+impl UsbError {
     fn description(&self) -> &'static str {
-        match self { PciError::Full => "PCI Device is Full" }
+        match self {
+            UsbError::Timeout => "Controller did not respond in time",
+            UsbError::NoDevice => "No usable controller or device is attached",
+            UsbError::NoMemory => "Failed to allocate memory for the controller",
+            UsbError::HostControllerError => "Host controller stopped with an internal error",
+            UsbError::Completion(_) => "Controller reported an unsuccessful completion",
+            UsbError::InvalidDescriptor => "Device returned a malformed descriptor",
+        }
     }
 }
@@ -0,0 +1,117 @@
+//! Human Interface Deviceクラス。ブートプロトコルのキーボードとマウスを扱う
+
+pub mod keyboard;
+pub mod mouse;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::debug;
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::usb::descriptor::{Interface, TransferType};
+use crate::usb::device::UsbDevice;
+use crate::usb::error::UsbError;
+use crate::usb::request::{SetupPacket, REQUEST_RECIPIENT_INTERFACE, REQUEST_TYPE_CLASS};
+
+pub const CLASS_HID: u8 = 3;
+/// ブートプロトコルに対応しているサブクラス
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+pub const PROTOCOL_MOUSE: u8 = 2;
+
+// HIDクラスのリクエスト
+const REQUEST_SET_IDLE: u8 = 0x0A;
+const REQUEST_SET_PROTOCOL: u8 = 0x0B;
+/// SET_PROTOCOLで指定するブートプロトコル。1ならレポートプロトコル
+const BOOT_PROTOCOL: u16 = 0;
+
+/// 受け取ったレポートを解釈する
+pub trait ReportHandler: Send {
+    fn handle(&mut self, report: &[u8]);
+}
+
+/// 割り込みINエンドポイントからレポートを受け取り続ける
+struct ReportPipe {
+    device: Arc<UsbDevice>,
+    endpoint: u8,
+    length: usize,
+    buffer: DmaBuffer,
+    /// コントローラに積んである転送のTRBのアドレス
+    pending: Option<u64>,
+    handler: Box<dyn ReportHandler>,
+}
+
+static PIPES: Mutex<Vec<ReportPipe>> = Mutex::new(Vec::new());
+
+/// インターフェースをブートプロトコルに切り替え、レポートを`handler`に渡し始める
+pub fn attach(device: &Arc<UsbDevice>, interface: &Interface, handler: Box<dyn ReportHandler>) -> Result<(), UsbError> {
+    let endpoint = interface
+        .endpoints
+        .iter()
+        .find(|endpoint| endpoint.is_in() && endpoint.transfer_type() == TransferType::Interrupt)
+        .ok_or(UsbError::NoDevice)?;
+    let number = interface.descriptor.number as u16;
+    device.control_out(class_request(REQUEST_SET_PROTOCOL, BOOT_PROTOCOL, number), &[])?;
+    // 変化があったときだけレポートを送らせる。対応していないデバイスもあるので失敗しても続ける
+    if let Err(err) = device.control_out(class_request(REQUEST_SET_IDLE, 0, number), &[]) {
+        debug!("hid: slot {}: SET_IDLE failed: {}", device.slot(), err);
+    }
+
+    let mut pipe = ReportPipe {
+        device: device.clone(),
+        endpoint: endpoint.address,
+        length: endpoint.max_packet_size() as usize,
+        buffer: DmaBuffer::new(endpoint.max_packet_size() as usize).ok_or(UsbError::NoMemory)?,
+        pending: None,
+        handler,
+    };
+    pipe.submit()?;
+    PIPES.lock().push(pipe);
+    Ok(())
+}
+
+/// 届いたレポートを処理し、次の転送を積む。切断されたデバイスは外す
+pub fn poll() {
+    let mut pipes = PIPES.lock();
+    pipes.retain(|pipe| pipe.device.is_attached());
+    for pipe in pipes.iter_mut() {
+        if let Err(err) = pipe.poll() {
+            debug!("hid: slot {}: {}", pipe.device.slot(), err);
+        }
+    }
+}
+
+fn class_request(request: u8, value: u16, interface: u16) -> SetupPacket {
+    SetupPacket {
+        request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+        request,
+        value,
+        index: interface,
+        length: 0,
+    }
+}
+
+impl ReportPipe {
+    fn submit(&mut self) -> Result<(), UsbError> {
+        self.pending = Some(self.device.submit_transfer(self.endpoint, &mut self.buffer, self.length)?);
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Result<(), UsbError> {
+        if let Some(address) = self.pending {
+            let result = match self.device.take_transfer(address) {
+                Some(result) => result,
+                None => return Ok(()),
+            };
+            self.pending = None;
+            // 失敗しても次の転送は積んでおく
+            if let Ok(length) = result {
+                self.handler.handle(&self.buffer.as_slice()[..length]);
+            }
+            self.submit()?;
+            return result.map(|_| ());
+        }
+        self.submit()
+    }
+}
@@ -0,0 +1,140 @@
+//! ブートプロトコルのキーボード。レポートをスキャンコードセット1に直し、PS/2と同じ経路で渡す
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::debug;
+use crate::task::keyboard::add_scancode;
+use crate::usb::descriptor::Interface;
+use crate::usb::device::UsbDevice;
+use crate::usb::driver::{Driver, InterfaceMatch};
+use crate::usb::hid::{attach, ReportHandler, CLASS_HID, PROTOCOL_KEYBOARD, SUBCLASS_BOOT};
+
+pub static DRIVER: Driver = Driver {
+    name: "hid-keyboard",
+    matches: &[InterfaceMatch { class: CLASS_HID, subclass: Some(SUBCLASS_BOOT), protocol: Some(PROTOCOL_KEYBOARD) }],
+    probe,
+    disconnect: None,
+};
+
+/// ブートプロトコルのレポートの大きさ。修飾キー、予約、押されているキー6つ
+pub const REPORT_SIZE: usize = 8;
+/// 同時に押されたキーが多すぎると、すべてのキーがこの値になる
+const ERROR_ROLL_OVER: u8 = 0x01;
+/// 拡張キーの前に送るプレフィックス
+const EXTENDED_PREFIX: u8 = 0xE0;
+/// キーを離したときにセット1のコードに立てるビット
+const RELEASE: u8 = 0x80;
+
+fn probe(device: &Arc<UsbDevice>, interface: &Interface) -> bool {
+    match attach(device, interface, Box::new(BootKeyboard::new())) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("hid-keyboard: slot {}: {}", device.slot(), err);
+            false
+        }
+    }
+}
+
+/// 前回のレポートと比べて、押されたキーと離されたキーを求める
+pub struct BootKeyboard {
+    previous: [u8; REPORT_SIZE],
+}
+
+impl BootKeyboard {
+    pub fn new() -> Self {
+        Self { previous: [0; REPORT_SIZE] }
+    }
+
+    /// レポートを、PS/2キーボードが送るのと同じスキャンコードの列に直す
+    pub fn scancodes(&mut self, report: &[u8]) -> Vec<u8> {
+        let mut scancodes = Vec::new();
+        if report.len() < REPORT_SIZE || report[2..REPORT_SIZE].contains(&ERROR_ROLL_OVER) {
+            return scancodes;
+        }
+        let mut emit = |usage: u8, pressed: bool| {
+            if let Some(code) = set1_scancode(usage) {
+                if code > 0xFF {
+                    scancodes.push(EXTENDED_PREFIX);
+                }
+                scancodes.push(code as u8 | if pressed { 0 } else { RELEASE });
+            }
+        };
+
+        // 修飾キーはビットごとに、左Ctrl(0xE0)から右GUI(0xE7)までの使用法IDに対応する
+        let changed = report[0] ^ self.previous[0];
+        for bit in (0..8).filter(|bit| changed & 1 << bit != 0) {
+            emit(0xE0 + bit, report[0] & 1 << bit != 0);
+        }
+        let previous = &self.previous[2..REPORT_SIZE];
+        let current = &report[2..REPORT_SIZE];
+        for &usage in previous.iter().filter(|&&usage| usage != 0 && !current.contains(&usage)) {
+            emit(usage, false);
+        }
+        for &usage in current.iter().filter(|&&usage| usage != 0 && !previous.contains(&usage)) {
+            emit(usage, true);
+        }
+        self.previous.copy_from_slice(&report[..REPORT_SIZE]);
+        scancodes
+    }
+}
+
+impl Default for BootKeyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReportHandler for BootKeyboard {
+    fn handle(&mut self, report: &[u8]) {
+        for scancode in self.scancodes(report) {
+            add_scancode(scancode);
+        }
+    }
+}
+
+/// キーボードの使用法IDから、セット1のメイクコードを求める。0xE0で始まる拡張キーは0xE0xxになる
+pub fn set1_scancode(usage: u8) -> Option<u16> {
+    let code = match usage {
+        0x04..=0x65 => USAGE_TO_SET1[usage as usize - 0x04],
+        // 日本語キーボードのキー
+        0x87 => 0x73, // ろ
+        0x88 => 0x70, // カタカナ/ひらがな
+        0x89 => 0x7D, // ¥
+        0x8A => 0x79, // 変換
+        0x8B => 0x7B, // 無変換
+        0xE0 => 0x1D,   // 左Ctrl
+        0xE1 => 0x2A,   // 左Shift
+        0xE2 => 0x38,   // 左Alt
+        0xE3 => 0xE05B, // 左GUI
+        0xE4 => 0xE01D, // 右Ctrl
+        0xE5 => 0x36,   // 右Shift
+        0xE6 => 0xE038, // 右Alt
+        0xE7 => 0xE05C, // 右GUI
+        _ => 0,
+    };
+    if code == 0 { None } else { Some(code) }
+}
+
+/// 使用法ID 0x04(A)から0x65(Application)まで。対応するキーが無ければ0
+const USAGE_TO_SET1: [u16; 0x62] = [
+    // A-Z
+    0x1E, 0x30, 0x2E, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32,
+    0x31, 0x18, 0x19, 0x10, 0x13, 0x1F, 0x14, 0x16, 0x2F, 0x11, 0x2D, 0x15, 0x2C,
+    // 1-9, 0
+    0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B,
+    // Enter, Esc, Backspace, Tab, Space, - = [ ] \ #(非US) ; ' ` , . /
+    0x1C, 0x01, 0x0E, 0x0F, 0x39, 0x0C, 0x0D, 0x1A, 0x1B, 0x2B, 0x2B, 0x27, 0x28, 0x29, 0x33, 0x34, 0x35,
+    // CapsLock, F1-F12
+    0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x40, 0x41, 0x42, 0x43, 0x44, 0x57, 0x58,
+    // PrintScreen, ScrollLock, Pause(セット1では特殊な列になるので扱わない)
+    0xE037, 0x46, 0,
+    // Insert, Home, PageUp, Delete, End, PageDown, →, ←, ↓, ↑
+    0xE052, 0xE047, 0xE049, 0xE053, 0xE04F, 0xE051, 0xE04D, 0xE04B, 0xE050, 0xE048,
+    // NumLock, テンキーの / * - + Enter
+    0x45, 0xE035, 0x37, 0x4A, 0x4E, 0xE01C,
+    // テンキーの1-9, 0, .
+    0x4F, 0x50, 0x51, 0x4B, 0x4C, 0x4D, 0x47, 0x48, 0x49, 0x52, 0x53,
+    // \(非US), Application
+    0x56, 0xE05D,
+];
@@ -0,0 +1,51 @@
+//! ブートプロトコルのマウス
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use log::debug;
+use crate::task::mouse::{add_mouse_event, MouseEvent};
+use crate::usb::descriptor::Interface;
+use crate::usb::device::UsbDevice;
+use crate::usb::driver::{Driver, InterfaceMatch};
+use crate::usb::hid::{attach, ReportHandler, CLASS_HID, PROTOCOL_MOUSE, SUBCLASS_BOOT};
+
+pub static DRIVER: Driver = Driver {
+    name: "hid-mouse",
+    matches: &[InterfaceMatch { class: CLASS_HID, subclass: Some(SUBCLASS_BOOT), protocol: Some(PROTOCOL_MOUSE) }],
+    probe,
+    disconnect: None,
+};
+
+fn probe(device: &Arc<UsbDevice>, interface: &Interface) -> bool {
+    match attach(device, interface, Box::new(BootMouse)) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!("hid-mouse: slot {}: {}", device.slot(), err);
+            false
+        }
+    }
+}
+
+pub struct BootMouse;
+
+/// ボタン、X、Yの3バイトに、多くのマウスはホイールの1バイトを付け足して送る
+pub fn parse_report(report: &[u8]) -> Option<MouseEvent> {
+    if report.len() < 3 {
+        return None;
+    }
+    Some(MouseEvent {
+        dx: report[1] as i8 as i16,
+        dy: report[2] as i8 as i16,
+        // USBのホイールは奥に回すと正なので、反転させる
+        wheel: report.get(3).map_or(0, |&wheel| (wheel as i8).saturating_neg()),
+        buttons: report[0] & 0x7,
+    })
+}
+
+impl ReportHandler for BootMouse {
+    fn handle(&mut self, report: &[u8]) {
+        if let Some(event) = parse_report(report) {
+            add_mouse_event(event);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! コントロール転送で送るリクエスト
+
+// bmRequestType
+pub const REQUEST_DEVICE_TO_HOST: u8 = 1 << 7;
+pub const REQUEST_TYPE_STANDARD: u8 = 0 << 5;
+pub const REQUEST_TYPE_CLASS: u8 = 1 << 5;
+pub const REQUEST_RECIPIENT_DEVICE: u8 = 0;
+pub const REQUEST_RECIPIENT_INTERFACE: u8 = 1;
+pub const REQUEST_RECIPIENT_ENDPOINT: u8 = 2;
+
+// 標準リクエスト
+pub const REQUEST_CLEAR_FEATURE: u8 = 1;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_GET_CONFIGURATION: u8 = 8;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+pub const REQUEST_SET_INTERFACE: u8 = 11;
+
+/// CLEAR_FEATUREでエンドポイントのHaltを解除する
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+
+/// セットアップステージで送る8バイト
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// データステージの長さ。0ならデータステージは無い
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// GET_DESCRIPTOR。`value`の上位バイトが種類、下位バイトが番号
+    pub fn get_descriptor(descriptor_type: u8, index: u8, length: u16) -> Self {
+        Self {
+            request_type: REQUEST_DEVICE_TO_HOST | REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_DEVICE,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8 | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_DEVICE,
+            request: REQUEST_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    /// エンドポイントのHaltを解除する
+    pub fn clear_halt(endpoint_address: u8) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_STANDARD | REQUEST_RECIPIENT_ENDPOINT,
+            request: REQUEST_CLEAR_FEATURE,
+            value: FEATURE_ENDPOINT_HALT,
+            index: endpoint_address as u16,
+            length: 0,
+        }
+    }
+
+    /// データステージがデバイスからホストへの向きか
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQUEST_DEVICE_TO_HOST != 0
+    }
+
+    /// Setup Stage TRBのパラメータにそのまま入れる値
+    pub fn to_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
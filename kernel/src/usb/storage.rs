@@ -0,0 +1,293 @@
+//! USBマスストレージクラス。Bulk-Only TransportでSCSIコマンドを送る
+
+pub mod bot;
+pub mod scsi;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use log::debug;
+use spin::Mutex;
+use crate::block::error::BlockError;
+use crate::block::{self, check_range, register, unregister, BlockDevice, Geometry};
+use crate::usb::descriptor::{Interface, TransferType};
+use crate::usb::device::UsbDevice;
+use crate::usb::driver::{Driver, InterfaceMatch};
+use crate::usb::storage::bot::{BulkOnly, CommandError, MAX_DATA_SIZE};
+use crate::usb::storage::scsi::{
+    Capacity, InquiryData, Sense, DEVICE_TYPE_DIRECT_ACCESS, INQUIRY_LENGTH, MODE_SENSE_LENGTH,
+    READ_CAPACITY_10_LENGTH, READ_CAPACITY_16_LENGTH, SENSE_LENGTH,
+};
+
+pub const CLASS_MASS_STORAGE: u8 = 8;
+/// SCSIのコマンドをそのまま送るサブクラス
+pub const SUBCLASS_SCSI: u8 = 6;
+pub const PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// TEST UNIT READYを試す回数。接続直後はUNIT ATTENTIONを返すデバイスが多い
+const READY_RETRIES: usize = 5;
+/// READ(10)とWRITE(10)で指定できるLBAの数
+const MAX_SECTORS_10: u64 = 1 << 32;
+
+/// Bulk-Onlyのマスストレージを、LUNごとに`sda`, `sdb`...として登録する
+pub static DRIVER: Driver = Driver {
+    name: "usb-storage",
+    matches: &[InterfaceMatch {
+        class: CLASS_MASS_STORAGE,
+        subclass: Some(SUBCLASS_SCSI),
+        protocol: Some(PROTOCOL_BULK_ONLY),
+    }],
+    probe,
+    disconnect: Some(disconnect),
+};
+
+/// 登録したディスクの番号(`sda`の`a`にあたる)と、そのLUNを持つデバイス
+static DISKS: Mutex<Vec<(usize, Arc<UsbDevice>)>> = Mutex::new(Vec::new());
+
+/// 使われていない最小の番号を取る。切断で空いた番号は、次に挿されたものが使う
+fn allocate_disk(device: &Arc<UsbDevice>) -> usize {
+    let mut disks = DISKS.lock();
+    let index = (0..).find(|index| disks.iter().all(|(used, _)| used != index)).unwrap();
+    disks.push((index, device.clone()));
+    index
+}
+
+/// ディスクの番号から名前を作る。`sdz`の次は`sdaa`になる
+pub fn disk_name(index: usize) -> String {
+    block::disk_name("sd", index)
+}
+
+fn probe(device: &Arc<UsbDevice>, interface: &Interface) -> bool {
+    let bulk = |is_in: bool| {
+        interface
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.transfer_type() == TransferType::Bulk && endpoint.is_in() == is_in)
+            .map(|endpoint| endpoint.address)
+    };
+    let (bulk_in, bulk_out) = match (bulk(true), bulk(false)) {
+        (Some(bulk_in), Some(bulk_out)) => (bulk_in, bulk_out),
+        _ => return false,
+    };
+    let transport = match BulkOnly::new(device.clone(), interface.descriptor.number, bulk_in, bulk_out) {
+        Ok(transport) => Arc::new(transport),
+        Err(err) => {
+            debug!("usb-storage: slot {}: {}", device.slot(), err);
+            return false;
+        }
+    };
+
+    let mut registered = false;
+    for lun in 0..=transport.max_lun() {
+        match MassStorage::new(transport.clone(), lun) {
+            Ok(disk) => {
+                let name = disk_name(allocate_disk(device));
+                debug!(
+                    "usb-storage: {}: {} {}: {} sectors x {} bytes",
+                    name,
+                    disk.inquiry.vendor,
+                    disk.inquiry.product,
+                    disk.geometry.sector_count,
+                    disk.geometry.sector_size
+                );
+                let disk = register(name.clone(), Arc::new(disk));
+                // 起動後に挿されたものもすぐに使えるようにする
+                if let Err(err) = crate::fs::mount_device(&name, disk) {
+                    debug!("usb-storage: {} is not mounted: {}", name, err);
+                }
+                registered = true;
+            }
+            Err(err) => debug!("usb-storage: slot {} lun {}: {}", device.slot(), lun, err),
+        }
+    }
+    registered
+}
+
+/// 切断されたデバイスのLUNを一覧から外し、マウントしていれば外す
+fn disconnect(device: &Arc<UsbDevice>) {
+    let removed: Vec<usize> = {
+        let mut disks = DISKS.lock();
+        let removed = disks
+            .iter()
+            .filter(|(_, disk)| Arc::ptr_eq(disk, device))
+            .map(|(index, _)| *index)
+            .collect();
+        disks.retain(|(_, disk)| !Arc::ptr_eq(disk, device));
+        removed
+    };
+    for index in removed {
+        for name in unregister(&disk_name(index)) {
+            // マウントしていなければ何もしない
+            let _ = crate::fs::unmount_device(&name);
+            debug!("usb-storage: {}: removed", name);
+        }
+    }
+}
+
+/// マスストレージの一つのLUN
+pub struct MassStorage {
+    transport: Arc<BulkOnly>,
+    lun: u8,
+    inquiry: InquiryData,
+    geometry: Geometry,
+}
+
+impl MassStorage {
+    pub fn new(transport: Arc<BulkOnly>, lun: u8) -> Result<Self, BlockError> {
+        let inquiry = transport
+            .command_in(lun, &scsi::inquiry(), INQUIRY_LENGTH, scsi::parse_inquiry)?
+            .ok_or(BlockError::Io)?;
+        if inquiry.device_type != DEVICE_TYPE_DIRECT_ACCESS {
+            return Err(BlockError::NoDevice);
+        }
+        wait_ready(&transport, lun)?;
+
+        let capacity = read_capacity(&transport, lun)?;
+        let sector_size = capacity.block_size as usize;
+        if sector_size == 0 || sector_size > MAX_DATA_SIZE {
+            return Err(BlockError::NoDevice);
+        }
+        let sector_count = capacity.last_lba + 1;
+        if sector_count > MAX_SECTORS_10 {
+            debug!("usb-storage: lun {}: only the first {} sectors are addressable", lun, MAX_SECTORS_10);
+        }
+        // MODE SENSEに対応していないデバイスは、書き込めるものとして扱う
+        let read_only = transport
+            .command_in(lun, &scsi::mode_sense(), MODE_SENSE_LENGTH, scsi::parse_write_protected)
+            .ok()
+            .flatten()
+            .unwrap_or(false);
+
+        Ok(Self {
+            transport,
+            lun,
+            inquiry,
+            geometry: Geometry {
+                sector_size,
+                sector_count: sector_count.min(MAX_SECTORS_10),
+                read_only,
+            },
+        })
+    }
+
+    pub fn lun(&self) -> u8 {
+        self.lun
+    }
+
+    pub fn inquiry(&self) -> &InquiryData {
+        &self.inquiry
+    }
+
+    pub fn device(&self) -> &Arc<UsbDevice> {
+        self.transport.device()
+    }
+
+    /// コマンドが失敗した理由をログに残してから、ブロックデバイスのエラーに直す
+    fn error(&self, err: CommandError) -> BlockError {
+        if err == CommandError::Failed {
+            if let Some(sense) = request_sense(&self.transport, self.lun) {
+                debug!("usb-storage: lun {}: sense {:?}", self.lun, sense);
+            }
+        }
+        err.into()
+    }
+
+    /// 一度に転送するバイト数。セクタの倍数にする
+    fn chunk_size(&self) -> usize {
+        MAX_DATA_SIZE / self.geometry.sector_size * self.geometry.sector_size
+    }
+}
+
+fn request_sense(transport: &BulkOnly, lun: u8) -> Option<Sense> {
+    transport
+        .command_in(lun, &scsi::request_sense(), SENSE_LENGTH, scsi::parse_sense)
+        .ok()
+        .flatten()
+}
+
+/// メディアが読み書きできるようになるまで待つ。失敗するたびにセンスデータを読んで状態をクリアする
+fn wait_ready(transport: &BulkOnly, lun: u8) -> Result<(), BlockError> {
+    for _ in 0..READY_RETRIES {
+        match transport.command_out(lun, &scsi::test_unit_ready(), &[]) {
+            Ok(()) => return Ok(()),
+            Err(CommandError::Failed) => {
+                request_sense(transport, lun);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(BlockError::NoDevice)
+}
+
+/// 2TiBを超えるデバイスはREAD CAPACITY(10)で最後のLBAを0xFFFFFFFFと返すので、(16)で読み直す
+fn read_capacity(transport: &BulkOnly, lun: u8) -> Result<Capacity, BlockError> {
+    let capacity = transport
+        .command_in(lun, &scsi::read_capacity_10(), READ_CAPACITY_10_LENGTH, scsi::parse_read_capacity_10)?
+        .ok_or(BlockError::Io)?;
+    if capacity.last_lba != u32::MAX as u64 {
+        return Ok(capacity);
+    }
+    transport
+        .command_in(lun, &scsi::read_capacity_16(), READ_CAPACITY_16_LENGTH, scsi::parse_read_capacity_16)?
+        .ok_or(BlockError::Io)
+}
+
+impl From<CommandError> for BlockError {
+    fn from(value: CommandError) -> Self {
+        match value {
+            CommandError::Transport(err) => err.into(),
+            CommandError::Failed | CommandError::InvalidStatus => BlockError::Io,
+        }
+    }
+}
+
+impl BlockDevice for MassStorage {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let sector_size = self.geometry.sector_size;
+        let chunk_size = self.chunk_size();
+        for (index, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let sector = lba + (index * chunk_size / sector_size) as u64;
+            let cdb = scsi::read_10(sector as u32, (chunk.len() / sector_size) as u16);
+            let length = chunk.len();
+            let complete = self
+                .transport
+                .command_in(self.lun, &cdb, length, |data| {
+                    chunk[..data.len()].copy_from_slice(data);
+                    data.len() == length
+                })
+                .map_err(|err| self.error(err))?;
+            if !complete {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.geometry.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        check_range(self, lba, buf.len())?;
+        let sector_size = self.geometry.sector_size;
+        let chunk_size = self.chunk_size();
+        for (index, chunk) in buf.chunks(chunk_size).enumerate() {
+            let sector = lba + (index * chunk_size / sector_size) as u64;
+            let cdb = scsi::write_10(sector as u32, (chunk.len() / sector_size) as u16);
+            self.transport.command_out(self.lun, &cdb, chunk).map_err(|err| self.error(err))?;
+        }
+        Ok(())
+    }
+
+    /// キャッシュを持たないデバイスはSYNCHRONIZE CACHEを拒むので、そのときは何もしない
+    fn flush(&self) -> Result<(), BlockError> {
+        match self.transport.command_out(self.lun, &scsi::synchronize_cache(), &[]) {
+            Ok(()) | Err(CommandError::Failed) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Bulk-Only Transport。コマンドをCBWで送り、データを転送し、結果をCSWで受け取る
+
+use alloc::sync::Arc;
+use spin::Mutex;
+use crate::memory::dma::DmaBuffer;
+use crate::usb::device::UsbDevice;
+use crate::usb::error::UsbError;
+use crate::usb::request::{
+    SetupPacket, REQUEST_DEVICE_TO_HOST, REQUEST_RECIPIENT_INTERFACE, REQUEST_TYPE_CLASS,
+};
+use crate::usb::xhci::trb::COMPLETION_STALL;
+
+// クラスのリクエスト
+const REQUEST_RESET: u8 = 0xFF;
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+
+pub const CBW_SIGNATURE: u32 = 0x4342_5355;
+pub const CSW_SIGNATURE: u32 = 0x5342_5355;
+pub const CBW_SIZE: usize = 31;
+pub const CSW_SIZE: usize = 13;
+/// CBWのフラグ。データがデバイスからホストへ流れる
+const CBW_DIRECTION_IN: u8 = 0x80;
+
+pub const CSW_STATUS_PASSED: u8 = 0;
+pub const CSW_STATUS_FAILED: u8 = 1;
+pub const CSW_STATUS_PHASE_ERROR: u8 = 2;
+
+/// 一つのコマンドで転送できる最大のバイト数
+pub const MAX_DATA_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// コマンドの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    /// 転送そのものが失敗した
+    Transport(UsbError),
+    /// デバイスがコマンドを実行できなかった。REQUEST SENSEで理由が分かる
+    Failed,
+    /// CSWが壊れているか、別のコマンドのものだった
+    InvalidStatus,
+}
+
+/// Command Status Wrapper
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandStatus {
+    pub tag: u32,
+    /// 要求した長さのうち、転送されなかったバイト数
+    pub residue: u32,
+    pub status: u8,
+}
+
+/// Command Block Wrapperを組み立てる
+pub fn command_block(tag: u32, lun: u8, cdb: &[u8], direction: Direction, length: usize) -> [u8; CBW_SIZE] {
+    assert!(!cdb.is_empty() && cdb.len() <= 16, "invalid command block length: {}", cdb.len());
+    let mut cbw = [0; CBW_SIZE];
+    cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+    cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+    cbw[8..12].copy_from_slice(&(length as u32).to_le_bytes());
+    cbw[12] = if direction == Direction::In { CBW_DIRECTION_IN } else { 0 };
+    cbw[13] = lun & 0xF;
+    cbw[14] = cdb.len() as u8;
+    cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+    cbw
+}
+
+impl CommandStatus {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let read32 = |offset: usize| u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]);
+        if bytes.len() < CSW_SIZE || read32(0) != CSW_SIGNATURE {
+            return None;
+        }
+        Some(Self { tag: read32(4), residue: read32(8), status: bytes[12] })
+    }
+}
+
+impl From<UsbError> for CommandError {
+    fn from(value: UsbError) -> Self {
+        CommandError::Transport(value)
+    }
+}
+
+struct State {
+    tag: u32,
+    /// CBWとCSWを置く
+    wrapper: DmaBuffer,
+    data: DmaBuffer,
+}
+
+/// 一つのインターフェースのバルクエンドポイントの組。同じインターフェースのLUNで共有する
+pub struct BulkOnly {
+    device: Arc<UsbDevice>,
+    interface: u8,
+    bulk_in: u8,
+    bulk_out: u8,
+    state: Mutex<State>,
+}
+
+impl BulkOnly {
+    pub fn new(device: Arc<UsbDevice>, interface: u8, bulk_in: u8, bulk_out: u8) -> Result<Self, UsbError> {
+        Ok(Self {
+            device,
+            interface,
+            bulk_in,
+            bulk_out,
+            state: Mutex::new(State {
+                tag: 0,
+                wrapper: DmaBuffer::new(CBW_SIZE).ok_or(UsbError::NoMemory)?,
+                data: DmaBuffer::new(MAX_DATA_SIZE).ok_or(UsbError::NoMemory)?,
+            }),
+        })
+    }
+
+    pub fn device(&self) -> &Arc<UsbDevice> {
+        &self.device
+    }
+
+    /// 最大のLUN。LUNが一つしか無いデバイスはSTALLを返すことがあるので、そのときは0とする
+    pub fn max_lun(&self) -> u8 {
+        let setup = SetupPacket {
+            request_type: REQUEST_DEVICE_TO_HOST | REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+            request: REQUEST_GET_MAX_LUN,
+            value: 0,
+            index: self.interface as u16,
+            length: 1,
+        };
+        match self.device.control_in(setup) {
+            Ok(data) => data.first().map_or(0, |&lun| lun & 0xF),
+            Err(_) => 0,
+        }
+    }
+
+    /// データがINのコマンドを実行し、受け取ったデータを`f`に渡す
+    pub fn command_in<T>(
+        &self,
+        lun: u8,
+        cdb: &[u8],
+        length: usize,
+        f: impl FnOnce(&[u8]) -> T,
+    ) -> Result<T, CommandError> {
+        let mut state = self.state.lock();
+        let transferred = self.execute(&mut state, lun, cdb, Direction::In, length)?;
+        Ok(f(&state.data.as_slice()[..transferred]))
+    }
+
+    /// データがOUT、または無いコマンドを実行する
+    pub fn command_out(&self, lun: u8, cdb: &[u8], data: &[u8]) -> Result<(), CommandError> {
+        let mut state = self.state.lock();
+        state.data.as_mut_slice()[..data.len()].copy_from_slice(data);
+        self.execute(&mut state, lun, cdb, Direction::Out, data.len()).map(|_| ())
+    }
+
+    fn execute(
+        &self,
+        state: &mut State,
+        lun: u8,
+        cdb: &[u8],
+        direction: Direction,
+        length: usize,
+    ) -> Result<usize, CommandError> {
+        assert!(length <= MAX_DATA_SIZE, "command data does not fit in the buffer");
+        state.tag = state.tag.wrapping_add(1);
+        let tag = state.tag;
+        let cbw = command_block(tag, lun, cdb, direction, length);
+        state.wrapper.as_mut_slice()[..CBW_SIZE].copy_from_slice(&cbw);
+        if let Err(err) = self.device.transfer(self.bulk_out, &mut state.wrapper, CBW_SIZE) {
+            self.reset_recovery();
+            return Err(err.into());
+        }
+
+        let mut transferred = 0;
+        if length > 0 {
+            let endpoint = if direction == Direction::In { self.bulk_in } else { self.bulk_out };
+            match self.device.transfer(endpoint, &mut state.data, length) {
+                Ok(count) => transferred = count,
+                // STALLならHaltは解除されているので、そのままCSWを読む
+                Err(UsbError::Completion(COMPLETION_STALL)) => {}
+                Err(err) => {
+                    self.reset_recovery();
+                    return Err(err.into());
+                }
+            }
+        }
+
+        let status = match self.read_status(state) {
+            Ok(Some(status)) if status.tag == tag => status,
+            Ok(_) => {
+                self.reset_recovery();
+                return Err(CommandError::InvalidStatus);
+            }
+            Err(err) => {
+                self.reset_recovery();
+                return Err(err.into());
+            }
+        };
+        match status.status {
+            CSW_STATUS_PASSED => Ok(transferred),
+            CSW_STATUS_FAILED => Err(CommandError::Failed),
+            _ => {
+                self.reset_recovery();
+                Err(CommandError::Failed)
+            }
+        }
+    }
+
+    /// CSWを読む。一度STALLしたら、Haltを解除してもう一度だけ読む
+    fn read_status(&self, state: &mut State) -> Result<Option<CommandStatus>, UsbError> {
+        let mut result = self.device.transfer(self.bulk_in, &mut state.wrapper, CSW_SIZE);
+        if result == Err(UsbError::Completion(COMPLETION_STALL)) {
+            result = self.device.transfer(self.bulk_in, &mut state.wrapper, CSW_SIZE);
+        }
+        let length = result?;
+        Ok(CommandStatus::parse(&state.wrapper.as_slice()[..length]))
+    }
+
+    /// 手順が崩れたときに、インターフェースをリセットして両方のエンドポイントのHaltを解除する
+    fn reset_recovery(&self) {
+        let reset = SetupPacket {
+            request_type: REQUEST_TYPE_CLASS | REQUEST_RECIPIENT_INTERFACE,
+            request: REQUEST_RESET,
+            value: 0,
+            index: self.interface as u16,
+            length: 0,
+        };
+        let _ = self.device.control_out(reset, &[]);
+        let _ = self.device.control_out(SetupPacket::clear_halt(self.bulk_in), &[]);
+        let _ = self.device.control_out(SetupPacket::clear_halt(self.bulk_out), &[]);
+    }
+}
@@ -0,0 +1,147 @@
+//! USBメモリが受け付けるSCSIコマンド。複数バイトの値はビッグエンディアンで書く
+
+use alloc::string::String;
+
+// 操作コード
+pub const TEST_UNIT_READY: u8 = 0x00;
+pub const REQUEST_SENSE: u8 = 0x03;
+pub const INQUIRY: u8 = 0x12;
+pub const MODE_SENSE_6: u8 = 0x1A;
+pub const READ_CAPACITY_10: u8 = 0x25;
+pub const READ_10: u8 = 0x28;
+pub const WRITE_10: u8 = 0x2A;
+pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+/// SERVICE ACTION IN(16)。サービスアクション0x10がREAD CAPACITY(16)
+pub const SERVICE_ACTION_IN_16: u8 = 0x9E;
+const SERVICE_ACTION_READ_CAPACITY_16: u8 = 0x10;
+
+/// INQUIRYで返される周辺機器の種類。ブロック単位で読み書きできるデバイス
+pub const DEVICE_TYPE_DIRECT_ACCESS: u8 = 0x00;
+
+pub const INQUIRY_LENGTH: usize = 36;
+pub const SENSE_LENGTH: usize = 18;
+pub const READ_CAPACITY_10_LENGTH: usize = 8;
+pub const READ_CAPACITY_16_LENGTH: usize = 32;
+pub const MODE_SENSE_LENGTH: usize = 192;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InquiryData {
+    pub device_type: u8,
+    pub removable: bool,
+    pub vendor: String,
+    pub product: String,
+}
+
+/// REQUEST SENSEで返される、直前のコマンドが失敗した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sense {
+    pub key: u8,
+    /// Additional Sense Code
+    pub code: u8,
+    pub qualifier: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capacity {
+    pub last_lba: u64,
+    pub block_size: u32,
+}
+
+pub fn test_unit_ready() -> [u8; 6] {
+    [TEST_UNIT_READY, 0, 0, 0, 0, 0]
+}
+
+pub fn request_sense() -> [u8; 6] {
+    [REQUEST_SENSE, 0, 0, 0, SENSE_LENGTH as u8, 0]
+}
+
+pub fn inquiry() -> [u8; 6] {
+    [INQUIRY, 0, 0, 0, INQUIRY_LENGTH as u8, 0]
+}
+
+/// すべてのモードページを要求する。書き込み禁止かどうかはヘッダで分かる
+pub fn mode_sense() -> [u8; 6] {
+    [MODE_SENSE_6, 0, 0x3F, 0, MODE_SENSE_LENGTH as u8, 0]
+}
+
+pub fn read_capacity_10() -> [u8; 10] {
+    [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+pub fn read_capacity_16() -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = SERVICE_ACTION_IN_16;
+    cdb[1] = SERVICE_ACTION_READ_CAPACITY_16;
+    cdb[10..14].copy_from_slice(&(READ_CAPACITY_16_LENGTH as u32).to_be_bytes());
+    cdb
+}
+
+pub fn read_10(lba: u32, blocks: u16) -> [u8; 10] {
+    transfer_10(READ_10, lba, blocks)
+}
+
+pub fn write_10(lba: u32, blocks: u16) -> [u8; 10] {
+    transfer_10(WRITE_10, lba, blocks)
+}
+
+/// 範囲を指定しなければ、デバイス全体のキャッシュを書き出す
+pub fn synchronize_cache() -> [u8; 10] {
+    [SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+fn transfer_10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0; 10];
+    cdb[0] = opcode;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+pub fn parse_inquiry(data: &[u8]) -> Option<InquiryData> {
+    if data.len() < INQUIRY_LENGTH {
+        return None;
+    }
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).trim_end().into();
+    Some(InquiryData {
+        device_type: data[0] & 0x1F,
+        removable: data[1] & 0x80 != 0,
+        vendor: text(&data[8..16]),
+        product: text(&data[16..32]),
+    })
+}
+
+/// 固定形式のセンスデータ
+pub fn parse_sense(data: &[u8]) -> Option<Sense> {
+    if data.len() < 14 || data[0] & 0x7E != 0x70 {
+        return None;
+    }
+    Some(Sense { key: data[2] & 0xF, code: data[12], qualifier: data[13] })
+}
+
+/// 最後のLBAが0xFFFFFFFFなら、READ CAPACITY(16)で読み直さなければならない
+pub fn parse_read_capacity_10(data: &[u8]) -> Option<Capacity> {
+    if data.len() < READ_CAPACITY_10_LENGTH {
+        return None;
+    }
+    Some(Capacity {
+        last_lba: u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as u64,
+        block_size: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+    })
+}
+
+pub fn parse_read_capacity_16(data: &[u8]) -> Option<Capacity> {
+    if data.len() < 12 {
+        return None;
+    }
+    let mut last_lba = [0; 8];
+    last_lba.copy_from_slice(&data[..8]);
+    Some(Capacity {
+        last_lba: u64::from_be_bytes(last_lba),
+        block_size: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+    })
+}
+
+/// MODE SENSE(6)のヘッダのWPビット
+pub fn parse_write_protected(data: &[u8]) -> Option<bool> {
+    data.get(2).map(|&parameter| parameter & 0x80 != 0)
+}
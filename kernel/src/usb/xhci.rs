@@ -0,0 +1,898 @@
+pub mod context;
+pub mod ring;
+pub mod trb;
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::debug;
+use spin::Mutex;
+use crate::apic;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::mmio::{self, MmioRegion};
+use crate::usb::descriptor::{EndpointDescriptor, TransferType};
+use crate::usb::error::UsbError;
+use crate::usb::request::SetupPacket;
+use crate::usb::{register, wait_until, wake};
+use crate::usb::xhci::context::{
+    EndpointContext, InputContext, SlotContext, ENDPOINT_BULK_IN, ENDPOINT_BULK_OUT, ENDPOINT_CONTROL,
+    ENDPOINT_INTERRUPT_IN, ENDPOINT_INTERRUPT_OUT, ENDPOINT_ISOCH_IN, ENDPOINT_ISOCH_OUT,
+};
+use crate::usb::xhci::ring::{EventRing, Ring};
+use crate::usb::xhci::trb::{
+    Trb, COMPLETION_SHORT_PACKET, COMPLETION_STALL, COMPLETION_SUCCESS, CONTROL_DIRECTION_IN, CONTROL_IDT,
+    CONTROL_IOC, CONTROL_ISP, TYPE_ADDRESS_DEVICE, TYPE_COMMAND_COMPLETION, TYPE_CONFIGURE_ENDPOINT,
+    TYPE_DATA_STAGE, TYPE_DISABLE_SLOT, TYPE_ENABLE_SLOT, TYPE_EVALUATE_CONTEXT, TYPE_HOST_CONTROLLER_EVENT,
+    TYPE_NORMAL, TYPE_NO_OP_COMMAND, TYPE_PORT_STATUS_CHANGE, TYPE_RESET_ENDPOINT, TYPE_SETUP_STAGE,
+    TYPE_SET_TR_DEQUEUE_POINTER, TYPE_STATUS_STAGE, TYPE_TRANSFER_EVENT,
+};
+use pci::device::Device;
+use pci::register::Command;
+use pci::{DeviceMatch, Driver};
+
+// ケーパビリティレジスタ
+const CAP_LENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+/// 64バイトのコンテキストを使う
+const HCCPARAMS1_CSZ: u32 = 1 << 2;
+
+// オペレーショナルレジスタ。ケーパビリティレジスタの後ろにある
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_PAGESIZE: usize = 0x08;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC: usize = 0x400;
+const PORT_REGISTER_SIZE: usize = 0x10;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBCMD_INTERRUPT_ENABLE: u32 = 1 << 2;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_HOST_SYSTEM_ERROR: u32 = 1 << 2;
+const USBSTS_CONTROLLER_NOT_READY: u32 = 1 << 11;
+const USBSTS_HOST_CONTROLLER_ERROR: u32 = 1 << 12;
+
+// 割り込み0のレジスタ。ランタイムレジスタの0x20バイト目から並ぶ
+const IR0: usize = 0x20;
+const IR_IMAN: usize = 0x00;
+const IR_IMOD: usize = 0x04;
+const IR_ERSTSZ: usize = 0x08;
+const IR_ERSTBA: usize = 0x10;
+const IR_ERDP: usize = 0x18;
+/// 1を書くとクリアされる割り込み要求のビット
+const IMAN_PENDING: u32 = 1 << 0;
+const IMAN_ENABLE: u32 = 1 << 1;
+/// 割り込みの最小間隔。250ns単位なので1ms
+const IMOD_INTERVAL: u32 = 4000;
+/// 書き込むとイベントハンドラの処理中フラグがクリアされる
+const ERDP_HANDLER_BUSY: u64 = 1 << 3;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_POWER: u32 = 1 << 9;
+/// 1を書くとクリアされる変化のビット
+const PORTSC_CHANGES: u32 = 0x7F << 17;
+
+// 拡張ケーパビリティ
+const XECP_LEGACY_SUPPORT: u8 = 1;
+const XECP_SUPPORTED_PROTOCOL: u8 = 2;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+/// USBLEGCTLSTSのうち、SMIを起こす許可のビット
+const LEGACY_SMI_ENABLES: u32 = 0xE011;
+
+const PAGE_SIZE: usize = 4096;
+const TIMEOUT_SPINS: usize = 10_000_000;
+
+/// エンドポイント0のDCI
+pub const CONTROL_ENDPOINT_DCI: u8 = 1;
+/// 一つのTRBで転送できる最大のバイト数。データは64KiBの境界をまたいではいけない
+pub const MAX_TRANSFER_SIZE: usize = 0x10000;
+
+/// PCIバス上のxHCIコントローラを初期化し、接続されているポートをリセットする
+pub static DRIVER: Driver = Driver {
+    name: "xhci",
+    matches: &[DeviceMatch::Class { base: 0x0C, sub: 0x03, interface: Some(0x30) }],
+    probe,
+};
+
+static CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &Device) -> bool {
+    let mut controller = match XhciController::new(device) {
+        Ok(controller) => controller,
+        Err(err) => {
+            debug!("xhci: {}.{}.{}: {}", device.bus, device.device, device.function, err);
+            return false;
+        }
+    };
+    let index = CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed);
+    debug!("xhci{}: {} slots, {} ports", index, controller.max_slots, controller.ports.len());
+    if let Err(err) = controller.scan_ports() {
+        debug!("xhci{}: failed to reset ports: {}", index, err);
+    }
+    // 割り込みが使えなければ、`usb::poll`を呼んだときにだけイベントを処理する
+    match apic::msi::enable(device, &[interrupt_handler]) {
+        Ok(_) => controller.enable_interrupts(),
+        Err(err) => debug!("xhci{}: interrupts are not available: {}", index, err),
+    }
+    register(device, Arc::new(Mutex::new(controller)));
+    true
+}
+
+/// イベントの処理はコントローラのロックが要るので、USBのタスクを起こすだけにする
+fn interrupt_handler() {
+    wake();
+}
+
+/// ポートの速度。PORTSCのPort Speedの値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Full,
+    Low,
+    High,
+    Super,
+    SuperPlus,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    Disconnected,
+    /// USB2のポートで、リセットが終わるのを待っている
+    Resetting,
+    /// リセットが終わり、デバイスと通信できる
+    Enabled(Speed),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port {
+    /// 1から始まるポート番号
+    pub number: u8,
+    /// USBのメジャーバージョン。USB3のポートはリセットしなくても、リンクが確立すれば有効になる
+    pub major_version: u8,
+    pub state: PortState,
+}
+
+/// Enable Slotで割り当てたデバイスの状態
+struct Slot {
+    port: u8,
+    speed: Speed,
+    input: InputContext,
+    /// コントローラが書き換えるDevice Context。DCBAAから指される
+    _output: DmaBuffer,
+    /// DCIごとの転送リング
+    rings: BTreeMap<u8, Ring>,
+}
+
+pub struct XhciController {
+    registers: MmioRegion,
+    operational: usize,
+    runtime: usize,
+    doorbells: usize,
+    max_slots: u8,
+    /// デバイスコンテキストの一つのエントリの大きさ
+    context_size: usize,
+    /// Device Context Base Address Array。スロットIDごとのデバイスコンテキストのアドレス
+    dcbaa: DmaBuffer,
+    _scratchpad: Vec<DmaBuffer>,
+    command_ring: Ring,
+    event_ring: EventRing,
+    ports: Vec<Port>,
+    slots: BTreeMap<u8, Slot>,
+    /// 完了を待っている転送。最後のTRBのアドレスと、要求したバイト数
+    pending: BTreeMap<u64, usize>,
+    /// 完了した転送と、実際に転送されたバイト数
+    completions: BTreeMap<u64, Result<usize, UsbError>>,
+    /// STALLで止まり、リセットが必要な(スロット, DCI)
+    halted: Vec<(u8, u8)>,
+    /// 新しく有効になったポートと、切断されたポート。USBのコアが取り出してデバイスを列挙する
+    attached_ports: Vec<u8>,
+    detached_ports: Vec<u8>,
+}
+
+impl XhciController {
+    pub fn new(device: &Device) -> Result<Self, UsbError> {
+        device.enable_command(Command::MEMORY_SPACE | Command::BUS_MASTER);
+        let registers = mmio::map_bar(device, 0)?;
+
+        let operational = registers.read8(CAP_LENGTH) as usize;
+        let runtime = (registers.read32(CAP_RTSOFF) & !0x1F) as usize;
+        let doorbells = (registers.read32(CAP_DBOFF) & !0x3) as usize;
+        let hcsparams1 = registers.read32(CAP_HCSPARAMS1);
+        let max_slots = hcsparams1 as u8;
+        let max_ports = (hcsparams1 >> 24) as u8;
+        let context_size = if registers.read32(CAP_HCCPARAMS1) & HCCPARAMS1_CSZ != 0 { 64 } else { 32 };
+        if registers.read32(operational + OP_PAGESIZE) & 1 == 0 {
+            debug!("xhci: 4KiB pages are not supported");
+            return Err(UsbError::NoDevice);
+        }
+
+        take_ownership(&registers);
+
+        // リセットの前にコントローラを止めておく
+        let op = |offset: usize| operational + offset;
+        registers.write32(op(OP_USBCMD), registers.read32(op(OP_USBCMD)) & !USBCMD_RUN);
+        wait_until(TIMEOUT_SPINS, || registers.read32(op(OP_USBSTS)) & USBSTS_HALTED != 0)?;
+        registers.write32(op(OP_USBCMD), registers.read32(op(OP_USBCMD)) | USBCMD_RESET);
+        wait_until(TIMEOUT_SPINS, || registers.read32(op(OP_USBCMD)) & USBCMD_RESET == 0)?;
+        // Controller Not Readyが消えるまで、オペレーショナルレジスタに書いてはいけない
+        wait_until(TIMEOUT_SPINS, || registers.read32(op(OP_USBSTS)) & USBSTS_CONTROLLER_NOT_READY == 0)?;
+
+        registers.write32(op(OP_CONFIG), max_slots as u32);
+
+        let mut dcbaa = DmaBuffer::new((max_slots as usize + 1) * 8).ok_or(UsbError::NoMemory)?;
+        let scratchpad = allocate_scratchpad(&registers, &mut dcbaa)?;
+        registers.write64(op(OP_DCBAAP), dcbaa.phys_addr());
+
+        let command_ring = Ring::new()?;
+        registers.write64(op(OP_CRCR), command_ring.phys_addr() | command_ring.cycle() as u64);
+
+        // 割り込み0のイベントリング。割り込みは`enable_interrupts`で有効にする。ERSTBAは最後に書く
+        let event_ring = EventRing::new()?;
+        let interrupter = runtime + IR0;
+        registers.write32(interrupter + IR_ERSTSZ, 1);
+        registers.write64(interrupter + IR_ERDP, event_ring.dequeue_pointer());
+        registers.write64(interrupter + IR_ERSTBA, event_ring.table_phys_addr());
+
+        let ports = read_ports(&registers, operational, max_ports);
+
+        registers.write32(op(OP_USBCMD), registers.read32(op(OP_USBCMD)) | USBCMD_RUN);
+        wait_until(TIMEOUT_SPINS, || registers.read32(op(OP_USBSTS)) & USBSTS_HALTED == 0)?;
+
+        Ok(Self {
+            registers,
+            operational,
+            runtime,
+            doorbells,
+            max_slots,
+            context_size,
+            dcbaa,
+            _scratchpad: scratchpad,
+            command_ring,
+            event_ring,
+            ports,
+            slots: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            completions: BTreeMap::new(),
+            halted: Vec::new(),
+            attached_ports: Vec::new(),
+            detached_ports: Vec::new(),
+        })
+    }
+
+    /// イベントリングにイベントが積まれたら割り込みを起こさせる
+    pub fn enable_interrupts(&mut self) {
+        let interrupter = self.runtime + IR0;
+        self.registers.write32(interrupter + IR_IMOD, IMOD_INTERVAL);
+        self.registers.write32(interrupter + IR_IMAN, IMAN_ENABLE | IMAN_PENDING);
+        let usbcmd = self.operational + OP_USBCMD;
+        self.registers.write32(usbcmd, self.registers.read32(usbcmd) | USBCMD_INTERRUPT_ENABLE);
+    }
+
+    pub fn ports(&self) -> &[Port] {
+        &self.ports
+    }
+
+    pub fn port(&self, number: u8) -> Option<Port> {
+        self.ports.iter().find(|port| port.number == number).copied()
+    }
+
+    /// 前に呼んでから有効になったポートの番号
+    pub fn take_attached_ports(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.attached_ports)
+    }
+
+    /// 前に呼んでから切断されたポートの番号
+    pub fn take_detached_ports(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.detached_ports)
+    }
+
+    pub fn max_slots(&self) -> u8 {
+        self.max_slots
+    }
+
+    pub fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    /// スロット`slot`のデバイスコンテキストの物理アドレスをDCBAAに書く
+    pub fn set_device_context(&mut self, slot: u8, address: u64) {
+        self.dcbaa.write::<u64>(slot as usize * 8, address);
+    }
+
+    /// 何もしないコマンドを発行する。コマンドリングとイベントリングが動いているかを確かめられる
+    pub fn no_op(&mut self) -> Result<(), UsbError> {
+        self.execute_command(Trb::new(TYPE_NO_OP_COMMAND)).map(|_| ())
+    }
+
+    /// デバイスに使うスロットを割り当てる
+    pub fn enable_slot(&mut self) -> Result<u8, UsbError> {
+        let completion = self.execute_command(Trb::new(TYPE_ENABLE_SLOT))?;
+        Ok(completion.slot_id())
+    }
+
+    /// スロットを解放する。切断されたデバイスや、列挙に失敗したデバイスに使う
+    pub fn disable_slot(&mut self, slot: u8) -> Result<(), UsbError> {
+        let result = self.execute_command(Trb::new(TYPE_DISABLE_SLOT).with_endpoint(slot, 0));
+        self.slots.remove(&slot);
+        self.set_device_context(slot, 0);
+        result.map(|_| ())
+    }
+
+    /// エンドポイント0の転送リングを用意し、デバイスにアドレスを割り当てる
+    pub fn address_device(&mut self, slot: u8, port: u8, speed: Speed) -> Result<(), UsbError> {
+        let mut input = InputContext::new(self.context_size)?;
+        let output = DmaBuffer::new(self.context_size * 32).ok_or(UsbError::NoMemory)?;
+        let ring = Ring::new()?;
+        input.set_add_flags(1 << 0 | 1 << CONTROL_ENDPOINT_DCI);
+        input.set_slot(&SlotContext {
+            speed: speed.id(),
+            context_entries: CONTROL_ENDPOINT_DCI,
+            root_hub_port: port,
+            ..SlotContext::default()
+        });
+        input.set_endpoint(CONTROL_ENDPOINT_DCI, &control_endpoint(&ring, speed.default_max_packet_size()));
+
+        let mut command = Trb::new(TYPE_ADDRESS_DEVICE).with_endpoint(slot, 0);
+        command.parameter = input.phys_addr();
+        self.set_device_context(slot, output.phys_addr());
+        let mut rings = BTreeMap::new();
+        rings.insert(CONTROL_ENDPOINT_DCI, ring);
+        self.slots.insert(slot, Slot { port, speed, input, _output: output, rings });
+        self.execute_command(command).map(|_| ())
+    }
+
+    /// デバイスディスクリプタで分かったエンドポイント0の最大パケットサイズをコントローラに知らせる
+    pub fn set_max_packet_size0(&mut self, slot: u8, max_packet_size: u16) -> Result<(), UsbError> {
+        let state = self.slots.get_mut(&slot).ok_or(UsbError::NoDevice)?;
+        let context = control_endpoint(&state.rings[&CONTROL_ENDPOINT_DCI], max_packet_size);
+        state.input.set_add_flags(1 << CONTROL_ENDPOINT_DCI);
+        state.input.set_endpoint(CONTROL_ENDPOINT_DCI, &context);
+        let mut command = Trb::new(TYPE_EVALUATE_CONTEXT).with_endpoint(slot, 0);
+        command.parameter = state.input.phys_addr();
+        self.execute_command(command).map(|_| ())
+    }
+
+    /// コンフィギュレーションのエンドポイントに転送リングを用意し、コントローラに設定させる
+    pub fn configure_endpoints(&mut self, slot: u8, endpoints: &[EndpointDescriptor]) -> Result<(), UsbError> {
+        let state = self.slots.get_mut(&slot).ok_or(UsbError::NoDevice)?;
+        let mut flags = 1 << 0;
+        let mut context_entries = CONTROL_ENDPOINT_DCI;
+        for endpoint in endpoints {
+            if endpoint.transfer_type() == TransferType::Control {
+                continue;
+            }
+            let dci = endpoint_dci(endpoint.address);
+            let ring = Ring::new()?;
+            state.input.set_endpoint(dci, &endpoint_context(endpoint, state.speed, &ring));
+            state.rings.insert(dci, ring);
+            flags |= 1 << dci;
+            context_entries = context_entries.max(dci);
+        }
+        state.input.set_add_flags(flags);
+        state.input.set_slot(&SlotContext {
+            speed: state.speed.id(),
+            context_entries,
+            root_hub_port: state.port,
+            ..SlotContext::default()
+        });
+        let mut command = Trb::new(TYPE_CONFIGURE_ENDPOINT).with_endpoint(slot, 0);
+        command.parameter = state.input.phys_addr();
+        self.execute_command(command).map(|_| ())
+    }
+
+    /// エンドポイント0でコントロール転送を行い、データステージで転送されたバイト数を返す
+    ///
+    /// データステージの長さは`setup.length`で、`buffer`はその長さ以上でなければならない
+    pub fn control_transfer(
+        &mut self,
+        slot: u8,
+        setup: SetupPacket,
+        buffer: Option<&mut DmaBuffer>,
+    ) -> Result<usize, UsbError> {
+        let length = setup.length as usize;
+        let data = match buffer {
+            Some(buffer) if length > 0 => {
+                assert!(length <= buffer.size(), "control transfer does not fit in the buffer");
+                Some(buffer.phys_addr())
+            }
+            _ => None,
+        };
+        let direction_in = setup.is_in();
+        let ring = self.transfer_ring(slot, CONTROL_ENDPOINT_DCI)?;
+
+        // Transfer Type。0がデータステージ無し、2がOUT、3がIN
+        let transfer_type = match (data, direction_in) {
+            (None, _) => 0,
+            (Some(_), false) => 2,
+            (Some(_), true) => 3,
+        };
+        let mut setup_stage = Trb::new(TYPE_SETUP_STAGE);
+        setup_stage.parameter = setup.to_u64();
+        setup_stage.status = 8;
+        setup_stage.control |= CONTROL_IDT | transfer_type << 16;
+        ring.push(setup_stage);
+
+        let data_address = data.map(|phys| {
+            let mut data_stage = Trb::new(TYPE_DATA_STAGE);
+            data_stage.parameter = phys;
+            data_stage.status = length as u32;
+            data_stage.control |= CONTROL_ISP | if direction_in { CONTROL_DIRECTION_IN } else { 0 };
+            ring.push(data_stage)
+        });
+
+        // ステータスステージはデータステージと逆向き。データが無ければIN
+        let mut status_stage = Trb::new(TYPE_STATUS_STAGE);
+        status_stage.control |= CONTROL_IOC;
+        if data.is_none() || !direction_in {
+            status_stage.control |= CONTROL_DIRECTION_IN;
+        }
+        let status_address = ring.push(status_stage);
+
+        if let Some(address) = data_address {
+            self.pending.insert(address, length);
+        }
+        self.pending.insert(status_address, 0);
+        self.ring_doorbell(slot, CONTROL_ENDPOINT_DCI);
+
+        // データステージでエラーになると、ステータスステージのイベントは来ない
+        let waited = wait_until(TIMEOUT_SPINS, || {
+            self.poll();
+            self.completions.contains_key(&status_address)
+                || data_address.is_some_and(|address| matches!(self.completions.get(&address), Some(Err(_))))
+        });
+        let status_result = self.finish_transfer(status_address);
+        // データステージのイベントは、途中で終わったときしか来ない
+        let data_result = data_address.map(|address| match self.completions.remove(&address) {
+            Some(result) => result,
+            None => {
+                self.pending.remove(&address);
+                Ok(length)
+            }
+        });
+        self.reset_halted_endpoints();
+
+        if let Some(Err(err)) = data_result {
+            return Err(err);
+        }
+        waited.map_err(|err| self.check_error().unwrap_or(err))?;
+        status_result?;
+        data_result.unwrap_or(Ok(0))
+    }
+
+    /// バルク転送や割り込み転送を一つ積み、完了を待たずにそのTRBのアドレスを返す
+    ///
+    /// `phys`から`length`バイトの領域は64KiBの境界をまたいではいけない
+    pub fn submit_transfer(&mut self, slot: u8, endpoint_address: u8, phys: u64, length: usize) -> Result<u64, UsbError> {
+        assert!(
+            length <= MAX_TRANSFER_SIZE && phys / MAX_TRANSFER_SIZE as u64 == (phys + length.max(1) as u64 - 1) / MAX_TRANSFER_SIZE as u64,
+            "transfer must not cross a 64KiB boundary"
+        );
+        let dci = endpoint_dci(endpoint_address);
+        let ring = self.transfer_ring(slot, dci)?;
+        let mut trb = Trb::new(TYPE_NORMAL);
+        trb.parameter = phys;
+        trb.status = length as u32;
+        trb.control |= CONTROL_ISP | CONTROL_IOC;
+        let address = ring.push(trb);
+        self.pending.insert(address, length);
+        self.ring_doorbell(slot, dci);
+        Ok(address)
+    }
+
+    /// `submit_transfer`で積んだ転送が終わっていれば、その結果を返す
+    pub fn take_transfer(&mut self, address: u64) -> Option<Result<usize, UsbError>> {
+        self.poll();
+        let result = self.completions.remove(&address);
+        self.reset_halted_endpoints();
+        result
+    }
+
+    /// `submit_transfer`で積んだ転送が終わるのを待つ
+    pub fn wait_transfer(&mut self, address: u64) -> Result<usize, UsbError> {
+        let waited = wait_until(TIMEOUT_SPINS, || {
+            self.poll();
+            self.completions.contains_key(&address)
+        });
+        let result = self.finish_transfer(address);
+        self.reset_halted_endpoints();
+        waited.map_err(|err| self.check_error().unwrap_or(err))?;
+        result
+    }
+
+    /// 転送を一つ積み、終わるのを待つ
+    pub fn transfer(&mut self, slot: u8, endpoint_address: u8, phys: u64, length: usize) -> Result<usize, UsbError> {
+        let address = self.submit_transfer(slot, endpoint_address, phys, length)?;
+        self.wait_transfer(address)
+    }
+
+    /// 起動時にすでに接続されているポートを有効にし、リセットが終わるのを待つ
+    pub fn scan_ports(&mut self) -> Result<(), UsbError> {
+        for index in 0..self.ports.len() {
+            self.handle_port_change(self.ports[index].number);
+        }
+        let result = wait_until(TIMEOUT_SPINS, || {
+            self.poll();
+            self.ports.iter().all(|port| port.state != PortState::Resetting)
+        });
+        for port in self.ports.iter().filter(|port| port.state == PortState::Resetting) {
+            debug!("xhci: port {} did not finish the reset", port.number);
+        }
+        result
+    }
+
+    /// 溜まっているイベントをすべて処理する
+    pub fn poll(&mut self) {
+        while let Some(event) = self.next_event() {
+            self.handle_event(event);
+        }
+    }
+
+    /// コマンドを発行し、その完了イベントを返す。待っている間に来た他のイベントも処理する
+    pub fn execute_command(&mut self, command: Trb) -> Result<Trb, UsbError> {
+        let address = self.command_ring.push(command);
+        self.ring_doorbell(0, 0);
+
+        let mut completion = None;
+        wait_until(TIMEOUT_SPINS, || {
+            while let Some(event) = self.next_event() {
+                if event.trb_type() == TYPE_COMMAND_COMPLETION && event.parameter == address {
+                    completion = Some(event);
+                    return true;
+                }
+                self.handle_event(event);
+            }
+            false
+        })
+        .map_err(|err| self.check_error().unwrap_or(err))?;
+
+        let completion = completion.ok_or(UsbError::Timeout)?;
+        match completion.completion_code() {
+            COMPLETION_SUCCESS => Ok(completion),
+            code => Err(UsbError::Completion(code)),
+        }
+    }
+
+    /// ドアベルの0番はコマンドリング、それ以外はスロットの転送リングに使う
+    pub fn ring_doorbell(&self, slot: u8, target: u8) {
+        self.registers.write32(self.doorbells + slot as usize * 4, target as u32);
+    }
+
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.event_ring.pop()?;
+        // 読んだところまでをコントローラに知らせる
+        let dequeue = self.event_ring.dequeue_pointer() | ERDP_HANDLER_BUSY;
+        self.registers.write64(self.runtime + IR0 + IR_ERDP, dequeue);
+        Some(event)
+    }
+
+    fn handle_event(&mut self, event: Trb) {
+        match event.trb_type() {
+            TYPE_PORT_STATUS_CHANGE => self.handle_port_change(event.port_id()),
+            TYPE_COMMAND_COMPLETION => debug!("xhci: unexpected command completion {:#x}", event.parameter),
+            TYPE_TRANSFER_EVENT => self.handle_transfer_event(event),
+            TYPE_HOST_CONTROLLER_EVENT => debug!("xhci: host controller event {}", event.completion_code()),
+            other => debug!("xhci: unknown event type {}", other),
+        }
+    }
+
+    fn handle_transfer_event(&mut self, event: Trb) {
+        let length = match self.pending.remove(&event.parameter) {
+            Some(length) => length,
+            None => {
+                debug!("xhci: unexpected transfer event for slot {}", event.slot_id());
+                return;
+            }
+        };
+        let result = match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(length.saturating_sub(event.transfer_residual())),
+            code => {
+                if code == COMPLETION_STALL {
+                    self.halted.push((event.slot_id(), event.endpoint_id()));
+                }
+                Err(UsbError::Completion(code))
+            }
+        };
+        self.completions.insert(event.parameter, result);
+    }
+
+    /// 終わった転送の結果を取り出す。終わっていなければ待つのをやめる
+    fn finish_transfer(&mut self, address: u64) -> Result<usize, UsbError> {
+        match self.completions.remove(&address) {
+            Some(result) => result,
+            None => {
+                self.pending.remove(&address);
+                Err(UsbError::Timeout)
+            }
+        }
+    }
+
+    /// STALLで止まったエンドポイントをリセットし、次のTRBから再開できるようにする
+    fn reset_halted_endpoints(&mut self) {
+        for (slot, dci) in core::mem::take(&mut self.halted) {
+            if let Err(err) = self.reset_endpoint(slot, dci) {
+                debug!("xhci: failed to reset endpoint {} of slot {}: {}", dci, slot, err);
+            }
+        }
+    }
+
+    fn reset_endpoint(&mut self, slot: u8, dci: u8) -> Result<(), UsbError> {
+        self.execute_command(Trb::new(TYPE_RESET_ENDPOINT).with_endpoint(slot, dci))?;
+        // 止まったときのTRBは飛ばし、次に積む位置から再開させる
+        let ring = self.transfer_ring(slot, dci)?;
+        let mut command = Trb::new(TYPE_SET_TR_DEQUEUE_POINTER).with_endpoint(slot, dci);
+        command.parameter = ring.enqueue_pointer() | ring.cycle() as u64;
+        self.execute_command(command).map(|_| ())
+    }
+
+    fn transfer_ring(&mut self, slot: u8, dci: u8) -> Result<&mut Ring, UsbError> {
+        self.slots
+            .get_mut(&slot)
+            .and_then(|state| state.rings.get_mut(&dci))
+            .ok_or(UsbError::NoDevice)
+    }
+
+    /// ポートの変化のビットをクリアし、接続されたポートを有効にする
+    fn handle_port_change(&mut self, number: u8) {
+        let index = match self.ports.iter().position(|port| port.number == number) {
+            Some(index) => index,
+            None => return,
+        };
+        let portsc = self.read_portsc(number);
+        self.write_portsc(number, port_neutral(portsc) | (portsc & PORTSC_CHANGES));
+
+        let previous = self.ports[index].state;
+        let state = if portsc & PORTSC_CONNECTED == 0 {
+            PortState::Disconnected
+        } else if portsc & PORTSC_ENABLED != 0 {
+            PortState::Enabled(Speed::from((portsc >> 10) as u8 & 0xF))
+        } else if self.ports[index].major_version >= 3 {
+            // USB3のポートはリンクが確立すると自動で有効になり、もう一度変化のイベントが来る
+            previous
+        } else {
+            PortState::Resetting
+        };
+        self.ports[index].state = state;
+        if state == previous {
+            return;
+        }
+
+        match state {
+            PortState::Resetting => self.write_portsc(number, port_neutral(portsc) | PORTSC_RESET),
+            PortState::Enabled(speed) => {
+                debug!("xhci: port {}: {:?} speed device connected", number, speed);
+                self.attached_ports.push(number);
+            }
+            PortState::Disconnected => {
+                debug!("xhci: port {}: disconnected", number);
+                if matches!(previous, PortState::Enabled(_)) {
+                    self.detached_ports.push(number);
+                }
+            }
+        }
+    }
+
+    fn check_error(&self) -> Option<UsbError> {
+        let status = self.registers.read32(self.operational + OP_USBSTS);
+        if status & (USBSTS_HOST_SYSTEM_ERROR | USBSTS_HOST_CONTROLLER_ERROR) != 0 {
+            Some(UsbError::HostControllerError)
+        } else {
+            None
+        }
+    }
+
+    fn read_portsc(&self, number: u8) -> u32 {
+        self.registers.read32(portsc_offset(self.operational, number))
+    }
+
+    fn write_portsc(&self, number: u8, value: u32) {
+        self.registers.write32(portsc_offset(self.operational, number), value);
+    }
+}
+
+fn portsc_offset(operational: usize, number: u8) -> usize {
+    operational + OP_PORTSC + (number as usize - 1) * PORT_REGISTER_SIZE
+}
+
+/// 書き戻しても何も起こらない値。有効ビットと変化のビットは1を書くとクリアされるので落とす
+fn port_neutral(portsc: u32) -> u32 {
+    portsc & !(PORTSC_ENABLED | PORTSC_CHANGES | PORTSC_RESET)
+}
+
+impl Speed {
+    /// PORTSCやSlot Contextに書く値
+    pub fn id(self) -> u8 {
+        match self {
+            Speed::Full => 1,
+            Speed::Low => 2,
+            Speed::High => 3,
+            Speed::Super => 4,
+            Speed::SuperPlus => 5,
+            Speed::Unknown(id) => id,
+        }
+    }
+
+    /// デバイスディスクリプタを読む前に使う、エンドポイント0の最大パケットサイズ
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full => 8,
+            Speed::High => 64,
+            _ => 512,
+        }
+    }
+}
+
+/// エンドポイントのアドレスから、Device Context Indexを求める。INのエンドポイントは奇数になる
+pub fn endpoint_dci(endpoint_address: u8) -> u8 {
+    let number = endpoint_address & 0xF;
+    if number == 0 {
+        CONTROL_ENDPOINT_DCI
+    } else {
+        number * 2 + (endpoint_address >> 7)
+    }
+}
+
+fn control_endpoint(ring: &Ring, max_packet_size: u16) -> EndpointContext {
+    EndpointContext {
+        endpoint_type: ENDPOINT_CONTROL,
+        max_packet_size,
+        dequeue_pointer: ring.phys_addr(),
+        dequeue_cycle: ring.cycle(),
+        average_trb_length: 8,
+        error_count: 3,
+        ..EndpointContext::default()
+    }
+}
+
+fn endpoint_context(endpoint: &EndpointDescriptor, speed: Speed, ring: &Ring) -> EndpointContext {
+    let transfer_type = endpoint.transfer_type();
+    let endpoint_type = match (transfer_type, endpoint.is_in()) {
+        (TransferType::Control, _) => ENDPOINT_CONTROL,
+        (TransferType::Isochronous, false) => ENDPOINT_ISOCH_OUT,
+        (TransferType::Isochronous, true) => ENDPOINT_ISOCH_IN,
+        (TransferType::Bulk, false) => ENDPOINT_BULK_OUT,
+        (TransferType::Bulk, true) => ENDPOINT_BULK_IN,
+        (TransferType::Interrupt, false) => ENDPOINT_INTERRUPT_OUT,
+        (TransferType::Interrupt, true) => ENDPOINT_INTERRUPT_IN,
+    };
+    let periodic = matches!(transfer_type, TransferType::Isochronous | TransferType::Interrupt);
+    let max_packet_size = endpoint.max_packet_size();
+    // High Speedの周期的な転送では、マイクロフレームごとに複数のパケットを送れる
+    let max_burst = if periodic && speed == Speed::High { endpoint.additional_transactions() } else { 0 };
+    EndpointContext {
+        endpoint_type,
+        max_packet_size,
+        max_burst,
+        interval: endpoint_interval(endpoint, speed),
+        dequeue_pointer: ring.phys_addr(),
+        dequeue_cycle: ring.cycle(),
+        average_trb_length: match transfer_type {
+            TransferType::Control => 8,
+            TransferType::Interrupt => 1024,
+            _ => 3072,
+        },
+        max_esit_payload: if periodic { max_packet_size as u32 * (max_burst as u32 + 1) } else { 0 },
+        error_count: if transfer_type == TransferType::Isochronous { 0 } else { 3 },
+    }
+}
+
+/// bIntervalを、125us * 2^intervalの指数に直す
+fn endpoint_interval(endpoint: &EndpointDescriptor, speed: Speed) -> u8 {
+    let interval = endpoint.interval;
+    match (endpoint.transfer_type(), speed) {
+        (TransferType::Control | TransferType::Bulk, _) => 0,
+        // 2^(bInterval-1)マイクロフレーム
+        (_, Speed::High | Speed::Super | Speed::SuperPlus) => interval.clamp(1, 16) - 1,
+        // Full Speedのアイソクロナス転送は2^(bInterval-1)フレーム
+        (TransferType::Isochronous, _) => interval.clamp(1, 16) - 1 + 3,
+        // Full SpeedとLow Speedの割り込み転送はbIntervalフレーム。それ以下の2の冪に丸める
+        (TransferType::Interrupt, _) => (interval.max(1) as u32 * 8).ilog2().clamp(3, 10) as u8,
+    }
+}
+
+impl From<u8> for Speed {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Speed::Full,
+            2 => Speed::Low,
+            3 => Speed::High,
+            4 => Speed::Super,
+            5 => Speed::SuperPlus,
+            other => Speed::Unknown(other),
+        }
+    }
+}
+
+/// 拡張ケーパビリティを順にたどり、`(オフセット, ID)`を返す
+fn extended_capabilities(registers: &MmioRegion) -> Vec<(usize, u8)> {
+    let mut capabilities = Vec::new();
+    let mut offset = ((registers.read32(CAP_HCCPARAMS1) >> 16) as usize) << 2;
+    while offset != 0 && offset + 4 <= registers.size() {
+        let header = registers.read32(offset);
+        capabilities.push((offset, header as u8));
+        match (header >> 8) & 0xFF {
+            0 => break,
+            next => offset += (next as usize) << 2,
+        }
+    }
+    capabilities
+}
+
+/// BIOSがコントローラを使っていれば、所有権を譲ってもらう
+fn take_ownership(registers: &MmioRegion) {
+    let legacy = extended_capabilities(registers)
+        .into_iter()
+        .find(|&(_, id)| id == XECP_LEGACY_SUPPORT);
+    let offset = match legacy {
+        Some((offset, _)) => offset,
+        None => return,
+    };
+    registers.write32(offset, registers.read32(offset) | LEGACY_OS_OWNED);
+    let released = wait_until(TIMEOUT_SPINS, || {
+        let legacy = registers.read32(offset);
+        legacy & LEGACY_BIOS_OWNED == 0 && legacy & LEGACY_OS_OWNED != 0
+    });
+    if released.is_err() {
+        debug!("xhci: BIOS did not release the controller");
+    }
+    // BIOSのSMIを止め、溜まっている状態のビットをクリアする
+    let control = registers.read32(offset + 4);
+    registers.write32(offset + 4, control & !LEGACY_SMI_ENABLES | 0xE000_0000);
+}
+
+/// Supported Protocolケーパビリティから、ポートごとのUSBのバージョンを調べる
+fn read_ports(registers: &MmioRegion, operational: usize, max_ports: u8) -> Vec<Port> {
+    let mut ports: Vec<Port> = (1..=max_ports)
+        .map(|number| Port { number, major_version: 2, state: PortState::Disconnected })
+        .collect();
+    for (offset, id) in extended_capabilities(registers) {
+        if id != XECP_SUPPORTED_PROTOCOL {
+            continue;
+        }
+        let major_version = (registers.read32(offset) >> 24) as u8;
+        let range = registers.read32(offset + 8);
+        let first = range as u8 as usize;
+        let count = (range >> 8) as u8 as usize;
+        for port in ports.iter_mut().skip(first.saturating_sub(1)).take(count) {
+            port.major_version = major_version;
+        }
+    }
+    // 電源が入っていないポートには入れておく
+    for port in ports.iter() {
+        let offset = portsc_offset(operational, port.number);
+        let portsc = registers.read32(offset);
+        if portsc & PORTSC_POWER == 0 {
+            registers.write32(offset, port_neutral(portsc) | PORTSC_POWER);
+        }
+    }
+    ports
+}
+
+/// コントローラが作業用に使うページを確保し、DCBAAの先頭にその一覧のアドレスを書く
+fn allocate_scratchpad(registers: &MmioRegion, dcbaa: &mut DmaBuffer) -> Result<Vec<DmaBuffer>, UsbError> {
+    let hcsparams2 = registers.read32(CAP_HCSPARAMS2);
+    let count = (((hcsparams2 >> 21) & 0x1F) << 5 | (hcsparams2 >> 27) & 0x1F) as usize;
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let mut array = DmaBuffer::new(count * 8).ok_or(UsbError::NoMemory)?;
+    let mut pages = Vec::with_capacity(count + 1);
+    for index in 0..count {
+        let page = DmaBuffer::new(PAGE_SIZE).ok_or(UsbError::NoMemory)?;
+        array.write::<u64>(index * 8, page.phys_addr());
+        pages.push(page);
+    }
+    dcbaa.write::<u64>(0, array.phys_addr());
+    pages.push(array);
+    Ok(pages)
+}
@@ -0,0 +1,101 @@
+//! コントローラとやり取りするスロットとエンドポイントのコンテキスト
+
+use crate::memory::dma::DmaBuffer;
+use crate::usb::error::UsbError;
+
+// エンドポイントの種類。Endpoint ContextのEP Typeの値
+pub const ENDPOINT_ISOCH_OUT: u8 = 1;
+pub const ENDPOINT_BULK_OUT: u8 = 2;
+pub const ENDPOINT_INTERRUPT_OUT: u8 = 3;
+pub const ENDPOINT_CONTROL: u8 = 4;
+pub const ENDPOINT_ISOCH_IN: u8 = 5;
+pub const ENDPOINT_BULK_IN: u8 = 6;
+pub const ENDPOINT_INTERRUPT_IN: u8 = 7;
+
+/// Slot Context。デバイスがどのポートにどの速度でつながっているか
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SlotContext {
+    pub route_string: u32,
+    /// PORTSCのPort Speedの値
+    pub speed: u8,
+    /// 有効なエンドポイントコンテキストのうち、最大のDCI
+    pub context_entries: u8,
+    pub root_hub_port: u8,
+}
+
+/// Endpoint Context
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointContext {
+    pub endpoint_type: u8,
+    pub max_packet_size: u16,
+    pub max_burst: u8,
+    /// 2^interval * 125us ごとにポーリングする
+    pub interval: u8,
+    /// 転送リングの物理アドレス
+    pub dequeue_pointer: u64,
+    pub dequeue_cycle: bool,
+    pub average_trb_length: u16,
+    pub max_esit_payload: u32,
+    /// エラーで止まるまでに再試行する回数
+    pub error_count: u8,
+}
+
+/// Input Context。Address DeviceやConfigure Endpointのコマンドで、コントローラに渡す
+///
+/// 先頭がInput Control Context、その後にSlot Context、DCI 1から31のエンドポイントコンテキストが並ぶ
+pub struct InputContext {
+    buffer: DmaBuffer,
+    context_size: usize,
+}
+
+impl InputContext {
+    pub fn new(context_size: usize) -> Result<Self, UsbError> {
+        Ok(Self {
+            buffer: DmaBuffer::new(context_size * 33).ok_or(UsbError::NoMemory)?,
+            context_size,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.buffer.phys_addr()
+    }
+
+    /// コマンドで読ませるコンテキストのフラグ。ビット0がスロット、ビットiがDCI iのエンドポイント
+    pub fn set_add_flags(&mut self, flags: u32) {
+        self.buffer.write::<u32>(0, 0);
+        self.buffer.write::<u32>(4, flags);
+    }
+
+    pub fn set_slot(&mut self, slot: &SlotContext) {
+        let base = self.context_size;
+        self.buffer.write::<u32>(
+            base,
+            slot.route_string & 0xF_FFFF | (slot.speed as u32) << 20 | (slot.context_entries as u32) << 27,
+        );
+        self.buffer.write::<u32>(base + 4, (slot.root_hub_port as u32) << 16);
+        self.buffer.write::<u32>(base + 8, 0);
+        self.buffer.write::<u32>(base + 12, 0);
+    }
+
+    /// `dci`は1から31のDevice Context Index
+    pub fn set_endpoint(&mut self, dci: u8, endpoint: &EndpointContext) {
+        assert!((1..32).contains(&dci), "invalid device context index: {}", dci);
+        let base = self.context_size * (1 + dci as usize);
+        self.buffer.write::<u32>(
+            base,
+            (endpoint.interval as u32) << 16 | (endpoint.max_esit_payload >> 16) << 24,
+        );
+        self.buffer.write::<u32>(
+            base + 4,
+            (endpoint.error_count as u32 & 0x3) << 1
+                | (endpoint.endpoint_type as u32) << 3
+                | (endpoint.max_burst as u32) << 8
+                | (endpoint.max_packet_size as u32) << 16,
+        );
+        self.buffer.write::<u64>(base + 8, endpoint.dequeue_pointer & !0xF | endpoint.dequeue_cycle as u64);
+        self.buffer.write::<u32>(
+            base + 16,
+            endpoint.average_trb_length as u32 | (endpoint.max_esit_payload & 0xFFFF) << 16,
+        );
+    }
+}
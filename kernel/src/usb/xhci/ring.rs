@@ -0,0 +1,113 @@
+use crate::memory::dma::DmaBuffer;
+use crate::usb::error::UsbError;
+use crate::usb::xhci::trb::{Trb, CONTROL_TOGGLE_CYCLE, TRB_SIZE, TYPE_LINK};
+
+/// 1ページに収まるTRBの数。64KiBの境界をまたがないように1ページにする
+pub const RING_SIZE: usize = 4096 / TRB_SIZE;
+
+/// ソフトウェアが積んでコントローラが読むリング。コマンドリングと転送リングに使う
+///
+/// 最後のエントリはリングの先頭に戻るリンクTRBで、一周するたびにサイクルビットを反転させる
+pub struct Ring {
+    buffer: DmaBuffer,
+    index: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    pub fn new() -> Result<Self, UsbError> {
+        Ok(Self {
+            buffer: DmaBuffer::new(RING_SIZE * TRB_SIZE).ok_or(UsbError::NoMemory)?,
+            index: 0,
+            cycle: true,
+        })
+    }
+
+    pub fn phys_addr(&self) -> u64 {
+        self.buffer.phys_addr()
+    }
+
+    /// 次に積むTRBに付けるサイクルビットの値
+    pub fn cycle(&self) -> bool {
+        self.cycle
+    }
+
+    /// 次にTRBを積む位置の物理アドレス
+    pub fn enqueue_pointer(&self) -> u64 {
+        self.phys_addr() + (self.index * TRB_SIZE) as u64
+    }
+
+    /// TRBを一つ積み、その物理アドレスを返す
+    pub fn push(&mut self, mut trb: Trb) -> u64 {
+        trb.set_cycle(self.cycle);
+        let address = self.write(self.index, trb);
+        self.index += 1;
+        if self.index == RING_SIZE - 1 {
+            let mut link = Trb::new(TYPE_LINK);
+            link.parameter = self.phys_addr();
+            link.control |= CONTROL_TOGGLE_CYCLE;
+            link.set_cycle(self.cycle);
+            self.write(self.index, link);
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        address
+    }
+
+    /// サイクルビットを含む制御フィールドは、残りを書き終えてから書く
+    fn write(&mut self, index: usize, trb: Trb) -> u64 {
+        let offset = index * TRB_SIZE;
+        self.buffer.write::<u64>(offset, trb.parameter);
+        self.buffer.write::<u32>(offset + 8, trb.status);
+        self.buffer.write::<u32>(offset + 12, trb.control);
+        self.phys_addr() + offset as u64
+    }
+}
+
+/// コントローラが積んでソフトウェアが読むイベントリング。セグメントは一つだけ使う
+pub struct EventRing {
+    segment: DmaBuffer,
+    /// Event Ring Segment Table。セグメントのアドレスと大きさを並べる
+    table: DmaBuffer,
+    index: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    pub fn new() -> Result<Self, UsbError> {
+        let segment = DmaBuffer::new(RING_SIZE * TRB_SIZE).ok_or(UsbError::NoMemory)?;
+        let mut table = DmaBuffer::new(16).ok_or(UsbError::NoMemory)?;
+        table.write::<u64>(0, segment.phys_addr());
+        table.write::<u32>(8, RING_SIZE as u32);
+        Ok(Self { segment, table, index: 0, cycle: true })
+    }
+
+    pub fn table_phys_addr(&self) -> u64 {
+        self.table.phys_addr()
+    }
+
+    /// 次に読むTRBの物理アドレス。読んだところまでをERDPに書いてコントローラに知らせる
+    pub fn dequeue_pointer(&self) -> u64 {
+        self.segment.phys_addr() + (self.index * TRB_SIZE) as u64
+    }
+
+    /// 新しいイベントがあれば取り出す
+    pub fn pop(&mut self) -> Option<Trb> {
+        let offset = self.index * TRB_SIZE;
+        let control = self.segment.read::<u32>(offset + 12);
+        if (control & 1 != 0) != self.cycle {
+            return None;
+        }
+        let trb = Trb {
+            parameter: self.segment.read::<u64>(offset),
+            status: self.segment.read::<u32>(offset + 8),
+            control,
+        };
+        self.index += 1;
+        if self.index == RING_SIZE {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+}
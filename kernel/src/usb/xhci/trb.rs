@@ -0,0 +1,100 @@
+//! Transfer Request Block。リングに並ぶ16バイトの単位
+
+// TRBの種類
+pub const TYPE_NORMAL: u8 = 1;
+pub const TYPE_SETUP_STAGE: u8 = 2;
+pub const TYPE_DATA_STAGE: u8 = 3;
+pub const TYPE_STATUS_STAGE: u8 = 4;
+pub const TYPE_LINK: u8 = 6;
+pub const TYPE_ENABLE_SLOT: u8 = 9;
+pub const TYPE_DISABLE_SLOT: u8 = 10;
+pub const TYPE_ADDRESS_DEVICE: u8 = 11;
+pub const TYPE_CONFIGURE_ENDPOINT: u8 = 12;
+pub const TYPE_EVALUATE_CONTEXT: u8 = 13;
+pub const TYPE_RESET_ENDPOINT: u8 = 14;
+pub const TYPE_SET_TR_DEQUEUE_POINTER: u8 = 16;
+pub const TYPE_NO_OP_COMMAND: u8 = 23;
+pub const TYPE_TRANSFER_EVENT: u8 = 32;
+pub const TYPE_COMMAND_COMPLETION: u8 = 33;
+pub const TYPE_PORT_STATUS_CHANGE: u8 = 34;
+pub const TYPE_HOST_CONTROLLER_EVENT: u8 = 37;
+
+// 完了コード
+pub const COMPLETION_SUCCESS: u8 = 1;
+/// エンドポイントがSTALLを返し、Halted状態になった
+pub const COMPLETION_STALL: u8 = 6;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+const CONTROL_CYCLE: u32 = 1 << 0;
+/// リンクTRBでサイクルビットを反転させる
+pub const CONTROL_TOGGLE_CYCLE: u32 = 1 << 1;
+/// Interrupt on Short Packet。転送が途中で終わったらイベントを出させる
+pub const CONTROL_ISP: u32 = 1 << 2;
+/// Interrupt On Completion。このTRBが終わったらイベントを出させる
+pub const CONTROL_IOC: u32 = 1 << 5;
+/// Immediate Data。パラメータのフィールドにデータそのものを入れる
+pub const CONTROL_IDT: u32 = 1 << 6;
+/// データステージとステータスステージの向き
+pub const CONTROL_DIRECTION_IN: u32 = 1 << 16;
+
+pub const TRB_SIZE: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Trb {
+    pub parameter: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    /// 種類だけを設定したTRB。サイクルビットはリングに積むときに設定される
+    pub fn new(trb_type: u8) -> Self {
+        Self {
+            control: (trb_type as u32) << 10,
+            ..Self::default()
+        }
+    }
+
+    pub fn trb_type(&self) -> u8 {
+        ((self.control >> 10) & 0x3F) as u8
+    }
+
+    pub fn cycle(&self) -> bool {
+        self.control & CONTROL_CYCLE != 0
+    }
+
+    pub fn set_cycle(&mut self, cycle: bool) {
+        self.control = self.control & !CONTROL_CYCLE | cycle as u32;
+    }
+
+    /// イベントTRBの完了コード
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// コマンド完了イベントや転送イベントのスロットID
+    pub fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// 転送イベントのエンドポイントのDCI
+    pub fn endpoint_id(&self) -> u8 {
+        ((self.control >> 16) & 0x1F) as u8
+    }
+
+    /// 転送イベントで、転送されずに残ったバイト数
+    pub fn transfer_residual(&self) -> usize {
+        (self.status & 0xFF_FFFF) as usize
+    }
+
+    /// スロットとエンドポイントを指定するコマンドを作る
+    pub fn with_endpoint(mut self, slot: u8, dci: u8) -> Self {
+        self.control |= (dci as u32) << 16 | (slot as u32) << 24;
+        self
+    }
+
+    /// ポート状態変化イベントのポート番号。1から始まる
+    pub fn port_id(&self) -> u8 {
+        (self.parameter >> 24) as u8
+    }
+}
@@ -0,0 +1,48 @@
+//! PICを初期化した後のATAドライブが、IRQ 14/15を待って読み書きすることを確かめる
+//!
+//! QEMUの起動ディスクはIDEのプライマリマスタ(`hda`)に繋がる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::block::ata;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    kernel::interrupts::init_pics();
+    x86_64::instructions::interrupts::enable();
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn legacy_channels_switch_to_interrupts() {
+    assert!(ata::interrupt_channels() > 0, "no ATA channel in compatibility mode");
+}
+
+#[test_case]
+fn read_completes_on_irq() {
+    let disk = kernel::block::find("hda").expect("the boot disk should be attached as hda");
+    let mut buf = vec![0u8; disk.sector_size()];
+    // キャッシュに載っていないはずの末尾を読む
+    let lba = disk.sector_count() - 1;
+    let before = ata::interrupt_count();
+    disk.read_sectors(lba, &mut buf).unwrap();
+    assert!(ata::interrupt_count() > before, "the read did not wait for IRQ 14");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
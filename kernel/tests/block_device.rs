@@ -0,0 +1,465 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::block::cache::BufferCache;
+use kernel::block::error::BlockError;
+use kernel::block::identify::IdentifyData;
+use kernel::block::partition::{crc32, partitions, scan, Guid, PartitionKind};
+use kernel::block::ram_disk::RamDisk;
+use kernel::block::{disk_name, BlockDevice, Geometry};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+const SECTOR_SIZE: usize = 512;
+
+fn sector(fill: u8) -> Vec<u8> {
+    vec![fill; SECTOR_SIZE]
+}
+
+fn read_sector(device: &dyn BlockDevice, lba: u64) -> Vec<u8> {
+    let mut buf = sector(0);
+    device.read_sectors(lba, &mut buf).unwrap();
+    buf
+}
+
+fn mbr_entry(sector: &mut [u8], index: usize, bootable: bool, system_id: u8, start: u32, count: u32) {
+    let entry = &mut sector[446 + index * 16..][..16];
+    entry[0] = if bootable { 0x80 } else { 0 };
+    entry[4] = system_id;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+}
+
+fn write_mbr(disk: &RamDisk, lba: u64, entries: &[(bool, u8, u32, u32)]) {
+    let mut mbr = sector(0);
+    for (index, &(bootable, system_id, start, count)) in entries.iter().enumerate() {
+        mbr_entry(&mut mbr, index, bootable, system_id, start, count);
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.write_sectors(lba, &mbr).unwrap();
+}
+
+#[test_case]
+fn ram_disk_read_write() {
+    let disk = RamDisk::new(SECTOR_SIZE, 16);
+    assert_eq!(disk.sector_count(), 16);
+    assert_eq!(disk.geometry().capacity(), 16 * SECTOR_SIZE as u64);
+
+    let mut data = sector(0xAB);
+    data.extend(sector(0xCD));
+    disk.write_sectors(14, &data).unwrap();
+    assert_eq!(read_sector(&disk, 15), sector(0xCD));
+
+    assert_eq!(disk.write_sectors(15, &data), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_sectors(0, &mut [0u8; 100]), Err(BlockError::InvalidBuffer));
+}
+
+#[test_case]
+fn mbr_primary_and_logical_partitions() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 4096));
+    write_mbr(&disk, 0, &[(true, 0x0C, 64, 1000), (false, 0x05, 2048, 2048)]);
+    // 論理パーティションの位置は各EBRからの相対位置、次のEBRの位置は拡張パーティションの先頭からの相対位置
+    write_mbr(&disk, 2048, &[(false, 0x83, 16, 500), (false, 0x05, 1024, 1024)]);
+    write_mbr(&disk, 3072, &[(false, 0x06, 16, 300)]);
+
+    let found = scan(disk.as_ref()).unwrap();
+    let summary: Vec<_> = found.iter().map(|p| (p.number, p.start_lba, p.sector_count)).collect();
+    assert_eq!(summary, [(1, 64, 1000), (5, 2064, 500), (6, 3088, 300)]);
+    assert_eq!(found[0].kind, PartitionKind::Mbr { system_id: 0x0C, bootable: true });
+}
+
+#[test_case]
+fn gpt_partitions() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 256));
+    write_mbr(&disk, 0, &[(false, 0xEE, 1, 255)]);
+
+    let type_guid = Guid([
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B,
+    ]);
+    let mut table = vec![0u8; 32 * SECTOR_SIZE];
+    let entry = &mut table[128..256];
+    entry[0..16].copy_from_slice(&type_guid.0);
+    entry[16] = 1;
+    entry[32..40].copy_from_slice(&40u64.to_le_bytes());
+    entry[40..48].copy_from_slice(&199u64.to_le_bytes());
+    for (index, unit) in "EFI system".encode_utf16().enumerate() {
+        entry[56 + index * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+    }
+    disk.write_sectors(2, &table).unwrap();
+
+    let mut header = sector(0);
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&table[..128 * 128]).to_le_bytes());
+    let header_crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    disk.write_sectors(1, &header).unwrap();
+
+    let found = scan(disk.as_ref()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!((found[0].number, found[0].start_lba, found[0].sector_count), (2, 40, 160));
+    match &found[0].kind {
+        PartitionKind::Gpt { type_guid: guid, name, .. } => {
+            assert_eq!(guid, &type_guid);
+            assert_eq!(name, "EFI system");
+        }
+        kind => panic!("unexpected partition kind {:?}", kind),
+    }
+    assert_eq!(format!("{}", type_guid), "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+
+    // ヘッダが壊れていれば保護MBRのエントリだけが見える
+    header[20] ^= 1;
+    disk.write_sectors(1, &header).unwrap();
+    let found = scan(disk.as_ref()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].kind, PartitionKind::Mbr { system_id: 0xEE, bootable: false });
+}
+
+#[test_case]
+fn gpt_with_oversized_table_is_ignored() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 64));
+    write_mbr(&disk, 0, &[(false, 0xEE, 1, 63)]);
+
+    let mut header = sector(0);
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+    header[84..88].copy_from_slice(&u32::MAX.to_le_bytes());
+    let header_crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    disk.write_sectors(1, &header).unwrap();
+
+    let found = scan(disk.as_ref()).unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].kind, PartitionKind::Mbr { system_id: 0xEE, bootable: false });
+}
+
+#[test_case]
+fn fat_boot_sector_is_not_a_partition_table() {
+    let disk = RamDisk::new(SECTOR_SIZE, 128);
+    let mut boot_sector = sector(0);
+    boot_sector[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot_sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+    boot_sector[13] = 4;
+    boot_sector[14..16].copy_from_slice(&1u16.to_le_bytes());
+    boot_sector[16] = 2;
+    // ブートコードがたまたまパーティションエントリとして読めても無視する
+    mbr_entry(&mut boot_sector, 0, false, 0x0C, 1, 100);
+    boot_sector[510] = 0x55;
+    boot_sector[511] = 0xAA;
+    disk.write_sectors(0, &boot_sector).unwrap();
+    assert!(scan(&disk).unwrap().is_empty());
+
+    // ブートフラグが0x00/0x80以外なら、パーティションテーブルではない
+    let mut code = sector(0x41);
+    code[510] = 0x55;
+    code[511] = 0xAA;
+    disk.write_sectors(0, &code).unwrap();
+    assert!(scan(&disk).unwrap().is_empty());
+}
+
+#[test_case]
+fn registered_disks_are_partition_scanned() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 256));
+    write_mbr(&disk, 0, &[(false, 0x83, 16, 32), (false, 0x83, 64, 64)]);
+    disk.write_sectors(64, &sector(0x5A)).unwrap();
+    kernel::block::register("rtest".into(), disk.clone());
+
+    let first = kernel::block::find("rtest1").expect("first partition");
+    assert_eq!(first.sector_count(), 32);
+    let second = kernel::block::find("rtest2").expect("second partition");
+    assert_eq!(read_sector(second.as_ref(), 0), sector(0x5A));
+    second.write_sectors(1, &sector(0xA5)).unwrap();
+    second.flush().unwrap();
+    assert_eq!(read_sector(disk.as_ref(), 65), sector(0xA5));
+}
+
+#[test_case]
+fn unregister_removes_partitions_too() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 128));
+    write_mbr(&disk, 0, &[(false, 0x83, 16, 32)]);
+    kernel::block::register("utest".into(), disk.clone());
+    kernel::block::register("utesta".into(), disk.clone());
+
+    assert_eq!(kernel::block::unregister("utest"), ["utest", "utest1"]);
+    assert!(kernel::block::find("utest").is_none());
+    assert!(kernel::block::find("utest1").is_none());
+    assert!(kernel::block::find("utesta1").is_some());
+    assert!(kernel::block::unregister("utest").is_empty());
+    kernel::block::unregister("utesta");
+}
+
+#[test_case]
+fn partition_of_cached_device_can_be_cached() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 128));
+    write_mbr(&disk, 0, &[(false, 0x83, 32, 64)]);
+    let cache = BufferCache::new(4);
+    let cached: Arc<dyn BlockDevice> = cache.attach(disk.clone());
+    let partition = cache.attach(Arc::new(partitions(&cached).unwrap().remove(0)));
+
+    // 追い出しと書き戻しで、パーティションから元のデバイスへ同じキャッシュを通る
+    for lba in 0..8 {
+        partition.write_sectors(lba, &sector(lba as u8 + 1)).unwrap();
+    }
+    for lba in 0..8 {
+        assert_eq!(read_sector(partition.as_ref(), lba), sector(lba as u8 + 1));
+    }
+    partition.flush().unwrap();
+    cached.flush().unwrap();
+    assert_eq!(read_sector(disk.as_ref(), 32 + 7), sector(8));
+}
+
+/// `lba`から`count`セクタのパーティションを並べたGPTを書く。`count`が0なら最後のLBAを先頭の手前にする
+fn write_gpt(disk: &RamDisk, partitions: &[(u64, u64)]) {
+    write_mbr(disk, 0, &[(false, 0xEE, 1, disk.sector_count() as u32 - 1)]);
+    let mut table = vec![0u8; 32 * SECTOR_SIZE];
+    for (entry, &(lba, count)) in table.chunks_exact_mut(128).zip(partitions) {
+        entry[0] = 0xAF;
+        entry[32..40].copy_from_slice(&lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&(lba + count).wrapping_sub(1).to_le_bytes());
+    }
+    disk.write_sectors(2, &table).unwrap();
+
+    let mut header = sector(0);
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    header[88..92].copy_from_slice(&crc32(&table[..128 * 128]).to_le_bytes());
+    let header_crc = crc32(&header[..92]);
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+    disk.write_sectors(1, &header).unwrap();
+}
+
+#[test_case]
+fn gpt_entries_outside_the_disk_are_skipped() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 256));
+    write_gpt(&disk, &[(40, 100), (150, 0), (200, 57), (200, 56)]);
+
+    let found = scan(disk.as_ref()).unwrap();
+    let summary: Vec<_> = found.iter().map(|p| (p.number, p.start_lba, p.sector_count)).collect();
+    assert_eq!(summary, [(1, 40, 100), (4, 200, 56)]);
+}
+
+#[test_case]
+fn mbr_partition_past_the_end_is_skipped() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 128));
+    write_mbr(&disk, 0, &[(false, 0x83, 1, 64), (false, 0x83, 100, 100)]);
+
+    let found = scan(disk.as_ref()).unwrap();
+    let summary: Vec<_> = found.iter().map(|p| (p.number, p.start_lba, p.sector_count)).collect();
+    assert_eq!(summary, [(1, 1, 64)]);
+}
+
+#[test_case]
+fn partition_is_bounded() {
+    let disk: Arc<dyn BlockDevice> = Arc::new(RamDisk::new(SECTOR_SIZE, 128));
+    let mut mbr = sector(0);
+    mbr_entry(&mut mbr, 0, false, 0x83, 100, 10);
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.write_sectors(0, &mbr).unwrap();
+
+    let partition = partitions(&disk).unwrap().remove(0);
+    assert_eq!(partition.sector_count(), 10);
+    partition.write_sectors(9, &sector(0x11)).unwrap();
+    assert_eq!(read_sector(disk.as_ref(), 109), sector(0x11));
+    assert_eq!(partition.write_sectors(10, &sector(0)), Err(BlockError::OutOfRange));
+}
+
+#[test_case]
+fn cache_writes_back_on_flush() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 32));
+    let cache = BufferCache::new(8);
+    let cached = cache.attach(disk.clone());
+
+    cached.write_sectors(3, &sector(0x42)).unwrap();
+    assert_eq!(read_sector(cached.as_ref(), 3), sector(0x42));
+    assert_eq!(read_sector(disk.as_ref(), 3), sector(0));
+    assert_eq!(cache.usage(), (1, 1));
+
+    cached.flush().unwrap();
+    assert_eq!(read_sector(disk.as_ref(), 3), sector(0x42));
+    assert_eq!(cache.usage(), (1, 0));
+    assert_eq!(cache.stats().write_backs, 1);
+}
+
+#[test_case]
+fn cache_evicts_least_recently_used() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 32));
+    let cache = BufferCache::new(4);
+    let cached = cache.attach(disk.clone());
+
+    cached.write_sectors(0, &sector(0xEE)).unwrap();
+    for lba in 1..4 {
+        read_sector(cached.as_ref(), lba);
+    }
+    // 0番を使い直したので、次に追い出されるのは1番
+    read_sector(cached.as_ref(), 0);
+    read_sector(cached.as_ref(), 10);
+    assert_eq!(cache.stats().evictions, 1);
+    assert_eq!(read_sector(disk.as_ref(), 0), sector(0));
+
+    let before = cache.stats();
+    read_sector(cached.as_ref(), 0);
+    read_sector(cached.as_ref(), 1);
+    let after = cache.stats();
+    assert_eq!(after.hits - before.hits, 1);
+    assert_eq!(after.misses - before.misses, 1);
+
+    // 追い出された変更はデバイスに書き戻される
+    for lba in 20..24 {
+        read_sector(cached.as_ref(), lba);
+    }
+    assert_eq!(read_sector(disk.as_ref(), 0), sector(0xEE));
+}
+
+#[test_case]
+fn dropping_cached_device_flushes() {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, 8));
+    let cache = BufferCache::new(8);
+    let cached = cache.attach(disk.clone());
+    cached.write_sectors(7, &sector(0x99)).unwrap();
+    drop(cached);
+
+    assert_eq!(read_sector(disk.as_ref(), 7), sector(0x99));
+    assert_eq!(cache.usage(), (0, 0));
+}
+
+/// 書き込みを`writes`回だけ受け付け、その後は失敗するディスク
+struct FlakyDisk {
+    disk: RamDisk,
+    writes: AtomicUsize,
+}
+
+impl FlakyDisk {
+    fn new(sector_count: usize, writes: usize) -> Self {
+        Self { disk: RamDisk::new(SECTOR_SIZE, sector_count), writes: AtomicUsize::new(writes) }
+    }
+
+    fn allow_writes(&self, writes: usize) {
+        self.writes.store(writes, Ordering::SeqCst);
+    }
+}
+
+impl BlockDevice for FlakyDisk {
+    fn geometry(&self) -> Geometry {
+        self.disk.geometry()
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.disk.read_sectors(lba, buf)
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.writes.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |writes| writes.checked_sub(1)).is_err() {
+            return Err(BlockError::Io);
+        }
+        self.disk.write_sectors(lba, buf)
+    }
+}
+
+#[test_case]
+fn failed_eviction_keeps_the_change() {
+    let disk = Arc::new(FlakyDisk::new(8, 0));
+    let cache = BufferCache::new(1);
+    let cached = cache.attach(disk.clone());
+
+    cached.write_sectors(0, &sector(0x5A)).unwrap();
+    // 1番を読むと0番が追い出されるが、書き戻せない
+    let mut buf = sector(0);
+    assert_eq!(cached.read_sectors(1, &mut buf), Err(BlockError::Io));
+    assert_eq!(read_sector(cached.as_ref(), 0), sector(0x5A));
+
+    disk.allow_writes(usize::MAX);
+    cached.flush().unwrap();
+    assert_eq!(read_sector(&disk.disk, 0), sector(0x5A));
+    assert_eq!(cache.usage().1, 0);
+}
+
+#[test_case]
+fn failed_flush_keeps_unwritten_changes() {
+    let disk = Arc::new(FlakyDisk::new(8, 1));
+    let cache = BufferCache::new(8);
+    let cached = cache.attach(disk.clone());
+
+    for lba in 0..3 {
+        cached.write_sectors(lba, &sector(0x10 + lba as u8)).unwrap();
+    }
+    assert_eq!(cached.flush(), Err(BlockError::Io));
+    // 書き戻せた0番だけが変更されていないものになる
+    assert_eq!(cache.usage(), (3, 2));
+    assert_eq!(read_sector(&disk.disk, 0), sector(0x10));
+
+    disk.allow_writes(usize::MAX);
+    cached.flush().unwrap();
+    assert_eq!(cache.usage(), (3, 0));
+    for lba in 0..3 {
+        assert_eq!(read_sector(&disk.disk, lba), sector(0x10 + lba as u8));
+    }
+}
+
+#[test_case]
+fn disk_names_continue_past_z() {
+    assert_eq!(disk_name("vd", 0), "vda");
+    assert_eq!(disk_name("vd", 25), "vdz");
+    assert_eq!(disk_name("vd", 26), "vdaa");
+    assert_eq!(disk_name("hd", 27), "hdab");
+}
+
+#[test_case]
+fn identify_data() {
+    let mut data = [0u8; 512];
+    let mut set_word = |index: usize, value: u16| data[index * 2..index * 2 + 2].copy_from_slice(&value.to_le_bytes());
+    set_word(83, 1 << 10);
+    set_word(100, 0x5678);
+    set_word(101, 0x1234);
+    set_word(106, 0x4000 | 1 << 12);
+    set_word(117, 2048);
+    // ATAの文字列は2バイトごとに入れ替わっている
+    data[54..94].fill(b' ');
+    data[54..68].copy_from_slice(b"EQUMH RADDSI K");
+
+    let identify = IdentifyData::parse(&data);
+    assert_eq!(identify.model, "QEMU HARDDISK");
+    assert!(identify.lba48);
+    assert_eq!(identify.sector_count, 0x1234_5678);
+    assert_eq!(identify.sector_size, 4096);
+    assert!(identify.has_valid_sector_size());
+
+    // 0や512の倍数でない論理セクタサイズは使えない
+    for words in [0u16, 300] {
+        data[117 * 2..118 * 2].copy_from_slice(&words.to_le_bytes());
+        assert!(!IdentifyData::parse(&data).has_valid_sector_size());
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
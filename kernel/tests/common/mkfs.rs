@@ -0,0 +1,165 @@
+//! テスト用のディスクをFATでフォーマットする
+//!
+//! カーネル自身はフォーマットしないので、テストのためだけに持っておく
+
+use alloc::vec;
+use kernel::block::BlockDevice;
+use kernel::fs::error::FsError;
+use kernel::fs::fat::bpb::FatType;
+
+const MEDIA_DESCRIPTOR: u8 = 0xF8;
+const NUM_FATS: u32 = 2;
+const ROOT_ENTRY_COUNT: u32 = 512;
+const FAT32_ROOT_CLUSTER: u32 = 2;
+const FAT32_FS_INFO_SECTOR: u32 = 1;
+const FAT32_BACKUP_BOOT_SECTOR: u32 = 6;
+
+/// デバイス全体を指定した種類のFATでフォーマットする
+///
+/// クラスタサイズは、指定した種類になる範囲でできるだけ小さく選ぶ
+pub fn format(device: &dyn BlockDevice, fat_type: FatType, label: &str) -> Result<(), FsError> {
+    let bytes_per_sector = device.sector_size() as u32;
+    if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) {
+        return Err(FsError::InvalidArgument);
+    }
+    let total_sectors = device.sector_count().min(u32::MAX as u64) as u32;
+    let (reserved_sectors, root_entry_count) = match fat_type {
+        FatType::Fat32 => (32, 0),
+        _ => (1, ROOT_ENTRY_COUNT),
+    };
+    let root_dir_sectors = (root_entry_count * 32 + bytes_per_sector - 1) / bytes_per_sector;
+
+    let (sectors_per_cluster, fat_size) = (0..8)
+        .map(|shift| 1u32 << shift)
+        .filter_map(|sectors_per_cluster| {
+            let fat_size = fat_size(fat_type, total_sectors, bytes_per_sector, reserved_sectors, root_dir_sectors, sectors_per_cluster)?;
+            let metadata = reserved_sectors + NUM_FATS * fat_size + root_dir_sectors;
+            let cluster_count = (total_sectors - metadata) / sectors_per_cluster;
+            (FatType::from_cluster_count(cluster_count) == fat_type).then_some((sectors_per_cluster, fat_size))
+        })
+        .next()
+        .ok_or(FsError::InvalidArgument)?;
+
+    let first_data_sector = reserved_sectors + NUM_FATS * fat_size + root_dir_sectors;
+    let metadata_end = match fat_type {
+        FatType::Fat32 => first_data_sector + sectors_per_cluster,
+        _ => first_data_sector,
+    };
+    let zeros = vec![0u8; bytes_per_sector as usize];
+    for lba in 0..metadata_end {
+        device.write_sectors(lba as u64, &zeros)?;
+    }
+
+    let mut boot_sector = vec![0u8; bytes_per_sector as usize];
+    boot_sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    boot_sector[3..11].copy_from_slice(b"RUST OS ");
+    write_u16(&mut boot_sector, 11, bytes_per_sector as u16);
+    boot_sector[13] = sectors_per_cluster as u8;
+    write_u16(&mut boot_sector, 14, reserved_sectors as u16);
+    boot_sector[16] = NUM_FATS as u8;
+    write_u16(&mut boot_sector, 17, root_entry_count as u16);
+    if total_sectors < 0x10000 && fat_type != FatType::Fat32 {
+        write_u16(&mut boot_sector, 19, total_sectors as u16);
+    } else {
+        write_u32(&mut boot_sector, 32, total_sectors);
+    }
+    boot_sector[21] = MEDIA_DESCRIPTOR;
+    write_u16(&mut boot_sector, 24, 63);
+    write_u16(&mut boot_sector, 26, 255);
+
+    let mut volume_label = [b' '; 11];
+    for (dst, src) in volume_label.iter_mut().zip(label.bytes()) {
+        *dst = src.to_ascii_uppercase();
+    }
+    // FAT12/16とFAT32で拡張BPBの位置が異なる
+    let extended = match fat_type {
+        FatType::Fat32 => {
+            write_u32(&mut boot_sector, 36, fat_size);
+            write_u32(&mut boot_sector, 44, FAT32_ROOT_CLUSTER);
+            write_u16(&mut boot_sector, 48, FAT32_FS_INFO_SECTOR as u16);
+            write_u16(&mut boot_sector, 50, FAT32_BACKUP_BOOT_SECTOR as u16);
+            64
+        }
+        _ => {
+            write_u16(&mut boot_sector, 22, fat_size as u16);
+            36
+        }
+    };
+    boot_sector[extended] = 0x80;
+    boot_sector[extended + 2] = 0x29;
+    write_u32(&mut boot_sector, extended + 3, total_sectors ^ 0x5255_5354);
+    boot_sector[extended + 7..extended + 18].copy_from_slice(&volume_label);
+    let fs_type: &[u8; 8] = match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    };
+    boot_sector[extended + 18..extended + 26].copy_from_slice(fs_type);
+    boot_sector[510] = 0x55;
+    boot_sector[511] = 0xAA;
+    device.write_sectors(0, &boot_sector)?;
+
+    // FATの先頭2エントリは予約されている。FAT32ではルートディレクトリのクラスタも使用中にする
+    let mut fat = vec![0u8; bytes_per_sector as usize];
+    match fat_type {
+        FatType::Fat12 => fat[0..3].copy_from_slice(&[MEDIA_DESCRIPTOR, 0xFF, 0xFF]),
+        FatType::Fat16 => fat[0..4].copy_from_slice(&[MEDIA_DESCRIPTOR, 0xFF, 0xFF, 0xFF]),
+        FatType::Fat32 => {
+            write_u32(&mut fat, 0, 0x0FFF_FF00 | MEDIA_DESCRIPTOR as u32);
+            write_u32(&mut fat, 4, 0x0FFF_FFFF);
+            write_u32(&mut fat, 8, 0x0FFF_FFFF);
+        }
+    }
+    for fat_index in 0..NUM_FATS {
+        device.write_sectors((reserved_sectors + fat_index * fat_size) as u64, &fat)?;
+    }
+
+    if fat_type == FatType::Fat32 {
+        let cluster_count = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let mut fs_info = vec![0u8; bytes_per_sector as usize];
+        write_u32(&mut fs_info, 0, 0x4161_5252);
+        write_u32(&mut fs_info, 484, 0x6141_7272);
+        write_u32(&mut fs_info, 488, cluster_count - 1);
+        write_u32(&mut fs_info, 492, FAT32_ROOT_CLUSTER + 1);
+        write_u32(&mut fs_info, 508, 0xAA55_0000);
+        device.write_sectors(FAT32_FS_INFO_SECTOR as u64, &fs_info)?;
+        device.write_sectors(FAT32_BACKUP_BOOT_SECTOR as u64, &boot_sector)?;
+        device.write_sectors((FAT32_BACKUP_BOOT_SECTOR + 1) as u64, &fs_info)?;
+    }
+    Ok(())
+}
+
+/// FAT自体が占める領域を引いたクラスタ数を表せるだけのFATのセクタ数を求める
+fn fat_size(
+    fat_type: FatType,
+    total_sectors: u32,
+    bytes_per_sector: u32,
+    reserved_sectors: u32,
+    root_dir_sectors: u32,
+    sectors_per_cluster: u32,
+) -> Option<u32> {
+    let mut fat_size = 1;
+    loop {
+        let metadata = reserved_sectors + NUM_FATS * fat_size + root_dir_sectors;
+        let data_sectors = total_sectors.checked_sub(metadata).filter(|sectors| *sectors > 0)?;
+        let entries = data_sectors / sectors_per_cluster + 2;
+        let bytes = match fat_type {
+            FatType::Fat12 => (entries * 3 + 1) / 2,
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        };
+        let needed = (bytes + bytes_per_sector - 1) / bytes_per_sector;
+        if needed <= fat_size {
+            return Some(fat_size);
+        }
+        fat_size = needed;
+    }
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,65 @@
+//! 複数のテストで使う道具
+
+pub mod mkfs;
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use kernel::block::error::BlockError;
+use kernel::block::{check_range, BlockDevice, Geometry};
+use spin::Mutex;
+
+/// 書き込まれたセクタだけをヒープに持つブロックデバイス
+///
+/// ヒープに収まらない大きさのディスク(FAT32など)をテストで使うためのもの
+/// 書き込まれていないセクタは0として読める
+pub struct SparseDisk {
+    sector_size: usize,
+    sector_count: u64,
+    sectors: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl SparseDisk {
+    pub fn new(sector_size: usize, sector_count: u64) -> Self {
+        Self {
+            sector_size,
+            sector_count,
+            sectors: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl BlockDevice for SparseDisk {
+    fn geometry(&self) -> Geometry {
+        Geometry {
+            sector_size: self.sector_size,
+            sector_count: self.sector_count,
+            read_only: false,
+        }
+    }
+
+    fn read_sectors(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let sectors = self.sectors.lock();
+        for (i, chunk) in buf.chunks_mut(self.sector_size).enumerate() {
+            match sectors.get(&(lba + i as u64)) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => chunk.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buf.len())?;
+        let mut sectors = self.sectors.lock();
+        for (i, chunk) in buf.chunks(self.sector_size).enumerate() {
+            // 0だけのセクタは持たなくてよい
+            if chunk.iter().all(|byte| *byte == 0) {
+                sectors.remove(&(lba + i as u64));
+            } else {
+                sectors.insert(lba + i as u64, chunk.to_vec());
+            }
+        }
+        Ok(())
+    }
+}
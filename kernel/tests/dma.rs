@@ -0,0 +1,64 @@
+//! DMAに使うメモリが物理的に連続して確保され、連続した領域を探すときに飛ばしたフレームも無駄にならないことを確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::memory::dma::DmaBuffer;
+use kernel::memory::kernel_memory;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+const PAGE_SIZE: usize = 4096;
+
+fn allocated_frames() -> usize {
+    kernel_memory().frame_allocator.allocated_frames()
+}
+
+#[test_case]
+fn buffers_are_page_aligned_and_zeroed() {
+    let mut buffer = DmaBuffer::new(3 * PAGE_SIZE + 1).expect("failed to allocate a DMA buffer");
+    assert_eq!(buffer.phys_addr() % PAGE_SIZE as u64, 0);
+    assert_eq!(buffer.size(), 4 * PAGE_SIZE);
+    assert!(buffer.as_slice().iter().all(|byte| *byte == 0));
+    buffer.write::<u64>(buffer.size() - 8, u64::MAX);
+    assert_eq!(buffer.read::<u64>(buffer.size() - 8), u64::MAX);
+}
+
+#[test_case]
+fn no_frame_is_lost_between_contiguous_buffers() {
+    // 大きさを変えながら確保して、連続した領域を探し直す場面を作る
+    let before = allocated_frames();
+    let buffers: Vec<DmaBuffer> = (0..16)
+        .map(|i| DmaBuffer::new(PAGE_SIZE << (i % 5)).expect("failed to allocate a DMA buffer"))
+        .collect();
+    let frames: usize = buffers.iter().map(|buffer| buffer.size() / PAGE_SIZE).sum();
+    assert_eq!(allocated_frames() - before, frames);
+
+    for (i, a) in buffers.iter().enumerate() {
+        for b in buffers[i + 1..].iter() {
+            let overlaps = a.phys_addr() < b.phys_addr() + b.size() as u64
+                && b.phys_addr() < a.phys_addr() + a.size() as u64;
+            assert!(!overlaps, "{:#x} and {:#x} overlap", a.phys_addr(), b.phys_addr());
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+mod common;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::block::cache::BufferCache;
+use kernel::block::BlockDevice;
+use kernel::block::ram_disk::RamDisk;
+use kernel::fs::error::FsError;
+use kernel::fs::fat::bpb::{BiosParameterBlock, FatType};
+use kernel::fs::fat::FatFs;
+use kernel::fs::file::OpenFlags;
+use kernel::fs::mount::Vfs;
+use kernel::fs::{FileSystem, FileType, VFS};
+use kernel::BOOTLOADER_CONFIG;
+use common::mkfs::format;
+use common::SparseDisk;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+const SECTOR_SIZE: usize = 512;
+
+fn formatted_disk(sectors: usize, fat_type: FatType) -> Arc<RamDisk> {
+    let disk = Arc::new(RamDisk::new(SECTOR_SIZE, sectors));
+    format(disk.as_ref(), fat_type, "TEST").unwrap();
+    disk
+}
+
+fn mount<D: BlockDevice + 'static>(disk: &Arc<D>) -> Vfs {
+    let fs = FatFs::new(disk.clone()).unwrap();
+    let mut vfs = Vfs::new();
+    vfs.mount("/", Arc::new(fs)).unwrap();
+    vfs
+}
+
+fn names(vfs: &Vfs, path: &str) -> Vec<String> {
+    vfs.read_dir(path).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+fn pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[test_case]
+fn format_detects_fat_type() {
+    let fat12 = formatted_disk(2048, FatType::Fat12);
+    assert_eq!(FatFs::new(fat12).unwrap().fat_type(), FatType::Fat12);
+
+    let fat16 = formatted_disk(5120, FatType::Fat16);
+    assert_eq!(FatFs::new(fat16).unwrap().fat_type(), FatType::Fat16);
+}
+
+#[test_case]
+fn corrupted_boot_sectors_are_rejected() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    let mut valid = vec![0u8; SECTOR_SIZE];
+    disk.read_sectors(0, &mut valid).unwrap();
+    assert!(BiosParameterBlock::parse(&valid).is_ok());
+
+    let corrupted = |f: &dyn Fn(&mut [u8])| {
+        let mut sector = valid.clone();
+        f(&mut sector);
+        BiosParameterBlock::parse(&sector).err()
+    };
+    // FATの大きさの合計が32ビットに収まらない
+    assert_eq!(corrupted(&|sector| {
+        sector[22..24].fill(0);
+        sector[36..40].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+    }), Some(FsError::Corrupted));
+    // 予約セクタとFATを足すとあふれる
+    assert_eq!(corrupted(&|sector| {
+        sector[14..16].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        sector[22..24].fill(0);
+        sector[36..40].copy_from_slice(&0x7FFF_FFFFu32.to_le_bytes());
+    }), Some(FsError::Corrupted));
+    // データ領域にクラスタが一つも無い
+    let first_data_sector = BiosParameterBlock::parse(&valid).unwrap().first_data_sector();
+    assert_eq!(corrupted(&|sector| {
+        sector[13] = 4;
+        sector[19..21].copy_from_slice(&(first_data_sector as u16 + 1).to_le_bytes());
+    }), Some(FsError::Corrupted));
+}
+
+#[test_case]
+fn short_and_long_names() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    let vfs = mount(&disk);
+    vfs.create("/README.TXT", FileType::File).unwrap();
+    vfs.create("/hello.txt", FileType::File).unwrap();
+    vfs.create("/A Long File Name.markdown", FileType::File).unwrap();
+
+    assert_eq!(names(&vfs, "/"), ["README.TXT", "hello.txt", "A Long File Name.markdown"]);
+    assert!(vfs.resolve("/readme.txt").is_ok());
+    assert!(vfs.resolve("/a long file name.MARKDOWN").is_ok());
+    assert_eq!(vfs.create("/HELLO.TXT", FileType::File).err(), Some(FsError::AlreadyExists));
+}
+
+#[test_case]
+fn data_survives_remount() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    let data = pattern(10_000);
+    {
+        let vfs = mount(&disk);
+        vfs.mkdir("/etc").unwrap();
+        let mut file = vfs.open("/etc/config.toml", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(file.write(&data).unwrap(), data.len());
+    }
+
+    let vfs = mount(&disk);
+    assert_eq!(vfs.stat("/etc/config.toml").unwrap().size, data.len());
+    let mut file = vfs.open("/etc/config.toml", OpenFlags::READ).unwrap();
+    assert_eq!(file.read_to_end().unwrap(), data);
+}
+
+#[test_case]
+fn truncate_and_rewrite() {
+    let disk = formatted_disk(5120, FatType::Fat16);
+    let vfs = mount(&disk);
+    let mut file = vfs.open("/log", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    file.write(&pattern(5000)).unwrap();
+
+    let mut file = vfs.open("/log", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(vfs.stat("/log").unwrap().size, 0);
+    file.write(b"fresh").unwrap();
+    assert_eq!(vfs.open("/log", OpenFlags::READ).unwrap().read_to_end().unwrap(), b"fresh");
+}
+
+#[test_case]
+fn directories_and_unlink() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    let vfs = mount(&disk);
+    vfs.mkdir("/bin").unwrap();
+    vfs.mkdir("/bin/tools").unwrap();
+    for i in 0..40 {
+        vfs.create(&alloc::format!("/bin/tools/program-number-{}", i), FileType::File).unwrap();
+    }
+    assert_eq!(names(&vfs, "/bin/tools").len(), 40);
+    assert_eq!(vfs.unlink("/bin/tools"), Err(FsError::NotEmpty));
+
+    for i in 0..40 {
+        vfs.unlink(&alloc::format!("/bin/tools/program-number-{}", i)).unwrap();
+    }
+    vfs.unlink("/bin/tools").unwrap();
+    assert!(names(&vfs, "/bin").is_empty());
+    assert!(vfs.resolve("/bin/tools/..").is_err());
+}
+
+#[test_case]
+fn changes_reach_disk_after_sync() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    let cache = BufferCache::new(64);
+    let cached = cache.attach(disk.clone());
+    let fs = Arc::new(FatFs::new(cached).unwrap());
+    let mut vfs = Vfs::new();
+    vfs.mount("/", fs.clone()).unwrap();
+    vfs.open("/notes.txt", OpenFlags::WRITE | OpenFlags::CREATE).unwrap().write(&pattern(3000)).unwrap();
+
+    // 書き戻す前はディスク上にファイルが無い
+    assert!(mount(&disk).resolve("/notes.txt").is_err());
+    assert!(cache.usage().1 > 0);
+
+    fs.sync().unwrap();
+    assert_eq!(cache.usage().1, 0);
+    let vfs = mount(&disk);
+    assert_eq!(vfs.open("/notes.txt", OpenFlags::READ).unwrap().read_to_end().unwrap(), pattern(3000));
+}
+
+/// FAT32になる最小のクラスタ数を、1クラスタ1セクタで持てるだけの大きさ
+const FAT32_SECTORS: u64 = 68 * 1024;
+
+fn fat32_disk() -> Arc<SparseDisk> {
+    let disk = Arc::new(SparseDisk::new(SECTOR_SIZE, FAT32_SECTORS));
+    format(disk.as_ref(), FatType::Fat32, "TEST32").unwrap();
+    disk
+}
+
+#[test_case]
+fn fat32_root_directory_grows_across_clusters() {
+    let disk = fat32_disk();
+    assert_eq!(FatFs::new(disk.clone()).unwrap().fat_type(), FatType::Fat32);
+    {
+        let vfs = mount(&disk);
+        // FAT32のルートディレクトリは固定の領域ではなくクラスタの連なりなので、1クラスタに収まらない数を作る
+        for i in 0..40 {
+            vfs.create(&alloc::format!("/entry-with-a-long-name-{}", i), FileType::File).unwrap();
+        }
+    }
+
+    let vfs = mount(&disk);
+    assert_eq!(names(&vfs, "/").len(), 40);
+    assert!(vfs.resolve("/ENTRY-WITH-A-LONG-NAME-39").is_ok());
+}
+
+#[test_case]
+fn fat32_data_survives_remount() {
+    let disk = fat32_disk();
+    let data = pattern(20_000);
+    {
+        let vfs = mount(&disk);
+        vfs.mkdir("/var").unwrap();
+        let mut file = vfs.open("/var/data.bin", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(file.write(&data).unwrap(), data.len());
+    }
+
+    let vfs = mount(&disk);
+    assert_eq!(vfs.stat("/var/data.bin").unwrap().size, data.len());
+    assert_eq!(vfs.open("/var/data.bin", OpenFlags::READ).unwrap().read_to_end().unwrap(), data);
+    vfs.unlink("/var/data.bin").unwrap();
+    assert!(names(&vfs, "/var").is_empty());
+}
+
+#[test_case]
+fn fat_devices_are_mounted_under_mnt() {
+    let disk = formatted_disk(2048, FatType::Fat12);
+    mount(&disk).open("/hello.txt", OpenFlags::WRITE | OpenFlags::CREATE).unwrap().write(b"hello").unwrap();
+    kernel::fs::mount_device("ramtest", disk.clone()).unwrap();
+    assert_eq!(kernel::fs::mount_device("ramtest", disk.clone()), Err(FsError::Busy));
+
+    let mut file = VFS.lock().open("/mnt/ramtest/hello.txt", OpenFlags::READ).unwrap();
+    assert_eq!(file.read_to_end().unwrap(), b"hello");
+    drop(file);
+
+    kernel::fs::unmount_device("ramtest").unwrap();
+    assert!(VFS.lock().resolve("/mnt/ramtest").is_err());
+
+    // FATでないものはマウントしない
+    let blank = Arc::new(RamDisk::new(SECTOR_SIZE, 64));
+    assert!(kernel::fs::mount_device("blank", blank).is_err());
+    assert!(VFS.lock().resolve("/mnt/blank").is_err());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,183 @@
+//! 裏の画面に描いたものが、書き換えた範囲だけ画面に写されることを確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use kernel::frame_buffer_writer::dirty::{DirtyRegion, Rectangle, MAX_DIRTY_RECTANGLES};
+use kernel::frame_buffer_writer::pixel_color::PixelColor;
+use kernel::frame_buffer_writer::vector2d::Vector2D;
+use kernel::frame_buffer_writer::{FrameBufferWriter, FRAME_BUFFER_WRITER};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+fn rectangle(x: usize, y: usize, width: usize, height: usize) -> Rectangle {
+    Rectangle::new(Vector2D::new(x, y), Vector2D::new(width, height))
+}
+
+/// 画面に写っているものが裏の画面と同じ
+fn screen_matches_back_buffer(writer: &FrameBufferWriter) -> bool {
+    (0..writer.info.height).all(|y| {
+        (0..writer.info.width).all(|x| {
+            let pos = Vector2D::new(x, y);
+            writer.read_screen_pixel(pos) == writer.read_pixel(pos)
+        })
+    })
+}
+
+/// 画面全体の大きさ(バイト)
+fn screen_bytes(writer: &FrameBufferWriter) -> u64 {
+    (writer.info.width * writer.info.height * writer.info.bytes_per_pixel) as u64
+}
+
+/// `f`の中の`flush`で画面に写したバイト数
+fn copied_bytes(writer: &mut FrameBufferWriter, f: impl FnOnce(&mut FrameBufferWriter)) -> u64 {
+    writer.flush();
+    let before = writer.copied_bytes();
+    f(writer);
+    writer.flush();
+    writer.copied_bytes() - before
+}
+
+#[test_case]
+fn rectangles_are_clipped_to_the_screen() {
+    assert_eq!(rectangle(630, 470, 20, 20).clipped(640, 480), rectangle(630, 470, 10, 10));
+    assert!(rectangle(700, 0, 20, 20).clipped(640, 480).is_empty());
+    assert_eq!(rectangle(0, 0, 10, 10).union(&rectangle(20, 5, 5, 10)), rectangle(0, 0, 25, 15));
+}
+
+#[test_case]
+fn touching_rectangles_are_merged() {
+    let mut dirty = DirtyRegion::new();
+    dirty.add(rectangle(0, 0, 10, 10));
+    dirty.add(rectangle(100, 100, 10, 10));
+    dirty.add(rectangle(0, 0, 0, 10));
+    assert_eq!(dirty.rectangles().len(), 2);
+
+    // 辺で接するものもまとめる
+    dirty.add(rectangle(10, 0, 10, 10));
+    assert_eq!(dirty.rectangles().len(), 2);
+    assert!(dirty.rectangles().contains(&rectangle(0, 0, 20, 10)));
+
+    // 二つをつなぐものが来たら、全部が一つになる
+    dirty.add(rectangle(15, 5, 90, 100));
+    assert_eq!(dirty.rectangles(), &[rectangle(0, 0, 110, 110)]);
+
+    dirty.clear();
+    assert!(dirty.is_empty());
+}
+
+#[test_case]
+fn full_region_merges_the_closest_rectangles() {
+    let mut dirty = DirtyRegion::new();
+    for i in 0..MAX_DIRTY_RECTANGLES {
+        dirty.add(rectangle(i * 100, 0, 10, 10));
+    }
+    assert_eq!(dirty.rectangles().len(), MAX_DIRTY_RECTANGLES);
+
+    dirty.add(rectangle(215, 0, 10, 10));
+    assert_eq!(dirty.rectangles().len(), MAX_DIRTY_RECTANGLES);
+    assert!(dirty.rectangles().contains(&rectangle(200, 0, 25, 10)));
+}
+
+#[test_case]
+fn drawing_is_flushed_explicitly() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    assert!(writer.has_back_buffer());
+    writer.flush();
+    assert!(writer.is_flushed());
+
+    let color = PixelColor::new(12, 34, 56);
+    writer.fill_rectangle(Vector2D::new(10, 10), Vector2D::new(40, 20), color);
+    assert!(!writer.is_flushed());
+    assert_eq!(writer.read_pixel(Vector2D::new(49, 29)), Some(color));
+    assert_ne!(writer.read_pixel(Vector2D::new(50, 29)), Some(color));
+    assert_ne!(writer.read_screen_pixel(Vector2D::new(10, 10)), Some(color));
+    writer.flush();
+    assert!(writer.is_flushed());
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(10, 10)), Some(color));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(49, 29)), Some(color));
+    assert!(screen_matches_back_buffer(&writer));
+
+    // 画面からはみ出した部分は描かない
+    let (width, height) = (writer.info.width, writer.info.height);
+    writer.fill_rectangle(Vector2D::new(width - 5, height - 5), Vector2D::new(10, 10), color);
+    assert_eq!(writer.read_pixel(Vector2D::new(width - 1, height - 1)), Some(color));
+    writer.flush();
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(width - 1, height - 1)), Some(color));
+}
+
+#[test_case]
+fn text_output_is_flushed_immediately() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    write!(writer, "\x1b[41mflushed\x1b[0m").unwrap();
+    assert!(writer.is_flushed());
+    assert!(screen_matches_back_buffer(&writer));
+    writer.toggle_cursor();
+    assert!(writer.is_flushed());
+    assert!(screen_matches_back_buffer(&writer));
+}
+
+#[test_case]
+fn only_dirty_rectangles_are_copied() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    let size = Vector2D::new(writer.info.width, writer.info.height);
+    let color = PixelColor::new(90, 60, 30);
+    let small = copied_bytes(&mut writer, |writer| {
+        writer.fill_rectangle(Vector2D::new(0, 0), Vector2D::new(16, 16), color)
+    });
+    assert_eq!(small, (16 * 16 * writer.info.bytes_per_pixel) as u64);
+    let full = copied_bytes(&mut writer, |writer| writer.fill_rectangle(Vector2D::new(0, 0), size, color));
+    assert_eq!(full, screen_bytes(&writer));
+    // 何も描かなければ何も写さない
+    assert_eq!(copied_bytes(&mut writer, |_| {}), 0);
+}
+
+#[test_case]
+fn text_output_copies_less_than_a_full_redraw() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    let screen = screen_bytes(&writer);
+    write!(writer, "\x1b[H").unwrap();
+    let text = copied_bytes(&mut writer, |writer| write!(writer, "x").unwrap());
+    assert!(text > 0 && text * 100 < screen, "writing one character copied {} bytes", text);
+
+    // スクロールすると文字の行をすべて写し直す
+    let rows = writer.rows();
+    let scrolled = copied_bytes(&mut writer, |writer| writeln!(writer, "\x1b[{};1H", rows).unwrap());
+    assert!(scrolled > screen / 2 && scrolled <= screen, "scrolling copied {} of {} bytes", scrolled, screen);
+}
+
+#[test_case]
+fn drawing_without_back_buffer_goes_to_the_screen() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    let color = PixelColor::new(200, 100, 50);
+    let back_buffer = writer.take_back_buffer().unwrap();
+    assert!(!writer.has_back_buffer());
+    let copied = copied_bytes(&mut writer, |writer| {
+        writer.fill_rectangle(Vector2D::new(20, 20), Vector2D::new(10, 10), color)
+    });
+    assert_eq!(copied, 0);
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(25, 25)), Some(color));
+
+    // 戻すときに今の画面を裏の画面に写す
+    writer.set_back_buffer(back_buffer);
+    assert_eq!(writer.read_pixel(Vector2D::new(25, 25)), Some(color));
+    assert!(screen_matches_back_buffer(&writer));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
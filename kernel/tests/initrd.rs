@@ -0,0 +1,102 @@
+//! tarのアーカイブから作ったinitrdのエントリの読み方を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::initrd::{EntryKind, Initrd};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+const BLOCK_SIZE: usize = 512;
+
+/// アーカイブに入れるエントリ。`prefix`が空でなければPOSIX ustarの、空ならGNU形式のヘッダを使う
+struct TarEntry<'a> {
+    name: &'a str,
+    prefix: &'a str,
+    kind: u8,
+    data: &'a [u8],
+}
+
+fn file<'a>(name: &'a str, data: &'a [u8]) -> TarEntry<'a> {
+    TarEntry { name, prefix: "", kind: b'0', data }
+}
+
+/// チェックサムは読まないので埋めない
+fn archive(entries: &[TarEntry]) -> Initrd {
+    let mut data = Vec::new();
+    for entry in entries {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", entry.data.len()).as_bytes());
+        header[156] = entry.kind;
+        if entry.prefix.is_empty() {
+            header[257..265].copy_from_slice(b"ustar  \0");
+        } else {
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            header[345..345 + entry.prefix.len()].copy_from_slice(entry.prefix.as_bytes());
+        }
+        data.extend(header);
+        data.extend(entry.data);
+        data.resize(data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    }
+    data.resize(data.len() + 2 * BLOCK_SIZE, 0);
+    Initrd::from_bytes(data.leak())
+}
+
+#[test_case]
+fn files_and_directories_are_listed() {
+    let initrd = archive(&[
+        TarEntry { name: "./etc/", prefix: "", kind: b'5', data: &[] },
+        file("./etc/motd", b"hello\n"),
+    ]);
+    let entries: Vec<_> = initrd.entries().map(|entry| (entry.path, entry.kind)).collect();
+    assert_eq!(entries, [("etc", EntryKind::Directory), ("etc/motd", EntryKind::File)]);
+    assert_eq!(initrd.lookup("/etc/motd").unwrap().data, b"hello\n");
+}
+
+#[test_case]
+fn long_name_applies_to_the_next_entry() {
+    let long = "a/".repeat(60) + "file";
+    let initrd = archive(&[
+        TarEntry { name: "././@LongLink", prefix: "", kind: b'L', data: long.as_bytes() },
+        file("a/a/a", b"long"),
+        file("short", b"short"),
+    ]);
+    let paths: Vec<_> = initrd.entries().map(|entry| entry.path).collect();
+    assert_eq!(paths, [long.as_str(), "short"]);
+}
+
+#[test_case]
+fn entries_with_a_ustar_prefix_are_skipped() {
+    let initrd = archive(&[
+        file("first", b"1"),
+        TarEntry { name: "second", prefix: "dir", kind: b'0', data: b"2" },
+        file("third", b"3"),
+    ]);
+    // 連結できない名前のエントリだけを飛ばし、後ろのエントリは読める
+    let paths: Vec<_> = initrd.entries().map(|entry| entry.path).collect();
+    assert_eq!(paths, ["first", "third"]);
+    assert_eq!(initrd.lookup("third").unwrap().data, b"3");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,194 @@
+//! キー配列ごとのスキャンコードの解釈と修飾キーの状態、複数の購読者への配り方を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::task::keyboard::layout::{Layout, LAYOUTS};
+use kernel::task::keyboard;
+use kernel::task::keyboard::{DecodedKey, Hotkey, KeyCode, KeyDecoder, KeyEvent, KeyState, Modifiers};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+// スキャンコードセット1
+const A: u8 = 0x1E;
+const C: u8 = 0x2E;
+const Q: u8 = 0x10;
+const DIGIT_2: u8 = 0x03;
+const DIGIT_3: u8 = 0x04;
+const SHIFT_LEFT: u8 = 0x2A;
+const CONTROL_LEFT: u8 = 0x1D;
+const ALT_LEFT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3A;
+const EXTENDED: u8 = 0xE0;
+const RELEASE: u8 = 0x80;
+
+fn press(decoder: &mut KeyDecoder, scancode: u8) -> KeyEvent {
+    decoder.add_scancode(scancode).expect("no key event")
+}
+
+fn typed(layout: Layout, scancodes: &[u8]) -> Option<char> {
+    let mut decoder = KeyDecoder::new(layout);
+    scancodes.iter().filter_map(|&scancode| decoder.add_scancode(scancode)).last()?.char()
+}
+
+#[test_case]
+fn press_and_release_with_shift() {
+    let mut decoder = KeyDecoder::new(Layout::Us);
+    let event = press(&mut decoder, A);
+    assert_eq!((event.code, event.state, event.char()), (KeyCode::A, KeyState::Down, Some('a')));
+    assert!(event.is_press());
+
+    let event = press(&mut decoder, SHIFT_LEFT);
+    assert!(event.modifiers.shift);
+    assert_eq!(event.key, None);
+    assert_eq!(press(&mut decoder, A).char(), Some('A'));
+
+    // 離したときは文字にならない
+    let event = press(&mut decoder, A | RELEASE);
+    assert_eq!((event.state, event.key), (KeyState::Up, None));
+    assert!(!press(&mut decoder, SHIFT_LEFT | RELEASE).modifiers.shift);
+}
+
+#[test_case]
+fn caps_lock_toggles_on_press() {
+    let mut decoder = KeyDecoder::new(Layout::Us);
+    assert!(press(&mut decoder, CAPS_LOCK).modifiers.caps_lock);
+    assert!(press(&mut decoder, CAPS_LOCK | RELEASE).modifiers.caps_lock);
+    assert_eq!(press(&mut decoder, A).char(), Some('A'));
+    press(&mut decoder, CAPS_LOCK);
+    assert!(!decoder.modifiers().caps_lock);
+}
+
+#[test_case]
+fn ctrl_and_letter_become_control_character() {
+    assert_eq!(typed(Layout::Us, &[CONTROL_LEFT, C]), Some('\u{3}'));
+    let mut decoder = KeyDecoder::new(Layout::Us);
+    press(&mut decoder, CONTROL_LEFT);
+    assert!(press(&mut decoder, C).modifiers.ctrl);
+}
+
+#[test_case]
+fn left_and_right_alt_are_distinguished() {
+    let mut decoder = KeyDecoder::new(Layout::Us);
+    let event = press(&mut decoder, ALT_LEFT);
+    assert!(event.modifiers.alt && !event.modifiers.alt_gr);
+    let event = press(&mut decoder, A);
+    assert!(event.modifiers.alt);
+    press(&mut decoder, ALT_LEFT | RELEASE);
+
+    // 右のAltはE0で始まる
+    assert_eq!(decoder.add_scancode(EXTENDED), None);
+    let event = press(&mut decoder, ALT_LEFT);
+    assert_eq!(event.code, KeyCode::AltRight);
+    assert!(event.modifiers.alt_gr && !event.modifiers.alt);
+}
+
+#[test_case]
+fn layouts_map_keys_differently() {
+    assert_eq!(typed(Layout::Us, &[Q]), Some('q'));
+    assert_eq!(typed(Layout::Dvorak, &[Q]), Some('\''));
+    assert_eq!(typed(Layout::Azerty, &[Q]), Some('a'));
+    assert_eq!(typed(Layout::Us, &[SHIFT_LEFT, DIGIT_2]), Some('@'));
+    assert_eq!(typed(Layout::Jis, &[SHIFT_LEFT, DIGIT_2]), Some('"'));
+    assert_eq!(typed(Layout::Uk, &[SHIFT_LEFT, DIGIT_3]), Some('£'));
+}
+
+#[test_case]
+fn switching_layout_releases_modifiers() {
+    let mut decoder = KeyDecoder::new(Layout::Us);
+    press(&mut decoder, SHIFT_LEFT);
+    decoder.set_layout(Layout::Azerty);
+    assert_eq!(decoder.layout(), Layout::Azerty);
+    assert!(!decoder.modifiers().shift);
+    assert_eq!(press(&mut decoder, Q).char(), Some('a'));
+}
+
+#[test_case]
+fn layouts_are_found_by_name() {
+    for layout in LAYOUTS.iter() {
+        assert_eq!(Layout::from_name(layout.name()), Some(*layout));
+    }
+    assert_eq!(Layout::from_name("jis"), Some(Layout::Jis));
+    assert_eq!(Layout::from_name("qwertz"), None);
+}
+
+fn key_event(code: KeyCode, state: KeyState, modifiers: Modifiers) -> KeyEvent {
+    let key = match (code, state) {
+        (KeyCode::A, KeyState::Down) => Some(DecodedKey::Unicode('a')),
+        _ => None,
+    };
+    KeyEvent { code, state, modifiers, key }
+}
+
+#[test_case]
+fn only_focused_subscriber_receives_input() {
+    let first = keyboard::subscribe();
+    let second = keyboard::subscribe();
+    assert!(first.is_focused() && !second.is_focused());
+
+    keyboard::publish(key_event(KeyCode::A, KeyState::Down, Modifiers::default()));
+    assert_eq!(first.try_next().and_then(|event| event.char()), Some('a'));
+    assert_eq!(second.try_next(), None);
+
+    second.focus();
+    keyboard::publish(key_event(KeyCode::A, KeyState::Up, Modifiers::default()));
+    assert_eq!(first.try_next(), None);
+    assert_eq!(second.try_next().map(|event| event.state), Some(KeyState::Up));
+
+    // フォーカスを持つストリームを捨てると、残っているものに移る
+    drop(second);
+    assert_eq!(keyboard::focused(), Some(first.id()));
+}
+
+#[test_case]
+fn hotkeys_are_delivered_regardless_of_focus() {
+    let focused = keyboard::subscribe();
+    let hotkeys = keyboard::subscribe_hotkeys(&[Hotkey::new(KeyCode::F1), Hotkey::new(KeyCode::A).with_ctrl()]);
+    let ctrl = Modifiers { ctrl: true, ..Modifiers::default() };
+
+    keyboard::publish(key_event(KeyCode::F1, KeyState::Down, Modifiers::default()));
+    keyboard::publish(key_event(KeyCode::A, KeyState::Down, ctrl));
+    assert_eq!(hotkeys.try_next().map(|event| event.code), Some(KeyCode::F1));
+    assert_eq!(hotkeys.try_next().map(|event| event.code), Some(KeyCode::A));
+    assert_eq!(focused.try_next(), None);
+
+    // 修飾キーが違うものや、離したときはホットキーではない
+    keyboard::publish(key_event(KeyCode::A, KeyState::Down, Modifiers::default()));
+    keyboard::publish(key_event(KeyCode::F1, KeyState::Up, Modifiers::default()));
+    assert_eq!(hotkeys.try_next(), None);
+    assert_eq!(focused.try_next().map(|event| event.code), Some(KeyCode::A));
+    assert_eq!(focused.try_next().map(|event| event.code), Some(KeyCode::F1));
+    assert!(!hotkeys.is_focused());
+}
+
+#[test_case]
+fn monitor_receives_everything() {
+    let focused = keyboard::subscribe();
+    let hotkeys = keyboard::subscribe_hotkeys(&[Hotkey::new(KeyCode::F1)]);
+    let monitor = keyboard::monitor();
+    keyboard::publish(key_event(KeyCode::F1, KeyState::Down, Modifiers::default()));
+    keyboard::publish(key_event(KeyCode::A, KeyState::Down, Modifiers::default()));
+    assert_eq!(monitor.try_next().map(|event| event.code), Some(KeyCode::F1));
+    assert_eq!(monitor.try_next().map(|event| event.code), Some(KeyCode::A));
+    assert_eq!(focused.try_next().map(|event| event.code), Some(KeyCode::A));
+    assert_eq!(hotkeys.try_next().map(|event| event.code), Some(KeyCode::F1));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,136 @@
+//! ロガーのレベルの絞り方と出力先、`dmesg`のリングバッファを確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use bootloader_api::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use kernel::logger;
+use kernel::logger::filter::LevelFilters;
+use kernel::logger::ring_buffer::RingBuffer;
+use kernel::logger::Output;
+use kernel::BOOTLOADER_CONFIG;
+use log::LevelFilter;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn longest_module_filter_wins() {
+    let mut filters = LevelFilters::new(LevelFilter::Info);
+    filters.set_module("kernel::usb", LevelFilter::Trace);
+    filters.set_module("kernel::usb::xhci", LevelFilter::Error);
+    assert_eq!(filters.level_for("kernel::usb::hid"), LevelFilter::Trace);
+    assert_eq!(filters.level_for("kernel::usb::xhci::ring"), LevelFilter::Error);
+    assert_eq!(filters.level_for("kernel::usb"), LevelFilter::Trace);
+    // 名前の途中で切れるものは当てはまらない
+    assert_eq!(filters.level_for("kernel::usbx"), LevelFilter::Info);
+    assert_eq!(filters.max_level(), LevelFilter::Trace);
+
+    filters.set_module("kernel::usb", LevelFilter::Warn);
+    assert_eq!(filters.modules().len(), 2);
+    assert!(filters.remove_module("kernel::usb"));
+    assert!(!filters.remove_module("kernel::usb"));
+    assert_eq!(filters.level_for("kernel::usb::hid"), LevelFilter::Info);
+    assert_eq!(filters.max_level(), LevelFilter::Info);
+}
+
+#[test_case]
+fn ring_buffer_keeps_the_newest_whole_lines() {
+    let mut buffer = RingBuffer::<16>::new();
+    assert!(buffer.is_empty());
+    write!(buffer, "one\ntwo\n").unwrap();
+    assert_eq!(buffer.read(), b"one\ntwo\n");
+
+    buffer.push(b"three\nfour\n");
+    assert_eq!(buffer.len(), 16);
+    // 上書きされたのは`one`だけなので、`two`は残る
+    assert_eq!(buffer.read(), b"two\nthree\nfour\n");
+    // `two`の途中まで上書きされたら、その行は捨てる
+    writeln!(buffer, "x").unwrap();
+    assert_eq!(buffer.read(), b"three\nfour\nx\n");
+
+    buffer.clear();
+    assert!(buffer.read().is_empty());
+}
+
+#[test_case]
+fn records_are_kept_for_dmesg() {
+    logger::clear_dmesg();
+    log::warn!("disk is {} full", "almost");
+    let dmesg = logger::dmesg();
+    assert!(dmesg.contains("WARN  logger: disk is almost full\n"));
+    assert!(dmesg.starts_with('['));
+}
+
+#[test_case]
+fn module_levels_filter_records() {
+    logger::clear_dmesg();
+    logger::set_module_level("logger", LevelFilter::Error);
+    log::warn!("hidden");
+    log::error!("shown");
+    assert!(logger::reset_module_level("logger"));
+
+    let dmesg = logger::dmesg();
+    assert!(!dmesg.contains("hidden"));
+    assert!(dmesg.contains("shown"));
+    assert!(logger::module_levels().is_empty());
+}
+
+#[test_case]
+fn default_level_sets_the_max_level() {
+    let previous = logger::level();
+    logger::set_level(LevelFilter::Trace);
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+    logger::set_module_level("kernel::usb", LevelFilter::Trace);
+    logger::set_level(LevelFilter::Warn);
+    // モジュールのレベルのほうが詳しいので、全体ではそちらまで通す
+    assert_eq!(log::max_level(), LevelFilter::Trace);
+    logger::reset_module_level("kernel::usb");
+    assert_eq!(log::max_level(), LevelFilter::Warn);
+    logger::set_level(previous);
+}
+
+#[test_case]
+fn output_levels_can_be_changed() {
+    let previous = logger::output_level(Output::Screen);
+    logger::set_output_level(Output::Screen, LevelFilter::Off);
+    assert_eq!(logger::output_level(Output::Screen), LevelFilter::Off);
+    // 画面に出さなくても、リングバッファには残る
+    logger::clear_dmesg();
+    log::error!("not on screen");
+    assert!(logger::dmesg().contains("not on screen"));
+    logger::set_output_level(Output::Screen, previous);
+    assert_eq!(Output::from_name("serial"), Some(Output::Serial));
+    assert_eq!(Output::from_name("printer"), None);
+}
+
+#[test_case]
+fn shell_log_command_changes_levels() {
+    let mut out = String::new();
+    kernel::shell::command::execute(&mut out, "log level kernel::block trace").unwrap();
+    kernel::shell::command::execute(&mut out, "log level").unwrap();
+    assert!(out.contains("kernel::block TRACE"));
+    kernel::shell::command::execute(&mut out, "log level kernel::block default").unwrap();
+    assert!(logger::module_levels().is_empty());
+    kernel::shell::command::execute(&mut out, "log output serial warn").unwrap();
+    assert_eq!(logger::output_level(Output::Serial), LevelFilter::Warn);
+    logger::set_output_level(Output::Serial, LevelFilter::Trace);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
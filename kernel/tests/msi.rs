@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::memory::mmio;
+use kernel::{apic, interrupts, BOOTLOADER_CONFIG};
+use pci::capability::{Msi, MsiX, CAP_MSI, CAP_MSI_X};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn capability_lists_are_well_formed() {
+    for device in pci::devices() {
+        for capability in device.capabilities() {
+            // 標準のケーパビリティはヘッダの後ろ、256バイト以内に4バイト境界で置かれる
+            assert!((0x40..0x100).contains(&capability.offset), "{}.{}.{} {:#x}",
+                    device.bus, device.device, device.function, capability.offset);
+            assert_eq!(capability.offset % 4, 0);
+        }
+        for capability in device.extended_capabilities() {
+            assert!((0x100..0x1000).contains(&capability.offset));
+        }
+        assert_eq!(Msi::read(&device).is_some(), device.find_capability(CAP_MSI).is_some());
+        if let Some(msi_x) = MsiX::read(&device) {
+            assert!(device.find_capability(CAP_MSI_X).is_some());
+            assert!(device.bar(msi_x.table_bar).map(|bar| bar.is_memory()).unwrap_or(false));
+        }
+    }
+}
+
+#[test_case]
+fn bar_is_mapped_only_once() {
+    // MSI-Xのテーブルをマップするときも、ドライバがマップした領域をそのまま使う
+    let (device, index, first) = pci::devices()
+        .into_iter()
+        .find_map(|device| (0..6).find_map(|index| Some((device, index, mmio::map_bar(&device, index).ok()?))))
+        .expect("no device has a memory BAR");
+    let second = mmio::map_bar(&device, index).unwrap();
+    assert_eq!(first.base(), second.base());
+    assert_eq!(first.size(), second.size());
+}
+
+static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+fn count_interrupt() {
+    RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[test_case]
+fn allocated_vector_is_routed_to_handler() {
+    let vector = apic::allocate_vector(count_interrupt).expect("no free vector");
+    assert!(vector >= apic::DYNAMIC_VECTOR_START);
+
+    // 8259 PICのIRQがCPU例外のベクタに届かないように、先に付け替えておく
+    unsafe { interrupts::PICS.lock().initialize() };
+    x86_64::instructions::interrupts::enable();
+    apic::send_self_ipi(vector);
+    for _ in 0..1_000_000 {
+        if RECEIVED.load(Ordering::SeqCst) != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    x86_64::instructions::interrupts::disable();
+    assert_eq!(RECEIVED.load(Ordering::SeqCst), 1);
+
+    // 解放したベクタは次に確保したときにまた使われる
+    apic::free_vector(vector);
+    assert_eq!(apic::allocate_vector(count_interrupt), Some(vector));
+    apic::free_vector(vector);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::BOOTLOADER_CONFIG;
+use pci::device::{ClassCode, Device, HeaderType};
+use pci::register::Command;
+use pci::{DeviceMatch, Driver};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+fn host_bridge() -> Device {
+    pci::devices()
+        .into_iter()
+        .find(|device| (device.bus, device.device, device.function) == (0, 0, 0))
+        .expect("host bridge not found")
+}
+
+#[test_case]
+fn host_bridge_is_found() {
+    let host = host_bridge();
+    assert!(host.class_code.equal_bs(0x06, 0x00));
+    assert_eq!(host.header_kind(), HeaderType::Endpoint);
+    assert_ne!(host.vendor_id, 0xFFFF);
+}
+
+#[test_case]
+fn lookup_by_class_and_id() {
+    let host = host_bridge();
+    let by_class = pci::find_by_class(0x06, 0x00, None);
+    assert!(by_class.iter().any(|device| device.device == host.device && device.bus == host.bus));
+    let by_id = pci::find_by_id(host.vendor_id, host.device_id);
+    assert!(by_id.iter().all(|device| device.vendor_id == host.vendor_id));
+    assert!(!by_id.is_empty());
+    assert!(pci::find_by_id(0xFFFF, 0xFFFF).is_empty());
+}
+
+#[test_case]
+fn device_match_conditions() {
+    let device = Device {
+        class_code: ClassCode::new(0x01, 0x06, 0x01),
+        vendor_id: 0x8086,
+        device_id: 0x2922,
+        ..Device::default()
+    };
+    assert!(DeviceMatch::Class { base: 0x01, sub: 0x06, interface: None }.matches(&device));
+    assert!(DeviceMatch::Class { base: 0x01, sub: 0x06, interface: Some(0x01) }.matches(&device));
+    assert!(!DeviceMatch::Class { base: 0x01, sub: 0x06, interface: Some(0x00) }.matches(&device));
+    assert!(DeviceMatch::Id { vendor: 0x8086, device: 0x2922 }.matches(&device));
+    assert!(!DeviceMatch::Id { vendor: 0x8086, device: 0x2923 }.matches(&device));
+}
+
+#[test_case]
+fn bars_are_naturally_aligned_powers_of_two() {
+    // ドライバが使っているデバイスもあるので、走査のときに調べた値を見る。BARの値は変わらない
+    for device in pci::devices() {
+        let raw: Vec<u32> = (0..6).map(|index| device.read_conf_reg(0x10 + index * 4)).collect();
+        for bar in device.bars() {
+            assert!(bar.size.is_power_of_two(), "{}.{}.{} BAR{}", device.bus, device.device, device.function, bar.index);
+            assert_eq!(bar.address % bar.size, 0);
+        }
+        assert!((0..6).all(|index| device.read_conf_reg(0x10 + index * 4) == raw[index as usize]));
+    }
+}
+
+#[test_case]
+fn narrow_reads_match_dword_reads() {
+    for device in pci::devices() {
+        let ids = device.read_conf_reg(0x00);
+        assert_eq!(device.read_conf_reg16(0x00), ids as u16);
+        assert_eq!(device.read_conf_reg16(0x02), (ids >> 16) as u16);
+        assert_eq!(device.read_conf_reg8(0x0B), device.class_code.base());
+        assert_eq!(device.read_conf_reg8(0x08), device.revision);
+    }
+}
+
+#[test_case]
+fn bound_dma_drivers_enable_bus_mastering() {
+    for device in pci::devices() {
+        if let Some("ahci" | "nvme" | "virtio-blk") = pci::bound_driver(&device) {
+            assert!(device.command().contains(Command::MEMORY_SPACE | Command::BUS_MASTER));
+        }
+    }
+}
+
+#[test_case]
+fn lspci_lists_every_device() {
+    let devices = pci::devices();
+    let text = pci::lspci(false);
+    assert_eq!(text.lines().count(), devices.len());
+    assert!(text.lines().next().unwrap().starts_with("00:00.0 Host bridge [0600]"));
+    assert!(pci::lspci(true).contains("\tControl: "));
+}
+
+static DECLINED: AtomicUsize = AtomicUsize::new(0);
+static ACCEPTED: AtomicUsize = AtomicUsize::new(0);
+
+static DECLINING_DRIVER: Driver = Driver {
+    name: "declining",
+    matches: &[DeviceMatch::Class { base: 0x06, sub: 0x00, interface: None }],
+    probe: |_| {
+        DECLINED.fetch_add(1, Ordering::Relaxed);
+        false
+    },
+};
+
+static ACCEPTING_DRIVER: Driver = Driver {
+    name: "accepting",
+    matches: &[DeviceMatch::Class { base: 0x06, sub: 0x00, interface: None }],
+    probe: |_| {
+        ACCEPTED.fetch_add(1, Ordering::Relaxed);
+        true
+    },
+};
+
+#[test_case]
+fn probe_falls_through_to_next_driver() {
+    pci::register_driver(&DECLINING_DRIVER);
+    pci::register_driver(&ACCEPTING_DRIVER);
+    pci::probe();
+    assert_eq!(pci::bound_driver(&host_bridge()), Some("accepting"));
+
+    // 結び付いたデバイスはもう一度probeしても試されない
+    let accepted = ACCEPTED.load(Ordering::Relaxed);
+    pci::probe();
+    assert_eq!(ACCEPTED.load(Ordering::Relaxed), accepted);
+    assert!(DECLINED.load(Ordering::Relaxed) >= 1);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,106 @@
+//! QEMUの`q35`マシン(`-machine q35`)で、PCIの走査結果とECAMを確かめる
+//! テストランナーがこのテストだけ`-machine q35`で起動する
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::acpi_table;
+use kernel::BOOTLOADER_CONFIG;
+use pci::config::{self, ConfigAccess, PortIo, EXTENDED_CONFIG_SIZE};
+use pci::device::{Device, Header};
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+/// Q35のMCH(ホストブリッジ)のデバイスID
+const Q35_HOST_BRIDGE: (u16, u16) = (0x8086, 0x29C0);
+
+fn find(bus: u8, device: u8, function: u8) -> Option<Device> {
+    pci::devices()
+        .into_iter()
+        .find(|found| (found.bus, found.device, found.function) == (bus, device, function))
+}
+
+fn is_q35() -> bool {
+    find(0, 0, 0).map(|host| (host.vendor_id, host.device_id)) == Some(Q35_HOST_BRIDGE)
+}
+
+#[test_case]
+fn every_function_is_found_once() {
+    let devices = pci::devices();
+    let mut locations: Vec<(u8, u8, u8)> = devices
+        .iter()
+        .map(|device| (device.bus, device.device, device.function))
+        .collect();
+    locations.sort_unstable();
+    locations.dedup();
+    assert_eq!(locations.len(), devices.len());
+}
+
+#[test_case]
+fn devices_behind_bridges_are_in_bridge_ranges() {
+    let devices = pci::devices();
+    let ranges: Vec<(u8, u8)> = devices
+        .iter()
+        .filter_map(|device| match device.header() {
+            Header::PciBridge { secondary_bus, subordinate_bus, .. } => Some((secondary_bus, subordinate_bus)),
+            _ => None,
+        })
+        .collect();
+    for device in devices.iter().filter(|device| device.bus != 0) {
+        assert!(ranges.iter().any(|&(secondary, subordinate)| (secondary..=subordinate).contains(&device.bus)),
+                "{}.{}.{} is not behind any bridge", device.bus, device.device, device.function);
+    }
+}
+
+#[test_case]
+fn q35_topology() {
+    assert!(is_q35(), "pci_q35 must be run with -machine q35");
+    // ICH9のLPC、AHCI、SMBusは00:1fのファンクション0, 2, 3にある
+    let expected = [
+        ((0x1F, 0), (0x8086, 0x2918), (0x06, 0x01)),
+        ((0x1F, 2), (0x8086, 0x2922), (0x01, 0x06)),
+        ((0x1F, 3), (0x8086, 0x2930), (0x0C, 0x05)),
+    ];
+    for ((device, function), ids, (base, sub)) in expected {
+        let found = find(0, device, function).expect("ICH9 function is missing");
+        assert_eq!((found.vendor_id, found.device_id), ids);
+        assert!(found.class_code.equal_bs(base, sub));
+    }
+    assert!(find(0, 0x1F, 0).unwrap().is_multi_function());
+
+    let region = acpi_table::pci_config_region(0).expect("q35 has an MCFG table");
+    assert_eq!(region.start_bus, 0);
+    assert_eq!(config::access().config_size(), EXTENDED_CONFIG_SIZE);
+}
+
+#[test_case]
+fn ecam_matches_port_io() {
+    assert_eq!(config::access().config_size(), EXTENDED_CONFIG_SIZE, "ECAM is not in use");
+    // 読んでも状態が変わらないレジスタだけを比べる
+    let offsets = [0x00, 0x08, 0x0C, 0x10, 0x14, 0x18, 0x1C, 0x20, 0x24, 0x2C, 0x34, 0x3C];
+    for device in pci::devices() {
+        for &offset in offsets.iter() {
+            assert_eq!(device.read_conf_reg(offset), PortIo.read(device.bus, device.device, device.function, offset),
+                       "{}.{}.{} offset {:#x}", device.bus, device.device, device.function, offset);
+        }
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
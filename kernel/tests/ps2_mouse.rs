@@ -0,0 +1,125 @@
+//! PS/2マウスのパケットの解釈と、カーソルが画面に重ねて描かれることを確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::frame_buffer_writer::cursor::MouseCursor;
+use kernel::frame_buffer_writer::pixel_color::PixelColor;
+use kernel::frame_buffer_writer::vector2d::Vector2D;
+use kernel::frame_buffer_writer::FRAME_BUFFER_WRITER;
+use kernel::ps2::mouse::{parse_packet, PacketDecoder};
+use kernel::task::mouse::{MouseEvent, BUTTON_LEFT, BUTTON_RIGHT};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn movement_and_buttons_are_decoded() {
+    // 右へ5、上へ3。PS/2のY軸は上向きが正
+    assert_eq!(
+        parse_packet(&[0x08 | BUTTON_LEFT, 5, 3]),
+        MouseEvent { dx: 5, dy: -3, wheel: 0, buttons: BUTTON_LEFT }
+    );
+    // 符号ビットが立っていれば負の値。左へ2、下へ1
+    assert_eq!(
+        parse_packet(&[0x08 | 0x10 | 0x20 | BUTTON_RIGHT, 0xFE, 0xFF]),
+        MouseEvent { dx: -2, dy: 1, wheel: 0, buttons: BUTTON_RIGHT }
+    );
+    // あふれたらボタンだけを伝える
+    assert_eq!(
+        parse_packet(&[0x08 | 0x40 | BUTTON_LEFT, 0xFF, 0]),
+        MouseEvent { dx: 0, dy: 0, wheel: 0, buttons: BUTTON_LEFT }
+    );
+}
+
+#[test_case]
+fn wheel_is_read_from_fourth_byte() {
+    assert_eq!(parse_packet(&[0x08, 0, 0, 0x01]).wheel, 1);
+    assert_eq!(parse_packet(&[0x08, 0, 0, 0x0F]).wheel, -1);
+    // 上位4ビットは5ボタンのマウスが使うので、回転量には含めない
+    assert_eq!(parse_packet(&[0x08, 0, 0, 0x3F]).wheel, -1);
+}
+
+#[test_case]
+fn decoder_waits_for_whole_packet() {
+    let mut decoder = PacketDecoder::new(true);
+    assert_eq!(decoder.packet_size(), 4);
+    assert_eq!(decoder.push(0x08), None);
+    assert_eq!(decoder.push(1), None);
+    assert_eq!(decoder.push(2), None);
+    assert_eq!(decoder.push(0x0F), Some(MouseEvent { dx: 1, dy: -2, wheel: -1, buttons: 0 }));
+}
+
+#[test_case]
+fn decoder_resynchronizes_on_first_byte() {
+    let mut decoder = PacketDecoder::new(false);
+    // ビット3が立っていないバイトはパケットの先頭ではない
+    assert_eq!(decoder.push(0x00), None);
+    assert_eq!(decoder.push(0x08), None);
+    assert_eq!(decoder.push(0x00), None);
+    assert_eq!(decoder.push(0x00), Some(MouseEvent::default()));
+}
+
+#[test_case]
+fn cursor_is_drawn_over_the_screen_only() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    let background = PixelColor::new(12, 34, 56);
+    writer.fill_rectangle(Vector2D::new(0, 0), Vector2D::new(40, 40), background);
+
+    let mut cursor = MouseCursor::new();
+    cursor.move_to(&mut writer, Vector2D::new(5, 5));
+    cursor.show(&mut writer);
+    writer.flush();
+    // 先端は黒い縁、その内側は白
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(5, 5)), Some(PixelColor::black()));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(5, 7)), Some(PixelColor::black()));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(6, 7)), Some(PixelColor::white()));
+    // 裏の画面には描かない
+    assert_eq!(writer.read_pixel(Vector2D::new(6, 7)), Some(background));
+
+    cursor.move_by(&mut writer, 10, 0);
+    writer.flush();
+    assert_eq!(cursor.position(), Vector2D::new(15, 5));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(5, 5)), Some(background));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(6, 7)), Some(background));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(16, 7)), Some(PixelColor::white()));
+
+    // カーソルの下に描いたものは、消したときにそのまま見える
+    let drawn = PixelColor::new(78, 90, 12);
+    writer.fill_rectangle(Vector2D::new(15, 5), Vector2D::new(10, 10), drawn);
+    cursor.hide(&mut writer);
+    writer.flush();
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(15, 5)), Some(drawn));
+    assert_eq!(writer.read_screen_pixel(Vector2D::new(16, 7)), Some(drawn));
+}
+
+#[test_case]
+fn cursor_stays_on_screen() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    let (width, height) = (writer.info.width, writer.info.height);
+    let mut cursor = MouseCursor::new();
+    cursor.show(&mut writer);
+    cursor.move_by(&mut writer, -10, -10);
+    assert_eq!(cursor.position(), Vector2D::new(0, 0));
+    cursor.move_to(&mut writer, Vector2D::new(width + 100, height + 100));
+    assert_eq!(cursor.position(), Vector2D::new(width - 1, height - 1));
+    cursor.hide(&mut writer);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,293 @@
+//! シェルの行編集と履歴、補完、組み込みコマンド、シリアル端末からの入力と、それを支えるACPIの解析とタスクの一覧を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use kernel::acpi_table;
+use kernel::shell::command;
+use kernel::shell::line_editor::{Action, EditKey, LineEditor, HISTORY_SIZE};
+use kernel::shell::serial::InputDecoder;
+use kernel::shell::{Console, Shell, PROMPT};
+use kernel::task::{self, Task};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+fn type_str(editor: &mut LineEditor, s: &str) {
+    for character in s.chars() {
+        editor.handle_key(EditKey::Char(character));
+    }
+}
+
+fn submit(editor: &mut LineEditor, s: &str) {
+    type_str(editor, s);
+    assert_eq!(editor.handle_key(EditKey::Enter), Action::Submit(s.into()));
+}
+
+/// 画面の代わりに、最後の行の内容とカーソルの位置を覚える
+#[derive(Default)]
+struct RecordingConsole {
+    output: String,
+    line: String,
+    column: usize,
+}
+
+impl Write for RecordingConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.output.push_str(s);
+        for character in s.chars() {
+            match character {
+                '\n' => self.line.clear(),
+                character => self.line.push(character),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Console for RecordingConsole {
+    fn clear_line(&mut self) {
+        self.line.clear();
+    }
+
+    fn set_column(&mut self, column: usize) {
+        self.column = column;
+    }
+}
+
+#[test_case]
+fn editing_in_the_middle_of_the_line() {
+    let mut editor = LineEditor::new();
+    type_str(&mut editor, "lsb");
+    assert_eq!(editor.handle_key(EditKey::Left), Action::Redraw);
+    type_str(&mut editor, "u");
+    assert_eq!(editor.line(), "lsub");
+    assert_eq!(editor.before_cursor(), "lsu");
+
+    editor.handle_key(EditKey::Home);
+    assert_eq!(editor.handle_key(EditKey::Left), Action::None);
+    assert_eq!(editor.handle_key(EditKey::Backspace), Action::None);
+    editor.handle_key(EditKey::Delete);
+    assert_eq!(editor.line(), "sub");
+    editor.handle_key(EditKey::End);
+    editor.handle_key(EditKey::Backspace);
+    assert_eq!((editor.line().as_str(), editor.cursor()), ("su", 2));
+}
+
+#[test_case]
+fn history_keeps_the_line_being_typed() {
+    let mut editor = LineEditor::new();
+    submit(&mut editor, "mem");
+    submit(&mut editor, "ps");
+    type_str(&mut editor, "up");
+
+    editor.handle_key(EditKey::Up);
+    assert_eq!(editor.line(), "ps");
+    editor.handle_key(EditKey::Up);
+    assert_eq!((editor.line().as_str(), editor.cursor()), ("mem", 3));
+    assert_eq!(editor.handle_key(EditKey::Up), Action::None);
+    editor.handle_key(EditKey::Down);
+    editor.handle_key(EditKey::Down);
+    assert_eq!(editor.line(), "up");
+    assert_eq!(editor.handle_key(EditKey::Down), Action::None);
+}
+
+#[test_case]
+fn history_skips_blank_and_repeated_lines() {
+    let mut editor = LineEditor::new();
+    submit(&mut editor, "ps");
+    submit(&mut editor, "ps");
+    submit(&mut editor, "  ");
+    assert_eq!(editor.history().collect::<Vec<_>>(), vec!["ps"]);
+
+    for i in 0..HISTORY_SIZE + 1 {
+        submit(&mut editor, &alloc::format!("echo {}", i));
+    }
+    assert_eq!(editor.history().count(), HISTORY_SIZE);
+    assert_eq!(editor.history().next(), Some("echo 1"));
+}
+
+#[test_case]
+fn cancel_discards_the_line() {
+    let mut editor = LineEditor::new();
+    type_str(&mut editor, "reboot");
+    assert_eq!(editor.handle_key(EditKey::Cancel), Action::Cancel);
+    assert_eq!((editor.line().as_str(), editor.cursor()), ("", 0));
+    assert_eq!(editor.history().count(), 0);
+}
+
+#[test_case]
+fn commands_are_completed_by_prefix() {
+    let mut candidates = command::complete("ls");
+    candidates.sort();
+    assert_eq!(candidates, vec!["ls ", "lspci ", "lsusb "]);
+    assert_eq!(command::complete("upt"), vec!["uptime "]);
+    assert_eq!(command::complete("log "), vec!["level ", "output "]);
+    assert_eq!(command::complete("log output s"), vec!["serial ", "screen "]);
+    assert_eq!(command::complete("log level w"), vec!["warn "]);
+    assert!(command::complete("nothing ").is_empty());
+}
+
+#[test_case]
+fn unknown_command_is_reported() {
+    let mut out = String::new();
+    command::execute(&mut out, "frobnicate now").unwrap();
+    assert!(out.starts_with("unknown command: frobnicate"));
+
+    out.clear();
+    command::execute(&mut out, "   ").unwrap();
+    assert!(out.is_empty());
+}
+
+#[test_case]
+fn help_lists_every_command() {
+    let mut out = String::new();
+    command::execute(&mut out, "help").unwrap();
+    for command in command::COMMANDS {
+        assert!(out.contains(command.usage));
+    }
+}
+
+#[test_case]
+fn log_level_can_be_changed() {
+    let previous = kernel::logger::level();
+    let mut out = String::new();
+    command::execute(&mut out, "log level warn").unwrap();
+    assert_eq!(log::max_level(), log::LevelFilter::Warn);
+    command::execute(&mut out, "log level").unwrap();
+    assert_eq!(out, "WARN\n");
+    command::execute(&mut out, "log level loud").unwrap();
+    assert!(out.contains("unknown level"));
+    kernel::logger::set_level(previous);
+}
+
+#[test_case]
+fn information_commands_do_not_fail() {
+    let mut out = String::new();
+    for line in ["mem", "uptime", "ps", "lspci", "lsusb", "ls /", "cat"] {
+        command::execute(&mut out, line).unwrap();
+    }
+    assert!(out.contains("heap:"));
+    assert!(out.contains("up "));
+}
+
+#[test_case]
+fn shell_redraws_the_prompt_and_completes() {
+    let mut shell = Shell::new(RecordingConsole::default());
+    shell.start();
+    assert_eq!(shell.console().line, PROMPT);
+
+    for character in "upt".chars() {
+        shell.handle_key(EditKey::Char(character));
+    }
+    shell.handle_key(EditKey::Tab);
+    assert_eq!(shell.editor().line(), "uptime ");
+    shell.handle_key(EditKey::Home);
+    assert_eq!(shell.console().column, PROMPT.len());
+
+    shell.handle_key(EditKey::Enter);
+    assert!(shell.console().output.contains("\nup "));
+    assert_eq!(shell.console().line, PROMPT);
+}
+
+#[test_case]
+fn ambiguous_completion_lists_candidates() {
+    let mut shell = Shell::new(RecordingConsole::default());
+    shell.handle_key(EditKey::Char('l'));
+    shell.handle_key(EditKey::Tab);
+    // `ls`、`lspci`、`lsusb`、`log`に共通するのは`l`だけ
+    assert_eq!(shell.editor().line(), "l");
+    assert!(shell.console().output.contains("lsusb  "));
+}
+
+fn decode(bytes: &[u8]) -> Vec<EditKey> {
+    let mut decoder = InputDecoder::new();
+    bytes.iter().filter_map(|&byte| decoder.push(byte)).collect()
+}
+
+#[test_case]
+fn serial_input_is_decoded_into_keys() {
+    assert_eq!(decode(b"ls\t\x7f\x03"), vec![
+        EditKey::Char('l'),
+        EditKey::Char('s'),
+        EditKey::Tab,
+        EditKey::Backspace,
+        EditKey::Cancel,
+    ]);
+    // `\r\n`も`\r`も`\n`も一回のEnter
+    assert_eq!(decode(b"\r\na\rb\n"), vec![
+        EditKey::Enter,
+        EditKey::Char('a'),
+        EditKey::Enter,
+        EditKey::Char('b'),
+        EditKey::Enter,
+    ]);
+    assert_eq!(decode("é→".as_bytes()), vec![EditKey::Char('é'), EditKey::Char('→')]);
+}
+
+#[test_case]
+fn serial_escape_sequences_are_decoded() {
+    assert_eq!(decode(b"\x1b[A\x1b[B\x1b[C\x1b[D"), vec![EditKey::Up, EditKey::Down, EditKey::Right, EditKey::Left]);
+    assert_eq!(decode(b"\x1bOH\x1b[F\x1b[1~\x1b[4~\x1b[3~"), vec![
+        EditKey::Home,
+        EditKey::End,
+        EditKey::Home,
+        EditKey::End,
+        EditKey::Delete,
+    ]);
+    // 修飾キーの引数は無視し、知らないシーケンスは読み捨てる
+    assert_eq!(decode(b"\x1b[1;5C\x1b[15~x"), vec![EditKey::Right, EditKey::Char('x')]);
+}
+
+#[test_case]
+fn tasks_are_listed_until_dropped() {
+    async fn sample_task() {}
+
+    let task = Task::new(sample_task());
+    let listed = task::tasks().into_iter().find(|info| info.name.ends_with("sample_task"));
+    assert_eq!(listed.map(|info| info.polls), Some(0));
+    drop(task);
+    assert!(task::tasks().iter().all(|info| !info.name.ends_with("sample_task")));
+}
+
+#[test_case]
+fn s5_sleep_type_is_read_from_aml() {
+    // Name(\_S5, Package(0x04) {0x05, Zero, Zero, Zero})
+    let aml = [0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00];
+    assert_eq!(acpi_table::parse_s5(&aml), Some((5, 0)));
+    // Name(_S5, Package() {Zero, One})
+    let aml = [0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x00, 0x01];
+    assert_eq!(acpi_table::parse_s5(&aml), Some((0, 1)));
+    // 名前の参照だけで定義の無いもの
+    assert_eq!(acpi_table::parse_s5(b"\x70_S5_\x12"), None);
+}
+
+#[test_case]
+fn fadt_of_this_machine_has_pm1a() {
+    if let Some(fadt) = acpi_table::fadt() {
+        assert_ne!(fadt.pm1a_control, 0);
+        assert!(acpi_table::dsdt().is_some());
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,102 @@
+//! 各ストレージコントローラに繋いだ空のディスクに、ドライバを通して読み書きできることを確かめる
+//!
+//! テストランナーが`storage`のために、AHCIのポートとNVMeのコントローラ、virtio-blkに空のディスクを繋ぐ
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::block::BlockDevice;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+/// テストランナーが繋ぐ空のディスクの大きさ
+const SCRATCH_DISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// キャッシュを通さないデバイスと、キャッシュを通すデバイス
+fn disk(name: &str) -> (Arc<dyn BlockDevice>, Arc<dyn BlockDevice>) {
+    let uncached = kernel::block::find_uncached(name).unwrap_or_else(|| panic!("{} is not attached", name));
+    let cached = kernel::block::find(name).unwrap();
+    assert_eq!(uncached.geometry().capacity(), SCRATCH_DISK_SIZE);
+    (uncached, cached)
+}
+
+/// LBAごとに違う内容になるように埋めた`sectors`セクタ分のデータ
+fn pattern(device: &dyn BlockDevice, lba: u64, sectors: usize) -> Vec<u8> {
+    (0..device.sector_size() * sectors)
+        .map(|index| (index as u64 / 7 + lba * 13) as u8)
+        .collect()
+}
+
+/// 書いたものが、そのまま読み戻せる
+fn assert_round_trip(device: &dyn BlockDevice, lba: u64, sectors: usize) {
+    let data = pattern(device, lba, sectors);
+    device.write_sectors(lba, &data).unwrap();
+    let mut buf = vec![0u8; data.len()];
+    device.read_sectors(lba, &mut buf).unwrap();
+    assert!(buf == data, "{} sectors at LBA {} were not read back", sectors, lba);
+}
+
+/// キャッシュを通して書いたものが、書き戻した後にドライバから読める
+fn assert_flushed(uncached: &dyn BlockDevice, cached: &dyn BlockDevice, lba: u64) {
+    let data = pattern(cached, lba, 1);
+    cached.write_sectors(lba, &data).unwrap();
+    cached.flush().unwrap();
+    let mut buf = vec![0u8; data.len()];
+    uncached.read_sectors(lba, &mut buf).unwrap();
+    assert!(buf == data, "LBA {} was not written back", lba);
+}
+
+#[test_case]
+fn sata_disk_reads_and_writes() {
+    let (uncached, cached) = disk("sata0");
+    // 先頭と末尾、それにバウンスバッファを何回も使う長さ
+    assert_round_trip(uncached.as_ref(), 0, 1);
+    assert_round_trip(uncached.as_ref(), uncached.sector_count() - 1, 1);
+    assert_round_trip(uncached.as_ref(), 100, 300);
+    assert_flushed(uncached.as_ref(), cached.as_ref(), 1000);
+}
+
+#[test_case]
+fn nvme_namespace_reads_and_writes() {
+    let (uncached, cached) = disk("nvme0n1");
+    assert_round_trip(uncached.as_ref(), 0, 1);
+    assert_round_trip(uncached.as_ref(), uncached.sector_count() - 1, 1);
+    // 2ページに収まる転送はPRPを2つ、超える転送はPRPリストを使う
+    let sectors_per_page = 4096 / uncached.sector_size();
+    assert_round_trip(uncached.as_ref(), 64, 2 * sectors_per_page);
+    assert_round_trip(uncached.as_ref(), 128, 3 * sectors_per_page + 1);
+    assert_round_trip(uncached.as_ref(), 256, 300);
+    assert_flushed(uncached.as_ref(), cached.as_ref(), 1000);
+}
+
+#[test_case]
+fn virtio_disk_reads_writes_and_flushes() {
+    let (uncached, cached) = disk("vda");
+    assert_round_trip(uncached.as_ref(), 0, 1);
+    assert_round_trip(uncached.as_ref(), uncached.sector_count() - 1, 1);
+    assert_round_trip(uncached.as_ref(), 100, 300);
+    uncached.flush().unwrap();
+    assert_flushed(uncached.as_ref(), cached.as_ref(), 1000);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,267 @@
+//! 画面の端末が解釈するエスケープシーケンスと、スクロールやカーソルの描画を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{entry_point, BootInfo};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use kernel::frame_buffer_writer::ansi::{palette, Action, Attributes, Color, Parameters, Parser, DEFAULT_BACKGROUND, DEFAULT_FOREGROUND};
+use kernel::frame_buffer_writer::cursor::MouseCursor;
+use kernel::frame_buffer_writer::pixel_color::PixelColor;
+use kernel::frame_buffer_writer::vector2d::Vector2D;
+use kernel::frame_buffer_writer::{FrameBufferWriter, FRAME_BUFFER_WRITER};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+/// 文字列を全部読ませて、最後の動作を返す
+fn parse(input: &str) -> Option<Action> {
+    let mut parser = Parser::new();
+    input.chars().fold(None, |_, c| parser.advance(c))
+}
+
+fn sgr(input: &str) -> Attributes {
+    let mut attributes = Attributes::new();
+    match parse(input) {
+        Some(Action::Csi { parameters, command: 'm', .. }) => attributes.apply_sgr(&parameters),
+        action => panic!("not an SGR sequence: {:?}", action),
+    }
+    attributes
+}
+
+/// 画面を初期状態に戻し、カーソルを隠す
+fn reset(writer: &mut FrameBufferWriter) {
+    write!(writer, "\x1bc\x1b[?25l").unwrap();
+}
+
+/// 文字の枠の中で、グリフの外にある左上のピクセルの色
+fn cell_color(writer: &FrameBufferWriter, column: usize, row: usize) -> PixelColor {
+    let position = writer.cell_position(column, row);
+    writer.read_pixel(position).unwrap()
+}
+
+#[test_case]
+fn parser_splits_text_and_sequences() {
+    let mut parser = Parser::new();
+    assert_eq!(parser.advance('a'), Some(Action::Print('a')));
+    assert_eq!(parser.advance('\n'), Some(Action::Control('\n')));
+    assert_eq!(parser.advance('\x1b'), None);
+    assert_eq!(parser.advance('7'), Some(Action::Escape('7')));
+
+    match parse("\x1b[12;;3H") {
+        Some(Action::Csi { private: None, parameters, command: 'H' }) => {
+            assert_eq!(parameters.len(), 3);
+            assert_eq!(parameters.get(0), 12);
+            assert_eq!(parameters.get(1), 0);
+            assert_eq!(parameters.get_or(1, 1), 1);
+            assert_eq!(parameters.get(2), 3);
+            assert_eq!(parameters.get(3), 0);
+        }
+        action => panic!("unexpected {:?}", action),
+    }
+    match parse("\x1b[?25l") {
+        Some(Action::Csi { private: Some('?'), parameters, command: 'l' }) => assert_eq!(parameters.get(0), 25),
+        action => panic!("unexpected {:?}", action),
+    }
+}
+
+#[test_case]
+fn parser_skips_operating_system_commands() {
+    let mut parser = Parser::new();
+    for c in "\x1b]0;title\x07".chars() {
+        assert_eq!(parser.advance(c), None);
+    }
+    for c in "\x1b]2;title\x1b\\".chars() {
+        assert_eq!(parser.advance(c), None);
+    }
+    assert_eq!(parser.advance('x'), Some(Action::Print('x')));
+}
+
+#[test_case]
+fn sgr_sets_basic_and_bright_colors() {
+    assert_eq!(sgr("\x1b[31m").colors(), (palette(1), DEFAULT_BACKGROUND));
+    assert_eq!(sgr("\x1b[1;31m").colors(), (palette(9), DEFAULT_BACKGROUND));
+    assert_eq!(sgr("\x1b[94;102m").colors(), (palette(12), palette(10)));
+    assert_eq!(sgr("\x1b[31;7m").colors(), (DEFAULT_BACKGROUND, palette(1)));
+    assert_eq!(sgr("\x1b[31;44;0m"), Attributes::new());
+    assert_eq!(sgr("\x1b[31;44;39;49m").colors(), (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+    assert_eq!(sgr("\x1b[m"), Attributes::new());
+}
+
+#[test_case]
+fn sgr_sets_extended_colors() {
+    let attributes = sgr("\x1b[38;5;208;48;2;1;2;3m");
+    assert_eq!(attributes.foreground, Color::Indexed(208));
+    assert_eq!(attributes.background, Color::Rgb(PixelColor::new(1, 2, 3)));
+    // 引数が足りなければ、それ以降は無視する
+    assert_eq!(sgr("\x1b[31;38;2;1m").foreground, Color::Indexed(1));
+}
+
+#[test_case]
+fn palette_follows_xterm() {
+    assert_eq!(palette(16), PixelColor::new(0, 0, 0));
+    assert_eq!(palette(196), PixelColor::new(255, 0, 0));
+    assert_eq!(palette(208), PixelColor::new(255, 135, 0));
+    assert_eq!(palette(231), PixelColor::new(255, 255, 255));
+    assert_eq!(palette(232), PixelColor::new(8, 8, 8));
+    assert_eq!(palette(255), PixelColor::new(238, 238, 238));
+}
+
+#[test_case]
+fn parameters_default_to_zero() {
+    let parameters: Parameters = match parse("\x1b[m") {
+        Some(Action::Csi { parameters, .. }) => parameters,
+        action => panic!("unexpected {:?}", action),
+    };
+    assert!(parameters.is_empty());
+    assert_eq!(parameters.get(0), 0);
+    assert_eq!(parameters.get_or(0, 1), 1);
+}
+
+#[test_case]
+fn cursor_moves_and_stays_on_screen() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+    let (columns, rows) = (writer.columns(), writer.rows());
+
+    write!(writer, "\x1b[5;10H").unwrap();
+    assert_eq!(writer.cursor_position(), (9, 4));
+    write!(writer, "\x1b[2A\x1b[3D").unwrap();
+    assert_eq!(writer.cursor_position(), (6, 2));
+    write!(writer, "\x1b[1000C\x1b[1000B").unwrap();
+    assert_eq!(writer.cursor_position(), (columns - 1, rows - 1));
+    write!(writer, "\x1b[H").unwrap();
+    assert_eq!(writer.cursor_position(), (0, 0));
+
+    write!(writer, "\tab").unwrap();
+    assert_eq!(writer.cursor_position(), (10, 0));
+    write!(writer, "\x08\x08\x08").unwrap();
+    assert_eq!(writer.cursor_position(), (7, 0));
+    write!(writer, "\x1b[s\x1b[3;4H\x1b[u").unwrap();
+    assert_eq!(writer.cursor_position(), (7, 0));
+    write!(writer, "x\r").unwrap();
+    assert_eq!(writer.cursor_position(), (0, 0));
+    writeln!(writer, "x").unwrap();
+    assert_eq!(writer.cursor_position(), (0, 1));
+}
+
+#[test_case]
+fn long_lines_wrap_after_the_last_column() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+    let columns = writer.columns();
+
+    write!(writer, "\x1b[{}G", columns).unwrap();
+    write!(writer, "x").unwrap();
+    // 最後の列に書いても、次の文字が来るまでは折り返さない
+    assert_eq!(writer.cursor_position(), (columns - 1, 0));
+    write!(writer, "y").unwrap();
+    assert_eq!(writer.cursor_position(), (1, 1));
+}
+
+#[test_case]
+fn text_is_drawn_with_sgr_colors() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+
+    write!(writer, "\x1b[41m \x1b[48;2;1;2;3m \x1b[0m ").unwrap();
+    assert_eq!(cell_color(&writer, 0, 0), palette(1));
+    assert_eq!(cell_color(&writer, 1, 0), PixelColor::new(1, 2, 3));
+    assert_eq!(cell_color(&writer, 2, 0), DEFAULT_BACKGROUND);
+}
+
+#[test_case]
+fn erase_fills_with_the_current_background() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+
+    write!(writer, "\x1b[44m\x1b[2J\x1b[0m").unwrap();
+    assert_eq!(cell_color(&writer, 5, 3), palette(4));
+    write!(writer, "\x1b[4;6H\x1b[42m\x1b[K\x1b[0m").unwrap();
+    assert_eq!(cell_color(&writer, 4, 3), palette(4));
+    assert_eq!(cell_color(&writer, 5, 3), palette(2));
+    assert_eq!(cell_color(&writer, writer.columns() - 1, 3), palette(2));
+    write!(writer, "\x1b[1K").unwrap();
+    assert_eq!(cell_color(&writer, 0, 3), DEFAULT_BACKGROUND);
+    assert_eq!(cell_color(&writer, 5, 3), DEFAULT_BACKGROUND);
+    assert_eq!(cell_color(&writer, 6, 3), palette(2));
+}
+
+#[test_case]
+fn new_line_on_the_last_row_scrolls_the_screen() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+    let rows = writer.rows();
+
+    writeln!(writer, "\x1b[{};1H\x1b[41m \x1b[0m", rows).unwrap();
+    assert_eq!(writer.cursor_position(), (0, rows - 1));
+    assert_eq!(cell_color(&writer, 0, rows - 2), palette(1));
+    assert_eq!(cell_color(&writer, 0, rows - 1), DEFAULT_BACKGROUND);
+}
+
+#[test_case]
+fn cursor_is_drawn_by_inverting_its_cell() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+
+    write!(writer, "\x1b[43m  \x1b[0m\x1b[D").unwrap();
+    assert_eq!(cell_color(&writer, 1, 0), palette(3));
+    write!(writer, "\x1b[?25h").unwrap();
+    assert_eq!(cell_color(&writer, 1, 0), palette(3).inverted());
+    writer.toggle_cursor();
+    assert_eq!(cell_color(&writer, 1, 0), palette(3));
+    writer.toggle_cursor();
+    assert_eq!(cell_color(&writer, 1, 0), palette(3).inverted());
+    // 動かすと、元の位置の色は戻る
+    write!(writer, "\x1b[D").unwrap();
+    assert_eq!(cell_color(&writer, 1, 0), palette(3));
+    assert_eq!(cell_color(&writer, 0, 0), palette(3).inverted());
+    reset(&mut writer);
+    assert_eq!(cell_color(&writer, 0, 0), DEFAULT_BACKGROUND);
+}
+
+#[test_case]
+fn scrolling_leaves_no_trace_of_the_mouse_cursor() {
+    let mut writer = FRAME_BUFFER_WRITER.lock();
+    reset(&mut writer);
+    let rows = writer.rows();
+    let tip = writer.cell_position(0, rows - 3);
+    let mut cursor = MouseCursor::new();
+    cursor.move_to(&mut writer, tip);
+    cursor.show(&mut writer);
+    writer.flush();
+
+    // 最後の行に赤い空白を書いては改行して、カーソルの下を通り過ぎさせる
+    write!(writer, "\x1b[{};1H", rows).unwrap();
+    for _ in 0..4 {
+        writeln!(writer, "\x1b[41m \x1b[0m").unwrap();
+    }
+    writer.flush();
+    assert_eq!(writer.read_screen_pixel(tip), Some(PixelColor::black()));
+    // 空白と背景しか描いていないので、白い画素はカーソルの跡
+    for y in 0..writer.info.height {
+        for x in tip.x..tip.x + 15 {
+            assert_ne!(writer.read_pixel(Vector2D::new(x, y)), Some(PixelColor::white()), "({}, {})", x, y);
+        }
+    }
+
+    // 消すと、カーソルを出した後に書いた文字が見える
+    cursor.hide(&mut writer);
+    writer.flush();
+    assert_eq!(writer.read_screen_pixel(tip), Some(palette(1)));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,176 @@
+//! xHCIのコントローラとUSBデバイスを確かめる
+//!
+//! テストランナーは`-device qemu-xhci`に`usb-kbd`と`usb-mouse`、空のディスクを入れた`usb-storage`をつないで起動する
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::block;
+use kernel::usb;
+use kernel::usb::descriptor::{DeviceDescriptor, DESCRIPTOR_DEVICE, DEVICE_DESCRIPTOR_SIZE};
+use kernel::usb::hid::{CLASS_HID, PROTOCOL_KEYBOARD, PROTOCOL_MOUSE, SUBCLASS_BOOT};
+use kernel::usb::request::SetupPacket;
+use kernel::usb::storage::CLASS_MASS_STORAGE;
+use kernel::usb::xhci::ring::RING_SIZE;
+use kernel::usb::xhci::PortState;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn every_xhci_controller_is_bound() {
+    let devices = pci::find_by_class(0x0C, 0x03, Some(0x30));
+    assert!(!devices.is_empty(), "no xHCI controller is attached");
+    for device in devices.iter() {
+        assert_eq!(pci::bound_driver(device), Some("xhci"));
+    }
+    assert_eq!(usb::controllers().len(), devices.len());
+}
+
+#[test_case]
+fn host_controllers_are_visible_through_pci() {
+    let controllers = pci::host_controllers();
+    assert_eq!(controllers.len(), usb::controllers().len());
+    for controller in controllers.iter() {
+        assert_eq!(pci::bound_driver(&controller.device()), Some(controller.name()));
+        assert!(controller.port_count() > 0);
+        let devices = controller.usb_devices();
+        for info in devices.iter() {
+            let bytes = controller
+                .read_descriptor(info.slot, DESCRIPTOR_DEVICE, 0, DEVICE_DESCRIPTOR_SIZE as u16)
+                .expect("GET_DESCRIPTOR failed");
+            let descriptor = DeviceDescriptor::parse(&bytes).unwrap();
+            assert_eq!((descriptor.vendor_id, descriptor.product_id), (info.vendor_id, info.product_id));
+        }
+    }
+}
+
+#[test_case]
+fn no_op_command_completes() {
+    for controller in usb::controllers() {
+        controller.lock().no_op().expect("no-op command failed");
+    }
+}
+
+#[test_case]
+fn command_ring_wraps_around() {
+    // リンクTRBをたどって、サイクルビットを反転させながら二周する
+    for controller in usb::controllers() {
+        let mut controller = controller.lock();
+        for _ in 0..RING_SIZE * 2 {
+            controller.no_op().expect("no-op command failed");
+        }
+    }
+}
+
+#[test_case]
+fn no_port_is_left_resetting() {
+    usb::poll();
+    for controller in usb::controllers() {
+        let controller = controller.lock();
+        assert!(!controller.ports().is_empty());
+        assert!(controller.ports().iter().all(|port| port.state != PortState::Resetting));
+    }
+}
+
+#[test_case]
+fn every_enabled_port_is_enumerated() {
+    usb::poll();
+    let devices = usb::devices();
+    // usb-kbdとusb-mouse
+    assert!(devices.len() >= 2, "only {} USB devices are enumerated", devices.len());
+    for controller in usb::controllers() {
+        for port in controller.lock().ports() {
+            if let PortState::Enabled(_) = port.state {
+                assert!(devices.iter().any(|device| device.port() == port.number && device.is_attached()));
+            }
+        }
+    }
+}
+
+#[test_case]
+fn device_descriptor_can_be_read_again() {
+    for device in usb::devices() {
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, DEVICE_DESCRIPTOR_SIZE as u16);
+        let bytes = device.control_in(setup).expect("GET_DESCRIPTOR failed");
+        assert_eq!(DeviceDescriptor::parse(&bytes).as_ref(), Some(device.descriptor()));
+        assert!(!device.configuration().interfaces.is_empty());
+    }
+}
+
+#[test_case]
+fn boot_keyboard_and_mouse_are_bound() {
+    usb::poll();
+    let mut bound = Vec::new();
+    for device in usb::devices() {
+        for interface in device.configuration().interfaces.iter() {
+            let descriptor = interface.descriptor;
+            if descriptor.class != CLASS_HID || descriptor.subclass != SUBCLASS_BOOT {
+                continue;
+            }
+            let expected = match descriptor.protocol {
+                PROTOCOL_KEYBOARD => "hid-keyboard",
+                PROTOCOL_MOUSE => "hid-mouse",
+                _ => continue,
+            };
+            assert_eq!(device.bound_driver(descriptor.number), Some(expected));
+            bound.push(expected);
+        }
+    }
+    assert!(bound.contains(&"hid-keyboard"), "usb-kbd is not attached");
+    assert!(bound.contains(&"hid-mouse"), "usb-mouse is not attached");
+    // レポートを待っている転送があっても、イベントの処理は止まらない
+    usb::poll();
+}
+
+#[test_case]
+fn mass_storage_is_registered_as_block_device() {
+    let bound = usb::devices().iter().any(|device| {
+        device
+            .configuration()
+            .interfaces
+            .iter()
+            .filter(|interface| interface.descriptor.class == CLASS_MASS_STORAGE)
+            .any(|interface| device.bound_driver(interface.descriptor.number) == Some("usb-storage"))
+    });
+    assert!(bound, "usb-storage is not attached");
+
+    // テストランナーが繋ぐのは16MiBの空のディスク
+    let disk = block::find("sda").expect("the mass storage device should be registered as sda");
+    let uncached = block::find_uncached("sda").unwrap();
+    let geometry = disk.geometry();
+    assert_eq!(geometry.capacity(), 16 * 1024 * 1024);
+    let mut sector = vec![0; geometry.sector_size];
+    disk.read_sectors(0, &mut sector).expect("failed to read the first sector");
+    assert!(sector.iter().all(|byte| *byte == 0));
+    assert!(disk.read_sectors(geometry.sector_count, &mut sector).is_err());
+
+    // 一度のBulk転送に収まらない長さも、書いたとおりに読み戻せる
+    let data: Vec<u8> = (0..geometry.sector_size * 300).map(|index| (index / 3) as u8).collect();
+    uncached.write_sectors(8, &data).unwrap();
+    let mut buf = vec![0; data.len()];
+    uncached.read_sectors(8, &mut buf).unwrap();
+    assert!(buf == data, "data read back from sda differs");
+    disk.read_sectors(8, &mut sector).unwrap();
+    assert_eq!(sector[..], data[..geometry.sector_size]);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,109 @@
+//! USBのディスクリプタの解釈を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::usb::descriptor::{parse_string, ConfigurationDescriptor, DeviceDescriptor, TransferType};
+use kernel::usb::xhci::endpoint_dci;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+/// QEMUのusb-kbdが返すコンフィギュレーションディスクリプタ
+const KEYBOARD_CONFIGURATION: [u8; 34] = [
+    0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0xA0, 0x32,
+    0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00,
+    0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00,
+    0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x07,
+];
+
+#[test_case]
+fn device_descriptor_is_parsed() {
+    let bytes = [
+        0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x27, 0x06, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x0B, 0x01,
+    ];
+    let descriptor = DeviceDescriptor::parse(&bytes).unwrap();
+    assert_eq!(descriptor.usb_version, 0x0200);
+    assert_eq!(descriptor.max_packet_size0, 64);
+    assert_eq!((descriptor.vendor_id, descriptor.product_id), (0x0627, 0x0001));
+    assert_eq!(descriptor.product_index, 4);
+    assert_eq!(descriptor.num_configurations, 1);
+    assert!(DeviceDescriptor::parse(&bytes[..8]).is_none());
+}
+
+#[test_case]
+fn configuration_collects_interfaces_and_endpoints() {
+    let configuration = ConfigurationDescriptor::parse(&KEYBOARD_CONFIGURATION).unwrap();
+    assert_eq!(configuration.total_length, 34);
+    assert_eq!(configuration.value, 1);
+    assert_eq!(configuration.interfaces.len(), 1);
+
+    let interface = &configuration.interfaces[0];
+    assert_eq!((interface.descriptor.class, interface.descriptor.subclass, interface.descriptor.protocol), (3, 1, 1));
+    assert_eq!(interface.hid.unwrap().report_descriptor_length, 0x3F);
+
+    let endpoint = interface.endpoints[0];
+    assert!(endpoint.is_in());
+    assert_eq!(endpoint.number(), 1);
+    assert_eq!(endpoint.transfer_type(), TransferType::Interrupt);
+    assert_eq!(endpoint.max_packet_size(), 8);
+    assert_eq!(endpoint.interval, 7);
+}
+
+#[test_case]
+fn truncated_configuration_only_has_the_header() {
+    let configuration = ConfigurationDescriptor::parse(&KEYBOARD_CONFIGURATION[..9]).unwrap();
+    assert_eq!(configuration.total_length, 34);
+    assert!(configuration.interfaces.is_empty());
+}
+
+#[test_case]
+fn alternate_settings_are_skipped() {
+    let mut bytes = alloc::vec::Vec::from(KEYBOARD_CONFIGURATION);
+    // 代替設定1のインターフェースとそのエンドポイントを後ろに足す
+    bytes.extend_from_slice(&[0x09, 0x04, 0x00, 0x01, 0x01, 0x03, 0x00, 0x00, 0x00]);
+    bytes.extend_from_slice(&[0x07, 0x05, 0x82, 0x03, 0x40, 0x00, 0x01]);
+    bytes[2] = bytes.len() as u8;
+    let configuration = ConfigurationDescriptor::parse(&bytes).unwrap();
+    assert_eq!(configuration.interfaces.len(), 1);
+    assert_eq!(configuration.endpoints().count(), 1);
+}
+
+#[test_case]
+fn malformed_descriptor_is_rejected() {
+    let mut bytes = KEYBOARD_CONFIGURATION;
+    bytes[27] = 0;
+    assert!(ConfigurationDescriptor::parse(&bytes).is_none());
+}
+
+#[test_case]
+fn string_descriptor_is_decoded() {
+    let bytes = [0x0A, 0x03, b'Q', 0, b'E', 0, b'M', 0, b'U', 0];
+    assert_eq!(parse_string(&bytes).as_deref(), Some("QEMU"));
+}
+
+#[test_case]
+fn endpoint_addresses_map_to_context_indices() {
+    assert_eq!(endpoint_dci(0x00), 1);
+    assert_eq!(endpoint_dci(0x01), 2);
+    assert_eq!(endpoint_dci(0x81), 3);
+    assert_eq!(endpoint_dci(0x8F), 31);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
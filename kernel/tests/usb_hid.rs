@@ -0,0 +1,77 @@
+//! HIDのブートプロトコルのレポートの解釈を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::task::mouse::{MouseEvent, BUTTON_LEFT};
+use kernel::usb::hid::keyboard::{set1_scancode, BootKeyboard};
+use kernel::usb::hid::mouse::parse_report;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn key_press_and_release_become_make_and_break_codes() {
+    let mut keyboard = BootKeyboard::new();
+    assert_eq!(keyboard.scancodes(&[0, 0, 0x04, 0, 0, 0, 0, 0]), [0x1E]);
+    // 同じレポートが続いても何も送らない
+    assert!(keyboard.scancodes(&[0, 0, 0x04, 0, 0, 0, 0, 0]).is_empty());
+    assert_eq!(keyboard.scancodes(&[0, 0, 0x04, 0x05, 0, 0, 0, 0]), [0x30]);
+    assert_eq!(keyboard.scancodes(&[0, 0, 0x05, 0, 0, 0, 0, 0]), [0x9E]);
+    assert_eq!(keyboard.scancodes(&[0; 8]), [0xB0]);
+}
+
+#[test_case]
+fn modifiers_and_extended_keys_are_translated() {
+    let mut keyboard = BootKeyboard::new();
+    // 左Shiftと右Ctrl
+    assert_eq!(keyboard.scancodes(&[0x12, 0, 0, 0, 0, 0, 0, 0]), [0x2A, 0xE0, 0x1D]);
+    assert_eq!(keyboard.scancodes(&[0x10, 0, 0x52, 0, 0, 0, 0, 0]), [0xAA, 0xE0, 0x48]);
+    assert_eq!(keyboard.scancodes(&[0x10, 0, 0, 0, 0, 0, 0, 0]), [0xE0, 0xC8]);
+}
+
+#[test_case]
+fn roll_over_error_is_ignored() {
+    let mut keyboard = BootKeyboard::new();
+    assert!(keyboard.scancodes(&[0, 0, 1, 1, 1, 1, 1, 1]).is_empty());
+    assert!(keyboard.scancodes(&[0, 0, 0x04]).is_empty());
+}
+
+#[test_case]
+fn usage_table_covers_common_keys() {
+    assert_eq!(set1_scancode(0x1D), Some(0x2C)); // Z
+    assert_eq!(set1_scancode(0x27), Some(0x0B)); // 0
+    assert_eq!(set1_scancode(0x28), Some(0x1C)); // Enter
+    assert_eq!(set1_scancode(0x45), Some(0x58)); // F12
+    assert_eq!(set1_scancode(0x65), Some(0xE05D)); // Application
+    assert_eq!(set1_scancode(0x89), Some(0x7D)); // ¥
+    assert_eq!(set1_scancode(0x00), None);
+}
+
+#[test_case]
+fn mouse_report_is_decoded() {
+    assert_eq!(
+        parse_report(&[0x01, 0x05, 0xFE, 0x01]),
+        Some(MouseEvent { dx: 5, dy: -2, wheel: -1, buttons: BUTTON_LEFT })
+    );
+    assert_eq!(parse_report(&[0x00, 0x80, 0x7F]), Some(MouseEvent { dx: -128, dy: 127, wheel: 0, buttons: 0 }));
+    assert_eq!(parse_report(&[0x00, 0x01]), None);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
@@ -0,0 +1,120 @@
+//! マスストレージのCBWとCSW、SCSIのコマンドと応答の形式を確かめる
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::usb::storage::bot::{command_block, CommandStatus, Direction, CSW_STATUS_FAILED};
+use kernel::usb::storage::disk_name;
+use kernel::usb::storage::scsi;
+use kernel::usb::storage::scsi::{Capacity, Sense, DEVICE_TYPE_DIRECT_ACCESS, INQUIRY_LENGTH};
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+#[test_case]
+fn command_block_wrapper_layout() {
+    let cbw = command_block(0x1234_5678, 1, &scsi::read_10(0x0102_0304, 8), Direction::In, 4096);
+    assert_eq!(&cbw[0..4], b"USBC");
+    assert_eq!(&cbw[4..8], &[0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(&cbw[8..12], &[0x00, 0x10, 0x00, 0x00]);
+    assert_eq!(cbw[12], 0x80);
+    assert_eq!(cbw[13], 1);
+    assert_eq!(cbw[14], 10);
+    assert_eq!(&cbw[15..25], &[0x28, 0, 0x01, 0x02, 0x03, 0x04, 0, 0x00, 0x08, 0]);
+    assert!(cbw[25..].iter().all(|&byte| byte == 0));
+
+    let cbw = command_block(1, 0, &scsi::test_unit_ready(), Direction::Out, 0);
+    assert_eq!(cbw[12], 0);
+    assert_eq!(cbw[14], 6);
+}
+
+#[test_case]
+fn command_status_wrapper_is_checked() {
+    let mut csw = [0; 13];
+    csw[0..4].copy_from_slice(b"USBS");
+    csw[4..8].copy_from_slice(&7u32.to_le_bytes());
+    csw[8..12].copy_from_slice(&512u32.to_le_bytes());
+    csw[12] = CSW_STATUS_FAILED;
+    assert_eq!(
+        CommandStatus::parse(&csw),
+        Some(CommandStatus { tag: 7, residue: 512, status: CSW_STATUS_FAILED })
+    );
+    // 短すぎるものや署名が違うものはCSWではない
+    assert_eq!(CommandStatus::parse(&csw[..12]), None);
+    csw[3] = b'C';
+    assert_eq!(CommandStatus::parse(&csw), None);
+}
+
+#[test_case]
+fn inquiry_data_is_parsed() {
+    let mut data = [b' '; INQUIRY_LENGTH];
+    data[0] = DEVICE_TYPE_DIRECT_ACCESS;
+    data[1] = 0x80;
+    data[8..12].copy_from_slice(b"QEMU");
+    data[16..29].copy_from_slice(b"QEMU HARDDISK");
+    let inquiry = scsi::parse_inquiry(&data).unwrap();
+    assert_eq!(inquiry.device_type, DEVICE_TYPE_DIRECT_ACCESS);
+    assert!(inquiry.removable);
+    assert_eq!(inquiry.vendor, "QEMU");
+    assert_eq!(inquiry.product, "QEMU HARDDISK");
+    assert!(scsi::parse_inquiry(&data[..INQUIRY_LENGTH - 1]).is_none());
+}
+
+#[test_case]
+fn capacity_is_big_endian() {
+    let data = [0x00, 0x01, 0xFF, 0xFF, 0x00, 0x00, 0x02, 0x00];
+    assert_eq!(scsi::parse_read_capacity_10(&data), Some(Capacity { last_lba: 0x1_FFFF, block_size: 512 }));
+
+    let mut data = [0; 32];
+    data[..8].copy_from_slice(&0x1_0000_0000u64.to_be_bytes());
+    data[8..12].copy_from_slice(&4096u32.to_be_bytes());
+    assert_eq!(
+        scsi::parse_read_capacity_16(&data),
+        Some(Capacity { last_lba: 0x1_0000_0000, block_size: 4096 })
+    );
+    assert_eq!(scsi::read_capacity_16()[0], scsi::SERVICE_ACTION_IN_16);
+}
+
+#[test_case]
+fn sense_and_write_protection_are_parsed() {
+    // NOT READY、MEDIUM NOT PRESENT
+    let mut data = [0; 18];
+    data[0] = 0x70;
+    data[2] = 0x02;
+    data[12] = 0x3A;
+    assert_eq!(scsi::parse_sense(&data), Some(Sense { key: 0x02, code: 0x3A, qualifier: 0 }));
+    data[0] = 0x72;
+    assert_eq!(scsi::parse_sense(&data), None);
+
+    assert_eq!(scsi::parse_write_protected(&[3, 0, 0x80, 0]), Some(true));
+    assert_eq!(scsi::parse_write_protected(&[3, 0, 0x00, 0]), Some(false));
+    assert_eq!(scsi::parse_write_protected(&[3]), None);
+}
+
+#[test_case]
+fn disk_names_continue_past_z() {
+    assert_eq!(disk_name(0), "sda");
+    assert_eq!(disk_name(25), "sdz");
+    assert_eq!(disk_name(26), "sdaa");
+    assert_eq!(disk_name(27), "sdab");
+    assert_eq!(disk_name(26 + 26 * 26 - 1), "sdzz");
+    assert_eq!(disk_name(26 + 26 * 26), "sdaaa");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
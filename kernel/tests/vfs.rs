@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use kernel::fs::error::FsError;
+use kernel::fs::file::{OpenFlags, SeekFrom};
+use kernel::fs::mount::Vfs;
+use kernel::fs::tmpfs::{TmpFs, MAX_FILE_SIZE};
+use kernel::fs::FileType;
+use kernel::BOOTLOADER_CONFIG;
+
+entry_point!(main, config = &BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    test_main();
+    kernel::hlt_loop();
+}
+
+fn new_vfs() -> Vfs {
+    let mut vfs = Vfs::new();
+    vfs.mount("/", Arc::new(TmpFs::new())).unwrap();
+    vfs
+}
+
+fn names(vfs: &Vfs, path: &str) -> Vec<String> {
+    vfs.read_dir(path).unwrap().into_iter().map(|entry| entry.name).collect()
+}
+
+#[test_case]
+fn write_then_read() {
+    let vfs = new_vfs();
+    let mut file = vfs.open("/hello.txt", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    assert_eq!(file.write(b"hello, world").unwrap(), 12);
+
+    let mut file = vfs.open("/hello.txt", OpenFlags::READ).unwrap();
+    assert_eq!(file.read_to_end().unwrap(), b"hello, world");
+    assert_eq!(vfs.stat("/hello.txt").unwrap().size, 12);
+}
+
+#[test_case]
+fn offsets_are_per_open_file() {
+    let vfs = new_vfs();
+    let mut writer = vfs.open("/data", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    writer.write(b"0123456789").unwrap();
+
+    let mut first = vfs.open("/data", OpenFlags::READ).unwrap();
+    let mut second = vfs.open("/data", OpenFlags::READ).unwrap();
+    let mut buf = [0u8; 4];
+    first.read(&mut buf).unwrap();
+    assert_eq!(&buf, b"0123");
+    second.read(&mut buf).unwrap();
+    assert_eq!(&buf, b"0123");
+
+    assert_eq!(first.seek(SeekFrom::End(-2)).unwrap(), 8);
+    assert_eq!(first.read(&mut buf).unwrap(), 2);
+    assert_eq!(&buf[..2], b"89");
+    assert_eq!(first.seek(SeekFrom::Current(-11)), Err(FsError::InvalidArgument));
+}
+
+#[test_case]
+fn append_and_truncate() {
+    let vfs = new_vfs();
+    vfs.open("/log", OpenFlags::WRITE | OpenFlags::CREATE).unwrap().write(b"abc").unwrap();
+    vfs.open("/log", OpenFlags::WRITE | OpenFlags::APPEND).unwrap().write(b"def").unwrap();
+    assert_eq!(vfs.open("/log", OpenFlags::READ).unwrap().read_to_end().unwrap(), b"abcdef");
+
+    vfs.open("/log", OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+    assert_eq!(vfs.stat("/log").unwrap().size, 0);
+}
+
+#[test_case]
+fn resolves_dot_and_dot_dot() {
+    let vfs = new_vfs();
+    vfs.mkdir("/a").unwrap();
+    vfs.mkdir("/a/b").unwrap();
+    vfs.create("/a/b/file", FileType::File).unwrap();
+
+    let file = vfs.resolve("/a/./b/../b/file").unwrap();
+    assert!(file.is(&vfs.resolve("/a/b/file").unwrap()));
+    assert!(vfs.resolve("/../..").unwrap().is(&vfs.root().unwrap()));
+    assert_eq!(vfs.resolve("/a/b/file/x").err(), Some(FsError::NotADirectory));
+    assert_eq!(vfs.resolve("/a/missing").err(), Some(FsError::NotFound));
+}
+
+#[test_case]
+fn readdir_and_unlink() {
+    let vfs = new_vfs();
+    vfs.mkdir("/dir").unwrap();
+    vfs.create("/dir/one", FileType::File).unwrap();
+    vfs.create("/dir/two", FileType::File).unwrap();
+    assert_eq!(names(&vfs, "/dir"), ["one", "two"]);
+
+    assert_eq!(vfs.create("/dir/one", FileType::File).err(), Some(FsError::AlreadyExists));
+    assert_eq!(vfs.unlink("/dir"), Err(FsError::NotEmpty));
+    vfs.unlink("/dir/one").unwrap();
+    vfs.unlink("/dir/two").unwrap();
+    vfs.unlink("/dir").unwrap();
+    assert!(names(&vfs, "/").is_empty());
+}
+
+#[test_case]
+fn writes_far_past_the_end_are_refused() {
+    let vfs = new_vfs();
+    let mut file = vfs.open("/sparse", OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+    file.write(b"head").unwrap();
+
+    // 大きな位置に書いても、ヒープを使い切るほど伸ばさない
+    file.seek(SeekFrom::Start(usize::MAX - 1)).unwrap();
+    assert_eq!(file.write(b"tail"), Err(FsError::NoSpace));
+    file.seek(SeekFrom::Start(MAX_FILE_SIZE)).unwrap();
+    assert_eq!(file.write(b"tail"), Err(FsError::NoSpace));
+    assert_eq!(vfs.stat("/sparse").unwrap().size, 4);
+
+    // 上限ちょうどまでは書ける
+    file.seek(SeekFrom::Start(MAX_FILE_SIZE - 4)).unwrap();
+    assert_eq!(file.write(b"tail").unwrap(), 4);
+    assert_eq!(vfs.stat("/sparse").unwrap().size, MAX_FILE_SIZE);
+    vfs.unlink("/sparse").unwrap();
+}
+
+#[test_case]
+fn mount_points_are_crossed() {
+    let mut vfs = new_vfs();
+    vfs.mkdir("/mnt").unwrap();
+    vfs.create("/outside", FileType::File).unwrap();
+    vfs.mount("/mnt", Arc::new(TmpFs::new())).unwrap();
+
+    vfs.create("/mnt/inside", FileType::File).unwrap();
+    assert_eq!(names(&vfs, "/mnt"), ["inside"]);
+    assert!(vfs.resolve("/mnt/../outside").is_ok());
+    assert_eq!(vfs.unlink("/mnt"), Err(FsError::Busy));
+
+    vfs.unmount("/mnt").unwrap();
+    assert!(names(&vfs, "/mnt").is_empty());
+}
+
+#[test_case]
+fn root_filesystem_is_mounted() {
+    let vfs = kernel::fs::VFS.lock();
+    assert!(vfs.root().unwrap().is_dir().unwrap());
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
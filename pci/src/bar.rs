@@ -0,0 +1,248 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::device::{calc_bar_address, Device, HeaderType};
+use crate::error::PciError;
+use crate::register::Command;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_TYPE_MASK: u32 = 0b110;
+const BAR_MEMORY_TYPE_64: u32 = 0b100;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// バス、デバイス、ファンクションの組
+type Location = (u8, u8, u8);
+
+/// 走査のときに調べたBAR
+static SIZED: Mutex<Vec<(Location, Vec<Bar>)>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BarKind {
+    Io,
+    Memory32,
+    /// 次のBARと合わせて64bitのアドレスになる
+    Memory64,
+}
+
+/// デコードし、大きさを調べたBAR
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Bar {
+    pub index: usize,
+    pub kind: BarKind,
+    /// フラグのビットを除いたアドレス。I/O空間ならポート番号
+    pub address: u64,
+    pub size: u64,
+    pub prefetchable: bool,
+}
+
+impl Bar {
+    pub fn is_io(&self) -> bool {
+        self.kind == BarKind::Io
+    }
+
+    pub fn is_memory(&self) -> bool {
+        !self.is_io()
+    }
+
+    /// I/O空間のBARなら、その先頭のポート番号
+    pub fn port(&self) -> Option<u16> {
+        match self.kind {
+            BarKind::Io => Some(self.address as u16),
+            _ => None,
+        }
+    }
+}
+
+/// ヘッダの形式ごとのBARの数
+pub fn bar_count(device: &Device) -> usize {
+    match device.header_kind() {
+        HeaderType::Endpoint => 6,
+        HeaderType::PciBridge => 2,
+        _ => 0,
+    }
+}
+
+fn is_64bit(raw: u32) -> bool {
+    raw & BAR_IO == 0 && raw & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_TYPE_64
+}
+
+/// BARの番号を先頭から順に見て、`index`が64bit BARの上位半分でないか確かめる
+fn check_index(device: &Device, index: usize) -> Result<(), PciError> {
+    let count = bar_count(device);
+    if index >= count {
+        return Err(PciError::InvalidBarIndex(index));
+    }
+    let mut current = 0;
+    while current < index {
+        if is_64bit(device.read_conf_reg(calc_bar_address(current))) {
+            current += 2;
+        } else {
+            current += 1;
+        }
+    }
+    if current != index {
+        return Err(PciError::UpperHalfOfBar(index));
+    }
+    if is_64bit(device.read_conf_reg(calc_bar_address(index))) && index + 1 >= count {
+        return Err(PciError::InvalidBarIndex(index));
+    }
+    Ok(())
+}
+
+/// BARに全て1を書いて読み返し、デバイスがデコードするアドレスのビットを調べる
+/// 途中でデコードされないように、I/O空間とメモリ空間へのアクセスを止めてから行う
+fn size_mask(device: &Device, offset: u16) -> u32 {
+    let original = device.read_conf_reg(offset);
+    device.write_conf_reg(offset, 0xFFFF_FFFF);
+    let mask = device.read_conf_reg(offset);
+    device.write_conf_reg(offset, original);
+    mask
+}
+
+pub(crate) fn read(device: &Device, index: usize) -> Result<Bar, PciError> {
+    check_index(device, index)?;
+    let offset = calc_bar_address(index);
+    let raw = device.read_conf_reg(offset);
+
+    let command = device.command();
+    device.set_command(command & !(Command::IO_SPACE | Command::MEMORY_SPACE));
+    let mask = size_mask(device, offset);
+    let upper = if is_64bit(raw) {
+        Some((device.read_conf_reg(offset + 4), size_mask(device, offset + 4)))
+    } else {
+        None
+    };
+    device.set_command(command);
+
+    let bar = if raw & BAR_IO != 0 {
+        let mask = mask & !0x3;
+        // 上位16bitを実装しないデバイスは、16bitのI/O空間しかデコードしない
+        let width_mask = if mask & 0xFFFF_0000 == 0 { 0xFFFF } else { 0xFFFF_FFFF };
+        Bar {
+            index,
+            kind: BarKind::Io,
+            address: (raw & !0x3) as u64,
+            size: ((!mask).wrapping_add(1) & width_mask) as u64,
+            prefetchable: false,
+        }
+    } else {
+        let prefetchable = raw & BAR_PREFETCHABLE != 0;
+        match upper {
+            Some((upper_raw, upper_mask)) => {
+                let mask = (upper_mask as u64) << 32 | (mask & !0xF) as u64;
+                Bar {
+                    index,
+                    kind: BarKind::Memory64,
+                    address: (upper_raw as u64) << 32 | (raw & !0xF) as u64,
+                    size: if mask == 0 { 0 } else { (!mask).wrapping_add(1) },
+                    prefetchable,
+                }
+            }
+            None => {
+                let mask = mask & !0xF;
+                Bar {
+                    index,
+                    kind: BarKind::Memory32,
+                    address: (raw & !0xF) as u64,
+                    size: if mask == 0 { 0 } else { (!mask).wrapping_add(1) as u64 },
+                    prefetchable,
+                }
+            }
+        }
+    };
+    if bar.size == 0 {
+        return Err(PciError::UnimplementedBar(index));
+    }
+    Ok(bar)
+}
+
+/// 走査で見つかったデバイスのBARを、ドライバが使い始める前にまとめて調べておく。調べたことのあるデバイスは飛ばす
+pub(crate) fn size_all(devices: &[Device]) {
+    for device in devices {
+        if sized(device).is_none() {
+            let bars = read_all(device);
+            SIZED.lock().push(((device.bus, device.device, device.function), bars));
+        }
+    }
+}
+
+fn sized(device: &Device) -> Option<Vec<Bar>> {
+    let location = (device.bus, device.device, device.function);
+    SIZED.lock()
+        .iter()
+        .find(|(sized, _)| *sized == location)
+        .map(|(_, bars)| bars.clone())
+}
+
+/// 走査で調べてあればその値を返し、BARには書き込まない。調べていなければ`read`と同じ
+pub(crate) fn get(device: &Device, index: usize) -> Result<Bar, PciError> {
+    match sized(device) {
+        Some(bars) => {
+            check_index(device, index)?;
+            bars.into_iter().find(|bar| bar.index == index).ok_or(PciError::UnimplementedBar(index))
+        }
+        None => read(device, index),
+    }
+}
+
+pub(crate) fn get_all(device: &Device) -> Vec<Bar> {
+    sized(device).unwrap_or_else(|| read_all(device))
+}
+
+/// 実装されている全てのBAR。64bit BARの上位半分は含まない
+pub(crate) fn read_all(device: &Device) -> Vec<Bar> {
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < bar_count(device) {
+        match read(device, index) {
+            Ok(bar) => {
+                index += if bar.kind == BarKind::Memory64 { 2 } else { 1 };
+                bars.push(bar);
+            }
+            Err(_) => index += 1,
+        }
+    }
+    bars
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bar::{Bar, BarKind};
+    use crate::config::ConfigAccess;
+    use crate::device::Device;
+    use crate::error::PciError;
+    use crate::testing::FakeConfig;
+
+    const LOCATION: (u8, u8, u8) = (0, 3, 0);
+
+    #[test]
+    fn bars_are_decoded_and_sized() {
+        let fake = FakeConfig::new();
+        fake.add(LOCATION, 0x1234_8086, 0x0106_0100, 0x00, 0);
+        // BAR0: 16KiBの64bitメモリ(プリフェッチ可能)、BAR2: 32バイトのI/O、BAR3: 実装なし、BAR4: 4KiBの32bitメモリ
+        fake.set_bar(LOCATION, 0, 0xFEB0_000C, 0xFFFF_C000);
+        fake.set_bar(LOCATION, 1, 0x0000_0001, 0xFFFF_FFFF);
+        fake.set_bar(LOCATION, 2, 0x0000_C041, 0x0000_FFE0);
+        fake.set_bar(LOCATION, 4, 0xFEB1_0000, 0xFFFF_F000);
+        fake.write(0, 3, 0, 0x04, 0x0107);
+        let _lock = fake.install();
+        let device = Device::new(0, 3, 0, 0x00);
+
+        assert_eq!(device.bar(0), Ok(Bar {
+            index: 0,
+            kind: BarKind::Memory64,
+            address: 0x1_FEB0_0000,
+            size: 0x4000,
+            prefetchable: true,
+        }));
+        assert_eq!(device.bar(1), Err(PciError::UpperHalfOfBar(1)));
+        let io = device.bar(2).unwrap();
+        assert_eq!((io.kind, io.port(), io.size), (BarKind::Io, Some(0xC040), 0x20));
+        assert_eq!(device.bar(3), Err(PciError::UnimplementedBar(3)));
+        assert_eq!(device.bar(6), Err(PciError::InvalidBarIndex(6)));
+        assert_eq!(device.bars().iter().map(|bar| bar.index).collect::<alloc::vec::Vec<_>>(), [0, 2, 4]);
+
+        // 調べ終わったら元の値とコマンドレジスタに戻っている
+        assert_eq!(device.read_conf_reg(0x10), 0xFEB0_000C);
+        assert_eq!(device.read_conf_reg(0x04), 0x0107);
+    }
+}
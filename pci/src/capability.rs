@@ -0,0 +1,303 @@
+use alloc::vec::Vec;
+use crate::config::{self, EXTENDED_CONFIG_SIZE};
+use crate::device::{Device, HeaderType};
+use crate::register::Status;
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSI_X: u8 = 0x11;
+
+pub const EXT_CAP_ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+pub const EXT_CAP_DEVICE_SERIAL_NUMBER: u16 = 0x0003;
+
+/// リストが壊れていても止まるように、たどる数に上限を設ける
+const MAX_CAPABILITIES: usize = 48;
+const EXTENDED_CAPABILITIES_START: u16 = 0x100;
+
+/// 標準のケーパビリティリストの一つ
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+/// PCI Expressの拡張コンフィギュレーション空間にあるケーパビリティ
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+/// 電源管理ケーパビリティ
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PowerManagement {
+    pub offset: u16,
+    pub version: u8,
+}
+
+/// MSIケーパビリティ
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Msi {
+    pub offset: u16,
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+    /// デバイスが要求できるベクタの数
+    pub max_vectors: u8,
+}
+
+/// MSI-Xケーパビリティ。ベクタごとの設定はBARの中のテーブルに置かれる
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsiX {
+    pub offset: u16,
+    pub table_size: u16,
+    pub table_bar: usize,
+    pub table_offset: u32,
+    pub pba_bar: usize,
+    pub pba_offset: u32,
+}
+
+/// PCI Expressケーパビリティ
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct PciExpress {
+    pub offset: u16,
+    pub version: u8,
+    /// エンドポイント、ルートポートなどの種類
+    pub port_type: u8,
+}
+
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASKING: u16 = 1 << 8;
+const MSI_X_FUNCTION_MASK: u16 = 1 << 14;
+const MSI_X_ENABLE: u16 = 1 << 15;
+/// 電源管理の制御レジスタのPME_Statusは、1を書くとクリアされる
+const PM_PME_STATUS: u16 = 1 << 15;
+
+/// MSI-Xテーブルの一つのエントリの大きさと、その中の配置
+pub const MSI_X_ENTRY_SIZE: usize = 16;
+pub const MSI_X_ENTRY_ADDRESS_LOW: usize = 0;
+pub const MSI_X_ENTRY_ADDRESS_HIGH: usize = 4;
+pub const MSI_X_ENTRY_DATA: usize = 8;
+pub const MSI_X_ENTRY_VECTOR_CONTROL: usize = 12;
+/// ベクタ制御のbit 0が立っていると、そのベクタは送られない
+pub const MSI_X_VECTOR_MASKED: u32 = 1 << 0;
+
+pub(crate) fn read_all(device: &Device) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if !device.status().contains(Status::CAPABILITIES_LIST) {
+        return capabilities;
+    }
+    // CardBusブリッジだけはポインタの場所が違う
+    let pointer_offset = if device.header_kind() == HeaderType::CardBusBridge { 0x14 } else { 0x34 };
+    let mut pointer = (device.read_conf_reg8(pointer_offset) & 0xFC) as u16;
+    while pointer != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = device.read_conf_reg16(pointer);
+        capabilities.push(Capability { id: header as u8, offset: pointer });
+        pointer = (header >> 8) & 0xFC;
+    }
+    capabilities
+}
+
+pub(crate) fn read_all_extended(device: &Device) -> Vec<ExtendedCapability> {
+    let mut capabilities = Vec::new();
+    // 拡張コンフィギュレーション空間はPCI Expressのデバイスにしかない
+    if config::access().config_size() < EXTENDED_CONFIG_SIZE || device.find_capability(CAP_PCI_EXPRESS).is_none() {
+        return capabilities;
+    }
+    let mut pointer = EXTENDED_CAPABILITIES_START;
+    while pointer != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = device.read_conf_reg(pointer);
+        if header == 0 || header == 0xFFFF_FFFF {
+            break;
+        }
+        capabilities.push(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xF) as u8,
+            offset: pointer,
+        });
+        pointer = ((header >> 20) & 0xFFC) as u16;
+        if pointer < EXTENDED_CAPABILITIES_START {
+            break;
+        }
+    }
+    capabilities
+}
+
+impl PowerManagement {
+    pub fn read(device: &Device) -> Option<Self> {
+        let offset = device.find_capability(CAP_POWER_MANAGEMENT)?.offset;
+        let version = (device.read_conf_reg16(offset + 2) & 0x7) as u8;
+        Some(Self { offset, version })
+    }
+
+    /// 今の電源状態。0がD0、3がD3hot
+    pub fn state(&self, device: &Device) -> u8 {
+        (device.read_conf_reg16(self.offset + 4) & 0x3) as u8
+    }
+
+    pub fn set_state(&self, device: &Device, state: u8) {
+        let control = device.read_conf_reg16(self.offset + 4) & !(0x3 | PM_PME_STATUS);
+        device.write_conf_reg16(self.offset + 4, control | (state & 0x3) as u16);
+    }
+}
+
+impl Msi {
+    pub fn read(device: &Device) -> Option<Self> {
+        let offset = device.find_capability(CAP_MSI)?.offset;
+        let control = device.read_conf_reg16(offset + 2);
+        Some(Self {
+            offset,
+            is_64bit: control & MSI_64BIT != 0,
+            per_vector_masking: control & MSI_PER_VECTOR_MASKING != 0,
+            max_vectors: 1 << ((control >> 1) & 0x7),
+        })
+    }
+
+    /// メッセージのアドレスとデータを設定して有効にする
+    ///
+    /// `vectors`個のベクタを使う場合、デバイスはデータの下位ビットにベクタの番号を入れて送る
+    pub fn enable(&self, device: &Device, address: u64, data: u16, vectors: u8) {
+        let vectors = vectors.clamp(1, self.max_vectors);
+        device.write_conf_reg(self.offset + 4, address as u32);
+        let data_offset = if self.is_64bit {
+            device.write_conf_reg(self.offset + 8, (address >> 32) as u32);
+            self.offset + 12
+        } else {
+            self.offset + 8
+        };
+        device.write_conf_reg16(data_offset, data);
+
+        let multiple_message = vectors.trailing_zeros().min(5) as u16;
+        let control = device.read_conf_reg16(self.offset + 2) & !(0x7 << 4);
+        device.write_conf_reg16(self.offset + 2, control | multiple_message << 4 | MSI_ENABLE);
+    }
+
+    pub fn is_enabled(&self, device: &Device) -> bool {
+        device.read_conf_reg16(self.offset + 2) & MSI_ENABLE != 0
+    }
+
+    pub fn disable(&self, device: &Device) {
+        let control = device.read_conf_reg16(self.offset + 2);
+        device.write_conf_reg16(self.offset + 2, control & !MSI_ENABLE);
+    }
+}
+
+impl MsiX {
+    pub fn read(device: &Device) -> Option<Self> {
+        let offset = device.find_capability(CAP_MSI_X)?.offset;
+        let control = device.read_conf_reg16(offset + 2);
+        let table = device.read_conf_reg(offset + 4);
+        let pba = device.read_conf_reg(offset + 8);
+        Some(Self {
+            offset,
+            table_size: (control & 0x7FF) + 1,
+            table_bar: (table & 0x7) as usize,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as usize,
+            pba_offset: pba & !0x7,
+        })
+    }
+
+    pub fn is_enabled(&self, device: &Device) -> bool {
+        device.read_conf_reg16(self.offset + 2) & MSI_X_ENABLE != 0
+    }
+
+    pub fn set_enabled(&self, device: &Device, enabled: bool) {
+        self.update_control(device, MSI_X_ENABLE, enabled);
+    }
+
+    /// 立てている間は、テーブルの設定に関わらずすべてのベクタが送られない
+    pub fn set_function_mask(&self, device: &Device, masked: bool) {
+        self.update_control(device, MSI_X_FUNCTION_MASK, masked);
+    }
+
+    fn update_control(&self, device: &Device, bit: u16, set: bool) {
+        let control = device.read_conf_reg16(self.offset + 2);
+        let control = if set { control | bit } else { control & !bit };
+        device.write_conf_reg16(self.offset + 2, control);
+    }
+}
+
+impl PciExpress {
+    pub fn read(device: &Device) -> Option<Self> {
+        let offset = device.find_capability(CAP_PCI_EXPRESS)?.offset;
+        let capabilities = device.read_conf_reg16(offset + 2);
+        Some(Self {
+            offset,
+            version: (capabilities & 0xF) as u8,
+            port_type: ((capabilities >> 4) & 0xF) as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capability::{Capability, Msi, MsiX, PowerManagement, CAP_MSI, CAP_MSI_X, CAP_POWER_MANAGEMENT};
+    use crate::config::ConfigAccess;
+    use crate::device::Device;
+    use crate::testing::FakeConfig;
+
+    const LOCATION: (u8, u8, u8) = (0, 4, 0);
+
+    fn fake_with_capabilities() -> FakeConfig {
+        let fake = FakeConfig::new();
+        fake.add(LOCATION, 0x1001_1AF4, 0x0100_0000, 0x00, 0);
+        fake.write(0, 4, 0, 0x04, 1 << 20);
+        fake.write(0, 4, 0, 0x34, 0x40);
+        // 0x40: 電源管理(D3hot) -> 0x50: 64bitのMSI(4ベクタ) -> 0x70: MSI-X(8エントリ、テーブルはBAR1の0x800)
+        fake.write(0, 4, 0, 0x40, 0x0003_5001);
+        fake.write(0, 4, 0, 0x44, 0x0000_0003);
+        fake.write(0, 4, 0, 0x50, 0x0084_7005);
+        fake.write(0, 4, 0, 0x70, 0x0007_0011);
+        fake.write(0, 4, 0, 0x74, 0x0000_0801);
+        fake.write(0, 4, 0, 0x78, 0x0000_0C01);
+        fake
+    }
+
+    #[test]
+    fn capability_list_is_walked_in_order() {
+        let _lock = fake_with_capabilities().install();
+        let device = Device::new(0, 4, 0, 0x00);
+        assert_eq!(device.capabilities(), [
+            Capability { id: CAP_POWER_MANAGEMENT, offset: 0x40 },
+            Capability { id: CAP_MSI, offset: 0x50 },
+            Capability { id: CAP_MSI_X, offset: 0x70 },
+        ]);
+        assert_eq!(device.find_capability(CAP_MSI_X).map(|capability| capability.offset), Some(0x70));
+        assert!(device.find_capability(0x10).is_none());
+        // ECAMが無いので拡張ケーパビリティは読まない
+        assert!(device.extended_capabilities().is_empty());
+
+        let pm = PowerManagement::read(&device).unwrap();
+        assert_eq!(pm.state(&device), 3);
+        pm.set_state(&device, 0);
+        assert_eq!(pm.state(&device), 0);
+
+        let msi_x = MsiX::read(&device).unwrap();
+        assert_eq!((msi_x.table_size, msi_x.table_bar, msi_x.table_offset), (8, 1, 0x800));
+        assert_eq!((msi_x.pba_bar, msi_x.pba_offset), (1, 0xC00));
+        msi_x.set_enabled(&device, true);
+        assert_eq!(device.read_conf_reg(0x70) >> 16, 0x8007);
+    }
+
+    #[test]
+    fn msi_is_programmed_with_address_and_data() {
+        let _lock = fake_with_capabilities().install();
+        let device = Device::new(0, 4, 0, 0x00);
+        let msi = Msi::read(&device).unwrap();
+        assert!(msi.is_64bit && msi.max_vectors == 4 && !msi.per_vector_masking);
+
+        msi.enable(&device, 0xFEE0_1000, 0x0050, 1);
+        assert_eq!(device.read_conf_reg(0x54), 0xFEE0_1000);
+        assert_eq!(device.read_conf_reg(0x58), 0);
+        assert_eq!(device.read_conf_reg(0x5C), 0x0050);
+        // 有効になり、Multiple Message Enableは1ベクタ(0)、次のポインタはそのまま
+        assert_eq!(device.read_conf_reg(0x50), 0x0085_7005);
+
+        msi.disable(&device);
+        assert_eq!(device.read_conf_reg(0x50), 0x0084_7005);
+    }
+}
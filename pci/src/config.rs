@@ -0,0 +1,324 @@
+use core::arch::asm;
+use spin::{Mutex, RwLock};
+
+// CONFIG_ADDRESSレジスタのIOポートアドレス
+const CONFIG_ADDRESS: u16 = 0x0CF8;
+// CONFIG_DATAレジスタ
+const CONFIG_DATA: u16 = 0x0CFC;
+
+/// 従来のコンフィギュレーション空間の大きさ
+pub const LEGACY_CONFIG_SIZE: usize = 0x100;
+/// PCI Expressで拡張されたコンフィギュレーション空間の大きさ
+pub const EXTENDED_CONFIG_SIZE: usize = 0x1000;
+
+/// コンフィギュレーション空間への読み書きの方法
+pub trait ConfigAccess: Send + Sync {
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32;
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32);
+    /// 読み書きできるコンフィギュレーション空間の大きさ
+    fn config_size(&self) -> usize;
+
+    fn read16(&self, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        (self.read(bus, device, function, offset & !0x3) >> ((offset & 0x2) * 8)) as u16
+    }
+
+    fn read8(&self, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        (self.read(bus, device, function, offset & !0x3) >> ((offset & 0x3) * 8)) as u8
+    }
+
+    /// 標準の実装は4バイト読んで書き戻すので、同じ4バイトの中の1を書くとクリアされるビットも消える
+    /// 2バイト単位で書ける方法では上書きする
+    fn write16(&self, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        let shift = (offset & 0x2) * 8;
+        let old = self.read(bus, device, function, offset & !0x3) & !(0xFFFF << shift);
+        self.write(bus, device, function, offset & !0x3, old | (value as u32) << shift);
+    }
+
+    fn write8(&self, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        let shift = (offset & 0x3) * 8;
+        let old = self.read(bus, device, function, offset & !0x3) & !(0xFF << shift);
+        self.write(bus, device, function, offset & !0x3, old | (value as u32) << shift);
+    }
+}
+
+/// 0xCF8/0xCFCのI/Oポートを使う方法。先頭の256バイトしか読み書きできない
+pub struct PortIo;
+
+/// PCI Expressのenhanced configuration access mechanism (ECAM)
+///
+/// 一つのバスにつき1MiB、一つのファンクションにつき4KiBの領域がメモリにマップされている
+pub struct Ecam {
+    base: usize,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// アドレスを書いてからデータを読み書きするまでの間に、他の処理が割り込まないようにする
+static PORT_LOCK: Mutex<()> = Mutex::new(());
+
+static ACCESS: RwLock<&'static dyn ConfigAccess> = RwLock::new(&PortIo);
+
+/// 以降のコンフィギュレーション空間へのアクセスに`access`を使う
+pub fn set_access(access: &'static dyn ConfigAccess) {
+    *ACCESS.write() = access;
+}
+
+pub fn access() -> &'static dyn ConfigAccess {
+    *ACCESS.read()
+}
+
+/// コンフィギュレーション空間の`offset`から4バイト読む
+pub fn read(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    access().read(bus, device, function, offset)
+}
+
+/// コンフィギュレーション空間の`offset`に4バイト書く
+pub fn write(bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+    access().write(bus, device, function, offset, value)
+}
+
+/// `offset`から2バイト読む。`offset`は2の倍数でなければならない
+pub fn read16(bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+    debug_assert!(offset.is_multiple_of(2), "unaligned config access: {:#x}", offset);
+    access().read16(bus, device, function, offset)
+}
+
+pub fn read8(bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+    access().read8(bus, device, function, offset)
+}
+
+/// `offset`に2バイト書く。`offset`は2の倍数でなければならない
+pub fn write16(bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+    debug_assert!(offset.is_multiple_of(2), "unaligned config access: {:#x}", offset);
+    access().write16(bus, device, function, offset, value)
+}
+
+pub fn write8(bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+    access().write8(bus, device, function, offset, value)
+}
+
+impl ConfigAccess for PortIo {
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return 0xFFFF_FFFF;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_in32(CONFIG_DATA)
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_out32(CONFIG_DATA, value);
+    }
+
+    fn config_size(&self) -> usize {
+        LEGACY_CONFIG_SIZE
+    }
+
+    /// CONFIG_DATAのうち、`offset`の下位2bitの位置のポートを読み書きすれば、その幅だけアクセスできる
+    fn read16(&self, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return 0xFFFF;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_in16(CONFIG_DATA + (offset & 0x2))
+    }
+
+    fn read8(&self, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return 0xFF;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_in8(CONFIG_DATA + (offset & 0x3))
+    }
+
+    fn write16(&self, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_out16(CONFIG_DATA + (offset & 0x2), value);
+    }
+
+    fn write8(&self, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        if offset as usize >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        let _lock = PORT_LOCK.lock();
+        io_out32(CONFIG_ADDRESS, make_address(bus, device, function, offset as u8));
+        io_out8(CONFIG_DATA + (offset & 0x3), value);
+    }
+}
+
+impl Ecam {
+    /// `base`は`start_bus`の領域がマップされている仮想アドレス
+    ///
+    /// # Safety
+    /// `start_bus`から`end_bus`までの領域が、キャッシュ無効でマップされていなければならない
+    pub unsafe fn new(base: usize, start_bus: u8, end_bus: u8) -> Self {
+        Self { base, start_bus, end_bus }
+    }
+
+    pub fn bus_range(&self) -> (u8, u8) {
+        (self.start_bus, self.end_bus)
+    }
+
+    /// アクセスの幅に合わせて`offset`の下位ビットを落とすのは呼び出し側で行う
+    fn address<T>(&self, bus: u8, device: u8, function: u8, offset: u16) -> Option<*mut T> {
+        if bus < self.start_bus || bus > self.end_bus {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as usize) << 20
+            | ((device & 0x1F) as usize) << 15
+            | ((function & 0x07) as usize) << 12
+            | (offset as usize & 0xFFF);
+        Some((self.base + offset) as *mut T)
+    }
+}
+
+impl ConfigAccess for Ecam {
+    /// 範囲外のバスはI/Oポートで読む
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        match self.address::<u32>(bus, device, function, offset & !0x3) {
+            Some(address) => unsafe { address.read_volatile() },
+            None => PortIo.read(bus, device, function, offset),
+        }
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        match self.address::<u32>(bus, device, function, offset & !0x3) {
+            Some(address) => unsafe { address.write_volatile(value) },
+            None => PortIo.write(bus, device, function, offset, value),
+        }
+    }
+
+    fn config_size(&self) -> usize {
+        EXTENDED_CONFIG_SIZE
+    }
+
+    fn read16(&self, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        match self.address::<u16>(bus, device, function, offset & !0x1) {
+            Some(address) => unsafe { address.read_volatile() },
+            None => PortIo.read16(bus, device, function, offset),
+        }
+    }
+
+    fn read8(&self, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        match self.address::<u8>(bus, device, function, offset) {
+            Some(address) => unsafe { address.read_volatile() },
+            None => PortIo.read8(bus, device, function, offset),
+        }
+    }
+
+    fn write16(&self, bus: u8, device: u8, function: u8, offset: u16, value: u16) {
+        match self.address::<u16>(bus, device, function, offset & !0x1) {
+            Some(address) => unsafe { address.write_volatile(value) },
+            None => PortIo.write16(bus, device, function, offset, value),
+        }
+    }
+
+    fn write8(&self, bus: u8, device: u8, function: u8, offset: u16, value: u8) {
+        match self.address::<u8>(bus, device, function, offset) {
+            Some(address) => unsafe { address.write_volatile(value) },
+            None => PortIo.write8(bus, device, function, offset, value),
+        }
+    }
+}
+
+fn make_address(bus: u8, device: u8, function: u8, reg_addr: u8) -> u32 {
+    let shl = |x: u8, bits: usize| -> u32 {
+        (x as u32) << bits
+    };
+
+    shl(1, 31) | shl(bus, 16) | shl(device, 11) | shl(function, 8) | (reg_addr & 0xFC) as u32
+}
+
+fn io_out32(addr: u16, data: u32) {
+    unsafe {
+        asm!("out dx, eax", in("dx") addr, in("eax") data, options(nomem, nostack, preserves_flags));
+    }
+}
+
+fn io_in32(addr: u16) -> u32 {
+    let ret: u32;
+    unsafe {
+        asm!("in eax, dx", out("eax") ret, in("dx") addr, options(nomem, nostack, preserves_flags));
+    }
+    ret
+}
+
+fn io_out16(addr: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax", in("dx") addr, in("ax") data, options(nomem, nostack, preserves_flags));
+    }
+}
+
+fn io_in16(addr: u16) -> u16 {
+    let ret: u16;
+    unsafe {
+        asm!("in ax, dx", out("ax") ret, in("dx") addr, options(nomem, nostack, preserves_flags));
+    }
+    ret
+}
+
+fn io_out8(addr: u16, data: u8) {
+    unsafe {
+        asm!("out dx, al", in("dx") addr, in("al") data, options(nomem, nostack, preserves_flags));
+    }
+}
+
+fn io_in8(addr: u16) -> u8 {
+    let ret: u8;
+    unsafe {
+        asm!("in al, dx", out("al") ret, in("dx") addr, options(nomem, nostack, preserves_flags));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigAccess;
+    use crate::device::Device;
+    use crate::register::{Command, Status};
+    use crate::testing::FakeConfig;
+
+    #[test]
+    fn narrow_writes_keep_the_rest_of_the_dword() {
+        let fake = FakeConfig::new();
+        fake.add((0, 2, 0), 0x1111_8086, 0x0C03_3000, 0x00, 0);
+        fake.write(0, 2, 0, 0x3C, 0x0000_010B);
+        let _lock = fake.install();
+        let device = Device::new(0, 2, 0, 0x00);
+
+        assert_eq!(device.read_conf_reg16(0x02), 0x1111);
+        assert_eq!(device.read_conf_reg8(0x0B), 0x0C);
+        device.write_conf_reg8(0x3C, 0x05);
+        device.write_conf_reg16(0x3E, 0xABCD);
+        assert_eq!(device.read_conf_reg(0x3C), 0xABCD_0105);
+    }
+
+    #[test]
+    fn command_bits_are_set_and_cleared() {
+        let fake = FakeConfig::new();
+        fake.add((0, 2, 0), 0x1111_8086, 0x0C03_3000, 0x00, 0);
+        fake.write(0, 2, 0, 0x04, 0x0010_0001);
+        let _lock = fake.install();
+        let device = Device::new(0, 2, 0, 0x00);
+
+        device.enable_command(Command::MEMORY_SPACE | Command::BUS_MASTER);
+        assert_eq!(device.command(), Command(0b111));
+        device.disable_command(Command::IO_SPACE);
+        device.enable_command(Command::INTERRUPT_DISABLE);
+        assert_eq!(device.command().bits(), 0x0406);
+        assert!(device.status().contains(Status::CAPABILITIES_LIST));
+        assert!(!device.status().contains(Status::RECEIVED_MASTER_ABORT));
+    }
+}
@@ -0,0 +1,300 @@
+use alloc::vec::Vec;
+use crate::bar::{self, Bar};
+use crate::capability::{self, Capability, ExtendedCapability};
+use crate::config;
+use crate::error::PciError;
+use crate::register::{Command, Status};
+
+#[derive(Default, Debug, Copy, Clone)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub header_type: u8,
+    pub class_code: ClassCode,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub revision: u8,
+    /// ヘッダタイプ0と2にだけある。ブリッジでは0になる
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+}
+
+#[derive(Default, Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClassCode {
+    base: u8,
+    sub: u8,
+    interface: u8,
+}
+
+/// ヘッダタイプの下位7bitが表すヘッダの形式
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HeaderType {
+    Endpoint,
+    PciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+/// ヘッダの形式ごとに異なる部分を読み出したもの
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Header {
+    Endpoint {
+        subsystem_vendor_id: u16,
+        subsystem_id: u16,
+        interrupt_line: u8,
+        interrupt_pin: u8,
+    },
+    PciBridge {
+        primary_bus: u8,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+        interrupt_line: u8,
+        interrupt_pin: u8,
+    },
+    CardBusBridge {
+        primary_bus: u8,
+        secondary_bus: u8,
+        subordinate_bus: u8,
+        subsystem_vendor_id: u16,
+        subsystem_id: u16,
+        interrupt_line: u8,
+        interrupt_pin: u8,
+    },
+    Unknown,
+}
+
+pub const fn calc_bar_address(bar_index: usize) -> u16 {
+    (0x10 + 4 * bar_index) as u16
+}
+
+impl Device {
+    pub fn new(bus: u8, device: u8, function: u8, header_type: u8) -> Self {
+        let ids = config::read(bus, device, function, 0x00);
+        let class_code = config::read(bus, device, function, 0x08);
+        let mut new = Self {
+            bus,
+            device,
+            function,
+            header_type,
+            class_code: ClassCode::from(class_code),
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            revision: class_code as u8,
+            ..Self::default()
+        };
+        match new.header() {
+            Header::Endpoint { subsystem_vendor_id, subsystem_id, .. }
+            | Header::CardBusBridge { subsystem_vendor_id, subsystem_id, .. } => {
+                new.subsystem_vendor_id = subsystem_vendor_id;
+                new.subsystem_id = subsystem_id;
+            }
+            _ => {}
+        }
+        new
+    }
+
+    pub fn header_kind(&self) -> HeaderType {
+        HeaderType::from(self.header_type)
+    }
+
+    pub fn is_multi_function(&self) -> bool {
+        self.header_type & 0x80 != 0
+    }
+
+    /// ヘッダの形式に合わせて、形式ごとに異なるフィールドを読む
+    pub fn header(&self) -> Header {
+        let interrupt = self.read_conf_reg(0x3C);
+        let (interrupt_line, interrupt_pin) = (interrupt as u8, (interrupt >> 8) as u8);
+        match self.header_kind() {
+            HeaderType::Endpoint => {
+                let subsystem = self.read_conf_reg(0x2C);
+                Header::Endpoint {
+                    subsystem_vendor_id: subsystem as u16,
+                    subsystem_id: (subsystem >> 16) as u16,
+                    interrupt_line,
+                    interrupt_pin,
+                }
+            }
+            HeaderType::PciBridge => {
+                let bus_numbers = self.read_conf_reg(0x18);
+                Header::PciBridge {
+                    primary_bus: bus_numbers as u8,
+                    secondary_bus: (bus_numbers >> 8) as u8,
+                    subordinate_bus: (bus_numbers >> 16) as u8,
+                    interrupt_line,
+                    interrupt_pin,
+                }
+            }
+            HeaderType::CardBusBridge => {
+                let bus_numbers = self.read_conf_reg(0x18);
+                let subsystem = self.read_conf_reg(0x40);
+                Header::CardBusBridge {
+                    primary_bus: bus_numbers as u8,
+                    secondary_bus: (bus_numbers >> 8) as u8,
+                    subordinate_bus: (bus_numbers >> 16) as u8,
+                    subsystem_vendor_id: subsystem as u16,
+                    subsystem_id: (subsystem >> 16) as u16,
+                    interrupt_line,
+                    interrupt_pin,
+                }
+            }
+            HeaderType::Unknown(_) => Header::Unknown,
+        }
+    }
+
+    /// BARをデコードし、大きさを調べる
+    ///
+    /// 大きさを調べる間はデバイスのデコードを止めるので、走査で見つかったデバイスは走査のときに調べた値を返す
+    /// ドライバが使っているデバイスのBARを書き換えることはない
+    pub fn bar(&self, bar_index: usize) -> Result<Bar, PciError> {
+        bar::get(self, bar_index)
+    }
+
+    /// 実装されている全てのBAR
+    pub fn bars(&self) -> Vec<Bar> {
+        bar::get_all(self)
+    }
+
+    /// 標準のケーパビリティリスト
+    pub fn capabilities(&self) -> Vec<Capability> {
+        capability::read_all(self)
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().into_iter().find(|capability| capability.id == id)
+    }
+
+    /// 拡張コンフィギュレーション空間のケーパビリティリスト。ECAMが使えないときは空になる
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability> {
+        capability::read_all_extended(self)
+    }
+
+    pub fn find_extended_capability(&self, id: u16) -> Option<ExtendedCapability> {
+        self.extended_capabilities().into_iter().find(|capability| capability.id == id)
+    }
+
+    pub fn read_class_code(&self) -> ClassCode {
+        self.class_code
+    }
+    pub fn read_vendor_id(&self) -> u32 {
+        self.vendor_id as u32
+    }
+    pub fn read_conf_reg(&self, reg_addr: u16) -> u32 {
+        config::read(self.bus, self.device, self.function, reg_addr)
+    }
+    pub fn write_conf_reg(&self, reg_addr: u16, value: u32) {
+        config::write(self.bus, self.device, self.function, reg_addr, value)
+    }
+    pub fn read_conf_reg16(&self, reg_addr: u16) -> u16 {
+        config::read16(self.bus, self.device, self.function, reg_addr)
+    }
+    pub fn write_conf_reg16(&self, reg_addr: u16, value: u16) {
+        config::write16(self.bus, self.device, self.function, reg_addr, value)
+    }
+    pub fn read_conf_reg8(&self, reg_addr: u16) -> u8 {
+        config::read8(self.bus, self.device, self.function, reg_addr)
+    }
+    pub fn write_conf_reg8(&self, reg_addr: u16, value: u8) {
+        config::write8(self.bus, self.device, self.function, reg_addr, value)
+    }
+
+    pub fn command(&self) -> Command {
+        Command(self.read_conf_reg16(0x04))
+    }
+
+    pub fn set_command(&self, command: Command) {
+        self.write_conf_reg16(0x04, command.bits())
+    }
+
+    /// コマンドレジスタの`bits`を立てる。他のビットはそのまま
+    pub fn enable_command(&self, bits: Command) {
+        self.set_command(self.command() | bits)
+    }
+
+    pub fn disable_command(&self, bits: Command) {
+        self.set_command(self.command() & !bits)
+    }
+
+    pub fn status(&self) -> Status {
+        Status(self.read_conf_reg16(0x06))
+    }
+
+    /// 1を書くとクリアされるステータスのビットのうち、`bits`だけをクリアする
+    pub fn clear_status(&self, bits: Status) {
+        self.write_conf_reg16(0x06, (bits & Status::ERRORS).bits())
+    }
+}
+
+impl From<u8> for HeaderType {
+    fn from(value: u8) -> Self {
+        match value & 0x7F {
+            0x00 => HeaderType::Endpoint,
+            0x01 => HeaderType::PciBridge,
+            0x02 => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        }
+    }
+}
+
+
+impl ClassCode {
+    pub fn new(base: u8, sub: u8, interface: u8) -> Self {
+        Self {
+            base,
+            sub,
+            interface,
+        }
+    }
+
+    pub fn base(&self) -> u8 {
+        self.base
+    }
+    pub fn sub(&self) -> u8 {
+        self.sub
+    }
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    pub fn equal_b(&self, base: u8) -> bool {
+        self.base == base
+    }
+    pub fn equal_bs(&self, base: u8, sub: u8) -> bool {
+        self.equal_b(base) && self.sub == sub
+    }
+    pub fn equal_bsi(&self, base: u8, sub: u8, interface: u8) -> bool {
+        self.equal_bs(base, sub) && self.interface == interface
+    }
+}
+
+impl From<u32> for ClassCode {
+    fn from(value: u32) -> Self {
+        Self::new(
+            ((value >> 24) & 0xFF) as u8,
+            ((value >> 16) & 0xFF) as u8,
+            ((value >> 8) & 0xFF) as u8,
+        )
+    }
+}
+
+impl From<ClassCode> for u32 {
+    fn from(value: ClassCode) -> Self {
+        ((value.base as u32) << 24) | ((value.sub as u32) << 16) | ((value.interface as u32) << 8)
+    }
+}
+
+#[test]
+fn class_code_from_test() {
+    let class_code = ClassCode::from(1010);
+    let casted_class_code = u32::from(class_code);
+
+    assert_eq!(class_code, ClassCode::from(casted_class_code), "From implementation is wrong");
+}
+
+#[test]
+fn header_type_ignores_multi_function_bit() {
+    assert_eq!(HeaderType::from(0x81), HeaderType::PciBridge);
+    assert_eq!(HeaderType::from(0x02), HeaderType::CardBusBridge);
+    assert_eq!(HeaderType::from(0x7F), HeaderType::Unknown(0x7F));
+}
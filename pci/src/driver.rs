@@ -0,0 +1,80 @@
+use alloc::vec::Vec;
+use log::debug;
+use spin::Mutex;
+use crate::device::Device;
+
+/// ドライバが受け持つデバイスの条件
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DeviceMatch {
+    /// ベンダIDとデバイスIDが一致するデバイス
+    Id { vendor: u16, device: u16 },
+    /// クラスコードが一致するデバイス。`interface`が`None`ならプログラミングインターフェースは問わない
+    Class { base: u8, sub: u8, interface: Option<u8> },
+}
+
+/// PCIデバイスのドライバ
+///
+/// `probe`はデバイスを初期化できたら`true`を返す。`false`なら次に条件が合うドライバを試す
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    pub probe: fn(&Device) -> bool,
+}
+
+/// 登録されたドライバ。先に登録されたものから順に試す
+static DRIVERS: Mutex<Vec<&'static Driver>> = Mutex::new(Vec::new());
+/// バス、デバイス、ファンクションの組
+type Location = (u8, u8, u8);
+
+/// デバイスの位置と、そこに結び付いたドライバの名前
+static BINDINGS: Mutex<Vec<(Location, &'static str)>> = Mutex::new(Vec::new());
+
+impl DeviceMatch {
+    pub fn matches(&self, device: &Device) -> bool {
+        match *self {
+            DeviceMatch::Id { vendor, device: id } => device.vendor_id == vendor && device.device_id == id,
+            DeviceMatch::Class { base, sub, interface: None } => device.class_code.equal_bs(base, sub),
+            DeviceMatch::Class { base, sub, interface: Some(interface) } => {
+                device.class_code.equal_bsi(base, sub, interface)
+            }
+        }
+    }
+}
+
+impl Driver {
+    pub fn matches(&self, device: &Device) -> bool {
+        self.matches.iter().any(|condition| condition.matches(device))
+    }
+}
+
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+}
+
+/// まだドライバが結び付いていないデバイスに、条件の合うドライバを探して結び付ける
+///
+/// ドライバを後から登録した場合も、もう一度呼べばそのドライバが試される
+pub fn probe() {
+    let drivers = DRIVERS.lock().clone();
+    for device in crate::devices() {
+        if bound_driver(&device).is_some() {
+            continue;
+        }
+        for driver in drivers.iter().filter(|driver| driver.matches(&device)) {
+            if (driver.probe)(&device) {
+                debug!("pci: {}.{}.{} bound to {}", device.bus, device.device, device.function, driver.name);
+                BINDINGS.lock().push(((device.bus, device.device, device.function), driver.name));
+                break;
+            }
+        }
+    }
+}
+
+/// デバイスに結び付いているドライバの名前
+pub fn bound_driver(device: &Device) -> Option<&'static str> {
+    let location = (device.bus, device.device, device.function);
+    BINDINGS.lock()
+        .iter()
+        .find(|(bound, _)| *bound == location)
+        .map(|(_, name)| *name)
+}
@@ -0,0 +1,66 @@
+use core::error;
+use core::error::Error;
+use core::fmt::{Debug, Display, Formatter};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// ヘッダの形式が持つ数を超えたBARの番号
+    InvalidBarIndex(usize),
+    /// 前の64bit BARの上位32bitになっているBAR
+    UpperHalfOfBar(usize),
+    /// デバイスが実装していないBAR
+    UnimplementedBar(usize),
+    /// アドレスが割り当てられていないBAR
+    UnassignedBar(usize),
+    /// メモリ空間ではなくI/O空間のBAR
+    NotMemoryBar(usize),
+    /// BARの領域をマップできなかった
+    MapFailed,
+    /// MSIにもMSI-Xにも対応していない
+    NoMsi,
+    /// デバイスが扱えるより多くのベクタを要求した
+    TooManyVectors,
+    /// 割り込みベクタが残っていない
+    NoFreeVector,
+}
+
+impl Debug for PciError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PciError::InvalidBarIndex(index)
+            | PciError::UpperHalfOfBar(index)
+            | PciError::UnimplementedBar(index)
+            | PciError::UnassignedBar(index)
+            | PciError::NotMemoryBar(index) => write!(f, "{} (BAR{})", self.description(), index),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Display for PciError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for PciError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        None
+    }
+}
+
+impl PciError {
+    fn description(&self) -> &'static str {
+        match self {
+            PciError::InvalidBarIndex(_) => "BAR index is out of range for this header type",
+            PciError::UpperHalfOfBar(_) => "BAR is the upper half of a 64-bit BAR",
+            PciError::UnimplementedBar(_) => "BAR is not implemented by the device",
+            PciError::UnassignedBar(_) => "BAR has no address assigned",
+            PciError::NotMemoryBar(_) => "BAR is not a memory BAR",
+            PciError::MapFailed => "Failed to map the BAR",
+            PciError::NoMsi => "Device supports neither MSI nor MSI-X",
+            PciError::TooManyVectors => "Device cannot use that many interrupt vectors",
+            PciError::NoFreeVector => "No free interrupt vector is left",
+        }
+    }
+}
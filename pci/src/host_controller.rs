@@ -0,0 +1,50 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::device::Device;
+
+/// ホストコントローラが列挙したUSBデバイスの概要
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbDeviceInfo {
+    /// つながっているルートハブのポート番号
+    pub port: u8,
+    /// コントローラが割り当てたスロットID
+    pub slot: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// デバイスディスクリプタのクラスコード。0ならインターフェースごとに決まる
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// PCIバス上のUSBホストコントローラ
+///
+/// ドライバとUSBのコアはカーネルにあり、ここではPCIデバイスとしてのコントローラと、
+/// その先に列挙されたUSBデバイスを見るための窓口だけを決める
+pub trait HostController: Send + Sync {
+    /// コントローラに結び付いたドライバの名前
+    fn name(&self) -> &'static str;
+
+    fn device(&self) -> Device;
+
+    /// ルートハブのポートの数
+    fn port_count(&self) -> usize;
+
+    /// 列挙が終わり、接続されているUSBデバイス
+    fn usb_devices(&self) -> Vec<UsbDeviceInfo>;
+
+    /// スロットのデバイスからGET_DESCRIPTORでディスクリプタを読む。`index`は文字列ディスクリプタなどの番号
+    fn read_descriptor(&self, slot: u8, descriptor_type: u8, index: u8, length: u16) -> Option<Vec<u8>>;
+}
+
+/// ドライバが初期化したホストコントローラ
+static HOST_CONTROLLERS: Mutex<Vec<Arc<dyn HostController>>> = Mutex::new(Vec::new());
+
+pub fn register_host_controller(controller: Arc<dyn HostController>) {
+    HOST_CONTROLLERS.lock().push(controller);
+}
+
+pub fn host_controllers() -> Vec<Arc<dyn HostController>> {
+    HOST_CONTROLLERS.lock().clone()
+}
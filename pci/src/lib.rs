@@ -1,3 +1,218 @@
 #![no_std]
 
-mod host_controller;
\ No newline at end of file
+extern crate alloc;
+
+pub mod bar;
+pub mod capability;
+pub mod config;
+pub mod device;
+pub mod driver;
+pub mod error;
+pub mod host_controller;
+pub mod lspci;
+pub mod names;
+pub mod register;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use log::debug;
+use spin::Mutex;
+use crate::device::{Device, Header};
+
+pub use crate::driver::{bound_driver, probe, register_driver, DeviceMatch, Driver};
+pub use crate::host_controller::{host_controllers, register_host_controller, HostController, UsbDeviceInfo};
+
+/// 走査で見つかったデバイス
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+
+/// 見つかったデバイスの一覧
+pub fn devices() -> Vec<Device> {
+    DEVICES.lock().clone()
+}
+
+/// クラスコードが一致するデバイス。`interface`が`None`ならプログラミングインターフェースは問わない
+pub fn find_by_class(base: u8, sub: u8, interface: Option<u8>) -> Vec<Device> {
+    find(DeviceMatch::Class { base, sub, interface })
+}
+
+/// ベンダIDとデバイスIDが一致するデバイス
+pub fn find_by_id(vendor: u16, device: u16) -> Vec<Device> {
+    find(DeviceMatch::Id { vendor, device })
+}
+
+pub fn find(condition: DeviceMatch) -> Vec<Device> {
+    DEVICES.lock()
+        .iter()
+        .filter(|device| condition.matches(device))
+        .copied()
+        .collect()
+}
+
+/// 見つかったデバイスすべてを`lspci`の形式で並べる。`verbose`なら`lspci -v`と同じく詳細も含める
+pub fn lspci(verbose: bool) -> String {
+    let mut text = String::new();
+    for device in devices() {
+        if verbose {
+            text.push_str(&lspci::verbose(&device));
+        } else {
+            text.push_str(&lspci::summary(&device));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// バス0から、ブリッジをたどって下流のバスを再帰的に走査する
+///
+/// ホストブリッジが複数あると、他のルートバスはどのブリッジからもたどれないので、
+/// まだ見ていないバスにデバイスがあればそこからも走査する
+pub fn scan_all_bus() {
+    let mut scanner = Scanner {
+        devices: Vec::new(),
+        visited: [false; 256],
+    };
+    scanner.scan_bus(0);
+    for bus in 1..=255 {
+        if !scanner.visited[bus as usize] && (0..32).any(|device| read_vendor_id(bus, device, 0) != 0xFFFF) {
+            scanner.scan_bus(bus);
+        }
+    }
+    // ドライバが使い始めてからBARの大きさを調べ直さなくて済むように、ここで調べておく
+    bar::size_all(&scanner.devices);
+    *DEVICES.lock() = scanner.devices;
+}
+
+struct Scanner {
+    devices: Vec<Device>,
+    /// ブリッジの設定がおかしくても、同じバスを二度走査しないようにする
+    visited: [bool; 256],
+}
+
+impl Scanner {
+    fn scan_bus(&mut self, bus: u8) {
+        if self.visited[bus as usize] {
+            return;
+        }
+        self.visited[bus as usize] = true;
+
+        for device in 0..32 {
+            if read_vendor_id(bus, device, 0) == 0xFFFF {
+                continue;
+            }
+            self.scan_device(bus, device);
+        }
+    }
+
+    fn scan_device(&mut self, bus: u8, device: u8) {
+        self.scan_function(bus, device, 0);
+        if is_single_function_device(read_header_type(bus, device, 0)) {
+            return;
+        }
+
+        for function in 1..8 {
+            if read_vendor_id(bus, device, function) == 0xFFFF {
+                continue;
+            }
+            self.scan_function(bus, device, function);
+        }
+    }
+
+    fn scan_function(&mut self, bus: u8, device: u8, function: u8) {
+        let device = Device::new(bus, device, function, read_header_type(bus, device, function));
+        self.devices.push(device);
+
+        // PCI-PCIブリッジとCardBusブリッジは、secondaryからsubordinateまでのバスを下流に持つ
+        let (primary_bus, secondary_bus, subordinate_bus) = match device.header() {
+            Header::PciBridge { primary_bus, secondary_bus, subordinate_bus, .. }
+            | Header::CardBusBridge { primary_bus, secondary_bus, subordinate_bus, .. } => {
+                (primary_bus, secondary_bus, subordinate_bus)
+            }
+            _ => return,
+        };
+        // バス番号が割り当てられていないブリッジは飛ばす
+        if secondary_bus <= bus || secondary_bus > subordinate_bus {
+            debug!("pci: {}.{}.{}: unconfigured bridge (primary {}, secondary {}, subordinate {})",
+                   bus, device.device, device.function, primary_bus, secondary_bus, subordinate_bus);
+            return;
+        }
+        self.scan_bus(secondary_bus);
+    }
+}
+
+fn read_header_type(bus: u8, device: u8, function: u8) -> u8 {
+    ((config::read(bus, device, function, 0x0C) >> 16) & 0xFF) as u8
+}
+
+fn read_vendor_id(bus: u8, device: u8, function: u8) -> u32 {
+    config::read(bus, device, function, 0x00) & 0xFFFF
+}
+
+fn is_single_function_device(header_type: u8) -> bool {
+    (header_type & 0x80) == 0
+}
+
+#[cfg(test)]
+mod testing;
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use crate::testing::FakeConfig;
+
+    #[test]
+    fn scan_follows_bridges_and_extra_root_buses() {
+        let fake = FakeConfig::new();
+        // q35に似た構成。0:1.0のルートポートの下に、さらにPCIブリッジがある
+        fake.add((0, 0, 0), 0x29C0_8086, 0x0600_0000, 0x00, 0);
+        fake.add((0, 1, 0), 0x000C_1B36, 0x0604_0000, 0x01, 0x0002_0100);
+        fake.add((0, 2, 0), 0x000C_1B36, 0x0604_0000, 0x01, 0);
+        fake.add((0, 0x1F, 0), 0x2918_8086, 0x0601_0000, 0x80, 0);
+        fake.add((0, 0x1F, 2), 0x2922_8086, 0x0106_0100, 0x00, 0);
+        fake.add((0, 0x1F, 3), 0x2930_8086, 0x0C05_0000, 0x00, 0);
+        fake.add((1, 0, 0), 0x000E_1B36, 0x0604_0000, 0x01, 0x0002_0201);
+        fake.add((2, 3, 0), 0x1001_1AF4, 0x0100_0000, 0x00, 0);
+        // どのブリッジからもたどれない、二つ目のホストブリッジの下のバス
+        fake.add((0x40, 0, 0), 0x000D_1B36, 0x0C03_3000, 0x00, 0);
+        let _lock = fake.install();
+
+        super::scan_all_bus();
+        let locations: Vec<(u8, u8, u8)> = super::devices()
+            .iter()
+            .map(|device| (device.bus, device.device, device.function))
+            .collect();
+        assert_eq!(locations, [
+            (0, 0, 0),
+            (0, 1, 0),
+            (1, 0, 0),
+            (2, 3, 0),
+            (0, 2, 0),
+            (0, 0x1F, 0),
+            (0, 0x1F, 2),
+            (0, 0x1F, 3),
+            (0x40, 0, 0),
+        ]);
+        assert_eq!(super::find_by_class(0x01, 0x06, Some(0x01)).len(), 1);
+        assert_eq!(super::find_by_id(0x1AF4, 0x1001)[0].bus, 2);
+    }
+
+    #[test]
+    fn scanned_bars_are_not_sized_again() {
+        let fake = FakeConfig::new();
+        fake.add((0, 0, 0), 0x29C0_8086, 0x0600_0000, 0x00, 0);
+        fake.add((0, 5, 0), 0x0010_1B36, 0x0108_0200, 0x00, 0);
+        fake.set_bar((0, 5, 0), 0, 0xFEB8_0000, 0xFFFF_C000);
+        let _lock = fake.install();
+
+        super::scan_all_bus();
+        let device = super::find_by_id(0x1B36, 0x0010)[0];
+        let bar = device.bar(0).unwrap();
+        assert_eq!((bar.address, bar.size), (0xFEB8_0000, 0x4000));
+
+        // 大きさを調べるには全て1を書き込む必要がある。走査の後は書き込まずに、走査のときの値を返す
+        device.write_conf_reg(0x10, 0xFEC0_0000);
+        assert_eq!(device.bar(0), Ok(bar));
+        assert_eq!(device.bars(), [bar]);
+        assert_eq!(device.read_conf_reg(0x10), 0xFEC0_0000);
+        assert_eq!(device.bar(1), Err(crate::error::PciError::UnimplementedBar(1)));
+    }
+}
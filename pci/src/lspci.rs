@@ -0,0 +1,196 @@
+//! `lspci`と同じような形式でデバイスを表示する
+
+use alloc::string::String;
+use core::fmt::Write;
+use crate::bar::{Bar, BarKind};
+use crate::capability::{Msi, MsiX, CAP_MSI, CAP_MSI_X};
+use crate::device::{Device, Header};
+use crate::driver::bound_driver;
+use crate::names::{capability_name, class_name, extended_capability_name, vendor_name};
+use crate::register::{Command, Status};
+
+/// `00:1f.2 SATA controller [0106]: Intel Corporation Device [8086:2922] (rev 02)`のような一行
+pub fn summary(device: &Device) -> String {
+    let mut line = String::new();
+    let class_code = device.class_code;
+    let _ = write!(
+        line,
+        "{:02x}:{:02x}.{} {} [{:02x}{:02x}]: {} Device [{:04x}:{:04x}]",
+        device.bus,
+        device.device,
+        device.function,
+        class_name(class_code),
+        class_code.base(),
+        class_code.sub(),
+        vendor_name(device.vendor_id).unwrap_or("Unknown vendor"),
+        device.vendor_id,
+        device.device_id,
+    );
+    if device.revision != 0 {
+        let _ = write!(line, " (rev {:02x})", device.revision);
+    }
+    if class_code.interface() != 0 {
+        let _ = write!(line, " (prog-if {:02x})", class_code.interface());
+    }
+    line
+}
+
+/// `lspci -v`のように、レジスタ、BAR、ケーパビリティと結び付いたドライバも並べる
+pub fn verbose(device: &Device) -> String {
+    let mut text = summary(device);
+    text.push('\n');
+    let _ = write_details(&mut text, device);
+    text
+}
+
+fn write_details(out: &mut String, device: &Device) -> core::fmt::Result {
+    if device.subsystem_vendor_id != 0 {
+        writeln!(out, "\tSubsystem: [{:04x}:{:04x}]", device.subsystem_vendor_id, device.subsystem_id)?;
+    }
+
+    let command = device.command();
+    writeln!(
+        out,
+        "\tControl: I/O{} Mem{} BusMaster{} SERR{} DisINTx{}",
+        flag(command.contains(Command::IO_SPACE)),
+        flag(command.contains(Command::MEMORY_SPACE)),
+        flag(command.contains(Command::BUS_MASTER)),
+        flag(command.contains(Command::SERR)),
+        flag(command.contains(Command::INTERRUPT_DISABLE)),
+    )?;
+    let status = device.status();
+    writeln!(
+        out,
+        "\tStatus: Cap{} INTx{} >TAbort{} <TAbort{} <MAbort{} >SERR{} <PERR{}",
+        flag(status.contains(Status::CAPABILITIES_LIST)),
+        flag(status.contains(Status::INTERRUPT)),
+        flag(status.contains(Status::SIGNALED_TARGET_ABORT)),
+        flag(status.contains(Status::RECEIVED_TARGET_ABORT)),
+        flag(status.contains(Status::RECEIVED_MASTER_ABORT)),
+        flag(status.contains(Status::SIGNALED_SYSTEM_ERROR)),
+        flag(status.contains(Status::DETECTED_PARITY_ERROR)),
+    )?;
+
+    let (interrupt_line, interrupt_pin) = match device.header() {
+        Header::Endpoint { interrupt_line, interrupt_pin, .. }
+        | Header::PciBridge { interrupt_line, interrupt_pin, .. }
+        | Header::CardBusBridge { interrupt_line, interrupt_pin, .. } => (interrupt_line, interrupt_pin),
+        Header::Unknown => (0, 0),
+    };
+    if (1..=4).contains(&interrupt_pin) {
+        writeln!(out, "\tInterrupt: pin {} routed to IRQ {}", (b'A' + interrupt_pin - 1) as char, interrupt_line)?;
+    }
+    if let Header::PciBridge { primary_bus, secondary_bus, subordinate_bus, .. }
+    | Header::CardBusBridge { primary_bus, secondary_bus, subordinate_bus, .. } = device.header()
+    {
+        writeln!(out, "\tBus: primary={:02x}, secondary={:02x}, subordinate={:02x}", primary_bus, secondary_bus, subordinate_bus)?;
+    }
+
+    for bar in device.bars() {
+        write_bar(out, &bar)?;
+    }
+
+    for capability in device.capabilities() {
+        write!(out, "\tCapabilities: [{:02x}] {}", capability.offset, capability_name(capability.id))?;
+        match capability.id {
+            CAP_MSI => {
+                if let Some(msi) = Msi::read(device) {
+                    write!(out, ": Enable{} Count={} 64bit{}", flag(msi.is_enabled(device)), msi.max_vectors, flag(msi.is_64bit))?;
+                }
+            }
+            CAP_MSI_X => {
+                if let Some(msi_x) = MsiX::read(device) {
+                    write!(out, ": Enable{} Count={}", flag(msi_x.is_enabled(device)), msi_x.table_size)?;
+                }
+            }
+            _ => {}
+        }
+        writeln!(out)?;
+    }
+    for capability in device.extended_capabilities() {
+        writeln!(
+            out,
+            "\tCapabilities: [{:03x} v{}] {}",
+            capability.offset,
+            capability.version,
+            extended_capability_name(capability.id)
+        )?;
+    }
+
+    if let Some(driver) = bound_driver(device) {
+        writeln!(out, "\tKernel driver in use: {}", driver)?;
+    }
+    Ok(())
+}
+
+fn write_bar(out: &mut String, bar: &Bar) -> core::fmt::Result {
+    match bar.kind {
+        BarKind::Io => writeln!(out, "\tRegion {}: I/O ports at {:04x} [size={}]", bar.index, bar.address, format_size(bar.size)),
+        BarKind::Memory32 | BarKind::Memory64 => writeln!(
+            out,
+            "\tRegion {}: Memory at {:x} ({}-bit, {}) [size={}]",
+            bar.index,
+            bar.address,
+            if bar.kind == BarKind::Memory64 { 64 } else { 32 },
+            if bar.prefetchable { "prefetchable" } else { "non-prefetchable" },
+            format_size(bar.size)
+        ),
+    }
+}
+
+fn flag(set: bool) -> char {
+    if set { '+' } else { '-' }
+}
+
+/// `lspci`と同じく、割り切れる最大の単位で表す
+fn format_size(size: u64) -> String {
+    let units = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    for (unit, suffix) in units {
+        if size >= unit && size.is_multiple_of(unit) {
+            return alloc::format!("{}{}", size / unit, suffix);
+        }
+    }
+    alloc::format!("{}", size)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConfigAccess;
+    use crate::device::Device;
+    use crate::lspci::{format_size, summary, verbose};
+    use crate::testing::FakeConfig;
+
+    #[test]
+    fn sizes_use_the_largest_exact_unit() {
+        assert_eq!(format_size(32), "32");
+        assert_eq!(format_size(0x4000), "16K");
+        assert_eq!(format_size(0x10_0000), "1M");
+        assert_eq!(format_size(0x1800), "6K");
+    }
+
+    #[test]
+    fn device_is_formatted_like_lspci() {
+        let fake = FakeConfig::new();
+        fake.add((0, 0x1F, 2), 0x2922_8086, 0x0106_0102, 0x00, 0);
+        fake.set_bar((0, 0x1F, 2), 4, 0x0000_C041, 0x0000_FFE0);
+        fake.set_bar((0, 0x1F, 2), 5, 0xFEBD_1000, 0xFFFF_F000);
+        fake.write(0, 0x1F, 2, 0x04, 0x0010_0407);
+        fake.write(0, 0x1F, 2, 0x34, 0x80);
+        fake.write(0, 0x1F, 2, 0x3C, 0x0000_010B);
+        fake.write(0, 0x1F, 2, 0x80, 0x0080_0005);
+        let _lock = fake.install();
+        let device = Device::new(0, 0x1F, 2, 0x00);
+
+        assert_eq!(summary(&device), "00:1f.2 SATA controller [0106]: Intel Corporation Device [8086:2922] (rev 02) (prog-if 01)");
+        let text = verbose(&device);
+        let lines: alloc::vec::Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1..], [
+            "\tControl: I/O+ Mem+ BusMaster+ SERR- DisINTx+",
+            "\tStatus: Cap+ INTx- >TAbort- <TAbort- <MAbort- >SERR- <PERR-",
+            "\tInterrupt: pin A routed to IRQ 11",
+            "\tRegion 4: I/O ports at c040 [size=32]",
+            "\tRegion 5: Memory at febd1000 (32-bit, non-prefetchable) [size=4K]",
+            "\tCapabilities: [80] MSI: Enable- Count=1 64bit+",
+        ]);
+    }
+}
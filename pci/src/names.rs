@@ -0,0 +1,131 @@
+use crate::device::ClassCode;
+
+/// クラスコードの名前。サブクラスが分からなければベースクラスの名前を返す
+pub fn class_name(class_code: ClassCode) -> &'static str {
+    match (class_code.base(), class_code.sub(), class_code.interface()) {
+        (0x00, 0x01, _) => "VGA compatible unclassified device",
+        (0x00, _, _) => "Unclassified device",
+
+        (0x01, 0x00, _) => "SCSI storage controller",
+        (0x01, 0x01, _) => "IDE interface",
+        (0x01, 0x02, _) => "Floppy disk controller",
+        (0x01, 0x04, _) => "RAID bus controller",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x07, _) => "Serial Attached SCSI controller",
+        (0x01, 0x08, _) => "Non-Volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, 0x80, _) => "Network controller",
+        (0x02, _, _) => "Network controller",
+
+        (0x03, 0x00, _) => "VGA compatible controller",
+        (0x03, 0x01, _) => "XGA compatible controller",
+        (0x03, 0x02, _) => "3D controller",
+        (0x03, _, _) => "Display controller",
+
+        (0x04, 0x00, _) => "Multimedia video controller",
+        (0x04, 0x01, _) => "Multimedia audio controller",
+        (0x04, 0x03, _) => "Audio device",
+        (0x04, _, _) => "Multimedia controller",
+
+        (0x05, 0x00, _) => "RAM memory",
+        (0x05, _, _) => "Memory controller",
+
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, 0x07, _) => "CardBus bridge",
+        (0x06, 0x80, _) => "Bridge",
+        (0x06, _, _) => "Bridge",
+
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, 0x01, _) => "Parallel controller",
+        (0x07, _, _) => "Communication controller",
+
+        (0x08, 0x00, _) => "PIC",
+        (0x08, 0x01, _) => "DMA controller",
+        (0x08, 0x02, _) => "Timer",
+        (0x08, 0x03, _) => "RTC",
+        (0x08, 0x05, _) => "SD Host controller",
+        (0x08, 0x06, _) => "IOMMU",
+        (0x08, _, _) => "System peripheral",
+
+        (0x09, 0x00, _) => "Keyboard controller",
+        (0x09, 0x02, _) => "Mouse controller",
+        (0x09, _, _) => "Input device controller",
+
+        (0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0C, 0x03, _) => "USB controller",
+        (0x0C, 0x00, _) => "FireWire (IEEE 1394)",
+        (0x0C, 0x05, _) => "SMBus",
+        (0x0C, _, _) => "Serial bus controller",
+
+        (0x0D, _, _) => "Wireless controller",
+        (0x0F, _, _) => "Satellite communications controller",
+        (0x10, _, _) => "Encryption controller",
+        (0x11, _, _) => "Signal processing controller",
+        (0x12, _, _) => "Processing accelerators",
+        (0x13, _, _) => "Non-Essential Instrumentation",
+        (0x40, _, _) => "Coprocessor",
+        _ => "Unassigned class",
+    }
+}
+
+/// よく見るベンダの名前
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    Some(match vendor_id {
+        0x8086 => "Intel Corporation",
+        0x1022 => "Advanced Micro Devices, Inc. [AMD]",
+        0x10DE => "NVIDIA Corporation",
+        0x10EC => "Realtek Semiconductor Co., Ltd.",
+        0x1AF4 => "Red Hat, Inc. (virtio)",
+        0x1B36 => "Red Hat, Inc.",
+        0x1234 => "QEMU",
+        0x15AD => "VMware",
+        0x80EE => "InnoTek Systemberatung GmbH (VirtualBox)",
+        _ => return None,
+    })
+}
+
+/// 標準のケーパビリティの名前
+pub fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x02 => "AGP",
+        0x03 => "Vital Product Data",
+        0x04 => "Slot Identification",
+        0x05 => "MSI",
+        0x07 => "PCI-X",
+        0x09 => "Vendor Specific Information",
+        0x0A => "Debug port",
+        0x0D => "Subsystem",
+        0x10 => "Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA HBA",
+        0x13 => "PCI Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+/// 拡張ケーパビリティの名前
+pub fn extended_capability_name(id: u16) -> &'static str {
+    match id {
+        0x0001 => "Advanced Error Reporting",
+        0x0002 => "Virtual Channel",
+        0x0003 => "Device Serial Number",
+        0x0004 => "Power Budgeting",
+        0x000B => "Vendor Specific Information",
+        0x000D => "Access Control Services",
+        0x000E => "Alternative Routing-ID Interpretation",
+        0x0010 => "Single Root I/O Virtualization",
+        0x0018 => "Latency Tolerance Reporting",
+        0x0019 => "Secondary PCI Express",
+        0x001E => "L1 PM Substates",
+        _ => "Unknown",
+    }
+}
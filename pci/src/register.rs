@@ -0,0 +1,89 @@
+use core::ops::{BitAnd, BitOr, Not};
+
+/// コマンドレジスタ(オフセット0x04)
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Command(pub u16);
+
+/// ステータスレジスタ(オフセット0x06)
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Status(pub u16);
+
+impl Command {
+    pub const IO_SPACE: Self = Self(1 << 0);
+    pub const MEMORY_SPACE: Self = Self(1 << 1);
+    /// デバイスがDMAでメモリを読み書きできるようにする
+    pub const BUS_MASTER: Self = Self(1 << 2);
+    pub const SPECIAL_CYCLES: Self = Self(1 << 3);
+    pub const MEMORY_WRITE_AND_INVALIDATE: Self = Self(1 << 4);
+    pub const VGA_PALETTE_SNOOP: Self = Self(1 << 5);
+    pub const PARITY_ERROR_RESPONSE: Self = Self(1 << 6);
+    pub const SERR: Self = Self(1 << 8);
+    pub const FAST_BACK_TO_BACK: Self = Self(1 << 9);
+    /// 従来のINTx#による割り込みを止める。MSIには影響しない
+    pub const INTERRUPT_DISABLE: Self = Self(1 << 10);
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl Status {
+    /// INTx#の割り込みを要求している
+    pub const INTERRUPT: Self = Self(1 << 3);
+    pub const CAPABILITIES_LIST: Self = Self(1 << 4);
+    pub const MHZ_66_CAPABLE: Self = Self(1 << 5);
+    pub const FAST_BACK_TO_BACK_CAPABLE: Self = Self(1 << 7);
+    pub const MASTER_DATA_PARITY_ERROR: Self = Self(1 << 8);
+    pub const SIGNALED_TARGET_ABORT: Self = Self(1 << 11);
+    pub const RECEIVED_TARGET_ABORT: Self = Self(1 << 12);
+    pub const RECEIVED_MASTER_ABORT: Self = Self(1 << 13);
+    pub const SIGNALED_SYSTEM_ERROR: Self = Self(1 << 14);
+    pub const DETECTED_PARITY_ERROR: Self = Self(1 << 15);
+    /// 1を書くとクリアされるエラーのビット
+    pub const ERRORS: Self = Self(0xF900);
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// DEVSEL#のタイミング。0が速い、1が中くらい、2が遅い
+    pub fn devsel_timing(self) -> u8 {
+        ((self.0 >> 9) & 0x3) as u8
+    }
+}
+
+macro_rules! impl_bit_ops {
+    ($name:ident) => {
+        impl BitOr for $name {
+            type Output = Self;
+            fn bitor(self, rhs: Self) -> Self {
+                Self(self.0 | rhs.0)
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = Self;
+            fn bitand(self, rhs: Self) -> Self {
+                Self(self.0 & rhs.0)
+            }
+        }
+
+        impl Not for $name {
+            type Output = Self;
+            fn not(self) -> Self {
+                Self(!self.0)
+            }
+        }
+    };
+}
+
+impl_bit_ops!(Command);
+impl_bit_ops!(Status);
@@ -0,0 +1,74 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use spin::{Mutex, MutexGuard};
+use crate::config::{self, ConfigAccess};
+
+/// コンフィギュレーションアクセスは全体で一つなので、テストを一つずつ実行する
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// ファンクションごとの先頭256バイトだけを持つコンフィギュレーション空間
+pub struct FakeConfig {
+    functions: Mutex<BTreeMap<(u8, u8, u8), Function>>,
+}
+
+struct Function {
+    registers: [u32; 64],
+    /// BARのうち、書き込めるビット。0のビットは読み出し専用のフラグ
+    bar_masks: [u32; 6],
+}
+
+impl FakeConfig {
+    pub fn new() -> Self {
+        Self { functions: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn add(&self, location: (u8, u8, u8), ids: u32, class_code: u32, header_type: u8, bus_numbers: u32) {
+        let mut registers = [0u32; 64];
+        registers[0] = ids;
+        registers[2] = class_code;
+        registers[3] = (header_type as u32) << 16;
+        registers[6] = bus_numbers;
+        self.functions.lock().insert(location, Function { registers, bar_masks: [0; 6] });
+    }
+
+    /// `value`の下位ビットがフラグ、`mask`が書き込めるアドレスのビット
+    pub fn set_bar(&self, location: (u8, u8, u8), index: usize, value: u32, mask: u32) {
+        let mut functions = self.functions.lock();
+        let function = functions.get_mut(&location).unwrap();
+        function.registers[4 + index] = value;
+        function.bar_masks[index] = mask;
+    }
+
+    /// これ以降のコンフィギュレーションアクセスをこの偽物に向ける
+    pub fn install(self) -> MutexGuard<'static, ()> {
+        let lock = LOCK.lock();
+        config::set_access(Box::leak(Box::new(self)));
+        lock
+    }
+}
+
+impl ConfigAccess for FakeConfig {
+    fn read(&self, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        match self.functions.lock().get(&(bus, device, function)) {
+            Some(function) => function.registers.get(offset as usize / 4).copied().unwrap_or(0),
+            None => 0xFFFF_FFFF,
+        }
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, offset: u16, value: u32) {
+        if let Some(function) = self.functions.lock().get_mut(&(bus, device, function)) {
+            let index = offset as usize / 4;
+            function.registers[index] = match index {
+                4..=9 => {
+                    let mask = function.bar_masks[index - 4];
+                    value & mask | function.registers[index] & !mask
+                }
+                _ => value,
+            };
+        }
+    }
+
+    fn config_size(&self) -> usize {
+        config::LEGACY_CONFIG_SIZE
+    }
+}
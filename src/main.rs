@@ -1,21 +1,75 @@
 // src/main.rs
 
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+/// size of the data disk created when the given image does not exist yet
+const DATA_DISK_SIZE: u64 = 64 * 1024 * 1024;
+
 fn main() {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
 
-    // choose whether to start the UEFI or BIOS image
-    let uefi = true;
+    // `--data-disk <image>` attaches an extra raw image as a virtio-blk device
+    // `--bios` boots the BIOS image from the IDE primary master instead of UEFI
+    // `--q35` emulates the PCI Express based q35 machine instead of the default i440fx
+    // `--usb` adds an xHCI controller with a USB keyboard and mouse attached
+    // `--usb-disk <image>` attaches a raw image as a USB mass storage device
+    let mut data_disk: Option<PathBuf> = None;
+    let mut usb_disk: Option<PathBuf> = None;
+    let mut uefi = true;
+    let mut q35 = false;
+    let mut usb = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-disk" => data_disk = Some(args.next().expect("--data-disk requires a path").into()),
+            "--bios" => uefi = false,
+            "--q35" => q35 = true,
+            "--usb" => usb = true,
+            "--usb-disk" => usb_disk = Some(args.next().expect("--usb-disk requires a path").into()),
+            _ => panic!("unknown argument: {arg}"),
+        }
+    }
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
+    if q35 {
+        cmd.arg("-machine").arg("q35");
+    }
     if uefi {
         cmd.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
         cmd.arg("-drive").arg(format!("format=raw,file={uefi_path}"));
     } else {
         cmd.arg("-drive").arg(format!("format=raw,file={bios_path}"));
     }
+    if let Some(path) = data_disk {
+        create_data_disk(&path);
+        cmd.arg("-drive").arg(format!("if=none,id=data,format=raw,file={}", path.display()));
+        cmd.arg("-device").arg("virtio-blk-pci,drive=data");
+    }
+    if usb || usb_disk.is_some() {
+        cmd.arg("-device").arg("qemu-xhci,id=xhci");
+    }
+    if usb {
+        cmd.arg("-device").arg("usb-kbd,bus=xhci.0");
+        cmd.arg("-device").arg("usb-mouse,bus=xhci.0");
+    }
+    if let Some(path) = usb_disk {
+        create_data_disk(&path);
+        cmd.arg("-drive").arg(format!("if=none,id=stick,format=raw,file={}", path.display()));
+        cmd.arg("-device").arg("usb-storage,bus=xhci.0,drive=stick");
+    }
     cmd.arg("-serial").arg("stdio");
     let mut child = cmd.spawn().unwrap();
     child.wait().unwrap();
-}
\ No newline at end of file
+}
+
+// keep the contents of an existing image, otherwise create an empty one
+fn create_data_disk(path: &Path) {
+    if path.exists() {
+        return;
+    }
+    let file = OpenOptions::new().write(true).create_new(true).open(path).unwrap();
+    file.set_len(DATA_DISK_SIZE).unwrap();
+}
@@ -0,0 +1,116 @@
+// test_runner/src/main.rs
+//
+// cargo runner for the kernel (see `kernel/.cargo/config.toml`): turns the kernel ELF into a
+// BIOS disk image and boots it in QEMU. Test binaries get the hardware they expect and their
+// `kernel::exit_qemu` code is turned back into a process exit code.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+use std::time::{Duration, Instant};
+
+/// `kernel::QemuExitCode::Success` as reported by QEMU, which exits with `(value << 1) | 1`
+const SUCCESS_EXIT_CODE: i32 = (0x10 << 1) | 1;
+/// tests that neither pass nor fail within this time (e.g. a deadlock) are killed
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// an xHCI controller with a USB keyboard and mouse attached
+const USB_DEVICES: &[&str] = &[
+    "-device", "qemu-xhci,id=xhci",
+    "-device", "usb-kbd,bus=xhci.0",
+    "-device", "usb-mouse,bus=xhci.0",
+];
+
+/// size of the blank disks attached for the storage tests
+const SCRATCH_DISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// extra QEMU arguments for the tests that check particular hardware
+fn machine_args(test: &str, kernel: &Path) -> Vec<String> {
+    match test {
+        "pci_q35" => strings(&["-machine", "q35"]),
+        "usb" => {
+            let mut args = strings(USB_DEVICES);
+            args.extend(strings(&[
+                "-drive", &scratch_drive(kernel, "stick"),
+                "-device", "usb-storage,bus=xhci.0,drive=stick",
+            ]));
+            args
+        }
+        // one blank disk on each storage controller
+        "storage" => strings(&[
+            "-device", "ahci,id=ahci",
+            "-drive", &scratch_drive(kernel, "sata"),
+            "-device", "ide-hd,drive=sata,bus=ahci.0",
+            "-drive", &scratch_drive(kernel, "nvme"),
+            "-device", "nvme,serial=scratch,drive=nvme",
+            "-drive", &scratch_drive(kernel, "virtio"),
+            "-device", "virtio-blk-pci,drive=virtio",
+        ]),
+        _ => Vec::new(),
+    }
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// creates an empty raw image next to the kernel and returns a `-drive` value for it
+///
+/// the image is recreated for every run so that a test never sees what the last one wrote
+fn scratch_drive(kernel: &Path, id: &str) -> String {
+    let path = kernel.with_extension(format!("{}.img", id));
+    std::fs::File::create(&path)
+        .and_then(|file| file.set_len(SCRATCH_DISK_SIZE))
+        .expect("failed to create a scratch disk image");
+    format!("if=none,id={},format=raw,file={}", id, path.display())
+}
+
+fn main() -> ExitCode {
+    let kernel = PathBuf::from(std::env::args().nth(1).expect("usage: test_runner <kernel executable>"));
+    let image = kernel.with_extension("img");
+    bootloader::BiosBoot::new(&kernel)
+        .create_disk_image(&image)
+        .expect("failed to create the disk image");
+
+    let mut cmd = Command::new("qemu-system-x86_64");
+    cmd.arg("-drive").arg(format!("format=raw,file={}", image.display()));
+    cmd.arg("-serial").arg("stdio");
+    if !is_test(&kernel) {
+        let status = cmd.status().expect("failed to run qemu-system-x86_64");
+        return if status.success() { ExitCode::SUCCESS } else { ExitCode::FAILURE };
+    }
+
+    cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    cmd.arg("-display").arg("none");
+    cmd.args(machine_args(&test_name(&kernel), &kernel));
+    let mut child = cmd.spawn().expect("failed to run qemu-system-x86_64");
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            child.kill().unwrap();
+            eprintln!("test timed out after {} seconds", TIMEOUT.as_secs());
+            return ExitCode::FAILURE;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    match status.code() {
+        Some(SUCCESS_EXIT_CODE) => ExitCode::SUCCESS,
+        _ => ExitCode::FAILURE,
+    }
+}
+
+/// cargo builds test executables into `deps/`
+fn is_test(kernel: &Path) -> bool {
+    kernel.parent().and_then(Path::file_name) == Some("deps".as_ref())
+}
+
+/// test executables are named `<test>-<hash>`
+fn test_name(kernel: &Path) -> String {
+    let stem = kernel.file_stem().unwrap().to_string_lossy();
+    match stem.rsplit_once('-') {
+        Some((name, hash)) if hash.chars().all(|c| c.is_ascii_hexdigit()) => name.to_string(),
+        _ => stem.to_string(),
+    }
+}